@@ -1,3 +1,13 @@
 pub mod aabb;
+pub mod accelerator;
+pub mod bvh;
+pub mod closest_point;
+pub mod instance;
+pub mod mesh_intersection;
 pub mod octree;
+pub mod packet;
 pub mod ray;
+pub mod region;
+pub mod spawn;
+#[cfg(test)]
+pub(crate) mod test_support;
@@ -1,4 +1,8 @@
-use crate::scene::{engine::Vector3d, entities::Triangle};
+use crate::scene::{
+    engine::{widen, CompactVector, Float, Vector3d},
+    mesh::Mesh,
+    transform::Transform,
+};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Aabb {
@@ -22,15 +26,15 @@ impl Aabb {
         }
     }
 
-    pub fn from_triangle<'a>(triangle: &Triangle) -> Aabb {
-        let min_x = f64::min(triangle.v1.x, f64::min(triangle.v2.x, triangle.v3.x));
-        let max_x = f64::max(triangle.v1.x, f64::max(triangle.v2.x, triangle.v3.x));
+    pub fn from_triangle([v1, v2, v3]: &[Vector3d; 3]) -> Aabb {
+        let min_x = f64::min(v1.x, f64::min(v2.x, v3.x));
+        let max_x = f64::max(v1.x, f64::max(v2.x, v3.x));
 
-        let min_y = f64::min(triangle.v1.y, f64::min(triangle.v2.y, triangle.v3.y));
-        let max_y = f64::max(triangle.v1.y, f64::max(triangle.v2.y, triangle.v3.y));
+        let min_y = f64::min(v1.y, f64::min(v2.y, v3.y));
+        let max_y = f64::max(v1.y, f64::max(v2.y, v3.y));
 
-        let min_z = f64::min(triangle.v1.z, f64::min(triangle.v2.z, triangle.v3.z));
-        let max_z = f64::max(triangle.v1.z, f64::max(triangle.v2.z, triangle.v3.z));
+        let min_z = f64::min(v1.z, f64::min(v2.z, v3.z));
+        let max_z = f64::max(v1.z, f64::max(v2.z, v3.z));
 
         Aabb {
             min_coords: Vector3d {
@@ -46,6 +50,175 @@ impl Aabb {
         }
     }
 
+    /// A box containing nothing, the union of it with any other box is that box
+    pub fn empty() -> Aabb {
+        Aabb::new(
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        )
+    }
+
+    pub fn centre(&self) -> Vector3d {
+        (self.min_coords + self.max_coords) / 2.0
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let size = self.max_coords - self.min_coords;
+
+        if size.x < 0.0 || size.y < 0.0 || size.z < 0.0 {
+            return 0.0;
+        }
+
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    /// The smallest box containing this box and the point
+    pub fn expanded_to(&self, point: Vector3d) -> Aabb {
+        Aabb::new(
+            f64::min(self.min_coords.x, point.x),
+            f64::max(self.max_coords.x, point.x),
+            f64::min(self.min_coords.y, point.y),
+            f64::max(self.max_coords.y, point.y),
+            f64::min(self.min_coords.z, point.z),
+            f64::max(self.max_coords.z, point.z),
+        )
+    }
+
+    /// The smallest box containing both boxes
+    pub fn union(&self, other: &Self) -> Aabb {
+        Aabb::new(
+            f64::min(self.min_coords.x, other.min_coords.x),
+            f64::max(self.max_coords.x, other.max_coords.x),
+            f64::min(self.min_coords.y, other.min_coords.y),
+            f64::max(self.max_coords.y, other.max_coords.y),
+            f64::min(self.min_coords.z, other.min_coords.z),
+            f64::max(self.max_coords.z, other.max_coords.z),
+        )
+    }
+
+    /// The box both boxes overlap in, its min is past its max on some axis if they don't
+    pub fn intersection(&self, other: &Self) -> Aabb {
+        Aabb::new(
+            f64::max(self.min_coords.x, other.min_coords.x),
+            f64::min(self.max_coords.x, other.max_coords.x),
+            f64::max(self.min_coords.y, other.min_coords.y),
+            f64::min(self.max_coords.y, other.max_coords.y),
+            f64::max(self.min_coords.z, other.min_coords.z),
+            f64::min(self.max_coords.z, other.max_coords.z),
+        )
+    }
+
+    /// The box covering everywhere one of the mesh's triangles goes over the frame
+    pub fn from_moving_triangle(mesh: &Mesh, triangle: usize) -> Aabb {
+        let vertices = mesh.vertices(triangle);
+        let aabb = Aabb::from_triangle(&vertices);
+
+        let Some(motion) = mesh.motion(triangle) else {
+            return aabb;
+        };
+
+        let start = aabb.transformed(&motion.transform_at(0.0));
+        let end = aabb.transformed(&motion.transform_at(1.0));
+
+        if !motion.rotates() {
+            // Every corner moves in a straight line from where it starts to where it ends
+            return start.union(&end);
+        }
+
+        // Turning corners swing out in arcs. Each one stays within its largest scaled distance
+        // from the translation, which moves in a straight line.
+        let [(start_translation, _, start_scale), (end_translation, _, end_scale)] = motion.parts();
+        let scaled = |v: Vector3d, scale: Vector3d| Vector3d {
+            x: v.x * scale.x,
+            y: v.y * scale.y,
+            z: v.z * scale.z,
+        };
+        let radius = vertices
+            .iter()
+            .map(|&v| {
+                f64::max(
+                    scaled(v, start_scale).length(),
+                    scaled(v, end_scale).length(),
+                )
+            })
+            .fold(0.0, f64::max)
+            * (1.0 + 1e-9);
+        let reach = Vector3d {
+            x: radius,
+            y: radius,
+            z: radius,
+        };
+        let path = Aabb::empty()
+            .expanded_to(start_translation)
+            .expanded_to(end_translation);
+
+        Aabb {
+            min_coords: path.min_coords - reach,
+            max_coords: path.max_coords + reach,
+        }
+        .union(&start)
+        .union(&end)
+    }
+
+    /// A box around this one once it's been moved by the transform, grown by however far
+    /// rounding could move its corners so it still contains everything this one did
+    pub fn transformed(&self, transform: &Transform) -> Aabb {
+        let no_error = Vector3d {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let mut aabb = Aabb::empty();
+
+        for corner in 0..8 {
+            let pick = |bit: usize, min: f64, max: f64| if corner & bit == 0 { min } else { max };
+            let point = Vector3d {
+                x: pick(1, self.min_coords.x, self.max_coords.x),
+                y: pick(2, self.min_coords.y, self.max_coords.y),
+                z: pick(4, self.min_coords.z, self.max_coords.z),
+            };
+
+            let moved = transform.transform_point(point);
+            let error = transform.point_error(point, no_error);
+
+            aabb = aabb.expanded_to(moved - error).expanded_to(moved + error);
+        }
+
+        aabb
+    }
+
+    /// Whether the other box is entirely inside this one
+    pub fn contains(&self, other: &Self) -> bool {
+        other.min_coords.x >= self.min_coords.x
+            && other.max_coords.x <= self.max_coords.x
+            && other.min_coords.y >= self.min_coords.y
+            && other.max_coords.y <= self.max_coords.y
+            && other.min_coords.z >= self.min_coords.z
+            && other.max_coords.z <= self.max_coords.z
+    }
+
+    /// Squared distance from the point to the nearest point of the box, 0 if it's inside
+    pub fn distance_squared_to(&self, point: Vector3d) -> f64 {
+        let dx = f64::max(
+            f64::max(self.min_coords.x - point.x, point.x - self.max_coords.x),
+            0.0,
+        );
+        let dy = f64::max(
+            f64::max(self.min_coords.y - point.y, point.y - self.max_coords.y),
+            0.0,
+        );
+        let dz = f64::max(
+            f64::max(self.min_coords.z - point.z, point.z - self.max_coords.z),
+            0.0,
+        );
+
+        dx * dx + dy * dy + dz * dz
+    }
+
     pub fn intersects(self, other: &Self) -> bool {
         if self.max_coords.x < other.min_coords.x || self.min_coords.x > other.max_coords.x {
             return false;
@@ -56,6 +229,62 @@ impl Aabb {
         if self.max_coords.z < other.min_coords.z || self.min_coords.z > other.max_coords.z {
             return false;
         }
-        return true;
+        true
+    }
+}
+
+/// An `Aabb` stored at `Float` precision, as the acceleration structures keep their node bounds.
+/// Coordinates are rounded outwards so it always contains the box it was made from.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct CompactAabb {
+    pub min_coords: CompactVector,
+    pub max_coords: CompactVector,
+}
+
+/// The nearest `Float` at or below `value`
+fn round_down(value: f64) -> Float {
+    let rounded = value as Float;
+
+    if widen(rounded) > value {
+        rounded.next_down()
+    } else {
+        rounded
+    }
+}
+
+/// The nearest `Float` at or above `value`
+fn round_up(value: f64) -> Float {
+    let rounded = value as Float;
+
+    if widen(rounded) < value {
+        rounded.next_up()
+    } else {
+        rounded
+    }
+}
+
+impl From<Aabb> for CompactAabb {
+    fn from(aabb: Aabb) -> Self {
+        CompactAabb {
+            min_coords: CompactVector {
+                x: round_down(aabb.min_coords.x),
+                y: round_down(aabb.min_coords.y),
+                z: round_down(aabb.min_coords.z),
+            },
+            max_coords: CompactVector {
+                x: round_up(aabb.max_coords.x),
+                y: round_up(aabb.max_coords.y),
+                z: round_up(aabb.max_coords.z),
+            },
+        }
+    }
+}
+
+impl From<CompactAabb> for Aabb {
+    fn from(aabb: CompactAabb) -> Self {
+        Aabb {
+            min_coords: aabb.min_coords.into(),
+            max_coords: aabb.max_coords.into(),
+        }
     }
 }
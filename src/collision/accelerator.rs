@@ -0,0 +1,204 @@
+use crate::scene::{engine::Vector3d, mesh::Mesh};
+
+use super::{
+    aabb::Aabb,
+    bvh::Bvh,
+    closest_point::{
+        closest_point_in_octree, sort_closest_points, triangles_within_octree, ClosestPoint,
+    },
+    octree::{Octree, OctreeBuildOptions},
+    packet::{self, PacketHits, PACKET_WIDTH},
+    ray::{Ray, RayTriangleIntersectionResult, TriangleTest},
+    region::{triangles_in_region_octree, Region},
+};
+
+/// A spatial structure over the scene's triangles that can answer ray queries
+/// faster than testing every triangle. Ray queries also see the mesh's analytic shapes,
+/// the point and region queries only look at its triangles. Shapes without a box, like planes,
+/// are kept out of the tree and tested against every ray before it.
+pub trait Accelerator {
+    /// The closest intersection along the ray with a t value below `max_t`
+    fn closest_hit(&self, ray: &Ray, max_t: f64) -> Option<RayTriangleIntersectionResult>;
+
+    /// Whether anything at all intersects the ray before `max_t`, which can stop
+    /// at the first hit it finds so is cheaper than `closest_hit` for shadow rays.
+    fn any_hit(&self, ray: &Ray, max_t: f64) -> bool;
+
+    /// `closest_hit` for several rays at once. Rays heading the same way, like neighbouring
+    /// primary rays, are traced together as a packet, other rays are traced one at a time.
+    fn closest_hits(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> PacketHits;
+
+    /// `any_hit` for several rays at once, traced together as a packet where they can be
+    fn any_hits(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> [bool; PACKET_WIDTH];
+
+    /// Every intersection along the ray before `max_t`, nearest first,
+    /// with each triangle appearing at most once.
+    fn all_hits(&self, ray: &Ray, max_t: f64) -> Vec<RayTriangleIntersectionResult>;
+
+    /// The nearest point on any triangle to `point`, ignoring triangles farther than `max_distance`.
+    /// Moving triangles are where they are at `time`.
+    fn closest_point(&self, point: Vector3d, max_distance: f64, time: f64) -> Option<ClosestPoint>;
+
+    /// The nearest point on every triangle with some part within `radius` of `point`,
+    /// nearest first, with each triangle appearing at most once.
+    fn triangles_within(&self, point: Vector3d, radius: f64, time: f64) -> Vec<ClosestPoint>;
+
+    /// Indices of every triangle with some part inside the region, in ascending order
+    fn triangles_in_region(&self, region: &Region, time: f64) -> Vec<usize>;
+
+    /// A box containing every primitive in the structure's tree
+    fn bounds(&self) -> Aabb;
+
+    /// The mesh the structure was built over, a hit's `triangle_index` is one of its triangles
+    fn mesh(&self) -> &Mesh;
+}
+
+/// Which acceleration structure to build for a scene
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AcceleratorKind {
+    Octree(OctreeBuildOptions),
+    Bvh,
+}
+
+/// The acceleration structure a scene was built with, so each scene can pick
+/// whichever suits its geometry best.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccelerationStructure {
+    Octree(Octree),
+    Bvh(Bvh),
+}
+
+impl AccelerationStructure {
+    pub fn kind(&self) -> AcceleratorKind {
+        match self {
+            AccelerationStructure::Octree(octree) => AcceleratorKind::Octree(octree.options),
+            AccelerationStructure::Bvh(_) => AcceleratorKind::Bvh,
+        }
+    }
+
+    /// Switch the ray-triangle test used when tracing rays through the structure
+    pub fn set_triangle_test(&mut self, test: TriangleTest) {
+        match self {
+            AccelerationStructure::Octree(octree) => octree.triangle_test = test,
+            AccelerationStructure::Bvh(bvh) => bvh.triangle_test = test,
+        }
+    }
+
+    /// Primitives kept out of the structure's tree because they have no box, see `Hittable::bounds`
+    pub fn unbounded(&self) -> &[usize] {
+        match self {
+            AccelerationStructure::Octree(octree) => &octree.unbounded,
+            AccelerationStructure::Bvh(bvh) => &bvh.unbounded,
+        }
+    }
+
+    fn inner(&self) -> &dyn Accelerator {
+        match self {
+            AccelerationStructure::Octree(octree) => octree,
+            AccelerationStructure::Bvh(bvh) => bvh,
+        }
+    }
+}
+
+impl Accelerator for AccelerationStructure {
+    fn closest_hit(&self, ray: &Ray, max_t: f64) -> Option<RayTriangleIntersectionResult> {
+        self.inner().closest_hit(ray, max_t)
+    }
+
+    fn any_hit(&self, ray: &Ray, max_t: f64) -> bool {
+        self.inner().any_hit(ray, max_t)
+    }
+
+    fn closest_hits(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> PacketHits {
+        self.inner().closest_hits(rays, max_t)
+    }
+
+    fn any_hits(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> [bool; PACKET_WIDTH] {
+        self.inner().any_hits(rays, max_t)
+    }
+
+    fn all_hits(&self, ray: &Ray, max_t: f64) -> Vec<RayTriangleIntersectionResult> {
+        self.inner().all_hits(ray, max_t)
+    }
+
+    fn closest_point(&self, point: Vector3d, max_distance: f64, time: f64) -> Option<ClosestPoint> {
+        self.inner().closest_point(point, max_distance, time)
+    }
+
+    fn triangles_within(&self, point: Vector3d, radius: f64, time: f64) -> Vec<ClosestPoint> {
+        self.inner().triangles_within(point, radius, time)
+    }
+
+    fn triangles_in_region(&self, region: &Region, time: f64) -> Vec<usize> {
+        self.inner().triangles_in_region(region, time)
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.inner().bounds()
+    }
+
+    fn mesh(&self) -> &Mesh {
+        self.inner().mesh()
+    }
+}
+
+impl Accelerator for Octree {
+    fn closest_hit(&self, ray: &Ray, max_t: f64) -> Option<RayTriangleIntersectionResult> {
+        ray.intersect_with_octree(self, max_t)
+    }
+
+    fn any_hit(&self, ray: &Ray, max_t: f64) -> bool {
+        ray.intersects_anything_in_octree(self, max_t)
+    }
+
+    fn closest_hits(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> PacketHits {
+        packet::closest_hits(self, rays, max_t)
+    }
+
+    fn any_hits(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> [bool; PACKET_WIDTH] {
+        packet::any_hits(self, rays, max_t)
+    }
+
+    fn all_hits(&self, ray: &Ray, max_t: f64) -> Vec<RayTriangleIntersectionResult> {
+        let mut hits = ray.intersect_all_in_octree(self, max_t);
+        sort_hits(&mut hits);
+        hits
+    }
+
+    fn closest_point(&self, point: Vector3d, max_distance: f64, time: f64) -> Option<ClosestPoint> {
+        closest_point_in_octree(self, point, max_distance, time)
+    }
+
+    fn triangles_within(&self, point: Vector3d, radius: f64, time: f64) -> Vec<ClosestPoint> {
+        let mut found = triangles_within_octree(self, point, radius, time);
+        sort_closest_points(&mut found);
+        found
+    }
+
+    fn triangles_in_region(&self, region: &Region, time: f64) -> Vec<usize> {
+        let mut found = triangles_in_region_octree(self, region, time);
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.nodes[0].aabb.into()
+    }
+
+    fn mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+}
+
+/// Order hits nearest first and drop repeats of the same hit on the same triangle,
+/// which structures that store a triangle in several places can report. A shape can be
+/// hit more than once at different distances.
+pub(crate) fn sort_hits(hits: &mut Vec<RayTriangleIntersectionResult>) {
+    hits.sort_by(|a, b| {
+        a.t.total_cmp(&b.t)
+            .then(a.instance.cmp(&b.instance))
+            .then(a.triangle_index.cmp(&b.triangle_index))
+    });
+    hits.dedup_by_key(|hit| (hit.t.to_bits(), hit.instance, hit.triangle_index));
+}
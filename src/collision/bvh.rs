@@ -0,0 +1,689 @@
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::scene::{engine::Vector3d, mesh::Mesh, shape::Hittable};
+
+use super::{
+    aabb::{Aabb, CompactAabb},
+    accelerator::{sort_hits, Accelerator},
+    closest_point::{closest_point_on_triangle, sort_closest_points, ClosestPoint},
+    packet::{self, PacketHits, PACKET_WIDTH},
+    ray::{Ray, RayTriangleIntersectionResult, TriangleTest},
+    region::Region,
+};
+
+/// Number of buckets centroids are sorted into when looking for the cheapest split
+static SAH_BINS: usize = 12;
+
+/// Nodes with this many triangles or fewer become leaves if splitting them wouldn't pay off
+static MAX_LEAF_TRIANGLES: usize = 4;
+
+/// Relative cost of stepping into a child node compared to testing one triangle
+static TRAVERSAL_COST: f64 = 1.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BvhNode {
+    pub aabb: CompactAabb,
+    /// For leaves the first entry in `triangle_indices`,
+    /// otherwise the index of the left child, with the right child straight after it.
+    pub first: usize,
+    /// Number of triangles (or instances, in an `InstanceTree`) in a leaf, 0 for interior nodes
+    pub triangle_count: usize,
+}
+
+/// A bounding volume hierarchy built with the surface area heuristic.
+/// Each node is split wherever the expected cost of tracing a ray through its two children,
+/// estimated from the children's surface areas and triangle counts, is lowest.
+/// Unlike the octree every triangle is in exactly one leaf and nodes can overlap.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    /// The mesh is shared with the scene, so triangle indices match the octree's
+    pub mesh: Arc<Mesh>,
+    /// Indices of the mesh's triangles ordered so every leaf's triangles are contiguous
+    pub triangle_indices: Vec<usize>,
+    /// Primitives with no box, like planes, which aren't in any leaf and are tested by every ray
+    pub unbounded: Vec<usize>,
+    pub triangle_test: TriangleTest,
+}
+
+/// A bucket of triangles or instances used while evaluating split candidates
+#[derive(Copy, Clone)]
+struct SahBin {
+    aabb: Aabb,
+    count: usize,
+}
+
+impl Bvh {
+    pub fn build(mesh: Arc<Mesh>) -> Bvh {
+        let bounds: Vec<Option<Aabb>> = (0..mesh.len())
+            .into_par_iter()
+            .map(|index| mesh.primitive(index).bounds())
+            .collect();
+
+        let (bounded, unbounded): (Vec<usize>, Vec<usize>) =
+            (0..mesh.len()).partition(|&index| bounds[index].is_some());
+        let triangle_aabbs: Vec<Aabb> = bounds.into_iter().flatten().collect();
+
+        let (nodes, indices) = build_nodes(&triangle_aabbs);
+        let triangle_indices = indices.into_iter().map(|index| bounded[index]).collect();
+
+        Bvh {
+            nodes,
+            mesh,
+            triangle_indices,
+            unbounded,
+            triangle_test: TriangleTest::default(),
+        }
+    }
+}
+
+/// Nodes over a list of boxes, along with the boxes' indices ordered so every leaf's are
+/// contiguous. Leaves hold triangles in a `Bvh` and whole instances in an `InstanceTree`.
+/// The two halves of each large node are built in parallel, the finished tree is the same
+/// however many threads there are.
+pub(super) fn build_nodes(aabbs: &[Aabb]) -> (Vec<BvhNode>, Vec<usize>) {
+    let mut nodes = vec![BvhNode {
+        aabb: Aabb::empty().into(),
+        first: 0,
+        triangle_count: 0,
+    }];
+    let mut indices: Vec<usize> = (0..aabbs.len()).collect();
+
+    if !aabbs.is_empty() {
+        let builder = BvhBuilder {
+            aabbs,
+            centroids: aabbs.par_iter().map(|aabb| aabb.centre()).collect(),
+        };
+        let root = builder.build_node(&mut indices, 0);
+        flatten(root, 0, &mut nodes);
+    }
+
+    (nodes, indices)
+}
+
+/// Nodes with fewer boxes than this build both their children on the current thread,
+/// splitting them further across threads costs more than it saves.
+static PARALLEL_BUILD_THRESHOLD: usize = 1024;
+
+struct BvhBuilder<'a> {
+    aabbs: &'a [Aabb],
+    centroids: Vec<Vector3d>,
+}
+
+/// A node while the tree is being built, before it's laid out in `Bvh::nodes`
+struct BuildNode {
+    aabb: Aabb,
+    /// Where the node's boxes start in the finished list of indices
+    first: usize,
+    count: usize,
+    children: Option<Box<[BuildNode; 2]>>,
+}
+
+impl BvhBuilder<'_> {
+    /// The node over `indices`, which start at `first` in the full list. The indices are
+    /// reordered in place so each child's are contiguous.
+    fn build_node(&self, indices: &mut [usize], first: usize) -> BuildNode {
+        let mut aabb = Aabb::empty();
+        let mut centroid_aabb = Aabb::empty();
+
+        for &index in indices.iter() {
+            aabb = aabb.union(&self.aabbs[index]);
+            centroid_aabb = centroid_aabb.expanded_to(self.centroids[index]);
+        }
+
+        let count = indices.len();
+        let leaf = BuildNode {
+            aabb,
+            first,
+            count,
+            children: None,
+        };
+
+        if count <= 1 {
+            return leaf;
+        }
+
+        let Some((axis, split_bin, split_cost)) =
+            self.find_best_split(indices, &aabb, &centroid_aabb)
+        else {
+            // Every centroid is in the same place so there's no way to separate them
+            return leaf;
+        };
+
+        let leaf_cost = count as f64;
+
+        if split_cost >= leaf_cost && count <= MAX_LEAF_TRIANGLES {
+            return leaf;
+        }
+
+        // Partition the triangles in place so everything left of the split comes first
+        let mut mid = 0;
+
+        for i in 0..count {
+            if bin_for(self.centroids[indices[i]], &centroid_aabb, axis) < split_bin {
+                indices.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        let (left_indices, right_indices) = indices.split_at_mut(mid);
+        let (left, right) = if count >= PARALLEL_BUILD_THRESHOLD {
+            rayon::join(
+                || self.build_node(left_indices, first),
+                || self.build_node(right_indices, first + mid),
+            )
+        } else {
+            (
+                self.build_node(left_indices, first),
+                self.build_node(right_indices, first + mid),
+            )
+        };
+
+        BuildNode {
+            children: Some(Box::new([left, right])),
+            ..leaf
+        }
+    }
+
+    /// Bin the centroids along each axis and find the split between bins with the lowest
+    /// surface area heuristic cost, returned as (axis, first bin on the right, cost).
+    fn find_best_split(
+        &self,
+        indices: &[usize],
+        aabb: &Aabb,
+        centroid_aabb: &Aabb,
+    ) -> Option<(usize, usize, f64)> {
+        let parent_area = aabb.surface_area();
+        let mut best: Option<(usize, usize, f64)> = None;
+
+        for axis in 0..3 {
+            if axis_value(centroid_aabb.max_coords, axis)
+                <= axis_value(centroid_aabb.min_coords, axis)
+            {
+                continue;
+            }
+
+            let mut bins = [SahBin {
+                aabb: Aabb::empty(),
+                count: 0,
+            }; SAH_BINS];
+
+            for &index in indices {
+                let bin = &mut bins[bin_for(self.centroids[index], centroid_aabb, axis)];
+                bin.aabb = bin.aabb.union(&self.aabbs[index]);
+                bin.count += 1;
+            }
+
+            // Sweep from the right so each split's right hand side is known up front
+            let mut right_areas = [0.0; SAH_BINS];
+            let mut right_counts = [0; SAH_BINS];
+            let mut right_aabb = Aabb::empty();
+            let mut right_count = 0;
+
+            for i in (1..SAH_BINS).rev() {
+                right_aabb = right_aabb.union(&bins[i].aabb);
+                right_count += bins[i].count;
+                right_areas[i] = right_aabb.surface_area();
+                right_counts[i] = right_count;
+            }
+
+            let mut left_aabb = Aabb::empty();
+            let mut left_count = 0;
+
+            for split in 1..SAH_BINS {
+                left_aabb = left_aabb.union(&bins[split - 1].aabb);
+                left_count += bins[split - 1].count;
+
+                if left_count == 0 || right_counts[split] == 0 {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + (left_aabb.surface_area() * left_count as f64
+                        + right_areas[split] * right_counts[split] as f64)
+                        / parent_area.max(f64::EPSILON);
+
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, split, cost));
+                }
+            }
+        }
+
+        best
+    }
+}
+
+/// Lay a built node out at `index`, with its two children next to each other at the end of
+/// `nodes` and their own children after them
+fn flatten(node: BuildNode, index: usize, nodes: &mut Vec<BvhNode>) {
+    let Some(children) = node.children else {
+        nodes[index] = BvhNode {
+            aabb: node.aabb.into(),
+            first: node.first,
+            triangle_count: node.count,
+        };
+        return;
+    };
+
+    let left = nodes.len();
+
+    for _ in 0..2 {
+        nodes.push(BvhNode {
+            aabb: Aabb::empty().into(),
+            first: 0,
+            triangle_count: 0,
+        });
+    }
+
+    nodes[index] = BvhNode {
+        aabb: node.aabb.into(),
+        first: left,
+        triangle_count: 0,
+    };
+
+    let [left_child, right_child] = *children;
+    flatten(left_child, left, nodes);
+    flatten(right_child, left + 1, nodes);
+}
+
+fn axis_value(v: Vector3d, axis: usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+fn bin_for(centroid: Vector3d, centroid_aabb: &Aabb, axis: usize) -> usize {
+    let min = axis_value(centroid_aabb.min_coords, axis);
+    let max = axis_value(centroid_aabb.max_coords, axis);
+    let relative = (axis_value(centroid, axis) - min) / (max - min);
+
+    ((relative * SAH_BINS as f64) as usize).min(SAH_BINS - 1)
+}
+
+impl Accelerator for Bvh {
+    fn closest_hit(&self, ray: &Ray, max_t: f64) -> Option<RayTriangleIntersectionResult> {
+        let mut closest: Option<RayTriangleIntersectionResult> = None;
+        let mut closest_t = max_t;
+
+        let inverse_direction = ray.inverse_direction();
+        let intersector = ray.triangle_intersector(self.triangle_test);
+
+        // Primitives with no box aren't in the tree, so every ray tests them first
+        for &index in &self.unbounded {
+            if let Some(hit) = intersector.intersect(&self.mesh, index) {
+                if hit.t < closest_t {
+                    closest_t = hit.t;
+                    closest = Some(hit);
+                }
+            }
+        }
+
+        let root_entry_t =
+            match ray.intersect_aabb_interval(&self.nodes[0].aabb.into(), &inverse_direction) {
+                Some((entry_t, _)) if !self.triangle_indices.is_empty() => entry_t,
+                _ => return closest,
+            };
+
+        // Nodes still to visit along with where the ray enters them
+        let mut stack: Vec<(usize, f64)> = Vec::with_capacity(64);
+        stack.push((0, root_entry_t));
+
+        while let Some((node_index, entry_t)) = stack.pop() {
+            // Something closer was found since this node was pushed
+            if entry_t >= closest_t {
+                continue;
+            }
+
+            let node = &self.nodes[node_index];
+
+            if node.triangle_count > 0 {
+                for &triangle_index in
+                    &self.triangle_indices[node.first..node.first + node.triangle_count]
+                {
+                    if let Some(tri) = intersector.intersect(&self.mesh, triangle_index) {
+                        if tri.t < closest_t {
+                            closest_t = tri.t;
+                            closest = Some(tri);
+                        }
+                    }
+                }
+
+                continue;
+            }
+
+            let left = node.first;
+            let right = node.first + 1;
+
+            let left_hit =
+                ray.intersect_aabb_interval(&self.nodes[left].aabb.into(), &inverse_direction);
+            let right_hit =
+                ray.intersect_aabb_interval(&self.nodes[right].aabb.into(), &inverse_direction);
+
+            // Push the farther child first so the nearer one is visited first
+            match (left_hit, right_hit) {
+                (Some((left_t, _)), Some((right_t, _))) => {
+                    if left_t < right_t {
+                        stack.push((right, right_t));
+                        stack.push((left, left_t));
+                    } else {
+                        stack.push((left, left_t));
+                        stack.push((right, right_t));
+                    }
+                }
+                (Some((left_t, _)), None) => stack.push((left, left_t)),
+                (None, Some((right_t, _))) => stack.push((right, right_t)),
+                (None, None) => {}
+            }
+        }
+
+        closest
+    }
+
+    fn any_hit(&self, ray: &Ray, max_t: f64) -> bool {
+        let inverse_direction = ray.inverse_direction();
+        let intersector = ray.triangle_intersector(self.triangle_test);
+
+        if self.unbounded.iter().any(|&index| {
+            intersector
+                .intersect(&self.mesh, index)
+                .is_some_and(|hit| hit.t < max_t)
+        }) {
+            return true;
+        }
+
+        if self.triangle_indices.is_empty() {
+            return false;
+        }
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+
+            match ray.intersect_aabb_interval(&node.aabb.into(), &inverse_direction) {
+                Some((entry_t, _)) if entry_t < max_t => {}
+                _ => continue,
+            }
+
+            if node.triangle_count > 0 {
+                for &triangle_index in
+                    &self.triangle_indices[node.first..node.first + node.triangle_count]
+                {
+                    if let Some(tri) = intersector.intersect(&self.mesh, triangle_index) {
+                        if tri.t < max_t {
+                            return true;
+                        }
+                    }
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+
+        false
+    }
+
+    fn closest_hits(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> PacketHits {
+        packet::closest_hits(self, rays, max_t)
+    }
+
+    fn any_hits(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> [bool; PACKET_WIDTH] {
+        packet::any_hits(self, rays, max_t)
+    }
+
+    fn all_hits(&self, ray: &Ray, max_t: f64) -> Vec<RayTriangleIntersectionResult> {
+        let inverse_direction = ray.inverse_direction();
+        let intersector = ray.triangle_intersector(self.triangle_test);
+        let mut hits: Vec<RayTriangleIntersectionResult> = self
+            .unbounded
+            .iter()
+            .flat_map(|&index| intersector.intersect_all(&self.mesh, index))
+            .filter(|hit| hit.t < max_t)
+            .collect();
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+
+        if !self.triangle_indices.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+
+            match ray.intersect_aabb_interval(&node.aabb.into(), &inverse_direction) {
+                Some((entry_t, _)) if entry_t < max_t => {}
+                _ => continue,
+            }
+
+            if node.triangle_count > 0 {
+                for &triangle_index in
+                    &self.triangle_indices[node.first..node.first + node.triangle_count]
+                {
+                    hits.extend(
+                        intersector
+                            .intersect_all(&self.mesh, triangle_index)
+                            .into_iter()
+                            .filter(|hit| hit.t < max_t),
+                    );
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+
+        sort_hits(&mut hits);
+        hits
+    }
+
+    fn closest_point(&self, point: Vector3d, max_distance: f64, time: f64) -> Option<ClosestPoint> {
+        if self.triangle_indices.is_empty() {
+            return None;
+        }
+
+        let mut closest: Option<ClosestPoint> = None;
+        let mut closest_distance_squared = max_distance * max_distance;
+
+        // Nodes still to visit along with how far away their boxes are
+        let mut stack: Vec<(usize, f64)> = Vec::with_capacity(64);
+        stack.push((0, Aabb::from(self.nodes[0].aabb).distance_squared_to(point)));
+
+        while let Some((node_index, distance_squared)) = stack.pop() {
+            if distance_squared > closest_distance_squared {
+                continue;
+            }
+
+            let node = &self.nodes[node_index];
+
+            if node.triangle_count > 0 {
+                for &triangle_index in
+                    &self.triangle_indices[node.first..node.first + node.triangle_count]
+                {
+                    // Only triangles, the mesh's shapes aren't covered by point queries
+                    if !self.mesh.is_triangle(triangle_index) {
+                        continue;
+                    }
+
+                    let candidate = closest_point_on_triangle(
+                        &self.mesh.vertices_at(triangle_index, time),
+                        triangle_index,
+                        point,
+                    );
+
+                    if candidate.distance * candidate.distance <= closest_distance_squared
+                        && closest.is_none_or(|c| candidate.distance < c.distance)
+                    {
+                        closest_distance_squared = candidate.distance * candidate.distance;
+                        closest = Some(candidate);
+                    }
+                }
+
+                continue;
+            }
+
+            let left = node.first;
+            let right = node.first + 1;
+
+            let left_distance_squared =
+                Aabb::from(self.nodes[left].aabb).distance_squared_to(point);
+            let right_distance_squared =
+                Aabb::from(self.nodes[right].aabb).distance_squared_to(point);
+
+            // Push the farther child first so the nearer one is visited first
+            if left_distance_squared < right_distance_squared {
+                stack.push((right, right_distance_squared));
+                stack.push((left, left_distance_squared));
+            } else {
+                stack.push((left, left_distance_squared));
+                stack.push((right, right_distance_squared));
+            }
+        }
+
+        closest
+    }
+
+    fn triangles_within(&self, point: Vector3d, radius: f64, time: f64) -> Vec<ClosestPoint> {
+        let radius_squared = radius * radius;
+        let mut found = vec![];
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+
+        if !self.triangle_indices.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+
+            if Aabb::from(node.aabb).distance_squared_to(point) > radius_squared {
+                continue;
+            }
+
+            if node.triangle_count > 0 {
+                for &triangle_index in
+                    &self.triangle_indices[node.first..node.first + node.triangle_count]
+                {
+                    // Only triangles, the mesh's shapes aren't covered by point queries
+                    if !self.mesh.is_triangle(triangle_index) {
+                        continue;
+                    }
+
+                    let candidate = closest_point_on_triangle(
+                        &self.mesh.vertices_at(triangle_index, time),
+                        triangle_index,
+                        point,
+                    );
+
+                    if candidate.distance <= radius {
+                        found.push(candidate);
+                    }
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+
+        sort_closest_points(&mut found);
+        found
+    }
+
+    fn triangles_in_region(&self, region: &Region, time: f64) -> Vec<usize> {
+        let mut found = vec![];
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+
+        if !self.triangle_indices.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+
+            if !region.overlaps_aabb(&node.aabb.into()) {
+                continue;
+            }
+
+            if node.triangle_count > 0 {
+                for &triangle_index in
+                    &self.triangle_indices[node.first..node.first + node.triangle_count]
+                {
+                    if !self.mesh.is_triangle(triangle_index) {
+                        continue;
+                    }
+
+                    if region.intersects_triangle(&self.mesh.vertices_at(triangle_index, time)) {
+                        found.push(triangle_index);
+                    }
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+
+        found.sort();
+        found
+    }
+
+    fn bounds(&self) -> Aabb {
+        self.nodes[0].aabb.into()
+    }
+
+    fn mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::test_support::*;
+    use crate::scene::sampling::Sampler;
+
+    use super::*;
+
+    #[test]
+    fn test_bvh_closest_hit_matches_brute_force() {
+        let triangles = mesh(&random_triangles(&mut Sampler::new(2)));
+        let mut bvh = Bvh::build(triangles.clone());
+
+        for index in 0..triangles.len() {
+            assert!(bvh
+                .bounds()
+                .contains(&Aabb::from_triangle(&triangles.vertices(index))));
+        }
+
+        assert_matches_brute_force(&bvh, &triangles);
+
+        bvh.triangle_test = TriangleTest::Watertight;
+        assert_matches_brute_force_with(&bvh, &triangles, TriangleTest::Watertight);
+    }
+
+    #[test]
+    fn test_parallel_bvh_build_matches_serial_build() {
+        // Enough triangles that the top of the tree is split across threads
+        let mut sampler = Sampler::new(6);
+        let triangles: Vec<[Vector3d; 3]> = (0..6)
+            .flat_map(|_| random_triangles(&mut sampler))
+            .collect();
+        let triangles = mesh(&triangles);
+        assert!(triangles.len() >= 2 * PARALLEL_BUILD_THRESHOLD);
+
+        let bvh = Bvh::build(triangles.clone());
+
+        let single_threaded = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| Bvh::build(triangles.clone()));
+
+        assert_eq!(bvh, single_threaded);
+        assert_matches_brute_force(&bvh, &triangles);
+    }
+}
@@ -0,0 +1,370 @@
+use crate::scene::engine::Vector3d;
+
+use super::{aabb::Aabb, octree::Octree};
+
+/// The nearest point on a triangle to some query point
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClosestPoint {
+    pub point: Vector3d,
+    pub distance: f64,
+    /// Weights of the triangle's v1, v2 and v3 at `point`, they always add up to 1
+    pub barycentrics: (f64, f64, f64),
+    /// Index of the triangle in the acceleration structure's triangle list
+    pub triangle_index: usize,
+}
+
+/// The point on the triangle with corners `vertices` nearest to `point`.
+/// Works out which vertex, edge or the face the nearest point lies on from the
+/// point's position relative to each edge, as in Ericson's Real-Time Collision Detection.
+pub fn closest_point_on_triangle(
+    &[a, b, c]: &[Vector3d; 3],
+    triangle_index: usize,
+    point: Vector3d,
+) -> ClosestPoint {
+    let barycentrics = closest_barycentrics(a, b, c, point);
+    let (w, u, v) = barycentrics;
+    let closest = a * w + b * u + c * v;
+
+    ClosestPoint {
+        point: closest,
+        distance: (point - closest).length(),
+        barycentrics,
+        triangle_index,
+    }
+}
+
+fn closest_barycentrics(a: Vector3d, b: Vector3d, c: Vector3d, p: Vector3d) -> (f64, f64, f64) {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+
+    let d1 = ab.dot(&ap);
+    let d2 = ac.dot(&ap);
+
+    // Behind a, away from both edges leaving it
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (1.0, 0.0, 0.0);
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(&bp);
+    let d4 = ac.dot(&bp);
+
+    if d3 >= 0.0 && d4 <= d3 {
+        return (0.0, 1.0, 0.0);
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let u = d1 / (d1 - d3);
+        return (1.0 - u, u, 0.0);
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(&cp);
+    let d6 = ac.dot(&cp);
+
+    if d6 >= 0.0 && d5 <= d6 {
+        return (0.0, 0.0, 1.0);
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let v = d2 / (d2 - d6);
+        return (1.0 - v, 0.0, v);
+    }
+
+    let va = d3 * d6 - d5 * d4;
+
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        let v = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return (0.0, 1.0 - v, v);
+    }
+
+    // Inside the face
+    let denominator = va + vb + vc;
+
+    // Every vertex is in the same place or along a line, the edge checks above
+    // have already found the nearest point unless the triangle is a single point
+    if denominator.abs() < f64::EPSILON {
+        return (1.0, 0.0, 0.0);
+    }
+
+    let u = vb / denominator;
+    let v = vc / denominator;
+
+    (1.0 - u - v, u, v)
+}
+
+/// The nearest point on any triangle in the octree closer than `max_distance`.
+/// Nodes are visited nearest first and skipped once they're farther away than the best point so far.
+pub fn closest_point_in_octree(
+    octree: &Octree,
+    point: Vector3d,
+    max_distance: f64,
+    time: f64,
+) -> Option<ClosestPoint> {
+    let mut closest: Option<ClosestPoint> = None;
+    let mut closest_distance_squared = max_distance * max_distance;
+
+    // Nodes still to visit along with how far away their boxes are
+    let mut stack: Vec<(u32, f64)> = Vec::with_capacity(64);
+    stack.push((
+        0,
+        Aabb::from(octree.nodes[0].aabb).distance_squared_to(point),
+    ));
+
+    while let Some((node_index, distance_squared)) = stack.pop() {
+        if distance_squared > closest_distance_squared {
+            continue;
+        }
+
+        let node = &octree.nodes[node_index as usize];
+        let first_triangle = node.first_triangle as usize;
+
+        for &triangle_index in
+            &octree.triangle_indices[first_triangle..first_triangle + node.triangle_count as usize]
+        {
+            let triangle_index = triangle_index as usize;
+
+            // Only triangles, the mesh's shapes aren't covered by point queries
+            if !octree.mesh.is_triangle(triangle_index) {
+                continue;
+            }
+
+            let candidate = closest_point_on_triangle(
+                &octree.mesh.vertices_at(triangle_index, time),
+                triangle_index,
+                point,
+            );
+
+            if candidate.distance * candidate.distance <= closest_distance_squared
+                && closest.is_none_or(|c| candidate.distance < c.distance)
+            {
+                closest_distance_squared = candidate.distance * candidate.distance;
+                closest = Some(candidate);
+            }
+        }
+
+        let mut children: [(f64, u32); 8] = [(0.0, 0); 8];
+        let mut num_children = 0;
+
+        for child_index in node.first_child..node.first_child + node.child_count {
+            let child_distance_squared =
+                Aabb::from(octree.nodes[child_index as usize].aabb).distance_squared_to(point);
+
+            if child_distance_squared <= closest_distance_squared {
+                children[num_children] = (child_distance_squared, child_index);
+                num_children += 1;
+            }
+        }
+
+        // Push the farthest child first so the nearest is popped first
+        let children = &mut children[..num_children];
+        children.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        for &(child_distance_squared, child_index) in children.iter() {
+            stack.push((child_index, child_distance_squared));
+        }
+    }
+
+    closest
+}
+
+/// Every triangle in the octree with some part within `radius` of the point, in no particular order.
+/// Triangles duplicated into several octants can appear more than once.
+pub fn triangles_within_octree(
+    octree: &Octree,
+    point: Vector3d,
+    radius: f64,
+    time: f64,
+) -> Vec<ClosestPoint> {
+    let radius_squared = radius * radius;
+    let mut found = vec![];
+
+    let mut stack: Vec<u32> = Vec::with_capacity(64);
+    stack.push(0);
+
+    while let Some(node_index) = stack.pop() {
+        let node = &octree.nodes[node_index as usize];
+
+        if Aabb::from(node.aabb).distance_squared_to(point) > radius_squared {
+            continue;
+        }
+
+        let first_triangle = node.first_triangle as usize;
+
+        for &triangle_index in
+            &octree.triangle_indices[first_triangle..first_triangle + node.triangle_count as usize]
+        {
+            let triangle_index = triangle_index as usize;
+
+            // Only triangles, the mesh's shapes aren't covered by point queries
+            if !octree.mesh.is_triangle(triangle_index) {
+                continue;
+            }
+
+            let candidate = closest_point_on_triangle(
+                &octree.mesh.vertices_at(triangle_index, time),
+                triangle_index,
+                point,
+            );
+
+            if candidate.distance <= radius {
+                found.push(candidate);
+            }
+        }
+
+        stack.extend(node.first_child..node.first_child + node.child_count);
+    }
+
+    found
+}
+
+/// Order points nearest first and drop repeats of the same triangle,
+/// which structures that store a triangle in several places can report.
+pub(super) fn sort_closest_points(points: &mut Vec<ClosestPoint>) {
+    points.sort_by(|a, b| {
+        a.distance
+            .total_cmp(&b.distance)
+            .then(a.triangle_index.cmp(&b.triangle_index))
+    });
+    points.dedup_by_key(|point| point.triangle_index);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::{
+        accelerator::Accelerator, bvh::Bvh, octree::OctreeBuildOptions, test_support::*,
+    };
+    use crate::scene::sampling::Sampler;
+
+    use super::*;
+
+    #[test]
+    fn test_closest_point_matches_brute_force() {
+        let triangles = mesh(&random_triangles(&mut Sampler::new(6)));
+        let octree = build_octree_with_options(
+            &triangles,
+            OctreeBuildOptions {
+                duplicate_straddling_triangles: true,
+                ..OctreeBuildOptions::default()
+            },
+        );
+        let accelerators: [&dyn Accelerator; 3] = [
+            &build_octree(&triangles),
+            &octree,
+            &Bvh::build(triangles.clone()),
+        ];
+
+        let mut sampler = Sampler::new(8);
+
+        for _ in 0..500 {
+            let point = random_point(&mut sampler, 40.0);
+            let radius = sampler.next_f64() * 5.0;
+
+            let candidates: Vec<ClosestPoint> = (0..triangles.len())
+                .map(|i| closest_point_on_triangle(&triangles.vertices(i), i, point))
+                .collect();
+            let nearest = candidates
+                .iter()
+                .map(|c| c.distance)
+                .min_by(f64::total_cmp)
+                .unwrap();
+            let mut within: Vec<usize> = candidates
+                .iter()
+                .filter(|c| c.distance <= radius)
+                .map(|c| c.triangle_index)
+                .collect();
+            within.sort();
+
+            for accelerator in accelerators {
+                let closest = accelerator
+                    .closest_point(point, f64::INFINITY, 0.0)
+                    .unwrap();
+                assert_eq!(closest.distance, nearest);
+
+                let mut found: Vec<usize> = accelerator
+                    .triangles_within(point, radius, 0.0)
+                    .iter()
+                    .map(|c| c.triangle_index)
+                    .collect();
+                found.sort();
+                assert_eq!(found, within);
+            }
+        }
+    }
+
+    #[test]
+    fn test_points_on_edges_and_corners_are_their_own_closest_point() {
+        let corners = [
+            vector(0.0, 0.0, 0.0),
+            vector(4.0, 0.0, 0.0),
+            vector(0.0, 2.0, 1.0),
+        ];
+        let [a, b, c] = corners;
+
+        for (point, expected) in [
+            (a, (1.0, 0.0, 0.0)),
+            (b, (0.0, 1.0, 0.0)),
+            (c, (0.0, 0.0, 1.0)),
+            (a * 0.75 + b * 0.25, (0.75, 0.25, 0.0)),
+            (a * 0.5 + c * 0.5, (0.5, 0.0, 0.5)),
+            (b * 0.5 + c * 0.5, (0.0, 0.5, 0.5)),
+        ] {
+            let closest = closest_point_on_triangle(&corners, 0, point);
+
+            assert_eq!(closest.distance, 0.0);
+            assert_eq!(closest.point, point);
+            assert_eq!(closest.barycentrics, expected);
+        }
+
+        // Just outside an edge, in the triangle's plane, the edge is nearest
+        let outside = a * 0.5 + b * 0.5 - vector(0.0, 1.0, 0.5);
+        let closest = closest_point_on_triangle(&corners, 0, outside);
+        assert_eq!(closest.barycentrics, (0.5, 0.5, 0.0));
+        assert!((closest.distance - vector(0.0, 1.0, 0.5).length()).abs() < 1e-12);
+
+        // A point on the edge two triangles share is on both of them
+        let triangles = mesh(&[corners, triangle(b, vector(4.0, 2.0, 1.0), c)]);
+        let on_shared_edge = b * 0.5 + c * 0.5;
+
+        for accelerator in [
+            &build_octree(&triangles) as &dyn Accelerator,
+            &Bvh::build(triangles.clone()),
+        ] {
+            let closest = accelerator
+                .closest_point(on_shared_edge, f64::INFINITY, 0.0)
+                .unwrap();
+            assert_eq!(closest.distance, 0.0);
+
+            let within: Vec<usize> = accelerator
+                .triangles_within(on_shared_edge, 0.0, 0.0)
+                .iter()
+                .map(|c| c.triangle_index)
+                .collect();
+            assert_eq!(within.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_degenerate_triangles_still_give_the_nearest_point() {
+        // All three corners on a line
+        let line = [
+            vector(0.0, 0.0, 0.0),
+            vector(1.0, 1.0, 0.0),
+            vector(3.0, 3.0, 0.0),
+        ];
+        let closest = closest_point_on_triangle(&line, 0, vector(2.0, 2.0, 5.0));
+        assert!((closest.point - vector(2.0, 2.0, 0.0)).length() < 1e-12);
+        assert!((closest.distance - 5.0).abs() < 1e-12);
+
+        // All three corners in the same place
+        let point = [vector(1.0, 2.0, 3.0); 3];
+        let closest = closest_point_on_triangle(&point, 0, vector(1.0, 2.0, 7.0));
+        assert_eq!(closest.point, vector(1.0, 2.0, 3.0));
+        assert_eq!(closest.distance, 4.0);
+    }
+}
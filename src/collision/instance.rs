@@ -0,0 +1,599 @@
+use std::ops::ControlFlow;
+
+use crate::scene::{mesh::Mesh, transform::Transform};
+
+use super::{
+    aabb::Aabb,
+    accelerator::{sort_hits, AccelerationStructure, Accelerator},
+    bvh::{build_nodes, BvhNode},
+    ray::{IgnoredTriangle, Ray, RayTriangleIntersectionResult},
+};
+
+/// One placement of a shared mesh in the scene
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instance {
+    /// Index of the mesh's structure in `InstanceTree::prototypes`
+    pub prototype: usize,
+    /// Moves the mesh from its own space into the scene
+    pub transform: Transform,
+    /// Id of a material used for every triangle of this instance instead of the mesh's own
+    pub material: Option<u32>,
+}
+
+/// Meshes placed around the scene any number of times without copying their triangles.
+///
+/// Each unique mesh, or prototype, has one acceleration structure however many instances of it
+/// there are. A BVH over the instances' boxes finds which ones a ray could hit, and the ray is
+/// moved into each of those instances' own space to be traced through its prototype's structure.
+/// The moved ray's direction isn't normalised, so a hit's t value is the same in both spaces
+/// and hits in different instances can be compared directly.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InstanceTree {
+    pub prototypes: Vec<AccelerationStructure>,
+    pub instances: Vec<Instance>,
+    pub nodes: Vec<BvhNode>,
+    /// Indices of the instances ordered so every leaf's are contiguous
+    pub instance_indices: Vec<usize>,
+    /// Instances of prototypes with primitives that have no box, like planes,
+    /// which are left out of the tree and visited by every ray
+    pub unbounded: Vec<usize>,
+}
+
+impl InstanceTree {
+    pub fn build(prototypes: Vec<AccelerationStructure>, instances: Vec<Instance>) -> InstanceTree {
+        let prototype_aabbs: Vec<Aabb> = prototypes
+            .iter()
+            .map(|prototype| prototype.mesh().bounds())
+            .collect();
+
+        // Copies of a mesh with something in it that goes on forever can't be boxed
+        let (unbounded, bounded): (Vec<usize>, Vec<usize>) =
+            (0..instances.len()).partition(|&instance| {
+                !prototypes[instances[instance].prototype]
+                    .unbounded()
+                    .is_empty()
+            });
+
+        let instance_aabbs: Vec<Aabb> = bounded
+            .iter()
+            .map(|&instance| {
+                let instance = &instances[instance];
+                prototype_aabbs[instance.prototype].transformed(&instance.transform)
+            })
+            .collect();
+
+        let (nodes, indices) = build_nodes(&instance_aabbs);
+        let instance_indices = indices.into_iter().map(|index| bounded[index]).collect();
+
+        InstanceTree {
+            prototypes,
+            instances,
+            nodes,
+            instance_indices,
+            unbounded,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// The mesh an instance is a copy of, a hit's `triangle_index` is one of its triangles
+    pub fn mesh(&self, instance: usize) -> &Mesh {
+        self.prototypes[self.instances[instance].prototype].mesh()
+    }
+
+    /// A box containing every instance in the tree, see `unbounded`
+    pub fn bounds(&self) -> Aabb {
+        match self.nodes.first() {
+            Some(node) => node.aabb.into(),
+            None => Aabb::empty(),
+        }
+    }
+
+    /// The closest intersection with any instance along the ray with a t value below `max_t`
+    pub fn closest_hit(&self, ray: &Ray, max_t: f64) -> Option<RayTriangleIntersectionResult> {
+        let mut closest: Option<RayTriangleIntersectionResult> = None;
+
+        self.traverse(ray, max_t, |instance, object_ray, max_t| {
+            match self.prototype(instance).closest_hit(object_ray, max_t) {
+                Some(hit) => {
+                    closest = Some(RayTriangleIntersectionResult {
+                        instance: Some(instance),
+                        ..hit
+                    });
+                    ControlFlow::Continue(hit.t)
+                }
+                None => ControlFlow::Continue(max_t),
+            }
+        });
+
+        closest
+    }
+
+    /// Whether any instance intersects the ray before `max_t`
+    pub fn any_hit(&self, ray: &Ray, max_t: f64) -> bool {
+        let mut found = false;
+
+        self.traverse(ray, max_t, |instance, object_ray, max_t| {
+            if self.prototype(instance).any_hit(object_ray, max_t) {
+                found = true;
+                // Nothing else needs looking at, not even the other unbounded instances
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(max_t)
+            }
+        });
+
+        found
+    }
+
+    /// Every intersection with any instance before `max_t`, nearest first
+    pub fn all_hits(&self, ray: &Ray, max_t: f64) -> Vec<RayTriangleIntersectionResult> {
+        let mut hits = vec![];
+
+        self.traverse(ray, max_t, |instance, object_ray, max_t| {
+            hits.extend(
+                self.prototype(instance)
+                    .all_hits(object_ray, max_t)
+                    .into_iter()
+                    .map(|hit| RayTriangleIntersectionResult {
+                        instance: Some(instance),
+                        ..hit
+                    }),
+            );
+            ControlFlow::Continue(max_t)
+        });
+
+        sort_hits(&mut hits);
+        hits
+    }
+
+    /// A ray moved into an instance's own space. The direction isn't normalised,
+    /// so t along it is the same as along the original ray.
+    pub fn object_ray(&self, instance: usize, ray: &Ray) -> Ray {
+        let inverse = &self.instances[instance].transform.inverse;
+
+        // Only a triangle of this instance is skipped, it's now in the mesh being traced
+        let ignored_triangle = ray
+            .ignored_triangle
+            .filter(|ignored| ignored.instance == Some(instance))
+            .map(|ignored| IgnoredTriangle {
+                instance: None,
+                ..ignored
+            });
+
+        Ray {
+            origin: inverse.transform_point(ray.origin),
+            direction: inverse.transform_vector(ray.direction),
+            time: ray.time,
+            ignored_triangle,
+        }
+    }
+
+    fn prototype(&self, instance: usize) -> &AccelerationStructure {
+        &self.prototypes[self.instances[instance].prototype]
+    }
+
+    /// Call `visit` with every instance whose box the ray enters before `max_t`, along with the
+    /// ray moved into the instance's space, nearest boxes first after the unbounded instances.
+    /// `visit` returns the new `max_t`, boxes the ray enters after it are skipped,
+    /// or breaks to stop straight away.
+    fn traverse(
+        &self,
+        ray: &Ray,
+        mut max_t: f64,
+        mut visit: impl FnMut(usize, &Ray, f64) -> ControlFlow<(), f64>,
+    ) {
+        for &instance in &self.unbounded {
+            match visit(instance, &self.object_ray(instance, ray), max_t) {
+                ControlFlow::Continue(t) => max_t = t,
+                ControlFlow::Break(()) => return,
+            }
+        }
+
+        if self.instance_indices.is_empty() {
+            return;
+        }
+
+        let inverse_direction = ray.inverse_direction();
+
+        let root_entry_t =
+            match ray.intersect_aabb_interval(&self.nodes[0].aabb.into(), &inverse_direction) {
+                Some((entry_t, _)) => entry_t,
+                None => return,
+            };
+
+        // Nodes still to visit along with where the ray enters them
+        let mut stack: Vec<(usize, f64)> = Vec::with_capacity(64);
+        stack.push((0, root_entry_t));
+
+        while let Some((node_index, entry_t)) = stack.pop() {
+            if entry_t >= max_t {
+                continue;
+            }
+
+            let node = &self.nodes[node_index];
+
+            if node.triangle_count > 0 {
+                for &instance in
+                    &self.instance_indices[node.first..node.first + node.triangle_count]
+                {
+                    match visit(instance, &self.object_ray(instance, ray), max_t) {
+                        ControlFlow::Continue(t) => max_t = t,
+                        ControlFlow::Break(()) => return,
+                    }
+                }
+
+                continue;
+            }
+
+            let left = node.first;
+            let right = node.first + 1;
+
+            let left_hit =
+                ray.intersect_aabb_interval(&self.nodes[left].aabb.into(), &inverse_direction);
+            let right_hit =
+                ray.intersect_aabb_interval(&self.nodes[right].aabb.into(), &inverse_direction);
+
+            // Push the farther child first so the nearer one is visited first
+            match (left_hit, right_hit) {
+                (Some((left_t, _)), Some((right_t, _))) => {
+                    if left_t < right_t {
+                        stack.push((right, right_t));
+                        stack.push((left, left_t));
+                    } else {
+                        stack.push((left, left_t));
+                        stack.push((right, right_t));
+                    }
+                }
+                (Some((left_t, _)), None) => stack.push((left, left_t)),
+                (None, Some((right_t, _))) => stack.push((right, right_t)),
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f64::consts::PI, sync::Arc};
+
+    use crate::collision::{bvh::Bvh, ray::TriangleTest, spawn::SurfacePoint, test_support::*};
+    use crate::scene::{
+        engine::{widen, Float, Vector3d},
+        mesh::MeshShape,
+        sampling::Sampler,
+        shape::{Plane, Shape},
+        transform::Quaternion,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_instances_match_brute_force_over_copied_triangles() {
+        let mut sampler = Sampler::new(17);
+
+        let prototype_meshes: Vec<Arc<Mesh>> = (0..2)
+            .map(|_| {
+                let triangles: Vec<[Vector3d; 3]> = (0..40)
+                    .map(|_| {
+                        let centre = random_point(&mut sampler, 3.0);
+                        triangle(
+                            centre + random_point(&mut sampler, 1.5),
+                            centre + random_point(&mut sampler, 1.5),
+                            centre + random_point(&mut sampler, 1.5),
+                        )
+                    })
+                    .collect();
+                mesh(&triangles)
+            })
+            .collect();
+
+        let instances: Vec<Instance> = (0..30)
+            .map(|i| Instance {
+                prototype: i % 2,
+                transform: Transform::from_parts(
+                    random_point(&mut sampler, 15.0),
+                    Quaternion::from_axis_angle(
+                        random_point(&mut sampler, 1.0),
+                        sampler.next_f64() * 6.0,
+                    ),
+                    vector(
+                        0.5 + sampler.next_f64() * 1.5,
+                        0.5 + sampler.next_f64() * 1.5,
+                        0.5 + sampler.next_f64() * 1.5,
+                    ),
+                ),
+                material: None,
+            })
+            .collect();
+
+        // Every instance's triangles copied out into one mesh, remembering where each came from
+        let mut copied = vec![];
+        let mut origins = vec![];
+
+        for (instance_index, instance) in instances.iter().enumerate() {
+            let prototype = &prototype_meshes[instance.prototype];
+
+            for triangle_index in 0..prototype.len() {
+                copied.push(
+                    prototype
+                        .vertices(triangle_index)
+                        .map(|v| instance.transform.transform_point(v)),
+                );
+                origins.push((instance_index, triangle_index));
+            }
+        }
+        let copied = Mesh::from_triangles(&copied);
+
+        let tree = InstanceTree::build(
+            vec![
+                AccelerationStructure::Octree(build_octree(&prototype_meshes[0])),
+                AccelerationStructure::Bvh(Bvh::build(prototype_meshes[1].clone())),
+            ],
+            instances,
+        );
+
+        let mut hits = 0;
+
+        for _ in 0..3000 {
+            let ray = random_ray(&mut sampler);
+
+            let expected =
+                brute_force_closest_hit(&ray, &copied, f64::INFINITY, TriangleTest::MollerTrumbore);
+            let actual = tree.closest_hit(&ray, f64::INFINITY);
+
+            assert_eq!(tree.any_hit(&ray, f64::INFINITY), expected.is_some());
+
+            match (actual, expected) {
+                (Some(actual), Some((t, copied_index))) => {
+                    // Moving the ray rounds differently to moving the triangles, and the
+                    // copies are stored at `Float` precision after they've been moved
+                    let tolerance = f64::max(1e-9, widen(Float::EPSILON) * 10.0);
+                    assert!((actual.t - t).abs() < tolerance * t.max(1.0));
+                    assert_eq!(
+                        (actual.instance.unwrap(), actual.triangle_index),
+                        origins[copied_index]
+                    );
+
+                    let all_hits = tree.all_hits(&ray, f64::INFINITY);
+                    assert_eq!(all_hits[0], actual);
+                    assert_eq!(
+                        all_hits.len(),
+                        brute_force_all_hits(
+                            &ray,
+                            &copied,
+                            f64::INFINITY,
+                            TriangleTest::MollerTrumbore
+                        )
+                        .len()
+                    );
+
+                    // Nothing is found once the closest hit is out of reach
+                    assert!(tree
+                        .closest_hit(&ray, actual.t * 0.999)
+                        .is_none_or(|hit| hit.t < actual.t));
+                    hits += 1;
+                }
+                (None, None) => {}
+                _ => panic!("instances found {actual:?}, copied triangles found {expected:?}"),
+            }
+        }
+
+        assert!(hits > 500, "only {hits} rays hit anything");
+    }
+
+    #[test]
+    fn test_rotated_and_scaled_instance_is_hit_where_it_was_placed() {
+        // A triangle facing down the z axis, in the instance's own space
+        let prototype = mesh(&[triangle(
+            vector(-1.0, -1.0, 0.0),
+            vector(1.0, -1.0, 0.0),
+            vector(0.0, 1.0, 0.0),
+        )]);
+
+        // Turned a quarter turn round the y axis to face down the x axis, doubled in size
+        // and moved 10 along x
+        let transform = Transform::from_parts(
+            vector(10.0, 0.0, 0.0),
+            Quaternion::from_axis_angle(vector(0.0, 1.0, 0.0), PI / 2.0),
+            vector(2.0, 2.0, 2.0),
+        );
+        let tree = InstanceTree::build(
+            vec![AccelerationStructure::Bvh(Bvh::build(prototype))],
+            vec![Instance {
+                prototype: 0,
+                transform,
+                material: Some(3),
+            }],
+        );
+
+        // Its own x axis now points down the scene's -z, so this is (-0.25, 0.25) on it
+        let ray = Ray {
+            origin: vector(0.0, 0.5, 0.5),
+            direction: vector(1.0, 0.0, 0.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        let hit = tree.closest_hit(&ray, f64::INFINITY).unwrap();
+        assert!((hit.t - 10.0).abs() < 1e-9);
+        assert_eq!(hit.instance, Some(0));
+        assert_eq!(hit.triangle_index, 0);
+
+        // The same spot on the triangle as a ray in its own space would hit
+        let object_ray = tree.object_ray(0, &ray);
+        let own = object_ray.intersect_with_triangle(tree.mesh(0), 0).unwrap();
+        assert!((own.u - hit.u).abs() < 1e-9 && (own.v - hit.v).abs() < 1e-9);
+
+        // The surface faces along the x axis once it's moved into the scene
+        let surface = SurfacePoint::from_intersection(tree.mesh(0), &hit, &object_ray)
+            .transformed(&transform);
+        assert!((surface.point - vector(10.0, 0.5, 0.5)).length() < 1e-9);
+        assert!((surface.normal.x.abs() - 1.0).abs() < 1e-9);
+
+        // Where the unmoved triangle would have been, and past the scaled one's edge
+        for origin in [vector(0.0, 0.0, -5.0), vector(0.0, 2.5, 0.0)] {
+            let miss = Ray {
+                origin,
+                direction: vector(1.0, 0.0, 0.0),
+                ..ray.clone()
+            };
+            assert!(tree.closest_hit(&miss, f64::INFINITY).is_none());
+        }
+    }
+
+    #[test]
+    fn test_ignored_triangle_is_only_skipped_in_its_own_instance() {
+        let prototype = mesh(&[triangle(
+            vector(-1.0, -1.0, 0.0),
+            vector(1.0, -1.0, 0.0),
+            vector(0.0, 1.0, 0.0),
+        )]);
+        // Two copies of the same triangle, one behind the other
+        let tree = InstanceTree::build(
+            vec![AccelerationStructure::Bvh(Bvh::build(prototype))],
+            [0.0, 5.0]
+                .map(|z| Instance {
+                    prototype: 0,
+                    transform: Transform::translation(vector(0.0, 0.0, z)),
+                    material: None,
+                })
+                .to_vec(),
+        );
+
+        let ray = Ray {
+            origin: vector(0.0, 0.0, -5.0),
+            direction: vector(0.0, 0.0, 1.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        let front = tree.closest_hit(&ray, f64::INFINITY).unwrap();
+        assert_eq!(front.instance, Some(0));
+
+        // A ray leaving the front copy skips it but still sees the same triangle in the other
+        let surface =
+            SurfacePoint::from_intersection(tree.mesh(0), &front, &tree.object_ray(0, &ray))
+                .transformed(&tree.instances[0].transform);
+
+        for test in [TriangleTest::MollerTrumbore, TriangleTest::Watertight] {
+            let mut tree = tree.clone();
+            tree.prototypes[0].set_triangle_test(test);
+
+            let through = surface.spawn_ray(ray.direction, 0.0, true);
+            assert_eq!(
+                through.ignored_triangle.map(|ignored| ignored.instance),
+                Some(Some(0))
+            );
+
+            let behind = tree.closest_hit(&through, f64::INFINITY).unwrap();
+            assert_eq!((behind.instance, behind.triangle_index), (Some(1), 0));
+            assert!(tree
+                .all_hits(&through, f64::INFINITY)
+                .iter()
+                .all(|hit| hit.instance == Some(1)));
+
+            // Back the way it came nothing is left to hit
+            let back = surface.spawn_ray(-ray.direction, 0.0, true);
+            assert!(!tree.any_hit(&back, f64::INFINITY));
+        }
+    }
+
+    #[test]
+    fn test_instances_of_a_plane_are_hit_far_from_the_rest_of_the_tree() {
+        let mut with_plane = Mesh::from_triangles(&[triangle(
+            vector(-1.0, -1.0, 0.0),
+            vector(1.0, -1.0, 0.0),
+            vector(0.0, 1.0, 0.0),
+        )]);
+        with_plane.shapes.push(MeshShape {
+            shape: Shape::Plane(Plane {
+                point: vector(0.0, -2.0, 0.0),
+                normal: vector(0.0, 1.0, 0.0),
+            }),
+            material: 0,
+        });
+
+        let tree = InstanceTree::build(
+            vec![
+                AccelerationStructure::Bvh(Bvh::build(Arc::new(with_plane))),
+                AccelerationStructure::Bvh(Bvh::build(mesh(&[triangle(
+                    vector(-1.0, -1.0, 0.0),
+                    vector(1.0, -1.0, 0.0),
+                    vector(0.0, 1.0, 0.0),
+                )]))),
+            ],
+            [(0, 0.0), (1, 5.0), (1, 10.0)]
+                .map(|(prototype, z)| Instance {
+                    prototype,
+                    transform: Transform::translation(vector(0.0, 0.0, z)),
+                    material: None,
+                })
+                .to_vec(),
+        );
+        assert_eq!(tree.unbounded, [0]);
+
+        // Nowhere near the tree's box, but the plane goes on forever
+        let ray = Ray {
+            origin: vector(1e6, 0.0, -1e6),
+            direction: vector(0.0, -1.0, 0.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        let hit = tree.closest_hit(&ray, f64::INFINITY).unwrap();
+        assert_eq!((hit.instance, hit.triangle_index, hit.t), (Some(0), 1, 2.0));
+        assert!(tree.any_hit(&ray, f64::INFINITY));
+        assert!(!tree.any_hit(&ray, 1.0));
+
+        // The plane doesn't hide the boxed instances in front of it
+        let ray = Ray {
+            origin: vector(0.0, 0.0, 20.0),
+            direction: vector(0.0, 0.0, -1.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        let hit = tree.closest_hit(&ray, f64::INFINITY).unwrap();
+        assert_eq!((hit.instance, hit.t), (Some(2), 10.0));
+        assert_eq!(tree.all_hits(&ray, f64::INFINITY).len(), 3);
+    }
+
+    #[test]
+    fn test_traversal_stops_once_any_hit_finds_something() {
+        let mut plane = Mesh::default();
+        plane.shapes.push(MeshShape {
+            shape: Shape::Plane(Plane {
+                point: vector(0.0, 0.0, 0.0),
+                normal: vector(0.0, 1.0, 0.0),
+            }),
+            material: 0,
+        });
+
+        let tree = InstanceTree::build(
+            vec![AccelerationStructure::Bvh(Bvh::build(Arc::new(plane)))],
+            (0..4)
+                .map(|i| Instance {
+                    prototype: 0,
+                    transform: Transform::translation(vector(0.0, -(i as f64), 0.0)),
+                    material: None,
+                })
+                .collect(),
+        );
+        assert_eq!(tree.unbounded.len(), 4);
+
+        let ray = Ray {
+            origin: vector(0.0, 1.0, 0.0),
+            direction: vector(0.0, -1.0, 0.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        assert!(tree.any_hit(&ray, f64::INFINITY));
+
+        // Breaking on the first instance leaves the other three alone
+        let mut visited = 0;
+        tree.traverse(&ray, f64::INFINITY, |_, _, _| {
+            visited += 1;
+            ControlFlow::Break(())
+        });
+        assert_eq!(visited, 1);
+    }
+}
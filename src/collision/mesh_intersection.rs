@@ -0,0 +1,409 @@
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::scene::{engine::Vector3d, mesh::Mesh, transform::Transform};
+
+use super::{aabb::Aabb, accelerator::Accelerator, bvh::Bvh, region::Region};
+
+/// How two intersecting triangles touch
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TriangleContact {
+    /// The triangles cross or touch along this segment, which is a single point
+    /// when they only meet at a vertex
+    Segment { start: Vector3d, end: Vector3d },
+    /// The triangles lie in the same plane and overlap, so they share an area rather than a segment
+    Coplanar,
+}
+
+/// A triangle from each mesh that intersect each other
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrianglePairIntersection {
+    /// Index into the first mesh's triangles
+    pub first_triangle: usize,
+    /// Index into the second mesh's triangles
+    pub second_triangle: usize,
+    pub contact: TriangleContact,
+}
+
+/// Whether two triangles intersect and where. Each triangle is cut by the other's plane,
+/// both cuts lie on the line where the planes meet, and the contact is where they overlap.
+pub fn intersect_triangles(a: &[Vector3d; 3], b: &[Vector3d; 3]) -> Option<TriangleContact> {
+    let scale = a
+        .iter()
+        .chain(b)
+        .map(|v| v.x.abs().max(v.y.abs()).max(v.z.abs()))
+        .fold(1.0, f64::max);
+    // Distances this close to a plane are treated as on it
+    let tolerance = scale * 1e-12;
+
+    let a_normal = (a[1] - a[0]).cross(&(a[2] - a[0]));
+    let b_normal = (b[1] - b[0]).cross(&(b[2] - b[0]));
+
+    // Degenerate triangles have no area to intersect with
+    if a_normal.length() == 0.0 || b_normal.length() == 0.0 {
+        return None;
+    }
+
+    let a_normal = a_normal.normalised();
+    let b_normal = b_normal.normalised();
+
+    let a_distances = b.map(|v| a_normal.dot(&(v - a[0])));
+    let b_distances = a.map(|v| b_normal.dot(&(v - b[0])));
+
+    if entirely_on_one_side(&a_distances, tolerance)
+        || entirely_on_one_side(&b_distances, tolerance)
+    {
+        return None;
+    }
+
+    if a_distances.iter().all(|d| d.abs() <= tolerance) {
+        return coplanar_triangles_overlap(a, b, &a_normal).then_some(TriangleContact::Coplanar);
+    }
+
+    let line_direction = a_normal.cross(&b_normal);
+
+    let (a_start, a_end) = plane_crossing(a, &b_distances, &line_direction, tolerance)?;
+    let (b_start, b_end) = plane_crossing(b, &a_distances, &line_direction, tolerance)?;
+
+    let (a_start_t, a_end_t) = (line_direction.dot(&a_start), line_direction.dot(&a_end));
+    let (b_start_t, b_end_t) = (line_direction.dot(&b_start), line_direction.dot(&b_end));
+
+    let (start_t, start) = if a_start_t > b_start_t {
+        (a_start_t, a_start)
+    } else {
+        (b_start_t, b_start)
+    };
+    let (end_t, end) = if a_end_t < b_end_t {
+        (a_end_t, a_end)
+    } else {
+        (b_end_t, b_end)
+    };
+
+    if start_t > end_t + tolerance * line_direction.length() {
+        return None;
+    }
+
+    Some(TriangleContact::Segment { start, end })
+}
+
+fn entirely_on_one_side(distances: &[f64; 3], tolerance: f64) -> bool {
+    distances.iter().all(|&d| d > tolerance) || distances.iter().all(|&d| d < -tolerance)
+}
+
+/// The segment where a triangle crosses a plane, given each vertex's distance from the plane,
+/// with its ends ordered along `line_direction`.
+fn plane_crossing(
+    triangle: &[Vector3d; 3],
+    distances: &[f64; 3],
+    line_direction: &Vector3d,
+    tolerance: f64,
+) -> Option<(Vector3d, Vector3d)> {
+    let mut points = vec![];
+
+    for i in 0..3 {
+        let j = (i + 1) % 3;
+
+        if distances[i].abs() <= tolerance {
+            points.push(triangle[i]);
+        } else if distances[j].abs() > tolerance && (distances[i] > 0.0) != (distances[j] > 0.0) {
+            let t = distances[i] / (distances[i] - distances[j]);
+            points.push(triangle[i] + (triangle[j] - triangle[i]) * t);
+        }
+    }
+
+    let along = |p: &&Vector3d| line_direction.dot(p);
+
+    let start = points.iter().min_by(|a, b| along(a).total_cmp(&along(b)))?;
+    let end = points.iter().max_by(|a, b| along(a).total_cmp(&along(b)))?;
+
+    Some((*start, *end))
+}
+
+/// Separating axis test between two triangles in the same plane,
+/// the only axes that can separate them are the in-plane normals of their edges.
+fn coplanar_triangles_overlap(a: &[Vector3d; 3], b: &[Vector3d; 3], normal: &Vector3d) -> bool {
+    let edges = (0..3)
+        .map(|i| a[(i + 1) % 3] - a[i])
+        .chain((0..3).map(|i| b[(i + 1) % 3] - b[i]));
+
+    for edge in edges {
+        let axis = normal.cross(&edge);
+
+        let project = |triangle: &[Vector3d; 3]| {
+            triangle
+                .iter()
+                .map(|v| axis.dot(v))
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), d| {
+                    (min.min(d), max.max(d))
+                })
+        };
+
+        let (a_min, a_max) = project(a);
+        let (b_min, b_max) = project(b);
+
+        if a_max < b_min || b_max < a_min {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Every pair of intersecting triangles between two meshes, as they are at `time`,
+/// ordered by the first mesh's triangle then the second's. Each triangle of the second mesh
+/// is checked against the first mesh's triangles near it, in parallel.
+pub fn intersect_meshes(
+    first: &(dyn Accelerator + Sync),
+    second: &(dyn Accelerator + Sync),
+    time: f64,
+) -> Vec<TrianglePairIntersection> {
+    let second_mesh = second.mesh();
+    let second_triangles: Vec<[Vector3d; 3]> = (0..second_mesh.triangle_count())
+        .into_par_iter()
+        .map(|triangle| second_mesh.vertices_at(triangle, time))
+        .collect();
+
+    intersect_with_placed_triangles(first, &second_triangles, time)
+}
+
+/// Like `intersect_meshes`, with each mesh moved into place by a transform first, e.g. to check
+/// instances of parts in an assembly. Contacts are in the space the transforms map into.
+pub fn intersect_transformed_meshes(
+    first: &(dyn Accelerator + Sync),
+    first_transform: &Transform,
+    second: &(dyn Accelerator + Sync),
+    second_transform: &Transform,
+    time: f64,
+) -> Vec<TrianglePairIntersection> {
+    // The first mesh's structure is in its own space, so it's rebuilt around the
+    // moved triangles. Triangle indices are kept so results still refer to the original mesh.
+    let first_mesh = first.mesh();
+    let placed_first: Vec<[Vector3d; 3]> = (0..first_mesh.triangle_count())
+        .into_par_iter()
+        .map(|triangle| {
+            first_mesh
+                .vertices_at(triangle, time)
+                .map(|v| first_transform.transform_point(v))
+        })
+        .collect();
+    let placed_first = Bvh::build(Arc::new(Mesh::from_triangles(&placed_first)));
+
+    let second_mesh = second.mesh();
+    let second_triangles: Vec<[Vector3d; 3]> = (0..second_mesh.triangle_count())
+        .into_par_iter()
+        .map(|triangle| {
+            second_mesh
+                .vertices_at(triangle, time)
+                .map(|v| second_transform.transform_point(v))
+        })
+        .collect();
+
+    intersect_with_placed_triangles(&placed_first, &second_triangles, time)
+}
+
+fn intersect_with_placed_triangles(
+    first: &(dyn Accelerator + Sync),
+    second_triangles: &[[Vector3d; 3]],
+    time: f64,
+) -> Vec<TrianglePairIntersection> {
+    let mut intersections: Vec<TrianglePairIntersection> = second_triangles
+        .par_iter()
+        .enumerate()
+        .flat_map_iter(|(second_triangle, second_vertices)| {
+            let aabb = second_vertices
+                .iter()
+                .fold(Aabb::empty(), |aabb, &v| aabb.expanded_to(v));
+
+            first
+                .triangles_in_region(&Region::from_aabb(&aabb), time)
+                .into_iter()
+                .filter_map(move |first_triangle| {
+                    let first_vertices = first.mesh().vertices_at(first_triangle, time);
+
+                    intersect_triangles(&first_vertices, second_vertices).map(|contact| {
+                        TrianglePairIntersection {
+                            first_triangle,
+                            second_triangle,
+                            contact,
+                        }
+                    })
+                })
+        })
+        .collect();
+
+    intersections.sort_by_key(|i| (i.first_triangle, i.second_triangle));
+    intersections
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::test_support::*;
+    use crate::scene::sampling::Sampler;
+
+    use super::*;
+
+    fn assert_segment(contact: Option<TriangleContact>, a: Vector3d, b: Vector3d) {
+        let Some(TriangleContact::Segment { start, end }) = contact else {
+            panic!("expected a segment, got {contact:?}");
+        };
+
+        let same = |p: Vector3d, q: Vector3d| (p - q).length() < 1e-9;
+        assert!(
+            (same(start, a) && same(end, b)) || (same(start, b) && same(end, a)),
+            "expected {a:?} to {b:?}, got {start:?} to {end:?}"
+        );
+    }
+
+    #[test]
+    fn test_crossing_triangles_meet_along_segment() {
+        let flat = [
+            vector(-2.0, 0.0, -2.0),
+            vector(2.0, 0.0, -2.0),
+            vector(0.0, 0.0, 2.0),
+        ];
+        let upright = [
+            vector(0.0, -1.0, -1.0),
+            vector(0.0, 1.0, -1.0),
+            vector(0.0, -1.0, 1.0),
+        ];
+
+        // The upright triangle crosses y = 0 from z = -1 to z = 0
+        assert_segment(
+            intersect_triangles(&flat, &upright),
+            vector(0.0, 0.0, -1.0),
+            vector(0.0, 0.0, 0.0),
+        );
+        assert_segment(
+            intersect_triangles(&upright, &flat),
+            vector(0.0, 0.0, -1.0),
+            vector(0.0, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn test_segment_is_clipped_to_both_triangles() {
+        let flat = [
+            vector(0.0, 0.0, 0.0),
+            vector(1.0, 0.0, 0.0),
+            vector(0.0, 0.0, 1.0),
+        ];
+        // Crosses the plane y = 0 along x = 0.25 for every z, but only part of that is on `flat`
+        let upright = [
+            vector(0.25, -1.0, -5.0),
+            vector(0.25, -1.0, 5.0),
+            vector(0.25, 1.0, 0.0),
+        ];
+
+        assert_segment(
+            intersect_triangles(&flat, &upright),
+            vector(0.25, 0.0, 0.0),
+            vector(0.25, 0.0, 0.75),
+        );
+    }
+
+    #[test]
+    fn test_separate_and_touching_triangles() {
+        let flat = [
+            vector(0.0, 0.0, 0.0),
+            vector(1.0, 0.0, 0.0),
+            vector(0.0, 0.0, 1.0),
+        ];
+
+        let above = [
+            vector(0.0, 0.5, 0.0),
+            vector(1.0, 0.5, 0.0),
+            vector(0.0, 1.5, 1.0),
+        ];
+        assert_eq!(intersect_triangles(&flat, &above), None);
+
+        // Crosses the plane of `flat` but off to the side of it
+        let beside = [
+            vector(2.0, -1.0, 0.0),
+            vector(2.0, 1.0, 0.0),
+            vector(2.0, 0.0, 1.0),
+        ];
+        assert_eq!(intersect_triangles(&flat, &beside), None);
+
+        // Only a vertex resting on the face
+        let resting = [
+            vector(0.25, 0.0, 0.25),
+            vector(0.0, 1.0, 0.0),
+            vector(1.0, 1.0, 0.0),
+        ];
+        assert_segment(
+            intersect_triangles(&flat, &resting),
+            vector(0.25, 0.0, 0.25),
+            vector(0.25, 0.0, 0.25),
+        );
+    }
+
+    #[test]
+    fn test_coplanar_triangles() {
+        let flat = [
+            vector(0.0, 0.0, 0.0),
+            vector(1.0, 0.0, 0.0),
+            vector(0.0, 0.0, 1.0),
+        ];
+        let overlapping = [
+            vector(0.2, 0.0, 0.2),
+            vector(2.0, 0.0, 0.2),
+            vector(0.2, 0.0, 2.0),
+        ];
+        let apart = [
+            vector(0.6, 0.0, 0.6),
+            vector(2.0, 0.0, 0.6),
+            vector(0.6, 0.0, 2.0),
+        ];
+
+        assert_eq!(
+            intersect_triangles(&flat, &overlapping),
+            Some(TriangleContact::Coplanar)
+        );
+        assert_eq!(intersect_triangles(&flat, &apart), None);
+    }
+
+    #[test]
+    fn test_mesh_intersections_match_brute_force() {
+        let triangles = random_triangles(&mut Sampler::new(12));
+        let (first, second) = triangles.split_at(200);
+        let (first, second) = (mesh(first), mesh(second));
+
+        let mut expected = vec![];
+
+        for i in 0..first.len() {
+            for j in 0..second.len() {
+                if let Some(contact) = intersect_triangles(&first.vertices(i), &second.vertices(j))
+                {
+                    expected.push(TrianglePairIntersection {
+                        first_triangle: i,
+                        second_triangle: j,
+                        contact,
+                    });
+                }
+            }
+        }
+
+        assert!(expected.len() > 50, "only {} intersections", expected.len());
+
+        let first_octree = build_octree(&first);
+        let second_bvh = Bvh::build(second.clone());
+
+        assert_eq!(intersect_meshes(&first_octree, &second_bvh, 0.0), expected);
+        assert_eq!(
+            intersect_meshes(&Bvh::build(first), &build_octree(&second), 0.0),
+            expected
+        );
+
+        // Moving both meshes by the same amount doesn't change which triangles touch
+        let shift = Transform::translation(vector(100.0, -40.0, 7.0));
+        let shifted = intersect_transformed_meshes(&first_octree, &shift, &second_bvh, &shift, 0.0);
+
+        assert_eq!(shifted.len(), expected.len());
+        assert!(shifted
+            .iter()
+            .zip(&expected)
+            .all(|(s, e)| (s.first_triangle, s.second_triangle)
+                == (e.first_triangle, e.second_triangle)));
+    }
+}
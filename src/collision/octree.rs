@@ -1,244 +1,503 @@
-use crate::scene::{engine::Vector3d, entities::Triangle};
+use std::{collections::VecDeque, fmt, sync::Arc};
 
-use super::aabb::Aabb;
+use rayon::prelude::*;
 
-/// Represents a single octant node in the octree
+use crate::scene::{engine::Vector3d, mesh::Mesh, shape::Hittable};
+
+use super::{
+    aabb::{Aabb, CompactAabb},
+    ray::TriangleTest,
+};
+
+/// A single node of a finished octree.
+/// Nodes only refer to other nodes and triangles by index ranges so the whole tree
+/// lives in a couple of contiguous arrays, which keeps traversal cache friendly.
 #[derive(Clone, Debug, PartialEq)]
-pub struct OctantNode {
-    pub aabb_index: usize,
-    pub triangles: Vec<usize>,
-    pub children: Vec<usize>,
-    pub triangle_count: usize,
+pub struct OctreeNode {
+    pub aabb: CompactAabb,
+    /// Children are stored next to each other, starting at `first_child` in `Octree::nodes`.
+    /// Octants without any triangles in them are left out entirely.
+    pub first_child: u32,
+    pub child_count: u32,
+    /// Triangles held by this node itself, a range into `Octree::triangle_indices`
+    pub first_triangle: u32,
+    pub triangle_count: u32,
 }
 
+/// A flattened octree, built with `Octree::build`.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Octree {
-    pub nodes: Vec<OctantNode>,
-    pub aabbs: Vec<Aabb>,
-    pub triangles: Vec<Triangle>,
-    pub triangle_aabbs: Vec<usize>,
+    /// The root is always node 0
+    pub nodes: Vec<OctreeNode>,
+    /// Indices of the mesh's triangles, each node's triangles are contiguous
+    pub triangle_indices: Vec<u32>,
+    pub mesh: Arc<Mesh>,
+    /// Primitives with no box, like planes, which aren't in any node and are tested by every ray
+    pub unbounded: Vec<usize>,
+    /// The options the tree was built with
+    pub options: OctreeBuildOptions,
+    pub triangle_test: TriangleTest,
 }
 
-impl Octree {
-    pub fn new(min_x: f64, max_x: f64, min_y: f64, max_y: f64, min_z: f64, max_z: f64) -> Octree {
-        let aabb = Aabb::new(min_x, max_x, min_y, max_y, min_z, max_z);
-
-        let root_node = OctantNode {
-            aabb_index: 0,
-            triangles: vec![],
-            children: vec![],
-            triangle_count: 0,
-        };
+/// Controls how far the octree is subdivided while it is built.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OctreeBuildOptions {
+    /// Octants this many levels below the root are never subdivided
+    pub max_depth: u32,
+    /// A leaf is subdivided once it holds more than this many triangles
+    pub leaf_capacity: usize,
+    /// Octants are never split into children with a side shorter than this
+    pub min_node_size: f64,
+    /// Triangles overlapping several child octants are normally kept in the parent,
+    /// so every ray passing through the parent tests them. When this is set they are
+    /// added to every child they overlap instead, at the cost of a bigger tree.
+    pub duplicate_straddling_triangles: bool,
+}
 
-        Octree {
-            nodes: vec![root_node],
-            aabbs: vec![aabb],
-            triangles: vec![],
-            triangle_aabbs: vec![],
+impl Default for OctreeBuildOptions {
+    fn default() -> Self {
+        OctreeBuildOptions {
+            max_depth: 10,
+            leaf_capacity: 8,
+            min_node_size: 0.0,
+            duplicate_straddling_triangles: false,
         }
     }
+}
+
+/// Shape of a built octree, useful for tuning `OctreeBuildOptions`
+#[derive(Clone, Debug, PartialEq)]
+pub struct OctreeStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    /// Number of nodes at each depth, the root is at depth 0
+    pub nodes_per_depth: Vec<usize>,
+    /// Number of leaves holding each number of triangles, indexed by triangle count
+    pub leaves_per_triangle_count: Vec<usize>,
+    /// Triangles held by nodes that have children
+    pub interior_triangle_count: usize,
+    /// Total triangle slots across all nodes, more than the triangle count when triangles are duplicated
+    pub triangle_reference_count: usize,
+    pub triangle_count: usize,
+}
 
-    pub fn push_triangle(&mut self, triangle: Triangle) {
-        let triangle_aabb = Aabb::from_triangle(&triangle);
+impl Octree {
+    pub fn stats(&self) -> OctreeStats {
+        let mut stats = OctreeStats {
+            node_count: self.nodes.len(),
+            leaf_count: 0,
+            nodes_per_depth: vec![],
+            leaves_per_triangle_count: vec![],
+            interior_triangle_count: 0,
+            triangle_reference_count: self.triangle_indices.len(),
+            triangle_count: self.mesh.len(),
+        };
 
-        let aabb_index = self.aabbs.len();
-        let triangle_index = self.triangles.len();
+        let mut stack = vec![(0u32, 0usize)];
 
-        self.triangles.push(triangle);
-        self.aabbs.push(triangle_aabb);
-        self.triangle_aabbs.push(aabb_index);
+        while let Some((node_index, depth)) = stack.pop() {
+            let node = &self.nodes[node_index as usize];
+            let triangle_count = node.triangle_count as usize;
 
-        self.push_at_octant(triangle_index, aabb_index, 0);
+            if stats.nodes_per_depth.len() <= depth {
+                stats.nodes_per_depth.resize(depth + 1, 0);
+            }
+            stats.nodes_per_depth[depth] += 1;
+
+            if node.child_count == 0 {
+                stats.leaf_count += 1;
+
+                if stats.leaves_per_triangle_count.len() <= triangle_count {
+                    stats
+                        .leaves_per_triangle_count
+                        .resize(triangle_count + 1, 0);
+                }
+                stats.leaves_per_triangle_count[triangle_count] += 1;
+            } else {
+                stats.interior_triangle_count += triangle_count;
+            }
+
+            for child_index in node.first_child..node.first_child + node.child_count {
+                stack.push((child_index, depth + 1));
+            }
+        }
+
+        stats
     }
+}
+
+impl fmt::Display for OctreeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "octree: {} nodes, {} leaves, {} triangles ({} references, {} in interior nodes)",
+            self.node_count,
+            self.leaf_count,
+            self.triangle_count,
+            self.triangle_reference_count,
+            self.interior_triangle_count
+        )?;
 
-    fn push_at_octant(&mut self, triangle_index: usize, aabb_index: usize, octant_index: usize) {
-        let intersects: bool;
-        let current_octant_has_triangle: bool;
-        let is_leaf_octant: bool;
-        let children: Vec<usize>;
-
-        {
-            let aabb = &self.aabbs[aabb_index];
-            let node = &self.nodes[octant_index];
-            let octant_aabb = &self.aabbs[node.aabb_index];
-
-            intersects = aabb.intersects(octant_aabb);
-            current_octant_has_triangle = !node.triangles.is_empty();
-            children = node.children.clone();
-            is_leaf_octant = children.is_empty();
+        writeln!(f, "  depth      nodes")?;
+        for (depth, count) in self.nodes_per_depth.iter().enumerate() {
+            writeln!(f, "  {depth:>5} {count:>10}")?;
         }
 
-        if !intersects {
-            return;
+        writeln!(f, "  triangles per leaf     leaves")?;
+        for (triangles, count) in self.leaves_per_triangle_count.iter().enumerate() {
+            if *count > 0 {
+                writeln!(f, "  {triangles:>18} {count:>10}")?;
+            }
         }
 
-        self.nodes[octant_index].triangle_count += 1;
+        Ok(())
+    }
+}
 
-        if is_leaf_octant && !current_octant_has_triangle {
-            self.nodes[octant_index].triangles.push(triangle_index);
-        } else if is_leaf_octant {
-            let child_indices = self.subdivide(octant_index);
+/// Octants holding fewer triangles than this are built on the current thread,
+/// splitting them further across threads costs more than it saves.
+static PARALLEL_BUILD_THRESHOLD: usize = 1024;
 
-            let intersecting_child_indices: Vec<usize> = child_indices
-                .iter()
-                .filter(|ci| self.octant_intersects_with_triangle_aabb(**ci, aabb_index))
-                .copied()
-                .collect();
+/// Represents a single octant in the octree while it is being built
+#[derive(Clone, Debug, PartialEq)]
+struct OctantNode {
+    aabb: Aabb,
+    triangles: Vec<u32>,
+    /// Only octants with triangles somewhere below them are kept
+    children: Vec<OctantNode>,
+}
 
-            if intersecting_child_indices.len() == 1 {
-                self.push_at_octant(triangle_index, aabb_index, intersecting_child_indices[0]);
-            } else {
-                self.nodes[octant_index].triangles.push(triangle_index);
+impl Octree {
+    /// Build an octree over every triangle at once, the subtrees of each octant are built in
+    /// parallel. Triangle indices in the finished tree are indices into `mesh`. The mesh's
+    /// shapes are placed by their boxes just like triangles, and come after them. Shapes
+    /// without a box go in `unbounded` instead.
+    ///
+    /// Traversal relies on every point of every triangle being inside the box of a node that
+    /// holds the triangle. Triangles inside `aabb` always are, either because they fit in a
+    /// single octant or because they were duplicated into every octant they overlap.
+    /// Triangles sticking out of `aabb` are kept in the root, so its box is grown to cover them.
+    pub fn build(mesh: Arc<Mesh>, aabb: Aabb, options: OctreeBuildOptions) -> Octree {
+        let bounds: Vec<Option<Aabb>> = (0..mesh.len())
+            .into_par_iter()
+            .map(|index| mesh.primitive(index).bounds())
+            .collect();
+
+        let (bounded, unbounded): (Vec<usize>, Vec<usize>) =
+            (0..mesh.len()).partition(|&index| bounds[index].is_some());
+        let triangle_aabbs: Vec<Aabb> = bounds
+            .into_iter()
+            .map(|bounds| bounds.unwrap_or_else(Aabb::empty))
+            .collect();
+
+        let (inside, outside): (Vec<u32>, Vec<u32>) = bounded
+            .into_iter()
+            .map(|t| t as u32)
+            .partition(|&t| aabb.contains(&triangle_aabbs[t as usize]));
+
+        let mut root = build_octant(aabb, inside, 0, &triangle_aabbs, &options);
+        root.triangles.extend(outside);
+
+        let (mut nodes, triangle_indices) = flatten(root);
+
+        // Children always come after their parents, so going backwards
+        // finishes every child's bounds before they're added to the parent.
+        for node_index in (0..nodes.len()).rev() {
+            let node = &nodes[node_index];
+            let mut node_aabb = Aabb::from(node.aabb);
+
+            let first_triangle = node.first_triangle as usize;
+            for &triangle_index in
+                &triangle_indices[first_triangle..first_triangle + node.triangle_count as usize]
+            {
+                let triangle_aabb = &triangle_aabbs[triangle_index as usize];
+
+                if !aabb.contains(triangle_aabb) {
+                    node_aabb = node_aabb.union(triangle_aabb);
+                }
             }
-        } else if intersects && !is_leaf_octant {
-            let intersecting_child_indices: Vec<usize> = children
-                .iter()
-                .filter(|ci| self.octant_intersects_with_triangle_aabb(**ci, aabb_index))
-                .copied()
-                .collect();
-
-            if intersecting_child_indices.len() == 1 {
-                self.push_at_octant(triangle_index, aabb_index, intersecting_child_indices[0]);
-            } else {
-                self.nodes[octant_index].triangles.push(triangle_index);
+
+            for child_index in node.first_child..node.first_child + node.child_count {
+                node_aabb = node_aabb.union(&nodes[child_index as usize].aabb.into());
             }
-        } else {
-            unreachable!()
+
+            nodes[node_index].aabb = node_aabb.into();
+        }
+
+        Octree {
+            nodes,
+            triangle_indices,
+            mesh,
+            unbounded,
+            options,
+            triangle_test: TriangleTest::default(),
         }
     }
 
-    fn octant_intersects_with_triangle_aabb(
-        &self,
-        octant_index: usize,
-        triangle_aabb_index: usize,
-    ) -> bool {
-        let octant_aabb = &self.aabbs[self.nodes[octant_index].aabb_index];
-        let triangle_aabb = &self.aabbs[triangle_aabb_index];
+    /// `Octree::build` with the smallest cube around every primitive that has a box as the root
+    pub fn build_around(mesh: Arc<Mesh>, options: OctreeBuildOptions) -> Octree {
+        let bounds = mesh.bounds();
+        let size = bounds.max_coords - bounds.min_coords;
+        let half_size = f64::max(size.x, f64::max(size.y, size.z)) / 2.0;
 
-        octant_aabb.intersects(triangle_aabb)
+        // With nothing to put in the tree the root is just a point
+        let aabb = if half_size >= 0.0 {
+            let centre = bounds.centre();
+            Aabb::new(
+                centre.x - half_size,
+                centre.x + half_size,
+                centre.y - half_size,
+                centre.y + half_size,
+                centre.z - half_size,
+                centre.z + half_size,
+            )
+        } else {
+            Aabb::new(0.0, 0.0, 0.0, 0.0, 0.0, 0.0)
+        };
+
+        Octree::build(mesh, aabb, options)
     }
+}
 
-    fn subdivide(&mut self, octant_index: usize) -> Vec<usize> {
-        let octant_aabb = self.aabbs[self.nodes[octant_index].aabb_index].clone();
-
-        let Vector3d {
-            x: x_min,
-            y: y_min,
-            z: z_min,
-        } = octant_aabb.min_coords;
-
-        let Vector3d {
-            x: x_max,
-            y: y_max,
-            z: z_max,
-        } = octant_aabb.max_coords;
-
-        let half_x_distance = (x_max - x_min) / 2.0;
-        let half_y_distance = (y_max - y_min) / 2.0;
-        let half_z_distance = (z_max - z_min) / 2.0;
-
-        let bottom_back_left = Aabb::new(
-            x_min,
-            x_min + half_x_distance,
-            y_min,
-            y_min + half_y_distance,
-            z_min,
-            z_min + half_z_distance,
-        );
+/// Build an octant and everything below it from the triangles that overlap it.
+/// Octants over capacity are split, triangles overlapping a single child move down into it
+/// and the rest stay here unless straddling triangles are being duplicated.
+fn build_octant(
+    aabb: Aabb,
+    triangles: Vec<u32>,
+    depth: u32,
+    triangle_aabbs: &[Aabb],
+    options: &OctreeBuildOptions,
+) -> OctantNode {
+    let size = aabb.max_coords - aabb.min_coords;
+    let smallest_child_side = f64::min(size.x, f64::min(size.y, size.z)) / 2.0;
 
-        let bottom_front_left = Aabb::new(
-            x_min,
-            x_min + half_x_distance,
-            y_min,
-            y_min + half_y_distance,
-            z_min + half_z_distance,
-            z_max,
-        );
+    if triangles.len() <= options.leaf_capacity
+        || depth >= options.max_depth
+        || smallest_child_side < options.min_node_size
+    {
+        return OctantNode {
+            aabb,
+            triangles,
+            children: vec![],
+        };
+    }
 
-        let bottom_front_right = Aabb::new(
-            x_min + half_x_distance,
-            x_max,
-            y_min,
-            y_min + half_y_distance,
-            z_min + half_z_distance,
-            z_max,
-        );
+    let child_aabbs = subdivide(&aabb);
 
-        let bottom_back_right = Aabb::new(
-            x_min + half_x_distance,
-            x_max,
-            y_min,
-            y_min + half_y_distance,
-            z_min,
-            z_min + half_z_distance,
-        );
+    // Which children each triangle overlaps, one bit per child
+    let overlap_masks: Vec<u8> = if triangles.len() >= PARALLEL_BUILD_THRESHOLD {
+        triangles
+            .par_iter()
+            .map(|&t| overlap_mask(&child_aabbs, &triangle_aabbs[t as usize]))
+            .collect()
+    } else {
+        triangles
+            .iter()
+            .map(|&t| overlap_mask(&child_aabbs, &triangle_aabbs[t as usize]))
+            .collect()
+    };
 
-        let top_back_left = Aabb::new(
-            x_min,
-            x_min + half_x_distance,
-            y_min + half_y_distance,
-            y_max,
-            z_min,
-            z_min + half_z_distance,
-        );
+    let mut kept = vec![];
+    let mut child_triangles: [Vec<u32>; 8] = Default::default();
 
-        let top_front_left = Aabb::new(
-            x_min,
-            x_min + half_x_distance,
-            y_min + half_y_distance,
-            y_max,
-            z_min + half_z_distance,
-            z_max,
-        );
+    for (&triangle_index, &mask) in triangles.iter().zip(&overlap_masks) {
+        let push_down =
+            mask.count_ones() == 1 || (options.duplicate_straddling_triangles && mask != 0);
 
-        let top_front_right = Aabb::new(
-            x_min + half_x_distance,
-            x_max,
-            y_min + half_y_distance,
-            y_max,
-            z_min + half_z_distance,
-            z_max,
-        );
+        if !push_down {
+            kept.push(triangle_index);
+            continue;
+        }
 
-        let top_back_right = Aabb::new(
-            x_min + half_x_distance,
-            x_max,
-            y_min + half_y_distance,
-            y_max,
-            z_min,
-            z_min + half_z_distance,
-        );
+        for (child, child_triangles) in child_triangles.iter_mut().enumerate() {
+            if mask & (1 << child) != 0 {
+                child_triangles.push(triangle_index);
+            }
+        }
+    }
+
+    let occupied: Vec<(Aabb, Vec<u32>)> = child_aabbs
+        .into_iter()
+        .zip(child_triangles)
+        .filter(|(_, triangles)| !triangles.is_empty())
+        .collect();
+
+    let children = if triangles.len() >= PARALLEL_BUILD_THRESHOLD {
+        occupied
+            .into_par_iter()
+            .map(|(aabb, triangles)| {
+                build_octant(aabb, triangles, depth + 1, triangle_aabbs, options)
+            })
+            .collect()
+    } else {
+        occupied
+            .into_iter()
+            .map(|(aabb, triangles)| {
+                build_octant(aabb, triangles, depth + 1, triangle_aabbs, options)
+            })
+            .collect()
+    };
+
+    OctantNode {
+        aabb,
+        triangles: kept,
+        children,
+    }
+}
+
+fn overlap_mask(child_aabbs: &[Aabb; 8], triangle_aabb: &Aabb) -> u8 {
+    child_aabbs
+        .iter()
+        .enumerate()
+        .filter(|(_, child_aabb)| child_aabb.intersects(triangle_aabb))
+        .fold(0, |mask, (child, _)| mask | (1 << child))
+}
+
+/// Lay the built octants out in contiguous arrays breadth first,
+/// so siblings (which are usually visited together) sit next to each other.
+fn flatten(root: OctantNode) -> (Vec<OctreeNode>, Vec<u32>) {
+    let mut nodes: Vec<OctreeNode> = vec![];
+    let mut triangle_indices: Vec<u32> = vec![];
 
-        let mut child_indices = vec![];
-
-        self.nodes[octant_index].children = vec![];
-
-        for abb in [
-            bottom_back_left,
-            bottom_front_left,
-            bottom_front_right,
-            bottom_back_right,
-            top_back_left,
-            top_front_left,
-            top_front_right,
-            top_back_right,
-        ] {
-            let new_node_index = self.nodes.len();
-            let new_aabb_index = self.aabbs.len();
-
-            self.aabbs.push(abb);
-            self.nodes.push(OctantNode {
-                aabb_index: new_aabb_index,
-                triangles: vec![],
-                children: vec![],
-                triangle_count: 0,
-            });
-            self.nodes[octant_index].children.push(new_node_index);
-            child_indices.push(new_node_index);
+    let empty_node = OctreeNode {
+        aabb: root.aabb.into(),
+        first_child: 0,
+        child_count: 0,
+        first_triangle: 0,
+        triangle_count: 0,
+    };
+
+    nodes.push(empty_node.clone());
+
+    // (octant, index in the flattened nodes)
+    let mut queue = VecDeque::from([(root, 0)]);
+
+    while let Some((octant, node_index)) = queue.pop_front() {
+        let first_triangle = triangle_indices.len() as u32;
+        let triangle_count = octant.triangles.len() as u32;
+        triangle_indices.extend(octant.triangles);
+
+        let first_child = nodes.len() as u32;
+        let child_count = octant.children.len() as u32;
+
+        for child in octant.children {
+            queue.push_back((child, nodes.len()));
+            nodes.push(empty_node.clone());
         }
 
-        child_indices
+        nodes[node_index] = OctreeNode {
+            aabb: octant.aabb.into(),
+            first_child,
+            child_count,
+            first_triangle,
+            triangle_count,
+        };
     }
+
+    (nodes, triangle_indices)
+}
+
+/// Split a box into its eight octants
+fn subdivide(aabb: &Aabb) -> [Aabb; 8] {
+    let Vector3d {
+        x: x_min,
+        y: y_min,
+        z: z_min,
+    } = aabb.min_coords;
+
+    let Vector3d {
+        x: x_max,
+        y: y_max,
+        z: z_max,
+    } = aabb.max_coords;
+
+    let half_x_distance = (x_max - x_min) / 2.0;
+    let half_y_distance = (y_max - y_min) / 2.0;
+    let half_z_distance = (z_max - z_min) / 2.0;
+
+    let bottom_back_left = Aabb::new(
+        x_min,
+        x_min + half_x_distance,
+        y_min,
+        y_min + half_y_distance,
+        z_min,
+        z_min + half_z_distance,
+    );
+
+    let bottom_front_left = Aabb::new(
+        x_min,
+        x_min + half_x_distance,
+        y_min,
+        y_min + half_y_distance,
+        z_min + half_z_distance,
+        z_max,
+    );
+
+    let bottom_front_right = Aabb::new(
+        x_min + half_x_distance,
+        x_max,
+        y_min,
+        y_min + half_y_distance,
+        z_min + half_z_distance,
+        z_max,
+    );
+
+    let bottom_back_right = Aabb::new(
+        x_min + half_x_distance,
+        x_max,
+        y_min,
+        y_min + half_y_distance,
+        z_min,
+        z_min + half_z_distance,
+    );
+
+    let top_back_left = Aabb::new(
+        x_min,
+        x_min + half_x_distance,
+        y_min + half_y_distance,
+        y_max,
+        z_min,
+        z_min + half_z_distance,
+    );
+
+    let top_front_left = Aabb::new(
+        x_min,
+        x_min + half_x_distance,
+        y_min + half_y_distance,
+        y_max,
+        z_min + half_z_distance,
+        z_max,
+    );
+
+    let top_front_right = Aabb::new(
+        x_min + half_x_distance,
+        x_max,
+        y_min + half_y_distance,
+        y_max,
+        z_min + half_z_distance,
+        z_max,
+    );
+
+    let top_back_right = Aabb::new(
+        x_min + half_x_distance,
+        x_max,
+        y_min + half_y_distance,
+        y_max,
+        z_min,
+        z_min + half_z_distance,
+    );
+
+    [
+        bottom_back_left,
+        bottom_front_left,
+        bottom_front_right,
+        bottom_back_right,
+        top_back_left,
+        top_front_left,
+        top_front_right,
+        top_back_right,
+    ]
 }
 
 // #[cfg(test)]
@@ -650,3 +909,156 @@ impl Octree {
 //         assert_eq!(octree.triangle_aabb_map, HashMap::from([(0, 1), (1, 2)]));
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::{accelerator::Accelerator, ray::Ray, test_support::*};
+    use crate::scene::sampling::Sampler;
+
+    use super::*;
+
+    #[test]
+    fn test_octree_closest_hit_matches_brute_force() {
+        let triangles = mesh(&random_triangles(&mut Sampler::new(1)));
+
+        assert_matches_brute_force(&build_octree(&triangles), &triangles);
+    }
+
+    #[test]
+    fn test_octree_with_duplicated_straddlers_matches_brute_force() {
+        let triangles = mesh(&random_triangles(&mut Sampler::new(3)));
+        let octree = build_octree_with_options(
+            &triangles,
+            OctreeBuildOptions {
+                max_depth: 4,
+                leaf_capacity: 4,
+                min_node_size: 2.0,
+                duplicate_straddling_triangles: true,
+            },
+        );
+
+        let stats = octree.stats();
+        assert!(stats.triangle_reference_count > triangles.len());
+        assert!(stats.nodes_per_depth.len() <= 5);
+        assert_eq!(
+            stats.nodes_per_depth.iter().sum::<usize>(),
+            stats.node_count
+        );
+
+        assert_matches_brute_force(&octree, &triangles);
+    }
+
+    #[test]
+    fn test_octree_leaf_capacity_limits_leaf_size() {
+        let triangles = mesh(&random_triangles(&mut Sampler::new(4)));
+        let octree = build_octree_with_options(
+            &triangles,
+            OctreeBuildOptions {
+                max_depth: 32,
+                leaf_capacity: 4,
+                min_node_size: 0.0,
+                duplicate_straddling_triangles: false,
+            },
+        );
+
+        let stats = octree.stats();
+        assert!(stats.leaves_per_triangle_count.len() <= 5);
+        assert_eq!(stats.triangle_reference_count, triangles.len());
+
+        assert_matches_brute_force(&octree, &triangles);
+    }
+
+    #[test]
+    fn test_parallel_octree_build_matches_brute_force() {
+        // Enough triangles that the top of the tree is split across threads
+        let mut sampler = Sampler::new(5);
+        let triangles: Vec<[Vector3d; 3]> = (0..6)
+            .flat_map(|_| random_triangles(&mut sampler))
+            .collect();
+        let triangles = mesh(&triangles);
+
+        let octree = build_octree(&triangles);
+
+        let single_threaded = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| build_octree(&triangles));
+
+        assert_eq!(octree, single_threaded);
+        assert_matches_brute_force(&octree, &triangles);
+    }
+
+    #[test]
+    fn test_octree_finds_closer_hit_in_later_octant() {
+        // A long sliver stuck in the root octant is hit far away, the triangle
+        // in the second octant the ray passes through is much closer.
+        let far_sliver = triangle(
+            vector(-19.0, 0.5, 19.0),
+            vector(19.0, 0.5, 19.0),
+            vector(0.0, 0.6, -19.0),
+        );
+        let near = triangle(
+            vector(5.0, -5.0, 1.0),
+            vector(5.0, 5.0, 1.0),
+            vector(15.0, 0.0, 1.0),
+        );
+        let triangles = mesh(&[far_sliver, near]);
+        let octree = build_octree(&triangles);
+
+        let ray = Ray {
+            origin: vector(8.0, 0.51, -30.0),
+            direction: vector(0.0, 0.0, 1.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+
+        let hit = octree.closest_hit(&ray, f64::INFINITY).unwrap();
+
+        assert_eq!(hit.triangle_index, 1);
+        assert_eq!(hit.t, 31.0);
+    }
+
+    #[test]
+    fn test_axis_parallel_ray_on_octant_boundary_is_not_culled() {
+        let triangles = mesh(&[
+            triangle(
+                vector(1.0, 1.0, 5.0),
+                vector(1.0, 3.0, 5.0),
+                vector(3.0, 1.0, 5.0),
+            ),
+            triangle(
+                vector(-3.0, -3.0, 5.0),
+                vector(-3.0, -1.0, 5.0),
+                vector(-1.0, -3.0, 5.0),
+            ),
+        ]);
+        let octree = build_octree(&triangles);
+
+        // x = 0 and y = 0 are both octant boundaries, and the direction has no x or y part
+        let ray = Ray {
+            origin: vector(0.0, 0.0, -10.0),
+            direction: vector(0.0, 0.0, 1.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        let box_containing_ray = Aabb::new(0.0, 20.0, 0.0, 20.0, -20.0, 20.0);
+
+        // The exit is widened very slightly so the slab test stays conservative
+        let (entry, exit) = ray
+            .intersect_aabb_interval(&box_containing_ray, &ray.inverse_direction())
+            .expect("ray lies on the box boundary");
+        assert_eq!(entry, 0.0);
+        assert!((30.0..30.0 + 1e-9).contains(&exit));
+        assert_eq!(
+            octree.closest_hit(&ray, f64::INFINITY).map(|hit| hit.t),
+            brute_force_closest_hit(
+                &ray,
+                &triangles,
+                f64::INFINITY,
+                TriangleTest::MollerTrumbore
+            )
+            .map(|hit| hit.0)
+        );
+    }
+}
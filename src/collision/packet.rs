@@ -0,0 +1,643 @@
+use std::ops::Range;
+
+use crate::scene::{csg::SolidHit, mesh::Mesh};
+
+use super::{
+    aabb::Aabb,
+    accelerator::Accelerator,
+    bvh::Bvh,
+    octree::Octree,
+    ray::{Ray, RayTriangleIntersectionResult, TriangleIntersector, TriangleTest, GAMMA_3},
+};
+
+/// Number of rays traced together in a packet
+pub const PACKET_WIDTH: usize = 4;
+
+/// One value per ray in a packet. Each step works on every lane in a loop simple enough
+/// for the compiler to turn into SIMD instructions.
+type Lanes = [f64; PACKET_WIDTH];
+
+/// A result for each ray of a packet, in the same order as the rays
+pub type PacketHits = [Option<RayTriangleIntersectionResult>; PACKET_WIDTH];
+
+/// What packet traversal needs from an acceleration structure's tree of boxes
+pub(super) trait PacketTree: Accelerator {
+    fn node_aabb(&self, node: usize) -> Aabb;
+
+    fn node_children(&self, node: usize) -> Range<usize>;
+
+    /// Triangles held by the node itself
+    fn node_triangles(&self, node: usize) -> impl Iterator<Item = usize> + '_;
+
+    /// Primitives with no box, which aren't in any node and are tested by every ray
+    fn unbounded(&self) -> &[usize];
+
+    /// Whether there are no nodes with anything in them to visit
+    fn is_tree_empty(&self) -> bool;
+
+    fn triangle_test(&self) -> TriangleTest;
+}
+
+impl PacketTree for Octree {
+    fn node_aabb(&self, node: usize) -> Aabb {
+        self.nodes[node].aabb.into()
+    }
+
+    fn node_children(&self, node: usize) -> Range<usize> {
+        let node = &self.nodes[node];
+        node.first_child as usize..(node.first_child + node.child_count) as usize
+    }
+
+    fn node_triangles(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        let node = &self.nodes[node];
+        let first = node.first_triangle as usize;
+
+        self.triangle_indices[first..first + node.triangle_count as usize]
+            .iter()
+            .map(|&index| index as usize)
+    }
+
+    fn unbounded(&self) -> &[usize] {
+        &self.unbounded
+    }
+
+    fn is_tree_empty(&self) -> bool {
+        self.triangle_indices.is_empty()
+    }
+
+    fn triangle_test(&self) -> TriangleTest {
+        self.triangle_test
+    }
+}
+
+impl PacketTree for Bvh {
+    fn node_aabb(&self, node: usize) -> Aabb {
+        self.nodes[node].aabb.into()
+    }
+
+    fn node_children(&self, node: usize) -> Range<usize> {
+        let node = &self.nodes[node];
+
+        if node.triangle_count > 0 {
+            0..0
+        } else {
+            node.first..node.first + 2
+        }
+    }
+
+    fn node_triangles(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        let node = &self.nodes[node];
+
+        // Interior nodes use `first` for their children instead
+        let triangles = if node.triangle_count > 0 {
+            &self.triangle_indices[node.first..node.first + node.triangle_count]
+        } else {
+            &[]
+        };
+
+        triangles.iter().copied()
+    }
+
+    fn unbounded(&self) -> &[usize] {
+        &self.unbounded
+    }
+
+    fn is_tree_empty(&self) -> bool {
+        self.triangle_indices.is_empty()
+    }
+
+    fn triangle_test(&self) -> TriangleTest {
+        self.triangle_test
+    }
+}
+
+/// The closest hit for each ray, traced together as a packet unless the rays diverge
+pub(super) fn closest_hits(
+    tree: &impl PacketTree,
+    rays: [Option<&Ray>; PACKET_WIDTH],
+    max_t: f64,
+) -> PacketHits {
+    match RayPacket::new(rays) {
+        Some(packet) => packet.intersect_with_tree(tree, max_t),
+        None => rays.map(|ray| ray.and_then(|ray| tree.closest_hit(ray, max_t))),
+    }
+}
+
+/// Whether each ray hits anything, traced together as a packet unless the rays diverge
+pub(super) fn any_hits(
+    tree: &impl PacketTree,
+    rays: [Option<&Ray>; PACKET_WIDTH],
+    max_t: f64,
+) -> [bool; PACKET_WIDTH] {
+    match RayPacket::new(rays) {
+        Some(packet) => packet.intersects_anything_in_tree(tree, max_t),
+        None => rays.map(|ray| ray.is_some_and(|ray| tree.any_hit(ray, max_t))),
+    }
+}
+
+fn direction_signs(ray: &Ray) -> [bool; 3] {
+    [
+        ray.direction.x.is_sign_negative(),
+        ray.direction.y.is_sign_negative(),
+        ray.direction.z.is_sign_negative(),
+    ]
+}
+
+/// Rays traced through an acceleration structure together, stored a lane per ray.
+///
+/// Neighbouring primary rays, and shadow rays from neighbouring points towards the same light,
+/// visit nearly the same nodes. Each node's box is tested against every ray at once and its
+/// triangles are only fetched once. Lanes that miss a node just sit it out, and lanes without
+/// a ray never report anything. Every lane gets exactly the answer its ray would on its own.
+pub(super) struct RayPacket<'r> {
+    rays: [Option<&'r Ray>; PACKET_WIDTH],
+    origin: [Lanes; 3],
+    direction: [Lanes; 3],
+    inverse_direction: [Lanes; 3],
+}
+
+impl<'r> RayPacket<'r> {
+    /// A packet of the given rays, or `None` if there are none or their directions point into
+    /// different octants. Rays like that split up straight away and are better traced alone.
+    pub(super) fn new(rays: [Option<&'r Ray>; PACKET_WIDTH]) -> Option<RayPacket<'r>> {
+        let first = rays.iter().flatten().next()?;
+        let signs = direction_signs(first);
+
+        if rays
+            .iter()
+            .flatten()
+            .any(|ray| direction_signs(ray) != signs)
+        {
+            return None;
+        }
+
+        let mut origin = [[0.0; PACKET_WIDTH]; 3];
+        let mut direction = [[0.0; PACKET_WIDTH]; 3];
+        let mut inverse_direction = [[0.0; PACKET_WIDTH]; 3];
+
+        for (lane, ray) in rays.iter().enumerate() {
+            // Empty lanes copy a real ray so their arithmetic stays finite, their results are dropped
+            let ray = ray.unwrap_or(first);
+            let inverse = ray.inverse_direction();
+
+            origin[0][lane] = ray.origin.x;
+            origin[1][lane] = ray.origin.y;
+            origin[2][lane] = ray.origin.z;
+            direction[0][lane] = ray.direction.x;
+            direction[1][lane] = ray.direction.y;
+            direction[2][lane] = ray.direction.z;
+            inverse_direction[0][lane] = inverse.x;
+            inverse_direction[1][lane] = inverse.y;
+            inverse_direction[2][lane] = inverse.z;
+        }
+
+        Some(RayPacket {
+            rays,
+            origin,
+            direction,
+            inverse_direction,
+        })
+    }
+
+    /// Where each ray enters the box, infinity for rays that miss it.
+    /// Lane for lane the same as `Ray::intersect_aabb_interval`.
+    fn box_entries(&self, aabb: &Aabb) -> Lanes {
+        let min = [aabb.min_coords.x, aabb.min_coords.y, aabb.min_coords.z];
+        let max = [aabb.max_coords.x, aabb.max_coords.y, aabb.max_coords.z];
+
+        let mut near = [f64::NEG_INFINITY; PACKET_WIDTH];
+        let mut far = [f64::INFINITY; PACKET_WIDTH];
+
+        for axis in 0..3 {
+            for lane in 0..PACKET_WIDTH {
+                let t1 = (min[axis] - self.origin[axis][lane]) * self.inverse_direction[axis][lane];
+                let t2 = (max[axis] - self.origin[axis][lane]) * self.inverse_direction[axis][lane];
+
+                // A ray parallel to the slab starting on one of its planes is inside for its whole length
+                let (slab_near, slab_far) = if t1.is_nan() || t2.is_nan() {
+                    (f64::NEG_INFINITY, f64::INFINITY)
+                } else {
+                    (f64::min(t1, t2), f64::max(t1, t2))
+                };
+
+                near[lane] = f64::max(near[lane], slab_near);
+                far[lane] = f64::min(far[lane], slab_far);
+            }
+        }
+
+        let mut entries = [f64::INFINITY; PACKET_WIDTH];
+
+        for lane in 0..PACKET_WIDTH {
+            let far = far[lane] * (1.0 + 2.0 * GAMMA_3);
+
+            if far >= 0.0 && near[lane] <= far {
+                entries[lane] = near[lane].max(0.0);
+            }
+        }
+
+        entries
+    }
+
+    /// Möller–Trumbore against every lane at once, lane for lane the same as
+    /// `Ray::intersect_with_triangle`. Only lanes in `lanes` can hit.
+    fn intersect_triangle_lanes(
+        &self,
+        mesh: &Mesh,
+        triangle_index: usize,
+        lanes: [bool; PACKET_WIDTH],
+        intersectors: &[Option<TriangleIntersector<'r>>; PACKET_WIDTH],
+    ) -> PacketHits {
+        let mut origin = self.origin;
+        let mut direction = self.direction;
+
+        // Each lane is moved into the triangle's space at its own time, worked out once per
+        // motion by the lane's intersector
+        if mesh.motion(triangle_index).is_some() {
+            for (lane, intersector) in intersectors.iter().enumerate() {
+                let Some(intersector) = intersector else {
+                    continue;
+                };
+                let (moved_origin, moved_direction) = intersector.at_rest(mesh, triangle_index);
+
+                origin[0][lane] = moved_origin.x;
+                origin[1][lane] = moved_origin.y;
+                origin[2][lane] = moved_origin.z;
+                direction[0][lane] = moved_direction.x;
+                direction[1][lane] = moved_direction.y;
+                direction[2][lane] = moved_direction.z;
+            }
+        }
+
+        let (v1, edge1, edge2) = mesh.edges(triangle_index);
+
+        let mut a = [0.0; PACKET_WIDTH];
+        let mut u = [0.0; PACKET_WIDTH];
+        let mut v = [0.0; PACKET_WIDTH];
+        let mut t = [0.0; PACKET_WIDTH];
+
+        for lane in 0..PACKET_WIDTH {
+            let (dx, dy, dz) = (direction[0][lane], direction[1][lane], direction[2][lane]);
+
+            // h = direction x edge2
+            let hx = dy * edge2.z - dz * edge2.y;
+            let hy = -(dx * edge2.z - dz * edge2.x);
+            let hz = dx * edge2.y - dy * edge2.x;
+
+            a[lane] = (edge1.x * hx) + (edge1.y * hy) + (edge1.z * hz);
+            let f = 1.0 / a[lane];
+
+            let sx = origin[0][lane] - v1.x;
+            let sy = origin[1][lane] - v1.y;
+            let sz = origin[2][lane] - v1.z;
+
+            u[lane] = f * ((sx * hx) + (sy * hy) + (sz * hz));
+
+            // q = s x edge1
+            let qx = sy * edge1.z - sz * edge1.y;
+            let qy = -(sx * edge1.z - sz * edge1.x);
+            let qz = sx * edge1.y - sy * edge1.x;
+
+            v[lane] = f * ((dx * qx) + (dy * qy) + (dz * qz));
+            t[lane] = f * ((edge2.x * qx) + (edge2.y * qy) + (edge2.z * qz));
+        }
+
+        std::array::from_fn(|lane| {
+            let ray = self.rays[lane]?;
+
+            let hit = lanes[lane]
+                && !ray.ignores(triangle_index)
+                && !(a[lane] > -f64::EPSILON && a[lane] < f64::EPSILON)
+                && (0.0..=1.0).contains(&u[lane])
+                && v[lane] >= 0.0
+                && u[lane] + v[lane] <= 1.0
+                && t[lane] > f64::EPSILON;
+
+            hit.then_some(RayTriangleIntersectionResult {
+                t: t[lane],
+                u: u[lane],
+                v: v[lane],
+                triangle_index,
+                instance: None,
+                solid: SolidHit::default(),
+            })
+        })
+    }
+
+    fn intersect_triangle(
+        &self,
+        mesh: &Mesh,
+        triangle_index: usize,
+        lanes: [bool; PACKET_WIDTH],
+        intersectors: &[Option<TriangleIntersector<'r>>; PACKET_WIDTH],
+        test: TriangleTest,
+    ) -> PacketHits {
+        // Shapes are few and varied, so they're tested a lane at a time
+        if let Some(shape) = mesh.shape(triangle_index) {
+            return std::array::from_fn(|lane| {
+                let ray = self.rays[lane]?;
+
+                if lanes[lane] {
+                    ray.intersect_with_shape(shape, triangle_index)
+                } else {
+                    None
+                }
+            });
+        }
+
+        match test {
+            TriangleTest::MollerTrumbore => {
+                self.intersect_triangle_lanes(mesh, triangle_index, lanes, intersectors)
+            }
+            // The watertight test depends on each ray's own axes, so it runs a lane at a time
+            TriangleTest::Watertight => std::array::from_fn(|lane| {
+                let intersector = intersectors[lane].as_ref()?;
+
+                if lanes[lane] {
+                    intersector.intersect(mesh, triangle_index)
+                } else {
+                    None
+                }
+            }),
+        }
+    }
+
+    /// Each lane's ray prepared for the test, and for moving triangles
+    fn intersectors(&self, test: TriangleTest) -> [Option<TriangleIntersector<'r>>; PACKET_WIDTH] {
+        self.rays
+            .map(|ray| ray.map(|ray| ray.triangle_intersector(test)))
+    }
+
+    /// The closest hit before `max_t` for each ray, nodes are visited nearest first for the packet
+    /// as a whole and skipped once every lane has found something closer than where it enters.
+    fn intersect_with_tree(&self, tree: &impl PacketTree, max_t: f64) -> PacketHits {
+        let mut closest: PacketHits = std::array::from_fn(|_| None);
+        let test = tree.triangle_test();
+        let intersectors = self.intersectors(test);
+
+        // Empty lanes start off with nothing left to look for
+        let mut closest_t = self.rays.map(|ray| {
+            if ray.is_some() {
+                max_t
+            } else {
+                f64::NEG_INFINITY
+            }
+        });
+
+        // Primitives with no box aren't in the tree, so every lane tests them first
+        let every_lane = self.rays.map(|ray| ray.is_some());
+        for &index in tree.unbounded() {
+            let hits = self.intersect_triangle(tree.mesh(), index, every_lane, &intersectors, test);
+
+            for (lane, hit) in hits.into_iter().enumerate() {
+                if let Some(hit) = hit {
+                    if hit.t < closest_t[lane] {
+                        closest_t[lane] = hit.t;
+                        closest[lane] = Some(hit);
+                    }
+                }
+            }
+        }
+
+        if tree.is_tree_empty() {
+            return closest;
+        }
+
+        // Nodes still to visit along with where each lane enters them
+        let mut stack: Vec<(usize, Lanes)> = Vec::with_capacity(64);
+        stack.push((0, self.box_entries(&tree.node_aabb(0))));
+
+        while let Some((node, entries)) = stack.pop() {
+            // Lanes that could still find something closer in this node
+            let lanes: [bool; PACKET_WIDTH] =
+                std::array::from_fn(|lane| entries[lane] < closest_t[lane]);
+
+            if !lanes.contains(&true) {
+                continue;
+            }
+
+            for triangle_index in tree.node_triangles(node) {
+                let hits = self.intersect_triangle(
+                    tree.mesh(),
+                    triangle_index,
+                    lanes,
+                    &intersectors,
+                    test,
+                );
+
+                for (lane, hit) in hits.into_iter().enumerate() {
+                    if let Some(hit) = hit {
+                        if hit.t < closest_t[lane] {
+                            closest_t[lane] = hit.t;
+                            closest[lane] = Some(hit);
+                        }
+                    }
+                }
+            }
+
+            // Octree nodes have at most 8 children and BVH nodes 2
+            let mut children: [(f64, usize, Lanes); 8] = [(0.0, 0, [0.0; PACKET_WIDTH]); 8];
+            let mut num_children = 0;
+
+            for child in tree.node_children(node) {
+                let child_entries = self.box_entries(&tree.node_aabb(child));
+
+                let nearest = (0..PACKET_WIDTH)
+                    .filter(|&lane| child_entries[lane] < closest_t[lane])
+                    .map(|lane| child_entries[lane])
+                    .min_by(f64::total_cmp);
+
+                if let Some(nearest) = nearest {
+                    children[num_children] = (nearest, child, child_entries);
+                    num_children += 1;
+                }
+            }
+
+            // Push the farthest child first so the nearest is popped first
+            let children = &mut children[..num_children];
+            children.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            for &(_, child, child_entries) in children.iter() {
+                stack.push((child, child_entries));
+            }
+        }
+
+        closest
+    }
+
+    /// Whether each ray hits anything before `max_t`, stops once every lane has found something
+    fn intersects_anything_in_tree(
+        &self,
+        tree: &impl PacketTree,
+        max_t: f64,
+    ) -> [bool; PACKET_WIDTH] {
+        let mut done = self.rays.map(|ray| ray.is_none());
+        let test = tree.triangle_test();
+        let intersectors = self.intersectors(test);
+
+        let every_lane = self.rays.map(|ray| ray.is_some());
+        for &index in tree.unbounded() {
+            let hits = self.intersect_triangle(tree.mesh(), index, every_lane, &intersectors, test);
+
+            for (lane, hit) in hits.iter().enumerate() {
+                if hit.as_ref().is_some_and(|hit| hit.t < max_t) {
+                    done[lane] = true;
+                }
+            }
+        }
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+
+        if !tree.is_tree_empty() {
+            stack.push(0);
+        }
+
+        while let Some(node) = stack.pop() {
+            let entries = self.box_entries(&tree.node_aabb(node));
+            let lanes: [bool; PACKET_WIDTH] =
+                std::array::from_fn(|lane| !done[lane] && entries[lane] < max_t);
+
+            if !lanes.contains(&true) {
+                continue;
+            }
+
+            for triangle_index in tree.node_triangles(node) {
+                let hits = self.intersect_triangle(
+                    tree.mesh(),
+                    triangle_index,
+                    lanes,
+                    &intersectors,
+                    test,
+                );
+
+                for (lane, hit) in hits.iter().enumerate() {
+                    if hit.as_ref().is_some_and(|hit| hit.t < max_t) {
+                        done[lane] = true;
+                    }
+                }
+            }
+
+            if done.iter().all(|&done| done) {
+                break;
+            }
+
+            stack.extend(tree.node_children(node));
+        }
+
+        std::array::from_fn(|lane| done[lane] && self.rays[lane].is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::{octree::OctreeBuildOptions, test_support::*};
+    use crate::scene::sampling::Sampler;
+
+    use super::*;
+
+    #[test]
+    fn test_octree_packets_match_single_rays() {
+        let mut sampler = Sampler::new(44);
+        let triangles = mesh(&random_triangles(&mut sampler));
+
+        let mut octree = build_octree(&triangles);
+        assert_packets_match_single_rays(&octree, &triangles);
+
+        octree.triangle_test = TriangleTest::Watertight;
+        assert_packets_match_single_rays(&octree, &triangles);
+
+        let duplicated = build_octree_with_options(
+            &triangles,
+            OctreeBuildOptions {
+                duplicate_straddling_triangles: true,
+                ..OctreeBuildOptions::default()
+            },
+        );
+        assert_packets_match_single_rays(&duplicated, &triangles);
+    }
+
+    #[test]
+    fn test_bvh_packets_match_single_rays() {
+        let mut sampler = Sampler::new(45);
+        let triangles = mesh(&random_triangles(&mut sampler));
+
+        let mut bvh = Bvh::build(triangles.clone());
+        assert_packets_match_single_rays(&bvh, &triangles);
+
+        bvh.triangle_test = TriangleTest::Watertight;
+        assert_packets_match_single_rays(&bvh, &triangles);
+    }
+
+    #[test]
+    fn test_diverging_rays_are_not_packed() {
+        let ray = |x: f64, y: f64, z: f64| Ray {
+            origin: vector(0.0, 0.0, 0.0),
+            direction: vector(x, y, z),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        let (a, b, c) = (ray(1.0, 0.2, 1.0), ray(1.0, 0.3, 1.1), ray(1.0, -0.1, 1.0));
+
+        assert!(RayPacket::new([Some(&a), Some(&b), None, Some(&a)]).is_some());
+        assert!(RayPacket::new([Some(&a), Some(&b), Some(&c), None]).is_none());
+        assert!(RayPacket::new([None; PACKET_WIDTH]).is_none());
+    }
+
+    #[test]
+    fn test_diverging_lane_gets_its_own_hit() {
+        // A wall straight ahead, and a small triangle off to the side only one lane is aimed at
+        let triangles = mesh(&[
+            triangle(
+                vector(-2.0, -2.0, 10.0),
+                vector(2.0, -2.0, 10.0),
+                vector(0.0, 2.0, 10.0),
+            ),
+            triangle(
+                vector(30.0, 0.0, 5.0),
+                vector(31.0, 0.0, 5.0),
+                vector(30.0, 1.0, 5.0),
+            ),
+        ]);
+        let ray = |x: f64, y: f64| Ray {
+            origin: vector(0.0, 0.0, 0.0),
+            direction: vector(x, y, 1.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+
+        // All heading the same way on every axis, so they're traced as one packet
+        let rays = [
+            ray(0.01, 0.01),
+            ray(6.1, 0.04),
+            ray(0.02, 0.02),
+            ray(3.0, 3.0),
+        ];
+        let lanes = rays.each_ref().map(Some);
+        assert!(RayPacket::new(lanes).is_some());
+
+        for accelerator in [
+            &build_octree(&triangles) as &dyn Accelerator,
+            &Bvh::build(triangles.clone()),
+        ] {
+            let hits = accelerator.closest_hits(lanes, f64::INFINITY);
+            let hit_triangles = hits.map(|hit| hit.map(|hit| hit.triangle_index));
+
+            // The wall for the lanes going forwards, the side triangle for the one that
+            // isn't, and nothing for the one passing between them
+            assert_eq!(hit_triangles, [Some(0), Some(1), Some(0), None]);
+            assert_eq!(
+                accelerator.any_hits(lanes, f64::INFINITY),
+                [true, true, true, false]
+            );
+
+            // Only the diverging lane's hit is close enough
+            assert_eq!(
+                accelerator.any_hits(lanes, 6.0),
+                [false, true, false, false]
+            );
+
+            for (ray, hit) in rays.iter().zip(hits) {
+                assert_eq!(hit, accelerator.closest_hit(ray, f64::INFINITY));
+            }
+        }
+    }
+}
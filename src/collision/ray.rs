@@ -1,169 +1,943 @@
-use crate::scene::{engine::Vector3d, entities::Triangle};
+use std::cell::RefCell;
+
+use crate::scene::{
+    csg::SolidHit,
+    engine::Vector3d,
+    mesh::Mesh,
+    shape::{Hittable, Shape},
+};
 
 use super::{aabb::Aabb, octree::Octree};
 
-pub struct RayTriangleIntersectionResult<'a> {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RayTriangleIntersectionResult {
     pub t: f64,
     pub u: f64,
     pub v: f64,
-    pub triangle: &'a Triangle,
+    /// Index of the triangle in the mesh, which is in the order the triangles were loaded,
+    /// or of one of the mesh's shapes after them. For shapes `u` and `v` are surface
+    /// coordinates rather than barycentric ones, see `Hittable`.
+    pub triangle_index: usize,
+    /// Which of the scene's instances the triangle belongs to, `None` for the scene's own mesh
+    pub instance: Option<usize>,
+    /// Which solid a hit on a CSG shape is on, so shading doesn't have to trace the ray again
+    pub solid: SolidHit,
+}
+
+/// Relative rounding error bound for a value computed with three floating point operations
+pub(super) static GAMMA_3: f64 = 3.0 * (f64::EPSILON * 0.5) / (1.0 - 3.0 * (f64::EPSILON * 0.5));
+
+/// Which ray-triangle intersection test traversal uses
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum TriangleTest {
+    /// Möller–Trumbore, fast but rays exactly along an edge shared by two triangles can miss both
+    #[default]
+    MollerTrumbore,
+    /// Woop, Benthin and Wald's watertight test, every ray hitting a mesh hits at least one
+    /// triangle, even along shared edges and through shared vertices
+    Watertight,
+}
+
+/// The range of t values for which a ray is between two parallel planes.
+fn slab_interval(min: f64, max: f64, origin: f64, inverse_direction: f64) -> (f64, f64) {
+    let t1 = (min - origin) * inverse_direction;
+    let t2 = (max - origin) * inverse_direction;
+
+    // 0 * infinity, the ray runs parallel to the planes and starts exactly on one of them.
+    // Boxes are closed so it is inside the slab for its whole length.
+    if t1.is_nan() || t2.is_nan() {
+        return (f64::NEG_INFINITY, f64::INFINITY);
+    }
+
+    (f64::min(t1, t2), f64::max(t1, t2))
+}
+
+/// The per ray part of the watertight test: the ray is turned into one along +z from the
+/// origin, by swapping axes so z is the direction's largest component and then shearing.
+#[derive(Copy, Clone)]
+struct WatertightRay {
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    shear_x: f64,
+    shear_y: f64,
+    shear_z: f64,
+}
+
+impl WatertightRay {
+    fn new(direction: &Vector3d) -> WatertightRay {
+        let abs = [direction.x.abs(), direction.y.abs(), direction.z.abs()];
+
+        let kz = if abs[0] > abs[1] && abs[0] > abs[2] {
+            0
+        } else if abs[1] > abs[2] {
+            1
+        } else {
+            2
+        };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+
+        // Keep the triangle winding the same after the axes are swapped
+        if component(direction, kz) < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+
+        let dz = component(direction, kz);
+
+        WatertightRay {
+            kx,
+            ky,
+            kz,
+            shear_x: component(direction, kx) / dz,
+            shear_y: component(direction, ky) / dz,
+            shear_z: 1.0 / dz,
+        }
+    }
+}
+
+fn component(v: &Vector3d, axis: usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+/// A ray prepared for testing against many triangles, see `Ray::triangle_intersector`
+pub struct TriangleIntersector<'r> {
+    ray: &'r Ray,
+    watertight: Option<WatertightRay>,
+    /// The ray moved into the space of each of the mesh's motions, worked out the first time
+    /// a triangle with that motion is tested and kept for the rest of the ray's traversal
+    moved: RefCell<Vec<Option<MovedRay>>>,
 }
-pub struct RayAABBIntersectionResult {
-    t: f64,
+
+/// A ray at rest relative to a moving triangle, see `Ray::at_rest`
+#[derive(Copy, Clone)]
+struct MovedRay {
+    origin: Vector3d,
+    direction: Vector3d,
+    watertight: Option<WatertightRay>,
 }
 
+impl TriangleIntersector<'_> {
+    /// The ray's origin and direction relative to a triangle of the mesh, as `Ray::at_rest`
+    /// but only working out the transform once for each of the mesh's motions.
+    /// An intersector is only ever used with one mesh.
+    pub(super) fn at_rest(&self, mesh: &Mesh, triangle_index: usize) -> (Vector3d, Vector3d) {
+        match self.moved(mesh, triangle_index) {
+            Some(moved) => (moved.origin, moved.direction),
+            None => (self.ray.origin, self.ray.direction),
+        }
+    }
+
+    fn moved(&self, mesh: &Mesh, triangle_index: usize) -> Option<MovedRay> {
+        let motion = mesh.faces.get(triangle_index)?.motion? as usize;
+        let mut moved = self.moved.borrow_mut();
+
+        if moved.is_empty() {
+            moved.resize(mesh.motions.len(), None);
+        }
+
+        Some(*moved[motion].get_or_insert_with(|| {
+            let (origin, direction) = self.ray.at_rest(mesh, triangle_index);
+
+            MovedRay {
+                origin,
+                direction,
+                // Turning or scaling the ray into a moving triangle's space changes its direction
+                watertight: self.watertight.map(|_| WatertightRay::new(&direction)),
+            }
+        }))
+    }
+
+    pub fn intersect(
+        &self,
+        mesh: &Mesh,
+        triangle_index: usize,
+    ) -> Option<RayTriangleIntersectionResult> {
+        if let Some(shape) = mesh.shape(triangle_index) {
+            return self.ray.intersect_with_shape(shape, triangle_index);
+        }
+
+        if self.ray.ignores(triangle_index) {
+            return None;
+        }
+
+        let (origin, direction, watertight) = match self.moved(mesh, triangle_index) {
+            Some(moved) => (moved.origin, moved.direction, moved.watertight),
+            None => (self.ray.origin, self.ray.direction, self.watertight),
+        };
+
+        match watertight {
+            None => intersect_moller_trumbore(origin, direction, mesh, triangle_index),
+            Some(prepared) => intersect_watertight(&prepared, origin, mesh, triangle_index),
+        }
+    }
+
+    /// Every hit on the triangle or shape at an index, nearest first. Only shapes can be hit
+    /// more than once, like a sphere's near and far sides.
+    pub fn intersect_all(
+        &self,
+        mesh: &Mesh,
+        triangle_index: usize,
+    ) -> Vec<RayTriangleIntersectionResult> {
+        match mesh.shape(triangle_index) {
+            Some(shape) => self.ray.intersect_all_with_shape(shape, triangle_index),
+            None => self.intersect(mesh, triangle_index).into_iter().collect(),
+        }
+    }
+}
+
+/// Möller–Trumbore for a ray from `origin` along `direction`
+fn intersect_moller_trumbore(
+    origin: Vector3d,
+    direction: Vector3d,
+    mesh: &Mesh,
+    triangle_index: usize,
+) -> Option<RayTriangleIntersectionResult> {
+    let (v1, edge1, edge2) = mesh.edges(triangle_index);
+    let h = direction.cross(&edge2);
+
+    let a = edge1.dot(&h);
+
+    if a > -f64::EPSILON && a < f64::EPSILON {
+        // This ray is parallel to this triangle.
+        return None;
+    }
+
+    let f = 1.0 / a;
+    let s = origin - v1;
+    let u = f * s.dot(&h);
+
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(&edge1);
+    let v = f * direction.dot(&q);
+
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    // At this stage we can compute t to find out where the intersection point is on the line.
+    let t = f * edge2.dot(&q);
+
+    if t > f64::EPSILON {
+        return Some(RayTriangleIntersectionResult {
+            t,
+            u,
+            v,
+            triangle_index,
+            instance: None,
+            solid: SolidHit::default(),
+        });
+    }
+
+    None
+}
+
+/// The watertight test for a ray from `origin` prepared with `WatertightRay::new`
+fn intersect_watertight(
+    prepared: &WatertightRay,
+    origin: Vector3d,
+    mesh: &Mesh,
+    triangle_index: usize,
+) -> Option<RayTriangleIntersectionResult> {
+    let [v1, v2, v3] = mesh.vertices(triangle_index);
+    let a = v1 - origin;
+    let b = v2 - origin;
+    let c = v3 - origin;
+
+    // Vertices in the ray's sheared space, where the ray runs along +z through (0, 0)
+    let sheared = |v: &Vector3d| {
+        (
+            component(v, prepared.kx) - prepared.shear_x * component(v, prepared.kz),
+            component(v, prepared.ky) - prepared.shear_y * component(v, prepared.kz),
+        )
+    };
+    let (ax, ay) = sheared(&a);
+    let (bx, by) = sheared(&b);
+    let (cx, cy) = sheared(&c);
+
+    // Scaled barycentrics, each is the signed area between the ray and one edge.
+    // An edge's value is worked out the same way for both triangles sharing it,
+    // so a ray can't miss both.
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let determinant = u + v + w;
+
+    if determinant == 0.0 {
+        return None;
+    }
+
+    let az = prepared.shear_z * component(&a, prepared.kz);
+    let bz = prepared.shear_z * component(&b, prepared.kz);
+    let cz = prepared.shear_z * component(&c, prepared.kz);
+
+    let t = (u * az + v * bz + w * cz) / determinant;
+
+    if t <= f64::EPSILON {
+        return None;
+    }
+
+    Some(RayTriangleIntersectionResult {
+        t,
+        u: v / determinant,
+        v: w / determinant,
+        triangle_index,
+        instance: None,
+        solid: SolidHit::default(),
+    })
+}
+
+#[derive(Clone, Debug)]
 pub struct Ray {
     pub origin: Vector3d,
     pub direction: Vector3d,
+    /// When the ray was fired, between 0.0 at the start of the frame and 1.0 at its end,
+    /// moving triangles are hit where they are at that time
+    pub time: f64,
+    /// A triangle traversal should never report a hit on, usually the one a secondary ray
+    /// leaves from. A flat triangle can't be hit again by a ray leaving it, so skipping it
+    /// only throws away hits caused by rounding.
+    pub ignored_triangle: Option<IgnoredTriangle>,
+}
+
+/// A triangle for a ray to skip, as a hit identifies it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IgnoredTriangle {
+    /// The instance the triangle is in, `None` for the mesh the ray is traced through directly.
+    /// Rays moved into an instance's space only keep triangles of that instance, with this `None`.
+    pub instance: Option<usize>,
+    pub triangle_index: usize,
 }
 
 impl Ray {
-    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<RayAABBIntersectionResult> {
-        let t1: f64 = (aabb.min_coords.x - self.origin.x) / self.direction.x;
-        let t2: f64 = (aabb.max_coords.x - self.origin.x) / self.direction.x;
-        let t3: f64 = (aabb.min_coords.y - self.origin.y) / self.direction.y;
-        let t4: f64 = (aabb.max_coords.y - self.origin.y) / self.direction.y;
-        let t5: f64 = (aabb.min_coords.z - self.origin.z) / self.direction.z;
-        let t6: f64 = (aabb.max_coords.z - self.origin.z) / self.direction.z;
-
-        let tmin = f64::max(
-            f64::max(f64::min(t1, t2), f64::min(t3, t4)),
-            f64::min(t5, t6),
+    /// Whether traversal should skip a triangle or flat shape of the mesh the ray is traced through
+    pub fn ignores(&self, triangle_index: usize) -> bool {
+        self.ignored_triangle
+            == Some(IgnoredTriangle {
+                instance: None,
+                triangle_index,
+            })
+    }
+
+    /// 1 / direction, worked out once per ray so box tests can multiply instead of divide
+    pub fn inverse_direction(&self) -> Vector3d {
+        Vector3d {
+            x: 1.0 / self.direction.x,
+            y: 1.0 / self.direction.y,
+            z: 1.0 / self.direction.z,
+        }
+    }
+
+    /// The range of t values for which the ray is inside the box, clipped so it never starts behind the origin.
+    /// `inverse_direction` should come from `Ray::inverse_direction`.
+    pub fn intersect_aabb_interval(
+        &self,
+        aabb: &Aabb,
+        inverse_direction: &Vector3d,
+    ) -> Option<(f64, f64)> {
+        let (x_near, x_far) = slab_interval(
+            aabb.min_coords.x,
+            aabb.max_coords.x,
+            self.origin.x,
+            inverse_direction.x,
         );
-        let tmax = f64::min(
-            f64::min(f64::max(t1, t2), f64::max(t3, t4)),
-            f64::max(t5, t6),
+        let (y_near, y_far) = slab_interval(
+            aabb.min_coords.y,
+            aabb.max_coords.y,
+            self.origin.y,
+            inverse_direction.y,
+        );
+        let (z_near, z_far) = slab_interval(
+            aabb.min_coords.z,
+            aabb.max_coords.z,
+            self.origin.z,
+            inverse_direction.z,
         );
 
-        // Intersection could have happened, but if so its behind the origin.
-        if tmax < 0.0 {
-            return None;
-        }
+        let tmin = f64::max(f64::max(x_near, y_near), z_near);
+        // Widened by the worst case rounding error of the slab distances, so a ray grazing
+        // the box is never rejected by rounding and can't slip between two adjacent boxes
+        let tmax = f64::min(f64::min(x_far, y_far), z_far) * (1.0 + 2.0 * GAMMA_3);
 
-        // No intersection
-        if tmin > tmax {
+        if tmax < 0.0 || tmin > tmax {
             return None;
         }
 
-        // Intersection has occured but O + D * tmin will be behind origin, so use tmax for closest intersection point
-        if tmin < 0.0 {
-            return Some(RayAABBIntersectionResult { t: tmax });
-        }
+        Some((tmin.max(0.0), tmax))
+    }
 
-        return Some(RayAABBIntersectionResult { t: tmin });
+    /// The ray's origin and direction moved by the opposite of a moving triangle's motion,
+    /// which is the same as moving the triangle to where it is at the ray's time.
+    /// Hits have the same t, u and v either way.
+    pub(super) fn at_rest(&self, mesh: &Mesh, triangle_index: usize) -> (Vector3d, Vector3d) {
+        match mesh.motion(triangle_index) {
+            Some(motion) => {
+                let inverse = motion.transform_at(self.time).inverse();
+
+                (
+                    inverse.transform_point(self.origin),
+                    inverse.transform_vector(self.direction),
+                )
+            }
+            None => (self.origin, self.direction),
+        }
     }
 
-    pub fn intersect_with_triangle<'a>(
+    pub fn intersect_with_triangle(
         &self,
-        triangle: &'a Triangle,
-    ) -> Option<RayTriangleIntersectionResult<'a>> {
-        let edge1 = triangle.v2 - triangle.v1;
-        let edge2 = triangle.v3 - triangle.v1;
-        let h = self.direction.cross(&edge2);
+        mesh: &Mesh,
+        triangle_index: usize,
+    ) -> Option<RayTriangleIntersectionResult> {
+        let (origin, direction) = self.at_rest(mesh, triangle_index);
 
-        let a = edge1.dot(&h);
+        intersect_moller_trumbore(origin, direction, mesh, triangle_index)
+    }
 
-        if a > -f64::EPSILON && a < f64::EPSILON {
-            // This ray is parallel to this triangle.
+    /// The ray against one of a mesh's shapes, `index` is the shape's primitive index.
+    /// For shapes `u` and `v` are surface coordinates, see `Hittable`.
+    pub fn intersect_with_shape(
+        &self,
+        shape: &Shape,
+        index: usize,
+    ) -> Option<RayTriangleIntersectionResult> {
+        // A ray leaving a curved shape can hit it again, like the far side of a sphere,
+        // so only flat ones are skipped
+        if self.ignores(index) && shape.is_flat() {
             return None;
         }
 
-        let f = 1.0 / a;
-        let s = self.origin - triangle.v1;
-        let u = f * s.dot(&h);
+        let (t, u, v, solid) = shape.intersect_solid(self)?;
 
-        if u < 0.0 || u > 1.0 {
-            return None;
+        Some(RayTriangleIntersectionResult {
+            t,
+            u,
+            v,
+            triangle_index: index,
+            instance: None,
+            solid,
+        })
+    }
+
+    /// `intersect_with_shape` for every hit on the shape, nearest first
+    pub fn intersect_all_with_shape(
+        &self,
+        shape: &Shape,
+        index: usize,
+    ) -> Vec<RayTriangleIntersectionResult> {
+        if self.ignores(index) && shape.is_flat() {
+            return vec![];
         }
 
-        let q = s.cross(&edge1);
-        let v = f * self.direction.dot(&q);
+        shape
+            .intersect_all_solids(self)
+            .into_iter()
+            .map(|(t, u, v, solid)| RayTriangleIntersectionResult {
+                t,
+                u,
+                v,
+                triangle_index: index,
+                instance: None,
+                solid,
+            })
+            .collect()
+    }
 
-        if v < 0.0 || u + v > 1.0 {
-            return None;
+    /// Get ready to test this ray against many triangles with the given test
+    pub fn triangle_intersector(&self, test: TriangleTest) -> TriangleIntersector<'_> {
+        let watertight = match test {
+            TriangleTest::MollerTrumbore => None,
+            TriangleTest::Watertight => Some(WatertightRay::new(&self.direction)),
+        };
+
+        TriangleIntersector {
+            ray: self,
+            watertight,
+            moved: RefCell::new(vec![]),
         }
+    }
+
+    /// The closest triangle in the octree that the ray hits before `max_t`.
+    /// Nodes are visited with an explicit stack, nearest first, and any node the ray
+    /// only enters after the closest hit found so far is skipped.
+    ///
+    /// This always finds the true closest hit: every point of every triangle is inside the box
+    /// of some node holding that triangle, and inside all of that node's ancestors' boxes
+    /// (see `Octree::build`). So the closest hit point is in a node the ray enters no
+    /// later than the hit itself, and nodes are only skipped once they provably can't beat the
+    /// current best. The search doesn't stop until the stack is empty.
+    pub fn intersect_with_octree(
+        &self,
+        octree: &Octree,
+        max_t: f64,
+    ) -> Option<RayTriangleIntersectionResult> {
+        let inverse_direction = self.inverse_direction();
+        let intersector = self.triangle_intersector(octree.triangle_test);
+
+        let mut closest: Option<RayTriangleIntersectionResult> = None;
+        let mut closest_t = max_t;
+
+        // Primitives with no box aren't in the tree, so every ray tests them first
+        for &index in &octree.unbounded {
+            if let Some(hit) = intersector.intersect(&octree.mesh, index) {
+                if hit.t < closest_t {
+                    closest_t = hit.t;
+                    closest = Some(hit);
+                }
+            }
+        }
+
+        let Some((root_entry_t, _)) =
+            self.intersect_aabb_interval(&octree.nodes[0].aabb.into(), &inverse_direction)
+        else {
+            return closest;
+        };
+
+        // Nodes still to visit along with where the ray enters them
+        let mut stack: Vec<(u32, f64)> = Vec::with_capacity(64);
+        stack.push((0, root_entry_t));
+
+        while let Some((node_index, entry_t)) = stack.pop() {
+            // Something closer was found since this node was pushed
+            if entry_t >= closest_t {
+                continue;
+            }
+
+            let node = &octree.nodes[node_index as usize];
+            let first_triangle = node.first_triangle as usize;
+
+            for &triangle_index in &octree.triangle_indices
+                [first_triangle..first_triangle + node.triangle_count as usize]
+            {
+                if let Some(tri) = intersector.intersect(&octree.mesh, triangle_index as usize) {
+                    if tri.t < closest_t {
+                        closest_t = tri.t;
+                        closest = Some(tri);
+                    }
+                }
+            }
+
+            // Use a small fixed-size array to avoid heap allocation (octrees have at most 8 children)
+            let mut children: [(f64, u32); 8] = [(0.0, 0); 8];
+            let mut num_children = 0;
 
-        // At this stage we can compute t to find out where the intersection point is on the line.
-        let t = f * edge2.dot(&q);
+            for child_index in node.first_child..node.first_child + node.child_count {
+                let child_aabb = &octree.nodes[child_index as usize].aabb.into();
 
-        if t > f64::EPSILON {
-            return Some(RayTriangleIntersectionResult { t, u, v, triangle });
+                if let Some((child_entry_t, _)) =
+                    self.intersect_aabb_interval(child_aabb, &inverse_direction)
+                {
+                    if child_entry_t < closest_t {
+                        children[num_children] = (child_entry_t, child_index);
+                        num_children += 1;
+                    }
+                }
+            }
+
+            // Push the farthest child first so the nearest is popped first
+            let children = &mut children[..num_children];
+            children.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            for &(child_entry_t, child_index) in children.iter() {
+                stack.push((child_index, child_entry_t));
+            }
         }
 
-        return None;
+        closest
     }
 
-    pub fn intersect_with_octant<'a>(
-        &self,
-        octree: &'a Octree,
-        octant_index: usize,
-    ) -> Option<RayTriangleIntersectionResult<'a>> {
-        self.intersect_with_octant_with_max_t(octree, octant_index, f64::INFINITY)
+    /// Whether any triangle in the octree intersects the ray before `max_t`,
+    /// returns as soon as one is found.
+    pub fn intersects_anything_in_octree(&self, octree: &Octree, max_t: f64) -> bool {
+        let inverse_direction = self.inverse_direction();
+        let intersector = self.triangle_intersector(octree.triangle_test);
+
+        if octree.unbounded.iter().any(|&index| {
+            intersector
+                .intersect(&octree.mesh, index)
+                .is_some_and(|hit| hit.t < max_t)
+        }) {
+            return true;
+        }
+
+        let mut stack: Vec<u32> = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &octree.nodes[node_index as usize];
+
+            match self.intersect_aabb_interval(&node.aabb.into(), &inverse_direction) {
+                Some((entry_t, _)) if entry_t < max_t => {}
+                _ => continue,
+            }
+
+            let first_triangle = node.first_triangle as usize;
+
+            for &triangle_index in &octree.triangle_indices
+                [first_triangle..first_triangle + node.triangle_count as usize]
+            {
+                if let Some(tri) = intersector.intersect(&octree.mesh, triangle_index as usize) {
+                    if tri.t < max_t {
+                        return true;
+                    }
+                }
+            }
+
+            stack.extend(node.first_child..node.first_child + node.child_count);
+        }
+
+        false
     }
 
-    pub fn intersect_with_octant_with_max_t<'a>(
+    /// Every triangle in the octree the ray intersects before `max_t`, in no particular order.
+    /// Triangles duplicated into several octants can appear more than once.
+    pub fn intersect_all_in_octree(
         &self,
-        octree: &'a Octree,
-        octant_index: usize,
+        octree: &Octree,
         max_t: f64,
-    ) -> Option<RayTriangleIntersectionResult<'a>> {
-        let node = &octree.nodes[octant_index];
+    ) -> Vec<RayTriangleIntersectionResult> {
+        let inverse_direction = self.inverse_direction();
+        let intersector = self.triangle_intersector(octree.triangle_test);
+        let mut hits: Vec<RayTriangleIntersectionResult> = octree
+            .unbounded
+            .iter()
+            .flat_map(|&index| intersector.intersect_all(&octree.mesh, index))
+            .filter(|hit| hit.t < max_t)
+            .collect();
+
+        let mut stack: Vec<u32> = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &octree.nodes[node_index as usize];
+
+            match self.intersect_aabb_interval(&node.aabb.into(), &inverse_direction) {
+                Some((entry_t, _)) if entry_t < max_t => {}
+                _ => continue,
+            }
 
-        if node.triangle_count == 0 {
-            return None;
+            let first_triangle = node.first_triangle as usize;
+
+            for &triangle_index in &octree.triangle_indices
+                [first_triangle..first_triangle + node.triangle_count as usize]
+            {
+                hits.extend(
+                    intersector
+                        .intersect_all(&octree.mesh, triangle_index as usize)
+                        .into_iter()
+                        .filter(|hit| hit.t < max_t),
+                );
+            }
+
+            stack.extend(node.first_child..node.first_child + node.child_count);
         }
 
-        let mut intersected_triangle_in_octant: Option<RayTriangleIntersectionResult> = None;
-        let mut closest_triangle_in_octant_distance = max_t;
+        hits
+    }
+}
 
-        for &triangle_index in &node.triangles {
-            let this_triangle = &octree.triangles[triangle_index];
-            let this_triangle_intersection = self.intersect_with_triangle(this_triangle);
+#[cfg(test)]
+mod tests {
+    use std::{f64::consts::PI, sync::Arc};
+
+    use crate::collision::{
+        aabb::CompactAabb, accelerator::Accelerator, bvh::Bvh, test_support::*,
+    };
+    use crate::scene::{
+        entities::Motion,
+        sampling::Sampler,
+        transform::{Quaternion, Transform},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_moving_triangles_are_hit_where_they_are_at_the_ray_time() {
+        let mut moving = Mesh::from_triangles(&[triangle(
+            vector(-1.0, -1.0, 5.0),
+            vector(1.0, -1.0, 5.0),
+            vector(0.0, 1.0, 5.0),
+        )]);
+        moving.faces[0].motion = Some(0);
+
+        let forward = |time: f64| Ray {
+            origin: vector(0.0, 0.0, 0.0),
+            direction: vector(0.0, 0.0, 1.0),
+            time,
+            ignored_triangle: None,
+        };
+        // Where the triangle would be if it were turned a quarter turn round the y axis
+        let sideways = |time: f64| Ray {
+            origin: vector(0.0, 0.0, 0.0),
+            direction: vector(1.0, 0.0, 0.0),
+            time,
+            ignored_triangle: None,
+        };
+
+        let quarter_turn = Quaternion::from_axis_angle(vector(0.0, 1.0, 0.0), PI / 2.0);
+        for (motion, moved_ray) in [
+            (Motion::translation(vector(3.0, 0.0, 0.0)), None),
+            (
+                Motion::new(Transform::identity(), Transform::rotation(quarter_turn)),
+                Some(sideways(1.0)),
+            ),
+            (
+                Motion::new(
+                    Transform::identity(),
+                    Transform::from_parts(
+                        vector(0.0, 0.0, 0.0),
+                        quarter_turn,
+                        vector(2.0, 2.0, 2.0),
+                    ),
+                ),
+                Some(sideways(1.0)),
+            ),
+        ] {
+            moving.motions = vec![motion];
+
+            for test in [TriangleTest::MollerTrumbore, TriangleTest::Watertight] {
+                let at_start = forward(0.0);
+                let hit = at_start
+                    .triangle_intersector(test)
+                    .intersect(&moving, 0)
+                    .expect("The triangle is in front of the ray at the start of the frame");
+                assert!((hit.t - 5.0).abs() < 1e-9);
+
+                // Same ray, later on, and the triangle has gone
+                let at_end = forward(1.0);
+                assert!(at_end
+                    .triangle_intersector(test)
+                    .intersect(&moving, 0)
+                    .is_none());
+
+                // The turned triangle is hit on the same spot of its surface
+                if let Some(ray) = &moved_ray {
+                    let moved = ray
+                        .triangle_intersector(test)
+                        .intersect(&moving, 0)
+                        .unwrap();
+                    let scale = motion
+                        .end()
+                        .transform_vector(vector(1.0, 0.0, 0.0))
+                        .length();
+                    assert!((moved.t - 5.0 * scale).abs() < 1e-9);
+                    assert!((moved.u - hit.u).abs() < 1e-9 && (moved.v - hit.v).abs() < 1e-9);
+                }
+            }
 
-            if let Some(tri) = this_triangle_intersection {
-                if tri.t < closest_triangle_in_octant_distance {
-                    closest_triangle_in_octant_distance = tri.t;
-                    intersected_triangle_in_octant = Some(tri);
+            // The bounds cover everywhere the triangle gets to, even as it swings round
+            let bounds = Aabb::from_moving_triangle(&moving, 0);
+            for step in 0..=20 {
+                for corner in moving.vertices_at(0, step as f64 / 20.0) {
+                    assert!(bounds.contains(&Aabb::from_triangle(&[corner; 3])));
                 }
             }
+
+            // Packets move each lane's ray by the motion at its own time
+            let bvh = Bvh::build(Arc::new(moving.clone()));
+            let rays = [forward(0.0), forward(1.0), forward(0.1), forward(0.9)];
+            let hits = bvh.closest_hits(rays.each_ref().map(Some), f64::INFINITY);
+            for (ray, hit) in rays.iter().zip(hits) {
+                assert_eq!(hit, bvh.closest_hit(ray, f64::INFINITY));
+            }
+            assert!(hits[0].is_some() && hits[1].is_none());
+        }
+    }
+
+    #[test]
+    fn test_one_intersector_moves_the_ray_once_for_each_motion() {
+        let mut sampler = Sampler::new(29);
+        let mut moving = Mesh::from_triangles(&random_triangles(&mut sampler));
+        let quarter_turn = Quaternion::from_axis_angle(vector(0.0, 1.0, 0.0), PI / 2.0);
+        moving.motions = vec![
+            Motion::translation(vector(3.0, 0.0, 0.0)),
+            Motion::new(Transform::identity(), Transform::rotation(quarter_turn)),
+        ];
+        // Triangles with different motions, or none, are mixed together
+        for (i, face) in moving.faces.iter_mut().enumerate() {
+            face.motion = [None, Some(0), Some(1)][i % 3];
         }
 
-        // Use a small fixed-size array to avoid heap allocation (octrees have at most 8 children)
-        let mut child_octant_intersection_distances: [(f64, usize); 8] = [(0.0, 0); 8];
-        let mut num_children = 0;
+        for _ in 0..200 {
+            let mut ray = random_ray(&mut sampler);
+            ray.time = sampler.next_f64();
 
-        for &coi in &node.children {
-            let child_node = &octree.nodes[coi];
-            let child_octant_aabb = &octree.aabbs[child_node.aabb_index];
-            let child_octant_ray_intersection = self.intersect_aabb(child_octant_aabb);
+            for test in [TriangleTest::MollerTrumbore, TriangleTest::Watertight] {
+                let intersector = ray.triangle_intersector(test);
 
-            if let Some(coirs) = child_octant_ray_intersection {
-                child_octant_intersection_distances[num_children] = (coirs.t, coi);
-                num_children += 1;
+                for triangle_index in 0..moving.len() {
+                    assert_eq!(
+                        intersector.intersect(&moving, triangle_index),
+                        ray.triangle_intersector(test)
+                            .intersect(&moving, triangle_index)
+                    );
+                }
             }
         }
+    }
 
-        let children_slice = &mut child_octant_intersection_distances[..num_children];
-        children_slice.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    #[test]
+    fn test_watertight_traversal_matches_brute_force() {
+        let triangles = mesh(&random_triangles(&mut Sampler::new(13)));
 
-        let mut intersected_triangle_in_child_octant: Option<RayTriangleIntersectionResult> = None;
-        let mut intersected_triangle_in_child_octant_distance = f64::INFINITY;
+        let mut octree = build_octree(&triangles);
+        octree.triangle_test = TriangleTest::Watertight;
+        assert_matches_brute_force_with(&octree, &triangles, TriangleTest::Watertight);
 
-        for &(_, coi) in children_slice.iter() {
-            let res = self.intersect_with_octant(octree, coi);
+        let mut bvh = Bvh::build(triangles.clone());
+        bvh.triangle_test = TriangleTest::Watertight;
+        assert_matches_brute_force_with(&bvh, &triangles, TriangleTest::Watertight);
+    }
 
-            if let Some(rti) = res {
-                intersected_triangle_in_child_octant_distance = rti.t;
-                intersected_triangle_in_child_octant = Some(rti);
+    #[test]
+    fn test_watertight_rays_never_slip_between_triangles() {
+        // A bumpy grid of triangles, rays are aimed straight at its shared vertices and edges
+        let mut sampler = Sampler::new(14);
+        let size = 12;
+        let heights: Vec<f64> = (0..(size + 1) * (size + 1))
+            .map(|_| sampler.next_f64() * 0.7)
+            .collect();
+        let grid_point = |i: usize, j: usize| {
+            vector(
+                i as f64 * 0.37 - 2.0,
+                heights[j * (size + 1) + i],
+                j as f64 * 0.41 - 2.5,
+            )
+        };
+
+        let mut triangles = vec![];
+        let mut targets = vec![];
+
+        for j in 0..size {
+            for i in 0..size {
+                let (a, b, c, d) = (
+                    grid_point(i, j),
+                    grid_point(i + 1, j),
+                    grid_point(i, j + 1),
+                    grid_point(i + 1, j + 1),
+                );
+                triangles.push(triangle(a, b, d));
+                triangles.push(triangle(a, d, c));
+
+                targets.push(a);
+                targets.push(a + (d - a) * 0.5);
+                targets.push(a + (b - a) * (1.0 / 3.0));
+                targets.push(d + (c - d) * 0.7);
+            }
+        }
 
-                break;
+        let targets = targets.iter().filter(|t| {
+            // Stay clear of the grid's outside edges, which really are edges
+            t.x > -1.9
+                && t.x < size as f64 * 0.37 - 2.1
+                && t.z > -2.4
+                && t.z < size as f64 * 0.41 - 2.6
+        });
+
+        let triangles = mesh(&triangles);
+        let mut octree = build_octree(&triangles);
+        octree.triangle_test = TriangleTest::Watertight;
+
+        for &target in targets {
+            for _ in 0..4 {
+                // Steeper than any slope in the grid, so the ray really crosses the surface
+                // rather than grazing a silhouette edge, where missing would be correct
+                let offset = random_point(&mut sampler, 1.0);
+                let origin = target + vector(offset.x, 10.0, offset.z);
+                let ray = Ray {
+                    origin,
+                    direction: target - origin,
+                    time: 0.0,
+                    ignored_triangle: None,
+                };
+
+                assert!(
+                    brute_force_closest_hit(
+                        &ray,
+                        &triangles,
+                        f64::INFINITY,
+                        TriangleTest::Watertight
+                    )
+                    .is_some(),
+                    "ray at {target:?} slipped through"
+                );
+                assert!(octree.closest_hit(&ray, f64::INFINITY).is_some());
             }
         }
+    }
 
-        if intersected_triangle_in_child_octant_distance < closest_triangle_in_octant_distance {
-            intersected_triangle_in_child_octant
-        } else {
-            intersected_triangle_in_octant
+    #[test]
+    fn test_compact_bounds_contain_the_original_box() {
+        let mut sampler = Sampler::new(46);
+
+        for _ in 0..1000 {
+            let a = random_point(&mut sampler, 1.0e3) * sampler.next_f64();
+            let b = a + random_point(&mut sampler, 1.0);
+            let aabb = Aabb::empty().expanded_to(a).expanded_to(b);
+
+            assert!(Aabb::from(CompactAabb::from(aabb)).contains(&aabb));
         }
     }
+
+    #[test]
+    fn test_ignored_triangle_is_skipped_by_traversal() {
+        let mut sampler = Sampler::new(42);
+        let triangles = mesh(&random_triangles(&mut sampler));
+        let octree = build_octree(&triangles);
+        let bvh = Bvh::build(triangles.clone());
+        let mut skipped = 0;
+
+        for _ in 0..1000 {
+            let mut ray = random_ray(&mut sampler);
+
+            let Some((_, closest)) = brute_force_closest_hit(
+                &ray,
+                &triangles,
+                f64::INFINITY,
+                TriangleTest::MollerTrumbore,
+            ) else {
+                continue;
+            };
+
+            ray.ignored_triangle = Some(IgnoredTriangle {
+                instance: None,
+                triangle_index: closest,
+            });
+            skipped += 1;
+
+            let expected = brute_force_closest_hit(
+                &ray,
+                &triangles,
+                f64::INFINITY,
+                TriangleTest::MollerTrumbore,
+            );
+            assert_ne!(expected.map(|hit| hit.1), Some(closest));
+
+            for accelerator in [&octree as &dyn Accelerator, &bvh] {
+                assert_eq!(
+                    accelerator
+                        .closest_hit(&ray, f64::INFINITY)
+                        .map(|hit| (hit.t, hit.triangle_index)),
+                    expected
+                );
+                assert!(accelerator
+                    .all_hits(&ray, f64::INFINITY)
+                    .iter()
+                    .all(|hit| hit.triangle_index != closest));
+            }
+        }
+
+        assert!(skipped > 100);
+    }
 }
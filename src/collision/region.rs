@@ -0,0 +1,464 @@
+use crate::scene::engine::Vector3d;
+
+use super::{aabb::Aabb, octree::Octree};
+
+/// Pairs of corner indices joined by an edge, corners are numbered so that bit 0 picks
+/// the left or right side, bit 1 the bottom or top, and bit 2 the near or far end.
+static EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (2, 3),
+    (4, 5),
+    (6, 7),
+    (0, 2),
+    (1, 3),
+    (4, 6),
+    (5, 7),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+/// The corners of each face, as two pairs of opposite corners. Crossing the diagonals
+/// gives the face's normal even when one of its edges has shrunk to nothing.
+static FACES: [[(usize, usize); 2]; 6] = [
+    [(0, 6), (2, 4)],
+    [(1, 7), (3, 5)],
+    [(0, 5), (1, 4)],
+    [(2, 7), (3, 6)],
+    [(0, 3), (1, 2)],
+    [(4, 7), (5, 6)],
+];
+
+/// A convex region with six flat faces, which covers axis aligned boxes, oriented boxes and frustums.
+/// Triangles are tested against it exactly with the separating axis theorem.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    /// Bit 0 of the index picks left or right, bit 1 bottom or top, bit 2 near or far
+    pub corners: [Vector3d; 8],
+    pub bounds: Aabb,
+    face_normals: [Vector3d; 6],
+    edge_directions: [Vector3d; 12],
+}
+
+impl Region {
+    /// The corners have to make a convex shape with flat faces, numbered as in `Region::corners`
+    pub fn from_corners(corners: [Vector3d; 8]) -> Region {
+        let bounds = corners
+            .iter()
+            .fold(Aabb::empty(), |aabb, &corner| aabb.expanded_to(corner));
+
+        let diagonals =
+            FACES.map(|[(a, d), (b, c)]| (corners[d] - corners[a], corners[c] - corners[b]));
+        let mut face_normals = diagonals.map(|(first, second)| first.cross(&second));
+
+        // A face squashed down to a line, as in a region with no thickness, still bounds the
+        // region sideways. Its normal is square to the line and to the faces that are left.
+        let unsquashed = face_normals;
+
+        for (normal, (first, second)) in face_normals.iter_mut().zip(diagonals) {
+            if !is_degenerate(normal) {
+                continue;
+            }
+
+            let line = if first.dot(&first) > second.dot(&second) {
+                first
+            } else {
+                second
+            };
+
+            *normal = unsquashed
+                .iter()
+                .map(|other| line.cross(other))
+                .max_by(|a, b| a.dot(a).total_cmp(&b.dot(b)))
+                .unwrap_or(*normal);
+        }
+
+        let edge_directions = EDGES.map(|(a, b)| corners[b] - corners[a]);
+
+        Region {
+            corners,
+            bounds,
+            face_normals,
+            edge_directions,
+        }
+    }
+
+    pub fn from_aabb(aabb: &Aabb) -> Region {
+        Region::from_corners(std::array::from_fn(|i| Vector3d {
+            x: if i & 1 == 0 {
+                aabb.min_coords.x
+            } else {
+                aabb.max_coords.x
+            },
+            y: if i & 2 == 0 {
+                aabb.min_coords.y
+            } else {
+                aabb.max_coords.y
+            },
+            z: if i & 4 == 0 {
+                aabb.min_coords.z
+            } else {
+                aabb.max_coords.z
+            },
+        }))
+    }
+
+    /// A box rotated to line up with `axes`, reaching `half_extents` along each of them from the centre
+    pub fn oriented_box(centre: Vector3d, axes: [Vector3d; 3], half_extents: Vector3d) -> Region {
+        let x = axes[0].normalised() * half_extents.x;
+        let y = axes[1].normalised() * half_extents.y;
+        let z = axes[2].normalised() * half_extents.z;
+
+        Region::from_corners(std::array::from_fn(|i| {
+            let side = |bit: usize| if i & bit == 0 { -1.0 } else { 1.0 };
+            centre + x * side(1) + y * side(2) + z * side(4)
+        }))
+    }
+
+    /// The part of a pyramid between `near` and `far`, where the pyramid's edges leave `apex` along
+    /// `directions` (bottom left, bottom right, top left, top right). The ends are at `apex + direction * t`.
+    pub fn frustum(apex: Vector3d, directions: [Vector3d; 4], near: f64, far: f64) -> Region {
+        Region::from_corners(std::array::from_fn(|i| {
+            let t = if i & 4 == 0 { near } else { far };
+            apex + directions[i & 3] * t
+        }))
+    }
+
+    /// Whether some part of the box might be inside the region. This only looks for separating
+    /// planes among the faces of the two shapes, so it can say yes for boxes just past an edge,
+    /// which is fine for deciding which nodes of a hierarchy to look inside.
+    pub fn overlaps_aabb(&self, aabb: &Aabb) -> bool {
+        if !self.bounds.intersects(aabb) {
+            return false;
+        }
+
+        let box_corners = Region::from_aabb(aabb).corners;
+
+        !self
+            .face_normals
+            .iter()
+            .any(|axis| separated_along(axis, &self.corners, &box_corners))
+    }
+
+    /// Whether any part of the triangle with corners `vertices` is inside the region
+    pub fn intersects_triangle(&self, vertices: &[Vector3d; 3]) -> bool {
+        if !self.bounds.intersects(
+            &vertices
+                .iter()
+                .fold(Aabb::empty(), |aabb, &v| aabb.expanded_to(v)),
+        ) {
+            return false;
+        }
+
+        let triangle_edges = [
+            vertices[1] - vertices[0],
+            vertices[2] - vertices[1],
+            vertices[0] - vertices[2],
+        ];
+        let triangle_normal = triangle_edges[0].cross(&triangle_edges[1]);
+
+        if separated_along(&triangle_normal, &self.corners, vertices) {
+            return false;
+        }
+
+        // Only needed when the region is flat or a single point and lies in the triangle's plane,
+        // its own faces can't separate it from the triangle then
+        if triangle_edges
+            .iter()
+            .any(|edge| separated_along(&triangle_normal.cross(edge), &self.corners, vertices))
+        {
+            return false;
+        }
+
+        if self
+            .face_normals
+            .iter()
+            .any(|axis| separated_along(axis, &self.corners, vertices))
+        {
+            return false;
+        }
+
+        !self.edge_directions.iter().any(|edge| {
+            triangle_edges.iter().any(|triangle_edge| {
+                separated_along(&edge.cross(triangle_edge), &self.corners, vertices)
+            })
+        })
+    }
+}
+
+/// Whether the two sets of points project onto the axis without overlapping.
+/// Degenerate axes, from parallel edges, can't separate anything.
+fn separated_along(axis: &Vector3d, a: &[Vector3d], b: &[Vector3d]) -> bool {
+    if is_degenerate(axis) {
+        return false;
+    }
+
+    let (a_min, a_max) = project(axis, a);
+    let (b_min, b_max) = project(axis, b);
+
+    a_max < b_min || b_max < a_min
+}
+
+fn is_degenerate(axis: &Vector3d) -> bool {
+    axis.dot(axis) < f64::EPSILON * f64::EPSILON
+}
+
+fn project(axis: &Vector3d, points: &[Vector3d]) -> (f64, f64) {
+    points
+        .iter()
+        .map(|point| axis.dot(point))
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), d| {
+            (min.min(d), max.max(d))
+        })
+}
+
+/// Every triangle in the octree with some part inside the region, in no particular order.
+/// Triangles duplicated into several octants can appear more than once.
+pub fn triangles_in_region_octree(octree: &Octree, region: &Region, time: f64) -> Vec<usize> {
+    let mut found = vec![];
+
+    let mut stack: Vec<u32> = Vec::with_capacity(64);
+    stack.push(0);
+
+    while let Some(node_index) = stack.pop() {
+        let node = &octree.nodes[node_index as usize];
+
+        if !region.overlaps_aabb(&node.aabb.into()) {
+            continue;
+        }
+
+        let first_triangle = node.first_triangle as usize;
+
+        for &triangle_index in
+            &octree.triangle_indices[first_triangle..first_triangle + node.triangle_count as usize]
+        {
+            // Only triangles, the mesh's shapes aren't covered by region queries
+            if !octree.mesh.is_triangle(triangle_index as usize) {
+                continue;
+            }
+
+            if region.intersects_triangle(&octree.mesh.vertices_at(triangle_index as usize, time)) {
+                found.push(triangle_index as usize);
+            }
+        }
+
+        stack.extend(node.first_child..node.first_child + node.child_count);
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::{
+        accelerator::Accelerator, bvh::Bvh, octree::OctreeBuildOptions, test_support::*,
+    };
+    use crate::scene::sampling::Sampler;
+
+    use super::*;
+
+    fn random_regions(sampler: &mut Sampler) -> Vec<Region> {
+        let mut regions = vec![];
+
+        for _ in 0..20 {
+            let a = random_point(sampler, 25.0);
+            let b = a + random_point(sampler, 8.0);
+            regions.push(Region::from_aabb(&Aabb::new(
+                a.x.min(b.x),
+                a.x.max(b.x),
+                a.y.min(b.y),
+                a.y.max(b.y),
+                a.z.min(b.z),
+                a.z.max(b.z),
+            )));
+
+            let x = random_point(sampler, 1.0);
+            let y = x.cross(&random_point(sampler, 1.0));
+            let z = x.cross(&y);
+            regions.push(Region::oriented_box(
+                random_point(sampler, 25.0),
+                [x, y, z],
+                vector(
+                    sampler.next_f64() * 6.0,
+                    sampler.next_f64() * 6.0,
+                    sampler.next_f64() * 6.0,
+                ),
+            ));
+
+            let apex = random_point(sampler, 30.0);
+            let forward = random_point(sampler, 1.0);
+            let directions = [0, 1, 2, 3].map(|_| forward + random_point(sampler, 0.3));
+            regions.push(Region::frustum(
+                apex,
+                directions,
+                sampler.next_f64() * 5.0,
+                5.0 + sampler.next_f64() * 40.0,
+            ));
+        }
+
+        regions
+    }
+
+    #[test]
+    fn test_region_queries_match_brute_force() {
+        let triangles = mesh(&random_triangles(&mut Sampler::new(9)));
+        let octree = build_octree_with_options(
+            &triangles,
+            OctreeBuildOptions {
+                duplicate_straddling_triangles: true,
+                ..OctreeBuildOptions::default()
+            },
+        );
+        let accelerators: [&dyn Accelerator; 3] = [
+            &build_octree(&triangles),
+            &octree,
+            &Bvh::build(triangles.clone()),
+        ];
+
+        let mut found_any = 0;
+
+        for region in random_regions(&mut Sampler::new(10)) {
+            let expected: Vec<usize> = (0..triangles.len())
+                .filter(|&i| region.intersects_triangle(&triangles.vertices(i)))
+                .collect();
+
+            for accelerator in accelerators {
+                assert_eq!(accelerator.triangles_in_region(&region, 0.0), expected);
+            }
+
+            if !expected.is_empty() {
+                found_any += 1;
+            }
+        }
+
+        assert!(
+            found_any > 20,
+            "only {found_any} regions contained anything"
+        );
+    }
+
+    #[test]
+    fn test_region_triangle_test_is_exact() {
+        let region = Region::from_aabb(&Aabb::new(0.0, 1.0, 0.0, 1.0, 0.0, 1.0));
+        let inside = |p: Vector3d| {
+            (0.0..=1.0).contains(&p.x) && (0.0..=1.0).contains(&p.y) && (0.0..=1.0).contains(&p.z)
+        };
+
+        // Its bounding box overlaps the region but the triangle passes beside the box's edge
+        let beside_edge = triangle(
+            vector(2.2, 0.0, -1.0),
+            vector(0.0, 2.2, -1.0),
+            vector(1.1, 1.1, 2.0),
+        );
+        assert!(!region.intersects_triangle(&beside_edge));
+
+        // Anything with a point inside has to be reported
+        let mut sampler = Sampler::new(11);
+
+        for _ in 0..2000 {
+            let centre = vector(0.5, 0.5, 0.5) + random_point(&mut sampler, 1.5);
+            let triangle = triangle(
+                centre + random_point(&mut sampler, 1.0),
+                centre + random_point(&mut sampler, 1.0),
+                centre + random_point(&mut sampler, 1.0),
+            );
+
+            let touches_inside = (0..=30).any(|i| {
+                (0..=30 - i).any(|j| {
+                    let u = i as f64 / 30.0;
+                    let v = j as f64 / 30.0;
+                    inside(triangle[0] * (1.0 - u - v) + triangle[1] * u + triangle[2] * v)
+                })
+            });
+
+            if touches_inside {
+                assert!(region.intersects_triangle(&triangle));
+            }
+        }
+    }
+
+    #[test]
+    fn test_flat_and_single_point_regions_are_exact() {
+        // A square with no thickness, turned 45 degrees round the z axis so its bounds are
+        // much bigger than it is
+        let square = Region::oriented_box(
+            vector(0.0, 0.0, 0.0),
+            [
+                vector(1.0, 1.0, 0.0),
+                vector(-1.0, 1.0, 0.0),
+                vector(0.0, 0.0, 1.0),
+            ],
+            vector(1.0, 1.0, 0.0),
+        );
+
+        // In the square's plane, inside its bounds, but beside its corner
+        let beside_corner = triangle(
+            vector(1.0, 1.0, 0.0),
+            vector(1.4, 0.9, 0.0),
+            vector(0.9, 1.4, 0.0),
+        );
+        assert!(!square.intersects_triangle(&beside_corner));
+        // Going through the plane there
+        let through_beside_corner = triangle(
+            vector(1.0, 1.0, -1.0),
+            vector(1.2, 1.2, 1.0),
+            vector(0.9, 1.3, 1.0),
+        );
+        assert!(!square.intersects_triangle(&through_beside_corner));
+
+        // In the plane and overlapping, or going through the middle
+        let overlapping = triangle(
+            vector(0.5, 0.5, 0.0),
+            vector(1.4, 0.9, 0.0),
+            vector(0.9, 1.4, 0.0),
+        );
+        assert!(square.intersects_triangle(&overlapping));
+        let through_middle = triangle(
+            vector(0.1, 0.0, -1.0),
+            vector(-0.1, 0.1, 1.0),
+            vector(-0.1, -0.1, 1.0),
+        );
+        assert!(square.intersects_triangle(&through_middle));
+
+        // A box shrunk to a point, only triangles through the point touch it
+        let point = Region::from_aabb(&Aabb::new(1.0, 1.0, 1.0, 1.0, 0.0, 0.0));
+        let around_point = triangle(
+            vector(0.0, 0.0, 0.0),
+            vector(3.0, 0.0, 0.0),
+            vector(0.0, 3.0, 0.0),
+        );
+        assert!(point.intersects_triangle(&around_point));
+        let beside_point = triangle(
+            vector(0.0, 0.0, 0.0),
+            vector(1.9, 0.0, 0.0),
+            vector(0.0, 1.9, 0.0),
+        );
+        assert!(!point.intersects_triangle(&beside_point));
+
+        // A frustum starting at its apex, where the four near corners are all the same point
+        let pyramid = Region::frustum(
+            vector(0.0, 0.0, 0.0),
+            [
+                vector(-1.0, -1.0, 1.0),
+                vector(1.0, -1.0, 1.0),
+                vector(-1.0, 1.0, 1.0),
+                vector(1.0, 1.0, 1.0),
+            ],
+            0.0,
+            10.0,
+        );
+        let outside_side = triangle(
+            vector(2.0, -3.0, 1.0),
+            vector(2.0, 3.0, 1.0),
+            vector(2.0, 0.0, 1.5),
+        );
+        assert!(!pyramid.intersects_triangle(&outside_side));
+        let inside = triangle(
+            vector(2.0, -3.0, 3.0),
+            vector(2.0, 3.0, 3.0),
+            vector(2.0, 0.0, 3.5),
+        );
+        assert!(pyramid.intersects_triangle(&inside));
+    }
+}
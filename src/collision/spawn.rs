@@ -0,0 +1,244 @@
+use crate::scene::{
+    engine::{abs, Vector3d},
+    mesh::Mesh,
+    shape::{Hittable, SurfaceHit, Triangle},
+    transform::Transform,
+};
+
+use super::ray::{IgnoredTriangle, Ray, RayTriangleIntersectionResult};
+
+/// Shadow rays stop this fraction short of their target, so a surface right at the target
+/// doesn't count as being in the way
+pub static SHADOW_EPSILON: f64 = 0.0001;
+
+/// The next representable value after `value` in the direction of `towards`' sign
+fn round_away(value: f64, towards: f64) -> f64 {
+    if towards > 0.0 {
+        value.next_up()
+    } else if towards < 0.0 {
+        value.next_down()
+    } else {
+        value
+    }
+}
+
+/// A point where a ray hit a triangle or shape, with a bound on how far it could be from the true surface.
+///
+/// Secondary rays start from here rather than from `origin + direction * t`. A hit point is only
+/// ever close to the surface, just in front of or just behind it, and how close depends on how
+/// big the coordinates are. A fixed nudge is too much for tiny models, where it jumps through
+/// nearby surfaces, and too little for big or far away ones, where it stays behind the surface.
+/// Here the point is worked out from the triangle's corners, or the shape's surface coordinates,
+/// where its rounding error can be bounded, and moved along the normal just past that error.
+#[derive(Copy, Clone, Debug)]
+pub struct SurfacePoint {
+    pub point: Vector3d,
+    /// Largest possible error in each of `point`'s coordinates
+    pub error: Vector3d,
+    /// Unit geometric normal of the triangle, on the side its winding makes the front,
+    /// or of the shape, pointing out of it
+    pub normal: Vector3d,
+    pub triangle_index: usize,
+    /// The instance the triangle belongs to, `None` for the scene's own mesh
+    pub instance: Option<usize>,
+}
+
+impl SurfacePoint {
+    /// The hit point of the ray's intersection with one of the mesh's triangles or shapes,
+    /// with triangles wherever they have moved to at the ray's time. The ray is in the
+    /// mesh's own space.
+    pub fn from_intersection(
+        mesh: &Mesh,
+        intersection: &RayTriangleIntersectionResult,
+        ray: &Ray,
+    ) -> SurfacePoint {
+        let hit = SurfaceHit::from_intersection(ray, intersection);
+
+        // A shape's exact normal is its geometric one, a triangle's comes from its corners
+        // rather than the blended normal used for shading
+        let (point, error, normal) = match mesh.shape(intersection.triangle_index) {
+            Some(shape) => {
+                let (point, error) = shape.point_at(&hit);
+                (point, error, shape.normal_at(&hit))
+            }
+            None => {
+                let triangle = Triangle {
+                    mesh,
+                    index: intersection.triangle_index,
+                };
+                let (point, error) = triangle.point_at(&hit);
+                let [v1, v2, v3] = mesh.vertices_at(intersection.triangle_index, ray.time);
+
+                (point, error, (v2 - v1).cross(&(v3 - v1)).normalised())
+            }
+        };
+
+        SurfacePoint {
+            point,
+            error,
+            normal,
+            triangle_index: intersection.triangle_index,
+            instance: intersection.instance,
+        }
+    }
+
+    /// The point moved out of an instance's own space into the scene,
+    /// with the error grown by the rounding of moving it
+    pub fn transformed(&self, transform: &Transform) -> SurfacePoint {
+        SurfacePoint {
+            point: transform.transform_point(self.point),
+            error: transform.point_error(self.point, self.error),
+            normal: transform.transform_normal(self.normal).normalised(),
+            ..*self
+        }
+    }
+
+    /// The triangle rays leaving the surface are told to skip
+    fn ignored_triangle(&self, ignore_triangle: bool) -> Option<IgnoredTriangle> {
+        ignore_triangle.then_some(IgnoredTriangle {
+            instance: self.instance,
+            triangle_index: self.triangle_index,
+        })
+    }
+
+    /// Where a ray leaving the surface in `direction` should start: moved along the normal,
+    /// to the side `direction` points to, far enough that rounding can't leave it on the surface
+    pub fn offset_origin(&self, direction: &Vector3d) -> Vector3d {
+        let distance = abs(self.normal).dot(&self.error);
+        let mut offset = self.normal * distance;
+
+        if direction.dot(&self.normal) < 0.0 {
+            offset = -offset;
+        }
+
+        let origin = self.point + offset;
+
+        // Adding the offset is rounded too, step one more value away so that can't undo it
+        Vector3d {
+            x: round_away(origin.x, offset.x),
+            y: round_away(origin.y, offset.y),
+            z: round_away(origin.z, offset.z),
+        }
+    }
+
+    /// A ray leaving the surface in `direction`, which can be told to ignore this triangle
+    pub fn spawn_ray(&self, direction: Vector3d, time: f64, ignore_triangle: bool) -> Ray {
+        Ray {
+            origin: self.offset_origin(&direction),
+            direction,
+            time,
+            ignored_triangle: self.ignored_triangle(ignore_triangle),
+        }
+    }
+
+    /// A ray leaving the surface towards `target`, which it reaches at t = 1.
+    /// Anything hit before `1.0 - SHADOW_EPSILON` is between the surface and the target.
+    pub fn spawn_ray_to(&self, target: Vector3d, time: f64, ignore_triangle: bool) -> Ray {
+        let origin = self.offset_origin(&(target - self.point));
+
+        Ray {
+            origin,
+            direction: target - origin,
+            time,
+            ignored_triangle: self.ignored_triangle(ignore_triangle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::{accelerator::Accelerator, ray::TriangleTest, test_support::*};
+    use crate::scene::{csg::SolidHit, sampling::Sampler};
+
+    use super::*;
+
+    #[test]
+    fn test_spawned_rays_never_hit_their_own_far_away_triangle() {
+        // Far enough from the origin that a hit point can be a long way off the surface
+        let centre = vector(3.0e11, -2.0e11, 5.0e11);
+        let mut sampler = Sampler::new(41);
+
+        for _ in 0..200 {
+            let tri = triangle(
+                centre + random_point(&mut sampler, 1000.0),
+                centre + random_point(&mut sampler, 1000.0),
+                centre + random_point(&mut sampler, 1000.0),
+            );
+
+            let (u, v) = (sampler.next_f64() * 0.5, sampler.next_f64() * 0.5);
+            let target = tri[0] * (1.0 - u - v) + tri[1] * u + tri[2] * v;
+            let origin = target + random_point(&mut sampler, 1000.0);
+            let ray = Ray {
+                origin,
+                direction: target - origin,
+                time: 0.0,
+                ignored_triangle: None,
+            };
+
+            let tri = Mesh::from_triangles(&[tri]);
+
+            let Some(hit) = ray.intersect_with_triangle(&tri, 0) else {
+                continue;
+            };
+            let surface = SurfacePoint::from_intersection(&tri, &hit, &ray);
+
+            let d = ray.direction.normalised();
+            let reflected = d - surface.normal * 2.0 * d.dot(&surface.normal);
+
+            for direction in [reflected, d] {
+                let spawned = surface.spawn_ray(direction, 0.0, false);
+
+                for test in [TriangleTest::MollerTrumbore, TriangleTest::Watertight] {
+                    assert!(
+                        spawned
+                            .triangle_intersector(test)
+                            .intersect(&tri, 0)
+                            .is_none(),
+                        "ray spawned at {:?} hit its own triangle",
+                        spawned.origin
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_spawned_shadow_rays_see_occluders_on_tiny_models() {
+        let floor = triangle(
+            vector(-1.0e-3, 0.0, -1.0e-3),
+            vector(-1.0e-3, 0.0, 1.0e-3),
+            vector(1.0e-3, 0.0, -1.0e-3),
+        );
+        // Closer to the floor than a fixed 0.0001 offset, which would start shadow rays above it
+        let occluder = triangle(
+            vector(-2.0e-3, 5.0e-5, -2.0e-3),
+            vector(-2.0e-3, 5.0e-5, 2.0e-3),
+            vector(2.0e-3, 5.0e-5, -2.0e-3),
+        );
+        let triangles = mesh(&[floor, occluder]);
+        let octree = build_octree(&triangles);
+
+        let hit = RayTriangleIntersectionResult {
+            t: 1.0,
+            u: 0.25,
+            v: 0.25,
+            triangle_index: 0,
+            instance: None,
+            solid: SolidHit::default(),
+        };
+        let ray = Ray {
+            origin: vector(0.0, 1.0, 0.0),
+            direction: vector(0.0, -1.0, 0.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        let surface = SurfacePoint::from_intersection(&triangles, &hit, &ray);
+
+        let light_above_occluder = surface.spawn_ray_to(vector(0.0, 1.0e-2, 0.0), 0.0, false);
+        assert!(octree.any_hit(&light_above_occluder, 1.0 - SHADOW_EPSILON));
+
+        // Between the floor and the occluder nothing is in the way, the floor included
+        let light_below_occluder = surface.spawn_ray_to(vector(0.0, 2.0e-5, 0.0), 0.0, false);
+        assert!(!octree.any_hit(&light_below_occluder, 1.0 - SHADOW_EPSILON));
+    }
+}
@@ -0,0 +1,235 @@
+//! Random scenes and brute force answers shared by the acceleration structures' tests
+
+use std::sync::Arc;
+
+use crate::scene::{engine::Vector3d, mesh::Mesh, sampling::Sampler};
+
+use super::{
+    aabb::Aabb,
+    accelerator::Accelerator,
+    octree::{Octree, OctreeBuildOptions},
+    packet::{RayPacket, PACKET_WIDTH},
+    ray::{IgnoredTriangle, Ray, TriangleTest},
+};
+
+pub fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+    Vector3d { x, y, z }
+}
+
+pub fn triangle(v1: Vector3d, v2: Vector3d, v3: Vector3d) -> [Vector3d; 3] {
+    [v1, v2, v3]
+}
+
+pub fn mesh(triangles: &[[Vector3d; 3]]) -> Arc<Mesh> {
+    Arc::new(Mesh::from_triangles(triangles))
+}
+
+pub fn random_point(sampler: &mut Sampler, extent: f64) -> Vector3d {
+    vector(
+        (sampler.next_f64() * 2.0 - 1.0) * extent,
+        (sampler.next_f64() * 2.0 - 1.0) * extent,
+        (sampler.next_f64() * 2.0 - 1.0) * extent,
+    )
+}
+
+/// Lots of small triangles, plus some big ones that straddle octants
+/// and some that stick out of (or sit entirely outside) the root octant.
+pub fn random_triangles(sampler: &mut Sampler) -> Vec<[Vector3d; 3]> {
+    let mut triangles = vec![];
+
+    for i in 0..400 {
+        let size = match i % 10 {
+            0 => 15.0,
+            1 => 40.0,
+            _ => 1.5,
+        };
+        let centre = random_point(sampler, if i % 7 == 0 { 26.0 } else { 18.0 });
+
+        triangles.push(triangle(
+            centre + random_point(sampler, size),
+            centre + random_point(sampler, size),
+            centre + random_point(sampler, size),
+        ));
+    }
+
+    triangles
+}
+
+pub fn random_ray(sampler: &mut Sampler) -> Ray {
+    let origin = random_point(sampler, 30.0);
+    let target = random_point(sampler, 15.0);
+
+    Ray {
+        origin,
+        direction: target - origin,
+        time: 0.0,
+        ignored_triangle: None,
+    }
+}
+
+pub fn build_octree(mesh: &Arc<Mesh>) -> Octree {
+    build_octree_with_options(mesh, OctreeBuildOptions::default())
+}
+
+pub fn build_octree_with_options(mesh: &Arc<Mesh>, options: OctreeBuildOptions) -> Octree {
+    Octree::build(
+        Arc::clone(mesh),
+        Aabb::new(-20.0, 20.0, -20.0, 20.0, -20.0, 20.0),
+        options,
+    )
+}
+
+pub fn brute_force_closest_hit(
+    ray: &Ray,
+    triangles: &Mesh,
+    max_t: f64,
+    test: TriangleTest,
+) -> Option<(f64, usize)> {
+    let intersector = ray.triangle_intersector(test);
+
+    (0..triangles.len())
+        .filter_map(|i| intersector.intersect(triangles, i))
+        .filter(|hit| hit.t < max_t)
+        .map(|hit| (hit.t, hit.triangle_index))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+pub fn brute_force_all_hits(
+    ray: &Ray,
+    triangles: &Mesh,
+    max_t: f64,
+    test: TriangleTest,
+) -> Vec<(f64, usize)> {
+    let intersector = ray.triangle_intersector(test);
+
+    let mut hits: Vec<(f64, usize)> = (0..triangles.len())
+        .flat_map(|i| intersector.intersect_all(triangles, i))
+        .filter(|hit| hit.t < max_t)
+        .map(|hit| (hit.t, hit.triangle_index))
+        .collect();
+
+    hits.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    hits
+}
+
+pub fn assert_matches_brute_force(accelerator: &impl Accelerator, triangles: &Mesh) {
+    assert_matches_brute_force_with(accelerator, triangles, TriangleTest::MollerTrumbore);
+}
+
+pub fn assert_matches_brute_force_with(
+    accelerator: &impl Accelerator,
+    triangles: &Mesh,
+    test: TriangleTest,
+) {
+    let mut sampler = Sampler::new(7);
+    let mut hits = 0;
+
+    for _ in 0..3000 {
+        let ray = random_ray(&mut sampler);
+        let max_t = if sampler.next_f64() < 0.5 {
+            f64::INFINITY
+        } else {
+            sampler.next_f64() * 2.0
+        };
+
+        let expected = brute_force_closest_hit(&ray, triangles, max_t, test);
+        let actual = accelerator
+            .closest_hit(&ray, max_t)
+            .map(|hit| (hit.t, hit.triangle_index));
+
+        assert_eq!(actual, expected);
+        assert_eq!(accelerator.any_hit(&ray, max_t), expected.is_some());
+
+        let all_hits: Vec<(f64, usize)> = accelerator
+            .all_hits(&ray, max_t)
+            .iter()
+            .map(|hit| (hit.t, hit.triangle_index))
+            .collect();
+        assert_eq!(all_hits, brute_force_all_hits(&ray, triangles, max_t, test));
+
+        if expected.is_some() {
+            hits += 1;
+        }
+    }
+
+    // Make sure the scene actually gets hit often enough for the comparison to mean something
+    assert!(hits > 1000, "only {hits} rays hit anything");
+}
+
+/// Four rays from the same point towards targets close together, or sometimes from all over
+pub fn random_packet(sampler: &mut Sampler) -> [Option<Ray>; PACKET_WIDTH] {
+    let coherent = sampler.next_f64() < 0.7;
+    let origin = random_point(sampler, 30.0);
+    let target = random_point(sampler, 15.0);
+
+    std::array::from_fn(|_| {
+        // Some lanes are left empty, as they are for pixels the camera has no ray for
+        if sampler.next_f64() < 0.1 {
+            return None;
+        }
+
+        if !coherent {
+            return Some(random_ray(sampler));
+        }
+
+        let target = target + random_point(sampler, 0.5);
+        Some(Ray {
+            origin,
+            direction: target - origin,
+            time: 0.0,
+            ignored_triangle: None,
+        })
+    })
+}
+
+pub fn assert_packets_match_single_rays(accelerator: &impl Accelerator, triangles: &Mesh) {
+    let mut sampler = Sampler::new(43);
+    let mut packets_traced = 0;
+
+    for _ in 0..1500 {
+        let mut rays = random_packet(&mut sampler);
+        let max_t = if sampler.next_f64() < 0.5 {
+            f64::INFINITY
+        } else {
+            sampler.next_f64() * 2.0
+        };
+
+        // Skipping the closest triangle in some lanes, as shadow rays leaving a surface do
+        for ray in rays.iter_mut().flatten() {
+            if sampler.next_f64() < 0.2 {
+                ray.ignored_triangle =
+                    brute_force_closest_hit(ray, triangles, max_t, TriangleTest::MollerTrumbore)
+                        .map(|hit| IgnoredTriangle {
+                            instance: None,
+                            triangle_index: hit.1,
+                        });
+            }
+        }
+
+        let rays = rays.each_ref().map(Option::as_ref);
+
+        if RayPacket::new(rays).is_some() {
+            packets_traced += 1;
+        }
+
+        let closest = accelerator.closest_hits(rays, max_t);
+        let any = accelerator.any_hits(rays, max_t);
+
+        for lane in 0..PACKET_WIDTH {
+            let expected = rays[lane].and_then(|ray| accelerator.closest_hit(ray, max_t));
+
+            assert_eq!(
+                closest[lane]
+                    .as_ref()
+                    .map(|hit| (hit.t, hit.triangle_index)),
+                expected.map(|hit| (hit.t, hit.triangle_index))
+            );
+            assert_eq!(
+                any[lane],
+                rays[lane].is_some_and(|ray| accelerator.any_hit(ray, max_t))
+            );
+        }
+    }
+
+    assert!(packets_traced > 500);
+}
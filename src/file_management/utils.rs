@@ -4,11 +4,17 @@ use std::fs;
 use std::str::{FromStr, Lines, SplitWhitespace};
 use std::sync::Arc;
 
-use crate::collision::octree::Octree;
+use crate::collision::aabb::Aabb;
+use crate::collision::accelerator::{AccelerationStructure, AcceleratorKind};
+use crate::collision::instance::InstanceTree;
+use crate::collision::octree::{Octree, OctreeBuildOptions};
 use crate::scene::engine::Vector3d;
-use crate::scene::entities::{Color, Texture, Triangle};
+use crate::scene::entities::{Color, Motion, Texture};
+use crate::scene::graph::{MeshPart, SceneGraph, ROOT_NODE};
 use crate::scene::material::{Material, MaterialMap};
+use crate::scene::mesh::{Face, Mesh};
 use crate::scene::scenedata::SceneData;
+use crate::scene::transform::{Quaternion, Transform};
 
 use image::ImageReader;
 
@@ -19,7 +25,7 @@ static DEFAULT_VERTICES: &Vector3d = &Vector3d {
 };
 static MISSING_VERTEX_ERROR_MESSAGE: &str = "No vertex with this index";
 
-pub fn parse_mtl_file_lines<'a>(material_map: &mut MaterialMap, lines: Lines) {
+pub fn parse_mtl_file_lines(material_map: &mut MaterialMap, lines: Lines) {
     // name: String,
     // /// The three below coefficients should be somewhere between { 0.0, 0.0, 0.0 } and { 1.0, 1.0, 1.0}
     // /// They are used to weight the R, G, B values sampled from the texture.
@@ -49,8 +55,10 @@ pub fn parse_mtl_file_lines<'a>(material_map: &mut MaterialMap, lines: Lines) {
         match line_type {
             Some("newmtl" | "END") => {
                 if let Some(actual_name) = name {
+                    let id = material_map.materials.len();
                     let mat = Material {
                         name: actual_name.to_string(),
+                        id,
                         ambient_color_coefficient: ambient_color_coefficient
                             .unwrap_or(*DEFAULT_VERTICES),
                         diffuse_color_coefficient: diffuse_color_coefficient
@@ -63,9 +71,8 @@ pub fn parse_mtl_file_lines<'a>(material_map: &mut MaterialMap, lines: Lines) {
                         reflectivity: reflectivity.unwrap_or(0.0),
                     };
 
-                    material_map
-                        .materials
-                        .insert(actual_name.to_string(), Arc::new(mat));
+                    material_map.materials.push(mat);
+                    material_map.ids_by_name.insert(actual_name.to_string(), id);
 
                     // Reset all material properties for the next material
                     ambient_color_coefficient = None;
@@ -136,31 +143,31 @@ pub fn parse_mtl_file_lines<'a>(material_map: &mut MaterialMap, lines: Lines) {
     }
 }
 
-pub fn parse_obj_file_lines<'a>(lines: Lines) -> SceneData {
-    let vertices = Vec::new();
-    let triangles = Vec::new();
-    let vertex_texture_coords = Vec::new();
-    let vertex_normal_coords = Vec::new();
+pub fn parse_obj_file_lines(lines: Lines) -> SceneData {
+    let mut positions = Vec::new();
+    // Obj indices start at 1, entry 0 is left for corners without texture coordinates or normals
+    let mut tex_coords = vec![*DEFAULT_VERTICES];
+    let mut normals = vec![*DEFAULT_VERTICES];
+    let mut faces = Vec::new();
+    let mut motions = Vec::new();
 
-    let octree: Octree = Octree::new(-20.0, 20.0, -20.0, 20.0, -20.0, 20.0);
-
-    let material_map = MaterialMap {
+    let mut material_map = MaterialMap {
         textures: vec![],
-        materials: HashMap::new(),
+        materials: vec![],
+        ids_by_name: HashMap::new(),
     };
 
-    let mut scene_data: SceneData = SceneData {
-        vertices,
-        triangles,
-        vertex_texture_coords,
-        vertex_normal_coords,
-        material_map,
-        octree,
-    };
+    let mut current_material: Option<u32> = None;
+    let mut current_motion: Option<u32> = None;
 
-    let mut current_material: Option<Arc<Material>> = None;
+    // Faces go into the current groups, or the current object if no group has been started
+    // since. Each node's faces are listed by index and handed the mesh once it's made.
+    let mut graph = SceneGraph::new();
+    let mut current_object = ROOT_NODE;
+    let mut current_nodes = vec![ROOT_NODE];
+    let mut node_faces: Vec<Vec<usize>> = vec![vec![]];
 
-    for line in lines {
+    for (line_index, line) in lines.enumerate() {
         let mut split_line = line.split_whitespace();
         let line_type = split_line.next();
 
@@ -171,44 +178,108 @@ pub fn parse_obj_file_lines<'a>(lines: Lines) -> SceneData {
                 let mtl_file = fs::read_to_string(mtllib_file_name).expect("Could not read file");
                 let mtl_file_lines = mtl_file.lines();
 
-                parse_mtl_file_lines(&mut scene_data.material_map, mtl_file_lines)
+                parse_mtl_file_lines(&mut material_map, mtl_file_lines)
             }
             Some("usemtl") => {
                 let material_name: String =
                     parse_next_value_from_split(&mut split_line).expect("Invalid material name");
 
-                let m = scene_data
-                    .material_map
-                    .materials
+                let id = material_map
+                    .ids_by_name
                     .get(&material_name)
                     .expect("Material not found, is it in your mtl file?");
 
-                current_material = Some(m.clone().clone());
+                current_material = Some(*id as u32);
             }
             Some("v") => {
                 let v = get_vertex(&mut split_line);
-                scene_data.vertices.push(v);
+                positions.push(v);
             }
             Some("f") => {
-                let cm = Arc::clone(&current_material.clone().unwrap());
+                let face = get_face(
+                    &mut split_line,
+                    (positions.len(), tex_coords.len(), normals.len()),
+                    current_material.expect("Faces need a material, set one with usemtl"),
+                    current_motion,
+                );
+                node_faces.resize(graph.nodes.len(), vec![]);
+                for &node in &current_nodes {
+                    node_faces[node].push(faces.len());
+                }
+                faces.push(face);
+            }
+            Some("o") => {
+                let name = split_line.collect::<Vec<_>>().join(" ");
 
-                let tri = get_triangle(&mut split_line, &scene_data, cm);
-                scene_data.octree.push_triangle(tri.clone());
-                scene_data.triangles.push(tri.clone());
+                current_object = graph
+                    .child(ROOT_NODE, &name)
+                    .unwrap_or_else(|| graph.add_node(ROOT_NODE, &name));
+                current_nodes = vec![current_object];
+            }
+            Some("g") => {
+                // `g a b` puts the following faces in both groups,
+                // a `g` without a name goes back to the object's own faces
+                current_nodes = split_line
+                    .map(|name| {
+                        graph
+                            .child(current_object, name)
+                            .unwrap_or_else(|| graph.add_node(current_object, name))
+                    })
+                    .collect();
+                current_nodes.sort_unstable();
+                current_nodes.dedup();
+
+                if current_nodes.is_empty() {
+                    current_nodes.push(current_object);
+                }
+            }
+            Some("motion") => {
+                current_motion = get_motion(split_line, line_index + 1).map(|motion| {
+                    motions.push(motion);
+                    motions.len() as u32 - 1
+                });
             }
             Some("vt") => {
                 let vt = get_vertex(&mut split_line);
-                scene_data.vertex_texture_coords.push(vt);
+                tex_coords.push(vt);
             }
             Some("vn") => {
                 let vn = get_vertex(&mut split_line);
-                scene_data.vertex_normal_coords.push(vn);
+                normals.push(vn);
             }
             Some(&_) => {}
             None => {}
         }
     }
 
+    node_faces.resize(graph.nodes.len(), vec![]);
+    let mesh = Arc::new(Mesh::new(positions, tex_coords, normals, faces, motions));
+
+    for (node, triangles) in graph.nodes.iter_mut().zip(node_faces) {
+        if !triangles.is_empty() {
+            node.meshes.push(MeshPart {
+                mesh: Arc::clone(&mesh),
+                triangles,
+            });
+        }
+    }
+
+    let mut scene_data = SceneData {
+        mesh,
+        material_map,
+        acceleration_structure: AccelerationStructure::Octree(Octree::build(
+            Arc::new(Mesh::default()),
+            Aabb::new(0.0, 0.0, 0.0, 0.0, 0.0, 0.0),
+            OctreeBuildOptions::default(),
+        )),
+        instances: InstanceTree::default(),
+        graph,
+    };
+
+    // Built once every triangle is known so the whole tree can be built in parallel
+    scene_data
+        .rebuild_acceleration_structure(AcceleratorKind::Octree(OctreeBuildOptions::default()));
+
     scene_data
 }
 
@@ -218,11 +289,8 @@ fn parse_next_value_from_split<'a, T: FromStr>(
 where
     <T as FromStr>::Err: Debug,
 {
-    if let Some(r) = line.next() {
-        return Some(r.parse::<T>().expect("Could not parse value"));
-    } else {
-        return None;
-    }
+    line.next()
+        .map(|r| r.parse::<T>().expect("Could not parse value"))
 }
 
 fn get_vertex(mut line: &mut SplitWhitespace<'_>) -> Vector3d {
@@ -230,10 +298,60 @@ fn get_vertex(mut line: &mut SplitWhitespace<'_>) -> Vector3d {
     let y: f64 = parse_next_value_from_split(&mut line).expect("Cannot parse vertex");
     let z: f64 = parse_next_value_from_split(&mut line).unwrap_or(0.0);
 
-    return Vector3d { x, y, z };
+    Vector3d { x, y, z }
+}
+
+/// The `motion` statement, which isn't part of the obj format:
+///
+/// `motion <x> <y> <z> [<y rotation> [<scale>]]`
+///
+/// All following faces move by the offset over the frame, turning by the rotation in degrees
+/// around the y axis and growing by the uniform scale, which default to 0 and 1.
+/// `motion 0 0 0` makes the following faces stationary again, giving `None`.
+fn get_motion(line: SplitWhitespace<'_>, line_number: usize) -> Option<Motion> {
+    let values: Vec<f64> = line
+        .map(|value| {
+            value.parse().unwrap_or_else(|_| {
+                panic!("Line {line_number}: {value} in motion statement is not a number")
+            })
+        })
+        .collect();
+
+    let (offset, degrees, scale) = match values[..] {
+        [x, y, z] => (Vector3d { x, y, z }, 0.0, 1.0),
+        [x, y, z, degrees] => (Vector3d { x, y, z }, degrees, 1.0),
+        [x, y, z, degrees, scale] => (Vector3d { x, y, z }, degrees, scale),
+        _ => panic!(
+            "Line {line_number}: expected motion <x> <y> <z> [<y rotation> [<scale>]], got {} values",
+            values.len()
+        ),
+    };
+
+    if offset == *DEFAULT_VERTICES && degrees == 0.0 && scale == 1.0 {
+        return None;
+    }
+
+    let rotation = Quaternion::from_axis_angle(
+        Vector3d {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        degrees.to_radians(),
+    );
+    let scale = Vector3d {
+        x: scale,
+        y: scale,
+        z: scale,
+    };
+
+    Some(Motion::new(
+        Transform::identity(),
+        Transform::from_parts(offset, rotation, scale),
+    ))
 }
 
-fn get_vertex_attributes<'a>(line: &str) -> (usize, Option<usize>, Option<usize>) {
+fn get_vertex_attributes(line: &str) -> (usize, Option<usize>, Option<usize>) {
     let mut line_split = line.split("/");
 
     let vertex_attribute_collection: String =
@@ -247,14 +365,19 @@ fn get_vertex_attributes<'a>(line: &str) -> (usize, Option<usize>, Option<usize>
 
     let normal_coord_index = parse_next_value_from_split::<usize>(&mut line_split);
 
-    return (index, tex_coord_index, normal_coord_index);
+    (index, tex_coord_index, normal_coord_index)
 }
 
-fn get_triangle<'a>(
-    line: &'a mut SplitWhitespace<'_>,
-    scene_data: &SceneData,
-    material: Arc<Material>,
-) -> Triangle {
+/// A face's indices into the mesh buffers, `counts` are how many positions, texture
+/// coordinates and normals have been read so far. Faces can only use ones defined before them.
+fn get_face(
+    line: &mut SplitWhitespace<'_>,
+    counts: (usize, usize, usize),
+    material: u32,
+    motion: Option<u32>,
+) -> Face {
+    let (position_count, tex_coord_count, normal_count) = counts;
+
     let v1_attribute_string: String =
         parse_next_value_from_split(line).expect("No data for vertex 1");
     let v2_attribute_string: String =
@@ -262,83 +385,34 @@ fn get_triangle<'a>(
     let v3_attribute_string: String =
         parse_next_value_from_split(line).expect("No data for vertex 3");
 
-    let (v1_index, v1_tex_coord_index, v1_normal_coord_index) =
-        get_vertex_attributes(&v1_attribute_string);
-    let (v2_index, v2_tex_coord_index, v2_normal_coord_index) =
-        get_vertex_attributes(&v2_attribute_string);
-    let (v3_index, v3_tex_coord_index, v3_normal_coord_index) =
-        get_vertex_attributes(&v3_attribute_string);
-
-    let v1 = scene_data
-        .vertices
-        .get(v1_index - 1)
-        .expect(MISSING_VERTEX_ERROR_MESSAGE);
-    let v2 = scene_data
-        .vertices
-        .get(v2_index - 1)
-        .expect(MISSING_VERTEX_ERROR_MESSAGE);
-    let v3 = scene_data
-        .vertices
-        .get(v3_index - 1)
-        .expect(MISSING_VERTEX_ERROR_MESSAGE);
-
-    let mut v1_tex_coords = DEFAULT_VERTICES;
-    let mut v2_tex_coords = DEFAULT_VERTICES;
-    let mut v3_tex_coords = DEFAULT_VERTICES;
-
-    if let Some(v1_tc_index) = v1_tex_coord_index {
-        v1_tex_coords = scene_data
-            .vertex_texture_coords
-            .get(v1_tc_index - 1)
-            .unwrap_or_else(|| DEFAULT_VERTICES);
-    }
-    if let Some(v2_tc_index) = v2_tex_coord_index {
-        v2_tex_coords = scene_data
-            .vertex_texture_coords
-            .get(v2_tc_index - 1)
-            .unwrap_or_else(|| DEFAULT_VERTICES);
-    }
-    if let Some(v3_tc_index) = v3_tex_coord_index {
-        v3_tex_coords = scene_data
-            .vertex_texture_coords
-            .get(v3_tc_index - 1)
-            .unwrap_or_else(|| DEFAULT_VERTICES);
-    }
-
-    let mut v1_normal_coords = DEFAULT_VERTICES;
-    let mut v2_normal_coords = DEFAULT_VERTICES;
-    let mut v3_normal_coords = DEFAULT_VERTICES;
+    let corners = [
+        get_vertex_attributes(&v1_attribute_string),
+        get_vertex_attributes(&v2_attribute_string),
+        get_vertex_attributes(&v3_attribute_string),
+    ];
+
+    let position = |index: usize| -> u32 {
+        assert!(
+            (1..=position_count).contains(&index),
+            "{MISSING_VERTEX_ERROR_MESSAGE}"
+        );
+        (index - 1) as u32
+    };
 
-    if let Some(v1_normal_index) = v1_normal_coord_index {
-        v1_normal_coords = scene_data
-            .vertex_normal_coords
-            .get(v1_normal_index - 1)
-            .unwrap_or_else(|| DEFAULT_VERTICES);
-    }
-    if let Some(v2_normal_index) = v2_normal_coord_index {
-        v2_normal_coords = scene_data
-            .vertex_normal_coords
-            .get(v2_normal_index - 1)
-            .unwrap_or_else(|| DEFAULT_VERTICES);
-    }
-    if let Some(v3_normal_index) = v3_normal_coord_index {
-        v3_normal_coords = scene_data
-            .vertex_normal_coords
-            .get(v3_normal_index - 1)
-            .unwrap_or_else(|| DEFAULT_VERTICES);
-    }
+    // Missing or out of range texture coordinates and normals fall back to entry 0, a zero vector
+    let attribute = |index: Option<usize>, count: usize| -> u32 {
+        match index {
+            Some(index) if index < count => index as u32,
+            _ => 0,
+        }
+    };
 
-    Triangle {
-        v1: *v1,
-        v2: *v2,
-        v3: *v3,
-        v1_tex_coords: *v1_tex_coords,
-        v2_tex_coords: *v2_tex_coords,
-        v3_tex_coords: *v3_tex_coords,
-        v1_normal_coords: *v1_normal_coords,
-        v2_normal_coords: *v2_normal_coords,
-        v3_normal_coords: *v3_normal_coords,
-        material: Arc::clone(&material),
+    Face {
+        positions: corners.map(|(index, _, _)| position(index)),
+        tex_coords: corners.map(|(_, tex_coord, _)| attribute(tex_coord, tex_coord_count)),
+        normals: corners.map(|(_, _, normal)| attribute(normal, normal_count)),
+        material,
+        motion,
     }
 }
 
@@ -360,11 +434,11 @@ fn get_texture_from_file_name(file_name: String) -> Texture {
         cols.push(new_col)
     }
 
-    return Texture {
+    Texture {
         width: img.width() as usize,
         height: img.height() as usize,
         colours: cols,
-    };
+    }
 }
 
 fn get_color_coefficient_from_split_lines(line: &mut SplitWhitespace<'_>) -> Vector3d {
@@ -375,5 +449,50 @@ fn get_color_coefficient_from_split_lines(line: &mut SplitWhitespace<'_>) -> Vec
         "All lighting intensity coefficients must be between 0.0 and 1.0"
     );
 
-    return Vector3d { x: r, y: g, z: b };
+    Vector3d { x: r, y: g, z: b }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_motion_statements() {
+        let motion = get_motion("1 2 3 90 2".split_whitespace(), 1).unwrap();
+        let (offset, rotation, scale) = motion.transform_at(1.0).to_parts();
+
+        assert_eq!(
+            offset,
+            Vector3d {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0
+            }
+        );
+        assert!(rotation.dot(&Quaternion::identity()).abs() < 0.99);
+        assert!((scale.x - 2.0).abs() < 1e-12);
+        assert_eq!(
+            get_motion("0.5 0 0".split_whitespace(), 1).unwrap().parts()[1].2,
+            Vector3d {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0
+            }
+        );
+
+        assert_eq!(get_motion("0 0 0".split_whitespace(), 1), None);
+        assert_eq!(get_motion("0 0 0 0 1".split_whitespace(), 1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Line 7: expected motion <x> <y> <z>")]
+    fn test_motion_with_too_few_values_names_the_line() {
+        get_motion("1 2".split_whitespace(), 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "Line 3: up in motion statement is not a number")]
+    fn test_motion_with_a_bad_value_names_the_line() {
+        get_motion("1 2 up".split_whitespace(), 3);
+    }
 }
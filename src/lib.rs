@@ -0,0 +1,8 @@
+//! The geometry and rendering engine behind the ray tracer binary.
+//!
+//! Load a model with `file_management::utils::parse_obj_file_lines`, then either render it
+//! with `scene::raytracer::RayTracer` or query it directly with the methods in `scene::query`.
+
+pub mod collision;
+pub mod file_management;
+pub mod scene;
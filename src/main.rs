@@ -1,15 +1,26 @@
-mod collision;
-mod file_management;
-mod scene;
-
 use std::time::Instant;
 use std::{fs, vec};
 
 use minifb::Key;
-use scene::engine::{Scene, Vector3d};
-use scene::entities::Light;
+use rust_ray_tracer::collision::accelerator::{
+    AccelerationStructure, Accelerator, AcceleratorKind,
+};
+use rust_ray_tracer::collision::instance::{Instance, InstanceTree};
+use rust_ray_tracer::collision::octree::OctreeBuildOptions;
+use rust_ray_tracer::collision::ray::TriangleTest;
+use rust_ray_tracer::scene::aov::AovBuffers;
+use rust_ray_tracer::scene::camera::{Camera, Projection, Viewport};
+use rust_ray_tracer::scene::csg::{CsgOperation, CsgShape, Solid};
+use rust_ray_tracer::scene::denoise::{DenoiseOptions, MAX_ITERATIONS};
+use rust_ray_tracer::scene::engine::{Scene, Vector3d};
+use rust_ray_tracer::scene::entities::{Light, Motion};
+use rust_ray_tracer::scene::mesh::MeshShape;
+use rust_ray_tracer::scene::sdf::{Sdf, SdfShape};
+use rust_ray_tracer::scene::shape::{Cone, Cuboid, Cylinder, Disk, Plane, Shape, Sphere};
+use rust_ray_tracer::scene::transform::{Quaternion, Transform};
 
-use crate::scene::raytracer::RayTracer;
+use rust_ray_tracer::scene::raytracer::RayTracer;
+use rust_ray_tracer::scene::scenedata::SceneData;
 
 const WIDTH: usize = 800;
 const HEIGHT: usize = 800;
@@ -25,11 +36,290 @@ fn main() {
 
     println!("using model file: {file_name}");
 
+    let mut camera = Camera::new(Vector3d {
+        x: 0.0,
+        y: 2.0,
+        z: -10.0,
+    });
+
+    let mut width = WIDTH;
+    let mut height = HEIGHT;
+    let mut projection_name = String::from("perspective");
+    let mut orthographic_size = 10.0;
+    let mut fisheye_field_of_view = 180.0;
+    let mut aov_prefix: Option<String> = None;
+    let mut denoise_iterations = 0;
+    let mut accelerator_name = String::from("octree");
+    let mut triangle_test = TriangleTest::MollerTrumbore;
+    let mut ignore_origin_triangle = false;
+    let mut octree_options = OctreeBuildOptions::default();
+    let mut instance_specs: Vec<String> = vec![];
+    let mut hidden_nodes: Vec<String> = vec![];
+    let mut node_moves: Vec<String> = vec![];
+    let mut shape_specs: Vec<String> = vec![];
+    let mut sdf_specs: Vec<String> = vec![];
+    let mut csg_specs: Vec<String> = vec![];
+    let mut sdf_blend: Option<f64> = None;
+
+    // Optional flags after the model file, e.g. `--aperture 0.1 --focal-distance 12 --blades 6`
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .unwrap_or_else(|| panic!("Expected a value after {flag}"));
+
+        match flag.as_str() {
+            "--aperture" => camera.aperture_radius = value.parse().expect("Invalid aperture"),
+            "--focal-distance" => {
+                camera.focal_distance = value.parse().expect("Invalid focal distance")
+            }
+            "--blades" => camera.aperture_blades = value.parse().expect("Invalid blade count"),
+            "--blade-rotation" => {
+                camera.aperture_rotation = value.parse().expect("Invalid blade rotation")
+            }
+            "--lens-samples" => camera.lens_samples = value.parse().expect("Invalid sample count"),
+            "--width" => width = value.parse().expect("Invalid width"),
+            "--height" => height = value.parse().expect("Invalid height"),
+            "--projection" => projection_name = value,
+            "--ortho-size" => orthographic_size = value.parse().expect("Invalid orthographic size"),
+            "--shutter" => {
+                let (open, close) = value
+                    .split_once(',')
+                    .expect("Expected the shutter interval as <open>,<close>");
+                camera.shutter_open = open.parse().expect("Invalid shutter open time");
+                camera.shutter_close = close.parse().expect("Invalid shutter close time");
+            }
+            "--camera-motion" => {
+                let numbers: Vec<f64> = value
+                    .split(',')
+                    .map(|o| o.parse().expect("Invalid camera motion"))
+                    .collect();
+                assert!(
+                    numbers.len() == 3 || numbers.len() == 4,
+                    "Expected camera motion as <x>,<y>,<z>[,<y rotation>]"
+                );
+
+                let offset = Vector3d {
+                    x: numbers[0],
+                    y: numbers[1],
+                    z: numbers[2],
+                };
+                let rotation = Quaternion::from_axis_angle(
+                    Vector3d {
+                        x: 0.0,
+                        y: 1.0,
+                        z: 0.0,
+                    },
+                    f64::to_radians(numbers.get(3).copied().unwrap_or(0.0)),
+                );
+
+                camera.motion = Some(Motion::new(
+                    Transform::identity(),
+                    Transform::translation(offset) * Transform::rotation(rotation),
+                ));
+            }
+            "--aov" => aov_prefix = Some(value),
+            "--accelerator" => accelerator_name = value,
+            "--triangle-test" => {
+                triangle_test = match value.as_str() {
+                    "moller-trumbore" => TriangleTest::MollerTrumbore,
+                    "watertight" => TriangleTest::Watertight,
+                    _ => panic!("Unknown triangle test {value}"),
+                }
+            }
+            "--ignore-origin-triangle" => {
+                ignore_origin_triangle = value
+                    .parse()
+                    .expect("Expected true or false for --ignore-origin-triangle")
+            }
+            "--octree-max-depth" => {
+                octree_options.max_depth = value.parse().expect("Invalid octree max depth")
+            }
+            "--octree-leaf-size" => {
+                octree_options.leaf_capacity = value.parse().expect("Invalid octree leaf size")
+            }
+            "--octree-min-node-size" => {
+                octree_options.min_node_size = value.parse().expect("Invalid octree min node size")
+            }
+            "--octree-duplicate" => {
+                octree_options.duplicate_straddling_triangles = value
+                    .parse()
+                    .expect("Expected true or false for --octree-duplicate")
+            }
+            "--instance" => instance_specs.push(value),
+            "--hide" => hidden_nodes.push(value),
+            "--move" => node_moves.push(value),
+            "--shape" => shape_specs.push(value),
+            "--sdf" => sdf_specs.push(value),
+            "--csg" => csg_specs.push(value),
+            "--sdf-blend" => sdf_blend = Some(value.parse().expect("Invalid SDF blend")),
+            "--denoise" => {
+                denoise_iterations = value.parse().expect("Invalid denoise iterations");
+                assert!(
+                    denoise_iterations <= MAX_ITERATIONS,
+                    "--denoise takes at most {MAX_ITERATIONS} passes"
+                );
+            }
+            "--fov" => fisheye_field_of_view = value.parse().expect("Invalid field of view"),
+            _ => panic!("Unknown option {flag}"),
+        }
+    }
+
+    let accelerator = match accelerator_name.as_str() {
+        "octree" => AcceleratorKind::Octree(octree_options),
+        "bvh" => AcceleratorKind::Bvh,
+        _ => panic!("Unknown accelerator {accelerator_name}"),
+    };
+
+    let aspect_ratio = width as f64 / height as f64;
+
+    camera.projection = match projection_name.as_str() {
+        "perspective" => Projection::Perspective(Viewport {
+            width: aspect_ratio,
+            ..Viewport::default()
+        }),
+        "orthographic" => Projection::Orthographic {
+            width: orthographic_size * aspect_ratio,
+            height: orthographic_size,
+        },
+        "fisheye" => Projection::Fisheye {
+            field_of_view: f64::to_radians(fisheye_field_of_view),
+        },
+        "equirectangular" => Projection::Equirectangular,
+        _ => panic!("Unknown projection {projection_name}"),
+    };
+
     let file = fs::read_to_string(file_name).expect("Could not read file");
 
-    let scene_data = file_management::utils::parse_obj_file_lines(file.lines());
+    let mut scene_data =
+        rust_ray_tracer::file_management::utils::parse_obj_file_lines(file.lines());
+
+    if !hidden_nodes.is_empty() || !node_moves.is_empty() {
+        let graph = &mut scene_data.graph;
+
+        for path in &hidden_nodes {
+            let node = graph
+                .find(path)
+                .unwrap_or_else(|| panic!("No object or group {path}"));
+            graph.nodes[node].visible = false;
+        }
+
+        for spec in &node_moves {
+            let (path, offset) = spec
+                .split_once(',')
+                .expect("Expected a move as <object or group>,<x>,<y>,<z>");
+            let offsets: Vec<f64> = offset
+                .split(',')
+                .map(|o| o.parse().expect("Invalid move offset"))
+                .collect();
+            assert!(
+                offsets.len() == 3,
+                "Expected a move as <object or group>,<x>,<y>,<z>"
+            );
+
+            let node = graph
+                .find(path)
+                .unwrap_or_else(|| panic!("No object or group {path}"));
+            graph.nodes[node].transform = Transform::translation(Vector3d {
+                x: offsets[0],
+                y: offsets[1],
+                z: offsets[2],
+            }) * graph.nodes[node].transform;
+        }
+
+        scene_data.rebuild_from_graph();
+    }
+
+    if !shape_specs.is_empty() {
+        let shapes: Vec<MeshShape> = shape_specs
+            .iter()
+            .map(|spec| parse_shape(spec, &scene_data))
+            .collect();
+        scene_data.add_shapes(shapes);
+    }
+
+    if !sdf_specs.is_empty() {
+        let fields: Vec<(Sdf, u32)> = sdf_specs
+            .iter()
+            .map(|spec| parse_sdf(spec, &scene_data))
+            .collect();
 
-    let lights = vec![
+        // Blended fields become one field, in the first one's material
+        let fields = match sdf_blend {
+            Some(smoothness) => {
+                let material = fields[0].1;
+                let blended = fields
+                    .into_iter()
+                    .map(|(sdf, _)| sdf)
+                    .reduce(|a, b| Sdf::Union {
+                        a: Box::new(a),
+                        b: Box::new(b),
+                        smoothness,
+                    })
+                    .unwrap();
+
+                vec![(blended, material)]
+            }
+            None => fields,
+        };
+
+        scene_data.add_shapes(fields.into_iter().map(|(sdf, material)| MeshShape {
+            shape: Shape::Sdf(
+                SdfShape::new(sdf).unwrap_or_else(|error| panic!("Invalid --sdf: {error}")),
+            ),
+            material,
+        }));
+    }
+
+    if !csg_specs.is_empty() {
+        let shapes: Vec<MeshShape> = csg_specs
+            .iter()
+            .map(|spec| parse_csg(spec, &scene_data))
+            .collect();
+        scene_data.add_shapes(shapes);
+    }
+
+    if accelerator != scene_data.acceleration_structure.kind() {
+        let now = Instant::now();
+        scene_data.rebuild_acceleration_structure(accelerator);
+        println!(
+            "It took: {:.2?} to build the {accelerator:?}",
+            now.elapsed()
+        );
+    }
+
+    if !instance_specs.is_empty() {
+        let instances = instance_specs
+            .iter()
+            .map(|spec| parse_instance(spec, &scene_data))
+            .collect();
+
+        // Every instance is a copy of the model, which shares its structure's mesh
+        let now = Instant::now();
+        scene_data.instances =
+            InstanceTree::build(vec![scene_data.acceleration_structure.clone()], instances);
+        println!(
+            "It took: {:.2?} to place {} instances",
+            now.elapsed(),
+            instance_specs.len()
+        );
+    }
+
+    scene_data.set_triangle_test(triangle_test);
+
+    let structure = &scene_data.acceleration_structure;
+    println!(
+        "using {:?} over {} triangles and {} shapes, bounds {:?}",
+        structure.kind(),
+        structure.mesh().triangle_count(),
+        structure.mesh().shapes.len(),
+        structure.bounds()
+    );
+
+    if let AccelerationStructure::Octree(octree) = structure {
+        print!("{}", octree.stats());
+    }
+
+    let mut lights = vec![
         Light::Ambient { intensity: 0.5 },
         Light::Point {
             intensity: 0.4,
@@ -56,16 +346,22 @@ fn main() {
             },
         },
     ];
+
+    // Lights held by the scene graph's nodes are placed where their nodes are
+    lights.extend(scene_data.graph.lights());
+
     let rt = RayTracer {
         scene_data,
         lights,
-        origin: Vector3d {
-            x: 0.0,
-            y: 2.0,
-            z: -10.0,
-        },
+        camera,
+        ignore_origin_triangle,
     };
-    let mut scene = Scene::new(WIDTH, HEIGHT);
+    let mut scene = Scene::new(width, height);
+
+    // The denoiser needs the passes to tell edges apart from noise
+    if aov_prefix.is_some() || denoise_iterations > 0 {
+        scene.aovs = Some(AovBuffers::new(width, height));
+    }
 
     // Limit to max ~60 fps update rate
     scene.canvas.window.set_target_fps(60);
@@ -77,7 +373,242 @@ fn main() {
 
     println!("draw finished");
 
+    if denoise_iterations > 0 {
+        let now = Instant::now();
+        scene.denoise(&DenoiseOptions {
+            iterations: denoise_iterations,
+            ..DenoiseOptions::default()
+        });
+        scene.canvas.update();
+        println!("It took: {:.2?} to denoise the scene", now.elapsed());
+    }
+
+    if let (Some(prefix), Some(aovs)) = (&aov_prefix, &scene.aovs) {
+        scene
+            .canvas
+            .save_image(&format!("{prefix}_beauty.png"))
+            .expect("Could not write beauty image");
+        aovs.write_images(prefix)
+            .expect("Could not write AOV images");
+
+        println!("wrote render passes to {prefix}_*");
+    }
+
     while scene.canvas.window.is_open() && !scene.canvas.window.is_key_down(Key::Escape) {
         scene.canvas.window.update();
     }
 }
+
+/// An instance of the model from `<x>,<y>,<z>[,<y rotation in degrees>[,<scale>[,<material>]]]`
+fn parse_instance(spec: &str, scene_data: &SceneData) -> Instance {
+    let parts: Vec<&str> = spec.split(',').collect();
+    assert!(
+        (3..=6).contains(&parts.len()),
+        "Expected an instance as <x>,<y>,<z>[,<y rotation>[,<scale>[,<material>]]]"
+    );
+
+    let number = |i: usize, default: f64| -> f64 {
+        parts
+            .get(i)
+            .map_or(default, |p| p.parse().expect("Invalid instance placement"))
+    };
+
+    let translation = Vector3d {
+        x: number(0, 0.0),
+        y: number(1, 0.0),
+        z: number(2, 0.0),
+    };
+    let rotation = Quaternion::from_axis_angle(
+        Vector3d {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        f64::to_radians(number(3, 0.0)),
+    );
+    let scale = number(4, 1.0);
+
+    let material = parts
+        .get(5)
+        .map(|&name| material_named(Some(name), scene_data));
+
+    Instance {
+        prototype: 0,
+        transform: Transform::from_parts(
+            translation,
+            rotation,
+            Vector3d {
+                x: scale,
+                y: scale,
+                z: scale,
+            },
+        ),
+        material,
+    }
+}
+
+/// A shape from its kind followed by its numbers, and optionally the name of its material:
+/// `sphere,<centre>,<radius>`, `plane,<point>,<normal>`, `disk,<centre>,<normal>,<radius>`,
+/// `cylinder,<base>,<top>,<radius>`, `cone,<base>,<apex>,<radius>` or `box,<min>,<max>`,
+/// with each point or direction as `<x>,<y>,<z>`
+fn parse_shape(spec: &str, scene_data: &SceneData) -> MeshShape {
+    let (kind, rest) = spec
+        .split_once(',')
+        .expect("Expected a shape as <kind>,<numbers>[,<material>]");
+    let parts: Vec<&str> = rest.split(',').collect();
+
+    let count = match kind {
+        "sphere" => 4,
+        "plane" | "box" => 6,
+        "disk" | "cylinder" | "cone" => 7,
+        _ => panic!("Unknown shape {kind}"),
+    };
+    assert!(
+        parts.len() == count || parts.len() == count + 1,
+        "Expected {count} numbers for a {kind}"
+    );
+
+    let numbers: Vec<f64> = parts[..count]
+        .iter()
+        .map(|p| p.parse().expect("Invalid shape number"))
+        .collect();
+    let point = |i: usize| Vector3d {
+        x: numbers[i],
+        y: numbers[i + 1],
+        z: numbers[i + 2],
+    };
+
+    let shape = match kind {
+        "sphere" => Shape::Sphere(Sphere {
+            centre: point(0),
+            radius: numbers[3],
+        }),
+        "plane" => Shape::Plane(Plane {
+            point: point(0),
+            normal: point(3),
+        }),
+        "disk" => Shape::Disk(Disk {
+            centre: point(0),
+            normal: point(3),
+            radius: numbers[6],
+        }),
+        "cylinder" => Shape::Cylinder(Cylinder {
+            base: point(0),
+            top: point(3),
+            radius: numbers[6],
+        }),
+        "cone" => Shape::Cone(Cone {
+            base: point(0),
+            apex: point(3),
+            radius: numbers[6],
+        }),
+        _ => Shape::Cuboid(Cuboid {
+            min: point(0),
+            max: point(3),
+        }),
+    };
+
+    MeshShape {
+        shape,
+        material: material_named(parts.get(count).copied(), scene_data),
+    }
+}
+
+/// A signed distance field from its kind followed by its numbers, and optionally the name of
+/// its material: `sphere,<centre>,<radius>`, `box,<centre>,<half size>`,
+/// `torus,<centre>,<major radius>,<minor radius>`, `capsule,<a>,<b>,<radius>` or
+/// `mandelbulb,<centre>,<scale>,<power>,<iterations>`, with each point as `<x>,<y>,<z>`
+fn parse_sdf(spec: &str, scene_data: &SceneData) -> (Sdf, u32) {
+    let (kind, rest) = spec
+        .split_once(',')
+        .expect("Expected an SDF as <kind>,<numbers>[,<material>]");
+    let parts: Vec<&str> = rest.split(',').collect();
+
+    let count = match kind {
+        "sphere" => 4,
+        "torus" => 5,
+        "box" | "mandelbulb" => 6,
+        "capsule" => 7,
+        _ => panic!("Unknown SDF {kind}"),
+    };
+    assert!(
+        parts.len() == count || parts.len() == count + 1,
+        "Expected {count} numbers for a {kind}"
+    );
+
+    let numbers: Vec<f64> = parts[..count]
+        .iter()
+        .map(|p| p.parse().expect("Invalid SDF number"))
+        .collect();
+    let point = |i: usize| Vector3d {
+        x: numbers[i],
+        y: numbers[i + 1],
+        z: numbers[i + 2],
+    };
+
+    let sdf = match kind {
+        "sphere" => Sdf::Sphere {
+            centre: point(0),
+            radius: numbers[3],
+        },
+        "box" => Sdf::Cuboid {
+            centre: point(0),
+            half_size: point(3),
+        },
+        "torus" => Sdf::Torus {
+            centre: point(0),
+            major_radius: numbers[3],
+            minor_radius: numbers[4],
+        },
+        "capsule" => Sdf::Capsule {
+            a: point(0),
+            b: point(3),
+            radius: numbers[6],
+        },
+        _ => Sdf::Mandelbulb {
+            centre: point(0),
+            scale: numbers[3],
+            power: numbers[4],
+            iterations: numbers[5] as u32,
+        },
+    };
+
+    (sdf, material_named(parts.get(count).copied(), scene_data))
+}
+
+/// Two closed shapes combined as `<operation>/<shape>/<shape>`, where the operation is `union`,
+/// `intersection` or `difference` and each shape is written as for `--shape`
+fn parse_csg(spec: &str, scene_data: &SceneData) -> MeshShape {
+    let parts: Vec<&str> = spec.split('/').collect();
+    let [operation, a, b] = parts[..] else {
+        panic!("Expected a CSG shape as <operation>/<shape>/<shape>");
+    };
+
+    let operation = match operation {
+        "union" => CsgOperation::Union,
+        "intersection" => CsgOperation::Intersection,
+        "difference" => CsgOperation::Difference,
+        _ => panic!("Unknown CSG operation {operation}"),
+    };
+    let a = parse_shape(a, scene_data);
+    let b = parse_shape(b, scene_data);
+
+    // Each solid keeps its own material, this one's only used for the shape as a whole
+    MeshShape {
+        material: a.material,
+        shape: Shape::Csg(CsgShape::new(operation, Solid::Shape(a), Solid::Shape(b))),
+    }
+}
+
+/// Id of the material with the given name, the first material if there's no name
+fn material_named(name: Option<&str>, scene_data: &SceneData) -> u32 {
+    let id = name.map_or(0, |name| {
+        *scene_data
+            .material_map
+            .ids_by_name
+            .get(name)
+            .unwrap_or_else(|| panic!("Unknown material {name}"))
+    });
+
+    id as u32
+}
@@ -1,5 +1,16 @@
+pub mod aov;
+pub mod camera;
+pub mod csg;
+pub mod denoise;
 pub mod engine;
 pub mod entities;
+pub mod graph;
 pub mod material;
+pub mod mesh;
+pub mod query;
 pub mod raytracer;
+pub mod sampling;
 pub mod scenedata;
+pub mod sdf;
+pub mod shape;
+pub mod transform;
@@ -0,0 +1,333 @@
+use std::{cmp::Reverse, fs};
+
+use image::{ImageResult, Rgb, Rgb32FImage, RgbImage};
+
+use super::{engine::Vector3d, entities::Color};
+
+/// What a primary ray sees at the first surface it hits, before any lighting is applied.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SurfaceSample {
+    /// Distance along the ray to the hit, in multiples of the ray's direction vector
+    pub depth: f64,
+    /// Shading normal, including any bump mapping
+    pub normal: Vector3d,
+    /// Raw texture colour
+    pub albedo: Color,
+    /// Interpolated texture coordinates
+    pub uv: (f64, f64),
+    pub triangle_index: usize,
+    pub material_id: usize,
+}
+
+impl SurfaceSample {
+    /// What several rays through a pixel saw, combined so the passes are antialiased and blurred
+    /// by depth of field and motion blur the same way as the colour. Depth, normal, albedo and
+    /// texture coordinates are averaged, the IDs are those of the triangle hit most often.
+    /// `None` if there are no samples.
+    pub fn average(samples: &[SurfaceSample]) -> Option<SurfaceSample> {
+        let count = samples.len() as f64;
+
+        // Ties go to the earliest sample
+        let (_, most_hit) = samples.iter().enumerate().max_by_key(|(i, sample)| {
+            let hits = samples
+                .iter()
+                .filter(|other| other.triangle_index == sample.triangle_index)
+                .count();
+            (hits, Reverse(*i))
+        })?;
+
+        let mut depth = 0.0;
+        let mut normal = Vector3d {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let mut uv = (0.0, 0.0);
+
+        for sample in samples {
+            depth += sample.depth;
+            normal += sample.normal;
+            uv.0 += sample.uv.0;
+            uv.1 += sample.uv.1;
+        }
+
+        // Opposite normals cancel out, fall back to the most common surface's
+        let normal = if normal.length() > 0.0 {
+            normal.normalised()
+        } else {
+            most_hit.normal
+        };
+        let albedo: Vec<Color> = samples.iter().map(|sample| sample.albedo).collect();
+
+        Some(SurfaceSample {
+            depth: depth / count,
+            normal,
+            albedo: Color::mix(&albedo),
+            uv: (uv.0 / count, uv.1 / count),
+            triangle_index: most_hit.triangle_index,
+            material_id: most_hit.material_id,
+        })
+    }
+}
+
+/// Arbitrary output variables, extra per pixel passes captured alongside the colour
+/// for compositing and debugging. Each pixel's passes are averaged over every ray through it,
+/// see `SurfaceSample::average`. Pixels where none of the rays hit anything are `None`.
+pub struct AovBuffers {
+    pub width: usize,
+    pub height: usize,
+    pub samples: Vec<Option<SurfaceSample>>,
+    /// Material names indexed by material ID
+    pub material_names: Vec<String>,
+}
+
+impl AovBuffers {
+    pub fn new(width: usize, height: usize) -> AovBuffers {
+        AovBuffers {
+            width,
+            height,
+            samples: vec![None; width * height],
+            material_names: vec![],
+        }
+    }
+
+    /// Write every pass as its own image next to `prefix`:
+    /// `_depth.exr`, `_normal.exr`, `_uv.exr`, `_triangle_id.exr` and `_material_id.exr` hold raw float values,
+    /// `_albedo.png` holds the texture colours and `_material_id.txt` maps material IDs to names.
+    /// The ID passes store the ID plus one so that 0.0 means nothing was hit.
+    pub fn write_images(&self, prefix: &str) -> ImageResult<()> {
+        self.float_image(|s| {
+            let d = s.depth as f32;
+            [d, d, d]
+        })
+        .save(format!("{prefix}_depth.exr"))?;
+
+        self.float_image(|s| [s.normal.x as f32, s.normal.y as f32, s.normal.z as f32])
+            .save(format!("{prefix}_normal.exr"))?;
+
+        self.float_image(|s| [s.uv.0 as f32, s.uv.1 as f32, 0.0])
+            .save(format!("{prefix}_uv.exr"))?;
+
+        self.float_image(|s| {
+            let id = (s.triangle_index + 1) as f32;
+            [id, id, id]
+        })
+        .save(format!("{prefix}_triangle_id.exr"))?;
+
+        self.float_image(|s| {
+            let id = (s.material_id + 1) as f32;
+            [id, id, id]
+        })
+        .save(format!("{prefix}_material_id.exr"))?;
+
+        let albedo = RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            match self.samples[y as usize * self.width + x as usize] {
+                Some(s) => Rgb([s.albedo.r, s.albedo.g, s.albedo.b]),
+                None => Rgb([0, 0, 0]),
+            }
+        });
+        albedo.save(format!("{prefix}_albedo.png"))?;
+
+        let legend: String = self
+            .material_names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| format!("{} {name}\n", id + 1))
+            .collect();
+        fs::write(format!("{prefix}_material_id.txt"), legend)?;
+
+        Ok(())
+    }
+
+    fn float_image(&self, value: impl Fn(&SurfaceSample) -> [f32; 3]) -> Rgb32FImage {
+        Rgb32FImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            match &self.samples[y as usize * self.width + x as usize] {
+                Some(s) => Rgb(value(s)),
+                None => Rgb([0.0, 0.0, 0.0]),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::collision::{
+        accelerator::{AccelerationStructure, AcceleratorKind},
+        bvh::Bvh,
+        instance::InstanceTree,
+    };
+    use crate::scene::{
+        camera::Camera,
+        engine::render_pixel,
+        entities::Texture,
+        graph::SceneGraph,
+        material::{Material, MaterialMap},
+        mesh::{Mesh, MeshShape},
+        raytracer::RayTracer,
+        scenedata::SceneData,
+        shape::{Cuboid, Shape},
+    };
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    fn material(name: &str, id: usize, colour: Color) -> Material {
+        Material {
+            name: String::from(name),
+            id,
+            ambient_color_coefficient: vector(1.0, 1.0, 1.0),
+            diffuse_color_coefficient: vector(1.0, 1.0, 1.0),
+            specular_color_coefficient: vector(0.0, 0.0, 0.0),
+            specular_weight: 1.0,
+            texture: Arc::new(Texture {
+                colours: vec![colour],
+                width: 1,
+                height: 1,
+            }),
+            bump_map: None,
+            reflectivity: 0.0,
+        }
+    }
+
+    /// A box from z = 4 to z = 6 straight in front of the camera, in the second of two materials
+    fn box_in_front() -> RayTracer {
+        let mut mesh = Mesh::from_triangles(&[]);
+        mesh.shapes.push(MeshShape {
+            shape: Shape::Cuboid(Cuboid {
+                min: vector(-1.0, -1.0, 4.0),
+                max: vector(1.0, 1.0, 6.0),
+            }),
+            material: 1,
+        });
+
+        let materials = vec![
+            material("floor", 0, Color { r: 0, g: 0, b: 255 }),
+            material(
+                "box",
+                1,
+                Color {
+                    r: 200,
+                    g: 10,
+                    b: 0,
+                },
+            ),
+        ];
+        let mut scene_data = SceneData {
+            mesh: Arc::new(mesh),
+            material_map: MaterialMap {
+                textures: vec![],
+                ids_by_name: materials
+                    .iter()
+                    .map(|m| (m.name.clone(), m.id))
+                    .collect::<HashMap<_, _>>(),
+                materials,
+            },
+            acceleration_structure: AccelerationStructure::Bvh(Bvh::build(Arc::default())),
+            instances: InstanceTree::default(),
+            graph: SceneGraph::default(),
+        };
+        scene_data.rebuild_acceleration_structure(AcceleratorKind::Bvh);
+
+        RayTracer {
+            scene_data,
+            lights: vec![],
+            camera: Camera::new(vector(0.0, 0.0, 0.0)),
+            ignore_origin_triangle: false,
+        }
+    }
+
+    #[test]
+    fn test_passes_record_the_surface_the_pixel_sees() {
+        let tracer = box_in_front();
+
+        // Every ray through the middle pixel of an 8 by 8 canvas hits the box's front face
+        let (_, _, _, centre) = render_pixel(&tracer, 0, 0, 8, 8, true);
+        let centre = centre.unwrap();
+        assert!((centre.depth - 4.0).abs() < 1e-9);
+        assert!((centre.normal - vector(0.0, 0.0, -1.0)).length() < 1e-9);
+        assert_eq!(
+            centre.albedo,
+            Color {
+                r: 200,
+                g: 10,
+                b: 0
+            }
+        );
+        assert_eq!((centre.triangle_index, centre.material_id), (0, 1));
+
+        // Nothing in the corner
+        let (_, _, _, corner) = render_pixel(&tracer, 3, 3, 8, 8, true);
+        assert_eq!(corner, None);
+
+        // Passes aren't worked out unless they're wanted, and come from the same trace as
+        // the colour, so the colour is the same either way
+        let (_, _, colour, skipped) = render_pixel(&tracer, 0, 0, 8, 8, false);
+        assert_eq!(skipped, None);
+        assert_eq!(colour, render_pixel(&tracer, 0, 0, 8, 8, true).2);
+
+        let mut aovs = AovBuffers::new(2, 1);
+        aovs.samples = vec![Some(centre), corner];
+        aovs.material_names = vec![String::from("floor"), String::from("box")];
+
+        let directory = std::env::temp_dir().join(format!("aov_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let prefix = directory.join("render");
+        let prefix = prefix.to_str().unwrap();
+        aovs.write_images(prefix).unwrap();
+
+        let pass = |name: &str| {
+            image::open(format!("{prefix}_{name}.exr"))
+                .unwrap()
+                .into_rgb32f()
+        };
+        assert_eq!(pass("depth").get_pixel(0, 0).0, [4.0; 3]);
+        assert_eq!(pass("normal").get_pixel(0, 0).0, [0.0, 0.0, -1.0]);
+        // IDs are stored plus one, so nothing is 0
+        assert_eq!(pass("triangle_id").get_pixel(0, 0).0, [1.0; 3]);
+        assert_eq!(pass("material_id").get_pixel(0, 0).0, [2.0; 3]);
+        assert_eq!(pass("material_id").get_pixel(1, 0).0, [0.0; 3]);
+        assert_eq!(
+            fs::read_to_string(format!("{prefix}_material_id.txt")).unwrap(),
+            "1 floor\n2 box\n"
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_average_takes_ids_from_the_most_hit_triangle() {
+        let sample = |depth: f64, normal: Vector3d, triangle_index: usize| SurfaceSample {
+            depth,
+            normal,
+            albedo: Color {
+                r: (depth * 10.0) as u8,
+                g: 0,
+                b: 0,
+            },
+            uv: (depth, 0.0),
+            triangle_index,
+            material_id: triangle_index * 10,
+        };
+
+        let average = SurfaceSample::average(&[
+            sample(2.0, vector(1.0, 0.0, 0.0), 7),
+            sample(4.0, vector(0.0, 1.0, 0.0), 3),
+            sample(6.0, vector(0.0, 1.0, 0.0), 3),
+            sample(8.0, vector(1.0, 0.0, 0.0), 5),
+        ])
+        .unwrap();
+
+        assert_eq!(average.depth, 5.0);
+        assert_eq!(average.uv, (5.0, 0.0));
+        assert_eq!(average.albedo.r, 50);
+        assert!((average.normal - vector(1.0, 1.0, 0.0).normalised()).length() < 1e-12);
+        assert_eq!((average.triangle_index, average.material_id), (3, 30));
+
+        assert_eq!(SurfaceSample::average(&[]), None);
+    }
+}
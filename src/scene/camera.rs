@@ -0,0 +1,546 @@
+use std::f64::consts::PI;
+
+use crate::collision::{ray::Ray, region::Region};
+
+use super::{
+    engine::Vector3d,
+    entities::Motion,
+    sampling::{sample_regular_polygon, sample_unit_disk, Sampler},
+    transform::Transform,
+};
+
+/// The rectangle that perspective rays are fired through, `distance` in front of the camera.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Viewport {
+    pub width: f64,
+    pub height: f64,
+    pub distance: f64,
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Viewport {
+            width: 1.0,
+            height: 1.0,
+            distance: 1.0,
+        }
+    }
+}
+
+/// How points on the canvas are turned into primary ray directions.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// Rays fan out from the camera origin through the viewport.
+    Perspective(Viewport),
+    /// Parallel rays along +z from a `width` by `height` rectangle around the camera origin,
+    /// useful for technical elevations where distances shouldn't be foreshortened.
+    Orthographic { width: f64, height: f64 },
+    /// Equidistant fisheye, the image circle spans `field_of_view` radians.
+    /// Anything outside the image circle is left black.
+    Fisheye { field_of_view: f64 },
+    /// Full 360 x 180 degree panorama, canvas x maps to longitude and y to latitude.
+    /// Renders are best done on a 2:1 canvas.
+    Equirectangular,
+}
+
+/// A thin lens camera looking down the +z axis, or wherever `orientation` turns that to.
+/// With an aperture radius of 0.0 it behaves as a pinhole camera and everything is in focus,
+/// otherwise primary rays start somewhere on the lens and converge on the focal plane,
+/// so anything in front of or behind that plane is blurred.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub origin: Vector3d,
+    pub projection: Projection,
+    pub aperture_radius: f64,
+    /// Distance from the lens to the plane that is perfectly in focus.
+    pub focal_distance: f64,
+    /// Number of aperture blades, fewer than 3 gives a perfectly round aperture (and round bokeh).
+    pub aperture_blades: u32,
+    /// Rotation of the aperture polygon in radians.
+    pub aperture_rotation: f64,
+    /// How many lens positions (and moments in the shutter interval) to sample for every sub-pixel ray.
+    pub lens_samples: u32,
+    /// Portion of the frame the shutter is open for, where the frame runs from time 0.0 to 1.0,
+    /// primary rays get a random time between the two.
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    /// Set when the camera itself moves during the frame, around its own resting place
+    pub motion: Option<Motion>,
+    /// Turns (and possibly scales) the camera's view around its origin, from looking down +z
+    /// with +y up. Only the transform's linear part is used, `origin` says where the camera is.
+    pub orientation: Transform,
+}
+
+impl Camera {
+    pub fn new(origin: Vector3d) -> Camera {
+        Camera {
+            origin,
+            projection: Projection::Perspective(Viewport::default()),
+            aperture_radius: 0.0,
+            focal_distance: 1.0,
+            aperture_blades: 0,
+            aperture_rotation: 0.0,
+            lens_samples: 1,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            motion: None,
+            orientation: Transform::identity(),
+        }
+    }
+
+    /// Number of rays to trace for each sub-pixel,
+    /// a pinhole with an instantaneous shutter only ever needs one.
+    pub fn sub_pixel_sample_count(&self) -> u32 {
+        if self.aperture_radius > 0.0 || self.shutter_close > self.shutter_open {
+            self.lens_samples.max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Build the primary ray for a point on the canvas.
+    /// `screen_x` and `screen_y` run from -0.5 to 0.5 across the canvas with +y pointing up,
+    /// `aspect_ratio` is the canvas width divided by its height.
+    /// Returns `None` if the point is outside the area covered by the projection.
+    pub fn primary_ray(
+        &self,
+        screen_x: f64,
+        screen_y: f64,
+        aspect_ratio: f64,
+        sampler: &mut Sampler,
+    ) -> Option<Ray> {
+        let time = if self.shutter_close > self.shutter_open {
+            self.shutter_open + (self.shutter_close - self.shutter_open) * sampler.next_f64()
+        } else {
+            self.shutter_open
+        };
+
+        let pinhole_ray = self.pinhole_ray(screen_x, screen_y, aspect_ratio, time)?;
+
+        if self.aperture_radius <= 0.0 {
+            return Some(self.moved(pinhole_ray));
+        }
+
+        let (lens_x, lens_y) = self.sample_aperture(sampler);
+
+        match self.projection {
+            Projection::Perspective(_) | Projection::Orthographic { .. } => {
+                // The point where the pinhole ray meets the focal plane stays sharp
+                // regardless of where on the lens the ray starts from.
+                let focus_point = pinhole_ray.origin
+                    + pinhole_ray.direction * (self.focal_distance / pinhole_ray.direction.z);
+
+                let origin = pinhole_ray.origin
+                    + Vector3d {
+                        x: lens_x * self.aperture_radius,
+                        y: lens_y * self.aperture_radius,
+                        z: 0.0,
+                    };
+
+                Some(self.moved(Ray {
+                    origin,
+                    direction: focus_point - origin,
+                    time,
+                    ignored_triangle: None,
+                }))
+            }
+            Projection::Fisheye { .. } | Projection::Equirectangular => {
+                // Wide angle projections focus on a sphere around the camera, the lens
+                // is the disk facing along each ray's direction.
+                let d = pinhole_ray.direction.normalised();
+                let focus_point = pinhole_ray.origin + d * self.focal_distance;
+
+                let helper = if d.y.abs() < 0.9 {
+                    Vector3d {
+                        x: 0.0,
+                        y: 1.0,
+                        z: 0.0,
+                    }
+                } else {
+                    Vector3d {
+                        x: 1.0,
+                        y: 0.0,
+                        z: 0.0,
+                    }
+                };
+                let right = helper.cross(&d).normalised();
+                let up = d.cross(&right);
+
+                let origin = pinhole_ray.origin
+                    + right * (lens_x * self.aperture_radius)
+                    + up * (lens_y * self.aperture_radius);
+
+                Some(self.moved(Ray {
+                    origin,
+                    direction: focus_point - origin,
+                    time,
+                    ignored_triangle: None,
+                }))
+            }
+        }
+    }
+
+    /// The part of the scene visible through a rectangle of the canvas between `near` and `far`,
+    /// measured along the view direction, for culling geometry before rendering part of the image.
+    /// Screen coordinates are as in `primary_ray`, the lens is treated as a pinhole.
+    /// Returns `None` for projections whose view isn't bounded by flat faces.
+    pub fn view_region(
+        &self,
+        screen_min: (f64, f64),
+        screen_max: (f64, f64),
+        aspect_ratio: f64,
+        near: f64,
+        far: f64,
+        time: f64,
+    ) -> Option<Region> {
+        if !matches!(
+            self.projection,
+            Projection::Perspective(_) | Projection::Orthographic { .. }
+        ) {
+            return None;
+        }
+
+        let mut corners = [self.origin; 8];
+
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let screen_x = if i & 1 == 0 {
+                screen_min.0
+            } else {
+                screen_max.0
+            };
+            let screen_y = if i & 2 == 0 {
+                screen_min.1
+            } else {
+                screen_max.1
+            };
+            let distance = if i & 4 == 0 { near } else { far };
+
+            let ray = self.pinhole_ray(screen_x, screen_y, aspect_ratio, time)?;
+            *corner = self.moved_point(
+                ray.origin + ray.direction * (distance / ray.direction.z),
+                time,
+            );
+        }
+
+        Some(Region::from_corners(corners))
+    }
+
+    /// Where the camera is at `time`
+    pub fn origin_at(&self, time: f64) -> Vector3d {
+        self.moved_point(self.origin, time)
+    }
+
+    /// A point that moves with the camera, turned by its orientation and moved to where it is
+    /// at `time`. The camera's motion turns and scales it around the camera's own resting place.
+    fn moved_point(&self, point: Vector3d, time: f64) -> Vector3d {
+        // Left exactly where it is when the camera is neither turned nor moving
+        if self.motion.is_none() && self.orientation == Transform::identity() {
+            return point;
+        }
+
+        let offset = self.orientation.transform_vector(point - self.origin);
+
+        match &self.motion {
+            Some(motion) => self.origin + motion.transform_at(time).transform_point(offset),
+            None => self.origin + offset,
+        }
+    }
+
+    /// A ray leaving the camera at rest looking down +z, turned by the camera's orientation
+    /// and moved to leave the camera where it is at its time
+    fn moved(&self, ray: Ray) -> Ray {
+        let direction = self.orientation.transform_vector(ray.direction);
+
+        Ray {
+            origin: self.moved_point(ray.origin, ray.time),
+            direction: match &self.motion {
+                Some(motion) => motion.transform_at(ray.time).transform_vector(direction),
+                None => direction,
+            },
+            ..ray
+        }
+    }
+
+    /// The ray through a point on the canvas from a pinhole at the camera's resting place
+    fn pinhole_ray(
+        &self,
+        screen_x: f64,
+        screen_y: f64,
+        aspect_ratio: f64,
+        time: f64,
+    ) -> Option<Ray> {
+        let camera_origin = self.origin;
+
+        match self.projection {
+            Projection::Perspective(viewport) => Some(Ray {
+                origin: camera_origin,
+                direction: Vector3d {
+                    x: screen_x * viewport.width,
+                    y: screen_y * viewport.height,
+                    z: viewport.distance,
+                },
+                time,
+                ignored_triangle: None,
+            }),
+            Projection::Orthographic { width, height } => Some(Ray {
+                origin: camera_origin
+                    + Vector3d {
+                        x: screen_x * width,
+                        y: screen_y * height,
+                        z: 0.0,
+                    },
+                direction: Vector3d {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                time,
+                ignored_triangle: None,
+            }),
+            Projection::Fisheye { field_of_view } => {
+                // Keep the image circle round on non-square canvases
+                let x = screen_x * 2.0 * aspect_ratio.max(1.0);
+                let y = screen_y * 2.0 / aspect_ratio.min(1.0);
+                let r = (x * x + y * y).sqrt();
+
+                if r > 1.0 {
+                    return None;
+                }
+
+                let theta = r * field_of_view / 2.0;
+                let phi = y.atan2(x);
+
+                Some(Ray {
+                    origin: camera_origin,
+                    direction: Vector3d {
+                        x: theta.sin() * phi.cos(),
+                        y: theta.sin() * phi.sin(),
+                        z: theta.cos(),
+                    },
+                    time,
+                    ignored_triangle: None,
+                })
+            }
+            Projection::Equirectangular => {
+                let longitude = screen_x * 2.0 * PI;
+                let latitude = screen_y * PI;
+
+                Some(Ray {
+                    origin: camera_origin,
+                    direction: Vector3d {
+                        x: latitude.cos() * longitude.sin(),
+                        y: latitude.sin(),
+                        z: latitude.cos() * longitude.cos(),
+                    },
+                    time,
+                    ignored_triangle: None,
+                })
+            }
+        }
+    }
+
+    fn sample_aperture(&self, sampler: &mut Sampler) -> (f64, f64) {
+        if self.aperture_blades >= 3 {
+            sample_regular_polygon(
+                self.aperture_blades,
+                self.aperture_rotation,
+                sampler.next_f64(),
+                sampler.next_f64(),
+                sampler.next_f64(),
+            )
+        } else {
+            sample_unit_disk(sampler.next_f64(), sampler.next_f64())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scene::transform::Quaternion;
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    /// Where a ray crosses the plane `z` in front of the camera
+    fn crossing(ray: &Ray, camera: &Camera, z: f64) -> Vector3d {
+        ray.origin + ray.direction * ((camera.origin.z + z - ray.origin.z) / ray.direction.z)
+    }
+
+    #[test]
+    fn test_lens_rays_meet_on_the_focal_plane() {
+        let mut camera = Camera::new(vector(1.0, -2.0, 3.0));
+        camera.aperture_radius = 0.25;
+        camera.focal_distance = 6.0;
+        camera.lens_samples = 16;
+        let mut sampler = Sampler::new(3);
+
+        for aperture_blades in [0, 6] {
+            camera.aperture_blades = aperture_blades;
+
+            for projection in [
+                Projection::Perspective(Viewport::default()),
+                Projection::Orthographic {
+                    width: 4.0,
+                    height: 2.0,
+                },
+            ] {
+                camera.projection = projection;
+
+                for (screen_x, screen_y) in [(0.0, 0.0), (-0.4, 0.3), (0.25, -0.5)] {
+                    let rays: Vec<Ray> = (0..16)
+                        .map(|_| {
+                            camera
+                                .primary_ray(screen_x, screen_y, 1.5, &mut sampler)
+                                .unwrap()
+                        })
+                        .collect();
+
+                    let focus = crossing(&rays[0], &camera, camera.focal_distance);
+                    let near = crossing(&rays[0], &camera, 1.0);
+                    let mut spread = 0.0_f64;
+
+                    for ray in &rays {
+                        // Each ray starts somewhere different on the lens...
+                        let from_centre = ray.origin - rays[0].origin;
+                        assert!(from_centre.z == 0.0 && from_centre.length() <= 0.5 + 1e-12);
+
+                        // ...but they all pass through the same point on the focal plane
+                        let at_focus = crossing(ray, &camera, camera.focal_distance);
+                        assert!((at_focus - focus).length() < 1e-9);
+
+                        spread = spread.max((crossing(ray, &camera, 1.0) - near).length());
+                    }
+
+                    // Away from the focal plane they're spread out, which is the blur
+                    assert!(spread > 0.05);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_centre_of_every_projection_looks_forward() {
+        let mut camera = Camera::new(vector(2.0, 1.0, -3.0));
+        let mut sampler = Sampler::new(5);
+
+        for projection in [
+            Projection::Perspective(Viewport::default()),
+            Projection::Orthographic {
+                width: 3.0,
+                height: 3.0,
+            },
+            Projection::Fisheye {
+                field_of_view: f64::to_radians(220.0),
+            },
+            Projection::Equirectangular,
+        ] {
+            camera.projection = projection;
+
+            for aspect_ratio in [1.0, 2.0, 0.5] {
+                let ray = camera
+                    .primary_ray(0.0, 0.0, aspect_ratio, &mut sampler)
+                    .unwrap();
+
+                assert_eq!(ray.origin, camera.origin, "{projection:?}");
+                assert_eq!(
+                    ray.direction.normalised(),
+                    vector(0.0, 0.0, 1.0),
+                    "{projection:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fisheye_edge_is_half_the_field_of_view_away() {
+        let mut sampler = Sampler::new(9);
+
+        // As `--fov` gives it, in degrees
+        for degrees in [90.0, 180.0, 270.0] {
+            let mut camera = Camera::new(vector(0.0, 0.0, 0.0));
+            camera.projection = Projection::Fisheye {
+                field_of_view: f64::to_radians(degrees),
+            };
+            let forward = vector(0.0, 0.0, 1.0);
+
+            // The image circle touches the middle of the shorter sides of the canvas
+            for (screen_x, screen_y, aspect_ratio) in [
+                (0.5, 0.0, 1.0),
+                (0.0, -0.5, 1.0),
+                (0.25, 0.0, 2.0),
+                (0.0, 0.25, 0.5),
+            ] {
+                let ray = camera
+                    .primary_ray(screen_x, screen_y, aspect_ratio, &mut sampler)
+                    .unwrap();
+                let angle = ray.direction.normalised().dot(&forward).acos();
+
+                assert!((angle.to_degrees() - degrees / 2.0).abs() < 1e-9);
+            }
+
+            // The corners are outside the image circle
+            assert!(camera.primary_ray(0.5, 0.5, 1.0, &mut sampler).is_none());
+        }
+    }
+
+    #[test]
+    fn test_equirectangular_left_and_right_columns_wrap_round() {
+        let mut camera = Camera::new(vector(0.0, 0.0, 0.0));
+        camera.projection = Projection::Equirectangular;
+        let mut sampler = Sampler::new(13);
+
+        for screen_y in [-0.4, -0.1, 0.0, 0.2, 0.45] {
+            let left = camera
+                .primary_ray(-0.5, screen_y, 2.0, &mut sampler)
+                .unwrap();
+            let right = camera
+                .primary_ray(0.5, screen_y, 2.0, &mut sampler)
+                .unwrap();
+
+            // Both look straight backwards at the same latitude
+            assert!((left.direction - right.direction).length() < 1e-12);
+            assert!(left.direction.z < 0.0);
+            assert!((left.direction.y - (screen_y * PI).sin()).abs() < 1e-12);
+        }
+
+        // The top row all looks straight up
+        let up = camera.primary_ray(0.3, 0.5, 2.0, &mut sampler).unwrap();
+        assert!((up.direction - vector(0.0, 1.0, 0.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_pinhole_rays_start_at_the_camera() {
+        let camera = Camera::new(vector(0.0, 0.0, -5.0));
+        let mut sampler = Sampler::new(1);
+
+        let ray = camera.primary_ray(0.5, 0.5, 1.0, &mut sampler).unwrap();
+        assert_eq!(ray.origin, camera.origin);
+        assert_eq!(ray.direction, vector(0.5, 0.5, 1.0));
+        assert_eq!(camera.sub_pixel_sample_count(), 1);
+    }
+
+    #[test]
+    fn test_turned_lens_rays_meet_on_the_turned_focal_plane() {
+        let mut camera = Camera::new(vector(1.0, 2.0, 3.0));
+        camera.aperture_radius = 0.5;
+        camera.focal_distance = 4.0;
+        // Looking down -x instead of +z
+        camera.orientation = Transform::rotation(Quaternion::from_axis_angle(
+            vector(0.0, 1.0, 0.0),
+            -PI / 2.0,
+        ));
+        let mut sampler = Sampler::new(8);
+
+        for _ in 0..16 {
+            let ray = camera.primary_ray(0.0, 0.0, 1.0, &mut sampler).unwrap();
+
+            // Every lens ray starts level with the camera and passes through the focus point
+            assert!((ray.origin.x - camera.origin.x).abs() < 1e-12);
+            let focus = ray.origin + ray.direction * ((-3.0 - ray.origin.x) / ray.direction.x);
+            assert!((focus - vector(-3.0, 2.0, 3.0)).length() < 1e-9);
+        }
+    }
+}
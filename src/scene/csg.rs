@@ -0,0 +1,637 @@
+use std::sync::Arc;
+
+use crate::collision::{
+    aabb::Aabb,
+    accelerator::Accelerator,
+    bvh::Bvh,
+    ray::{Ray, TriangleTest},
+    spawn::SurfacePoint,
+};
+
+use super::{
+    engine::Vector3d,
+    mesh::{Mesh, MeshShape},
+    shape::{Hittable, Primitive, Shape, SurfaceHit},
+};
+
+/// How a CSG shape puts its two solids together
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CsgOperation {
+    /// Everywhere inside either solid
+    Union,
+    /// Only where the solids overlap
+    Intersection,
+    /// The first solid with the second cut out of it
+    Difference,
+}
+
+impl CsgOperation {
+    /// Whether a point inside or outside each of the solids is inside the result
+    fn inside(self, a: bool, b: bool) -> bool {
+        match self {
+            CsgOperation::Union => a || b,
+            CsgOperation::Intersection => a && b,
+            CsgOperation::Difference => a && !b,
+        }
+    }
+}
+
+/// One of the closed solids a CSG shape is made of
+#[derive(Clone, Debug, PartialEq)]
+pub enum Solid {
+    /// A shape that isn't flat, in its own material. Putting a CSG shape here builds up a tree,
+    /// whose surfaces keep the materials of the solids they came from.
+    Shape(MeshShape),
+    /// A watertight mesh with its triangles wound so their fronts face out, in its triangles'
+    /// materials. It's traced with the watertight test so rays can't slip between triangles.
+    Mesh(Arc<Bvh>),
+}
+
+/// Which of a CSG shape's solids a hit is on, worked out while tracing the ray so shading
+/// doesn't have to trace it again. The hit's u and v are surface coordinates on that solid.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SolidHit {
+    /// The solid picked at each level of nested CSG shapes, the top level's in the lowest bit,
+    /// set for `b` and clear for `a`
+    pub path: u64,
+    /// Which of a mesh solid's triangles was hit, 0 for shapes
+    pub index: usize,
+    /// Whether the solid's normal needs turning around, like on the inside of a hole
+    pub flipped: bool,
+}
+
+/// Where a ray goes into or out of a solid
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Crossing {
+    pub t: f64,
+    /// Surface coordinates on whatever was crossed, see `Hittable`
+    pub u: f64,
+    pub v: f64,
+    /// What was crossed, for crossings of CSG shapes the solid they came from
+    pub solid: SolidHit,
+    /// Whether the ray goes in here rather than out
+    pub entering: bool,
+}
+
+impl Solid {
+    /// A watertight mesh as a solid
+    pub fn mesh(mesh: Arc<Mesh>) -> Solid {
+        let mut bvh = Bvh::build(mesh);
+        bvh.triangle_test = TriangleTest::Watertight;
+
+        Solid::Mesh(Arc::new(bvh))
+    }
+
+    /// Every crossing of the solid's surface in front of the ray's origin, nearest first
+    pub fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        match self {
+            Solid::Shape(MeshShape {
+                shape: Shape::Csg(csg),
+                ..
+            }) => csg.crossings(ray),
+            Solid::Shape(MeshShape { shape, .. }) => shape
+                .intersect_all(ray)
+                .into_iter()
+                .map(|(t, u, v)| Crossing {
+                    t,
+                    u,
+                    v,
+                    solid: SolidHit::default(),
+                    entering: shape
+                        .normal_at(&SurfaceHit {
+                            ray,
+                            t,
+                            u,
+                            v,
+                            solid: SolidHit::default(),
+                        })
+                        .dot(&ray.direction)
+                        < 0.0,
+                })
+                .collect(),
+            Solid::Mesh(bvh) => bvh
+                .all_hits(ray, f64::INFINITY)
+                .into_iter()
+                .map(|hit| Crossing {
+                    t: hit.t,
+                    u: hit.u,
+                    v: hit.v,
+                    solid: SolidHit {
+                        index: hit.triangle_index,
+                        ..SolidHit::default()
+                    },
+                    entering: SurfacePoint::from_intersection(bvh.mesh(), &hit, ray)
+                        .normal
+                        .dot(&ray.direction)
+                        < 0.0,
+                })
+                .collect(),
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match self {
+            Solid::Shape(shape) => shape.shape.bounds(),
+            Solid::Mesh(bvh) => Some(bvh.bounds()),
+        }
+    }
+
+    /// The shape or triangle a crossing is on
+    fn surface(&self, index: usize) -> Primitive<'_> {
+        match self {
+            Solid::Shape(shape) => Primitive::Shape(&shape.shape),
+            Solid::Mesh(bvh) => bvh.mesh().primitive(index),
+        }
+    }
+
+    /// Id of the material of the shape or triangle a crossing is on
+    fn material(&self, index: usize) -> u32 {
+        match self {
+            Solid::Shape(shape) => shape.material,
+            Solid::Mesh(bvh) => bvh.mesh().material(index) as u32,
+        }
+    }
+}
+
+/// Two closed solids combined into one, which goes in a mesh like any other shape.
+///
+/// The ray is traced against both solids for every place it goes in or out of them, and those
+/// are walked through together to find where it goes in and out of the result. The surface there
+/// is the surface of whichever solid it came from, in that solid's material, with its normal
+/// turned around where the result is inside out compared to it, like the inside of a hole.
+/// Hits are on the surface of one of the solids, which solid goes in the hit's `SolidHit` so
+/// shading can find it again without tracing the ray.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsgShape {
+    pub operation: CsgOperation,
+    pub a: Box<Solid>,
+    pub b: Box<Solid>,
+}
+
+impl CsgShape {
+    pub fn new(operation: CsgOperation, a: Solid, b: Solid) -> CsgShape {
+        for solid in [&a, &b] {
+            if let Solid::Shape(shape) = solid {
+                assert!(
+                    !shape.shape.is_flat(),
+                    "CSG needs closed shapes, not flat ones"
+                );
+            }
+        }
+
+        let shape = CsgShape {
+            operation,
+            a: Box::new(a),
+            b: Box::new(b),
+        };
+        assert!(
+            shape.depth() <= u64::BITS,
+            "CSG shapes can't be nested more than {} deep",
+            u64::BITS
+        );
+
+        shape
+    }
+
+    /// Every crossing of the combined solid's surface in front of the ray's origin, nearest
+    /// first, each with the solid it's on
+    pub fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        // The solids' own triangle and shape indices have nothing to do with the one the
+        // ray is told to skip
+        let ray = Ray {
+            ignored_triangle: None,
+            ..ray.clone()
+        };
+        let a = self.a.crossings(&ray);
+        let b = self.b.crossings(&ray);
+
+        // A ray whose first crossing of a closed solid is on the way out starts inside it
+        let mut inside_a = a.first().is_some_and(|crossing| !crossing.entering);
+        let mut inside_b = b.first().is_some_and(|crossing| !crossing.entering);
+        let mut inside = self.operation.inside(inside_a, inside_b);
+
+        let mut crossings = vec![];
+        let (mut next_a, mut next_b) = (a.iter().peekable(), b.iter().peekable());
+
+        loop {
+            let from_b = match (next_a.peek(), next_b.peek()) {
+                (Some(a), Some(b)) => b.t < a.t,
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (None, None) => break,
+            };
+
+            let crossing = if from_b {
+                let crossing = next_b.next().unwrap();
+                inside_b = crossing.entering;
+                crossing
+            } else {
+                let crossing = next_a.next().unwrap();
+                inside_a = crossing.entering;
+                crossing
+            };
+
+            let now_inside = self.operation.inside(inside_a, inside_b);
+            if now_inside != inside {
+                // This level's pick goes below the nested shapes' ones, and the normal turns
+                // around again wherever the result is inside out compared to the solid
+                crossings.push(Crossing {
+                    solid: SolidHit {
+                        path: (crossing.solid.path << 1) | u64::from(from_b),
+                        index: crossing.solid.index,
+                        flipped: crossing.solid.flipped != (crossing.entering != now_inside),
+                    },
+                    entering: now_inside,
+                    ..*crossing
+                });
+                inside = now_inside;
+            }
+        }
+
+        crossings
+    }
+
+    /// The stretches of the ray inside the combined solid as (entry t, exit t), nearest first.
+    /// A ray starting inside starts at 0, and one that never leaves ends at infinity.
+    pub fn intervals(&self, ray: &Ray) -> Vec<(f64, f64)> {
+        let crossings = self.crossings(ray);
+
+        // Crossings of the result always go in and out in turn
+        let mut ends: Vec<f64> = crossings.iter().map(|crossing| crossing.t).collect();
+        if crossings.first().is_some_and(|crossing| !crossing.entering) {
+            ends.insert(0, 0.0);
+        }
+        if ends.len() % 2 == 1 {
+            ends.push(f64::INFINITY);
+        }
+
+        ends.chunks(2).map(|ends| (ends[0], ends[1])).collect()
+    }
+
+    /// Id of the material of whichever solid was hit
+    pub fn material_at(&self, hit: &SurfaceHit) -> u32 {
+        self.solid(hit.solid.path).material(hit.solid.index)
+    }
+
+    /// How many CSG shapes deep the tree goes, counting this one
+    fn depth(&self) -> u32 {
+        let nested = |solid: &Solid| match solid {
+            Solid::Shape(MeshShape {
+                shape: Shape::Csg(csg),
+                ..
+            }) => csg.depth(),
+            _ => 0,
+        };
+
+        1 + u32::max(nested(&self.a), nested(&self.b))
+    }
+
+    /// The solid at the end of a `SolidHit`'s path, through any nested CSG shapes
+    fn solid(&self, path: u64) -> &Solid {
+        let solid = if path & 1 == 1 { &self.b } else { &self.a };
+
+        match &**solid {
+            Solid::Shape(MeshShape {
+                shape: Shape::Csg(csg),
+                ..
+            }) => csg.solid(path >> 1),
+            solid => solid,
+        }
+    }
+
+    /// The hit on whichever solid was hit
+    fn solid_hit<'s, 'r>(&'s self, hit: &SurfaceHit<'r>) -> (Primitive<'s>, SurfaceHit<'r>) {
+        (
+            self.solid(hit.solid.path).surface(hit.solid.index),
+            SurfaceHit {
+                solid: SolidHit::default(),
+                ..*hit
+            },
+        )
+    }
+}
+
+impl Hittable for CsgShape {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        self.crossings(ray)
+            .first()
+            .map(|crossing| (crossing.t, crossing.u, crossing.v))
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        self.crossings(ray)
+            .into_iter()
+            .map(|crossing| (crossing.t, crossing.u, crossing.v))
+            .collect()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match self.operation {
+            CsgOperation::Union => Some(self.a.bounds()?.union(&self.b.bounds()?)),
+            CsgOperation::Intersection => match (self.a.bounds(), self.b.bounds()) {
+                (Some(a), Some(b)) => Some(a.intersection(&b)),
+                (a, b) => a.or(b),
+            },
+            CsgOperation::Difference => self.a.bounds(),
+        }
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        let (surface, solid_hit) = self.solid_hit(hit);
+        surface.point_at(&solid_hit)
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        let (surface, solid_hit) = self.solid_hit(hit);
+        let normal = surface.normal_at(&solid_hit);
+
+        if hit.solid.flipped {
+            -normal
+        } else {
+            normal
+        }
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        let (surface, solid_hit) = self.solid_hit(hit);
+        surface.tex_coords_at(&solid_hit)
+    }
+
+    fn is_flat(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scene::{
+        engine::{widen, Float},
+        sampling::Sampler,
+        shape::{Cuboid, Sphere},
+    };
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    fn ray(origin: Vector3d, direction: Vector3d) -> Ray {
+        Ray {
+            origin,
+            direction,
+            time: 0.0,
+            ignored_triangle: None,
+        }
+    }
+
+    fn solid(shape: Shape, material: u32) -> Solid {
+        Solid::Shape(MeshShape { shape, material })
+    }
+
+    fn sphere(centre: Vector3d, radius: f64) -> Shape {
+        Shape::Sphere(Sphere { centre, radius })
+    }
+
+    fn cuboid(min: Vector3d, max: Vector3d) -> Shape {
+        Shape::Cuboid(Cuboid { min, max })
+    }
+
+    /// The box as twelve triangles wound to face out
+    fn cuboid_mesh(min: Vector3d, max: Vector3d) -> Arc<Mesh> {
+        let centre = (min + max) * 0.5;
+        let corner = |i: usize| {
+            vector(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+
+        // The four corners of each side, in order around it
+        let sides = [
+            [0, 2, 6, 4],
+            [1, 3, 7, 5],
+            [0, 1, 5, 4],
+            [2, 3, 7, 6],
+            [0, 1, 3, 2],
+            [4, 5, 7, 6],
+        ];
+
+        let triangles: Vec<[Vector3d; 3]> = sides
+            .iter()
+            .flat_map(|side| {
+                let [a, b, c, d] = side.map(corner);
+                [[a, b, c], [a, c, d]]
+            })
+            .map(|[a, b, c]| {
+                if (b - a).cross(&(c - a)).dot(&(a - centre)) < 0.0 {
+                    [a, c, b]
+                } else {
+                    [a, b, c]
+                }
+            })
+            .collect();
+
+        Arc::new(Mesh::from_triangles(&triangles))
+    }
+
+    #[test]
+    fn test_difference_shades_the_hole_inside_out_in_the_cut_solids_material() {
+        let block = CsgShape::new(
+            CsgOperation::Difference,
+            solid(cuboid(vector(-1.0, -1.0, -1.0), vector(1.0, 1.0, 1.0)), 1),
+            solid(sphere(vector(0.0, 0.0, 0.0), 0.5), 2),
+        );
+        // Nesting it keeps the materials of the solids the surfaces came from
+        let nested = CsgShape::new(
+            CsgOperation::Union,
+            solid(Shape::Csg(block.clone()), 5),
+            solid(sphere(vector(10.0, 10.0, 10.0), 1.0), 3),
+        );
+
+        let through = ray(vector(-5.0, 0.0, 0.0), vector(1.0, 0.0, 0.0));
+        assert_eq!(block.intervals(&through), [(4.0, 4.5), (5.5, 6.0)]);
+
+        for csg in [block.clone(), nested] {
+            let shape = Shape::Csg(csg.clone());
+            let surfaces: Vec<(f64, u32)> = shape
+                .intersect_all_solids(&through)
+                .into_iter()
+                .map(|(t, u, v, solid)| {
+                    let hit = SurfaceHit {
+                        ray: &through,
+                        t,
+                        u,
+                        v,
+                        solid,
+                    };
+                    (shape.normal_at(&hit).x, csg.material_at(&hit))
+                })
+                .collect();
+
+            // Out of the block's side, into the hole, out of the hole and out of the far side
+            assert_eq!(surfaces, [(-1.0, 1), (1.0, 2), (-1.0, 2), (1.0, 1)]);
+        }
+
+        // Missing the hole only goes through the block
+        let past = ray(vector(0.7, -5.0, 0.0), vector(0.0, 2.0, 0.0));
+        assert_eq!(block.intervals(&past), [(2.0, 3.0)]);
+
+        // Rays starting in the hole or in the block
+        let from_hole = ray(vector(0.0, 0.0, 0.0), vector(1.0, 0.0, 0.0));
+        assert_eq!(block.intervals(&from_hole), [(0.5, 1.0)]);
+        let from_block = ray(vector(0.75, 0.0, 0.0), vector(1.0, 0.0, 0.0));
+        assert_eq!(block.intervals(&from_block), [(0.0, 0.25)]);
+    }
+
+    #[test]
+    fn test_hits_keep_the_solid_they_are_on() {
+        // A box mesh in material 0 with a knob on one side and a hole through the middle,
+        // nested three deep
+        let knobbed = CsgShape::new(
+            CsgOperation::Union,
+            Solid::mesh(cuboid_mesh(vector(-1.0, -1.0, -1.0), vector(1.0, 1.0, 1.0))),
+            solid(sphere(vector(2.0, 0.0, 0.0), 0.5), 3),
+        );
+        let holed = CsgShape::new(
+            CsgOperation::Difference,
+            solid(Shape::Csg(knobbed), 5),
+            solid(sphere(vector(0.0, 0.0, 0.0), 0.5), 2),
+        );
+        let shape = Shape::Csg(CsgShape::new(
+            CsgOperation::Union,
+            solid(sphere(vector(0.0, 10.0, 0.0), 1.0), 4),
+            solid(Shape::Csg(holed), 6),
+        ));
+        let Shape::Csg(csg) = &shape else {
+            unreachable!()
+        };
+
+        let through = ray(vector(-5.0, 0.0, 0.0), vector(1.0, 0.0, 0.0));
+        let surfaces: Vec<(f64, f64, u32)> = through
+            .intersect_all_with_shape(&shape, 0)
+            .iter()
+            .map(|hit| {
+                let hit = SurfaceHit::from_intersection(&through, hit);
+                let point = shape.point_at(&hit).0;
+
+                assert!((point - (through.origin + through.direction * hit.t)).length() < 1e-6);
+                (
+                    hit.t,
+                    shape.normal_at(&hit).x.round(),
+                    csg.material_at(&hit),
+                )
+            })
+            .collect();
+
+        // Into the box, into and out of the hole, out of the box, into the knob and out of that.
+        // The box's triangles have no normals of their own, so only the spheres' are checked.
+        assert_eq!(
+            surfaces,
+            [
+                (4.0, 0.0, 0),
+                (4.5, 1.0, 2),
+                (5.5, -1.0, 2),
+                (6.0, 0.0, 0),
+                (6.5, -1.0, 3),
+                (7.5, 1.0, 3)
+            ]
+        );
+
+        // `b` at the top level, then `a` of the hole's difference and `a` of the knob's union
+        let nearest = through.intersect_with_shape(&shape, 0).unwrap();
+        assert_eq!(nearest.solid.path, 0b001);
+        assert!(!nearest.solid.flipped);
+    }
+
+    #[test]
+    fn test_intervals_agree_with_inside_tests() {
+        let mut sampler = Sampler::new(50);
+
+        let (ball, ball_radius) = (vector(0.5, 0.0, 0.0), 1.4);
+        let (hole, hole_radius) = (vector(-0.5, 0.5, 0.3), 0.8);
+        let (box_min, box_max) = (vector(-1.5, -1.0, -1.0), vector(0.5, 1.0, 1.2));
+        let (clip_min, clip_max) = (vector(-2.0, -0.8, -2.0), vector(2.0, 2.0, 0.9));
+
+        let inside_box = |p: Vector3d, min: Vector3d, max: Vector3d| {
+            p.x > min.x && p.x < max.x && p.y > min.y && p.y < max.y && p.z > min.z && p.z < max.z
+        };
+        let inside = |p: Vector3d| {
+            let body = (p - ball).length() < ball_radius || inside_box(p, box_min, box_max);
+            body && (p - hole).length() > hole_radius && inside_box(p, clip_min, clip_max)
+        };
+
+        // ((ball ∪ box) − hole) ∩ clip, with the box and clip as either shapes or meshes
+        let tree = |box_solid: Solid, clip_solid: Solid| {
+            let body = CsgShape::new(
+                CsgOperation::Union,
+                solid(sphere(ball, ball_radius), 0),
+                box_solid,
+            );
+            let carved = CsgShape::new(
+                CsgOperation::Difference,
+                solid(Shape::Csg(body), 0),
+                solid(sphere(hole, hole_radius), 0),
+            );
+
+            CsgShape::new(
+                CsgOperation::Intersection,
+                solid(Shape::Csg(carved), 0),
+                clip_solid,
+            )
+        };
+        let shapes = tree(
+            solid(cuboid(box_min, box_max), 0),
+            solid(cuboid(clip_min, clip_max), 0),
+        );
+        let meshes = tree(
+            Solid::mesh(cuboid_mesh(box_min, box_max)),
+            Solid::mesh(cuboid_mesh(clip_min, clip_max)),
+        );
+
+        let mut hits = 0;
+
+        for _ in 0..1000 {
+            let origin = vector(
+                sampler.next_f64() * 8.0 - 4.0,
+                sampler.next_f64() * 8.0 - 4.0,
+                sampler.next_f64() * 8.0 - 4.0,
+            );
+            let target = vector(
+                sampler.next_f64() * 3.0 - 1.5,
+                sampler.next_f64() * 3.0 - 1.5,
+                sampler.next_f64() * 3.0 - 1.5,
+            );
+            let ray = ray(origin, target - origin);
+            let at = |t: f64| ray.origin + ray.direction * t;
+
+            let intervals = shapes.intervals(&ray);
+            if !intervals.is_empty() {
+                hits += 1;
+            }
+
+            // Inside in the middle of every interval, outside in every gap between them
+            let mut previous_exit = 0.0;
+            for &(entry, exit) in &intervals {
+                assert!(entry >= previous_exit && exit > entry);
+                assert!(inside(at((entry + exit) * 0.5)));
+                if entry > 0.0 {
+                    assert!(!inside(at((previous_exit + entry) * 0.5)));
+                }
+                previous_exit = exit;
+            }
+            assert!(!inside(at(previous_exit + 100.0)));
+
+            // The meshes' corners are stored at `Float` precision
+            let tolerance = f64::max(1e-9, widen(Float::EPSILON) * 100.0);
+            let mesh_intervals = meshes.intervals(&ray);
+            assert_eq!(mesh_intervals.len(), intervals.len());
+            for (a, b) in intervals.iter().zip(&mesh_intervals) {
+                assert!((a.0 - b.0).abs() < tolerance && (a.1 - b.1).abs() < tolerance);
+            }
+        }
+
+        assert!(hits > 300, "only {hits} rays hit the CSG shape");
+    }
+}
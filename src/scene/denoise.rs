@@ -0,0 +1,269 @@
+use rayon::prelude::*;
+
+use super::{
+    aov::{AovBuffers, SurfaceSample},
+    engine::Vector3d,
+};
+
+/// B3 spline weights used by every pass of the à-trous filter
+static KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Most passes the filter runs, whatever `DenoiseOptions::iterations` asks for. The last one
+/// already looks 32768 pixels away, past the edges of any image this renders.
+pub const MAX_ITERATIONS: u32 = 16;
+
+/// Settings for the edge-avoiding à-trous wavelet filter.
+/// Each sigma controls how quickly a neighbour's weight falls off as it becomes
+/// less similar to the pixel being filtered, smaller values keep edges sharper.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DenoiseOptions {
+    /// Number of passes, each one doubles the filter's reach (5 passes covers 61x61 pixels).
+    /// Anything over `MAX_ITERATIONS` is treated as `MAX_ITERATIONS`.
+    pub iterations: u32,
+    /// Difference in colour, in 0-255 units
+    pub colour_sigma: f64,
+    /// Difference between normals, as the length of their difference
+    pub normal_sigma: f64,
+    /// Relative difference in depth
+    pub depth_sigma: f64,
+    /// Difference in texture colour, in 0-255 units
+    pub albedo_sigma: f64,
+}
+
+impl Default for DenoiseOptions {
+    fn default() -> Self {
+        DenoiseOptions {
+            iterations: 5,
+            colour_sigma: 40.0,
+            normal_sigma: 0.3,
+            depth_sigma: 0.05,
+            albedo_sigma: 20.0,
+        }
+    }
+}
+
+/// Smooth out sampling noise in `colours` without blurring across geometric or texture edges.
+/// `colours` is laid out the same way as the feature buffers in `aovs`, which are used to
+/// decide which neighbouring pixels show the same surface.
+pub fn denoise(colours: &[Vector3d], aovs: &AovBuffers, options: &DenoiseOptions) -> Vec<Vector3d> {
+    let mut current = colours.to_vec();
+
+    for iteration in 0..options.iterations.min(MAX_ITERATIONS) {
+        let scale = 2f64.powi(iteration as i32);
+        let step = scale as i64;
+        // Later passes compare against already smoothed colours, so they can be stricter
+        let colour_sigma = options.colour_sigma / scale;
+
+        current = (0..aovs.height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                let current = &current;
+
+                (0..aovs.width)
+                    .map(move |x| filter_pixel(current, aovs, options, colour_sigma, step, x, y))
+            })
+            .collect();
+    }
+
+    current
+}
+
+fn filter_pixel(
+    colours: &[Vector3d],
+    aovs: &AovBuffers,
+    options: &DenoiseOptions,
+    colour_sigma: f64,
+    step: i64,
+    x: usize,
+    y: usize,
+) -> Vector3d {
+    let index = y * aovs.width + x;
+    let colour = colours[index];
+    let surface = &aovs.samples[index];
+
+    let mut sum = Vector3d {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+    let mut weight_sum = 0.0;
+
+    for (ky, kernel_y) in KERNEL.iter().enumerate() {
+        let qy = y as i64 + (ky as i64 - 2) * step;
+
+        if qy < 0 || qy >= aovs.height as i64 {
+            continue;
+        }
+
+        for (kx, kernel_x) in KERNEL.iter().enumerate() {
+            let qx = x as i64 + (kx as i64 - 2) * step;
+
+            if qx < 0 || qx >= aovs.width as i64 {
+                continue;
+            }
+
+            let neighbour_index = qy as usize * aovs.width + qx as usize;
+            let neighbour_colour = colours[neighbour_index];
+
+            let colour_difference = colour - neighbour_colour;
+            let colour_weight =
+                (-colour_difference.dot(&colour_difference) / colour_sigma.powi(2)).exp();
+
+            let feature_weight = feature_weight(surface, &aovs.samples[neighbour_index], options);
+
+            let weight = kernel_x * kernel_y * colour_weight * feature_weight;
+
+            sum += neighbour_colour * weight;
+            weight_sum += weight;
+        }
+    }
+
+    // The centre pixel always has a weight of at least KERNEL[2]^2 so this can't divide by zero
+    sum / weight_sum
+}
+
+/// How alike two pixels' surfaces are, from 1.0 for identical down to 0.0
+fn feature_weight(
+    surface: &Option<SurfaceSample>,
+    neighbour: &Option<SurfaceSample>,
+    options: &DenoiseOptions,
+) -> f64 {
+    match (surface, neighbour) {
+        (Some(s), Some(n)) => {
+            let normal_difference = s.normal - n.normal;
+            let normal_weight =
+                (-normal_difference.dot(&normal_difference) / options.normal_sigma.powi(2)).exp();
+
+            let depth_difference = (s.depth - n.depth).abs() / s.depth.max(f64::EPSILON);
+            let depth_weight = (-depth_difference / options.depth_sigma).exp();
+
+            let albedo: Vector3d = s.albedo.into();
+            let albedo_difference = albedo - n.albedo.into();
+            let albedo_weight =
+                (-albedo_difference.dot(&albedo_difference) / options.albedo_sigma.powi(2)).exp();
+
+            normal_weight * depth_weight * albedo_weight
+        }
+        // Both pixels look out into the void
+        (None, None) => 1.0,
+        // Never blur an object into the background or vice versa
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::entities::Color;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    fn surface(normal: Vector3d, depth: f64) -> Option<SurfaceSample> {
+        Some(SurfaceSample {
+            depth,
+            normal,
+            albedo: Color {
+                r: 200,
+                g: 200,
+                b: 200,
+            },
+            uv: (0.0, 0.0),
+            triangle_index: 0,
+            material_id: 0,
+        })
+    }
+
+    /// Grey with a repeatable pattern of up to +-16 in each pixel
+    fn noisy(base: f64, x: usize, y: usize) -> Vector3d {
+        let noise = ((x * 7 + y * 13) % 5) as f64 * 8.0 - 16.0;
+        vector(base + noise, base + noise, base + noise)
+    }
+
+    fn variance(colours: &[Vector3d]) -> f64 {
+        let mean = colours.iter().map(|c| c.x).sum::<f64>() / colours.len() as f64;
+        colours.iter().map(|c| (c.x - mean).powi(2)).sum::<f64>() / colours.len() as f64
+    }
+
+    #[test]
+    fn test_flat_noisy_region_is_smoothed() {
+        let size = 16;
+        let mut aovs = AovBuffers::new(size, size);
+        aovs.samples = vec![surface(vector(0.0, 0.0, -1.0), 3.0); size * size];
+
+        let colours: Vec<Vector3d> = (0..size * size)
+            .map(|i| noisy(128.0, i % size, i / size))
+            .collect();
+
+        let denoised = denoise(&colours, &aovs, &DenoiseOptions::default());
+
+        assert!(variance(&denoised) < variance(&colours) / 10.0);
+
+        let mean = denoised.iter().map(|c| c.x).sum::<f64>() / denoised.len() as f64;
+        assert!((mean - 128.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn test_depth_and_normal_edges_are_kept() {
+        let size = 16;
+        let left = surface(vector(0.0, 0.0, -1.0), 2.0);
+        let right = surface(vector(1.0, 0.0, 0.0), 5.0);
+
+        // Left half darker than the right, close enough in colour alone to be blurred together
+        let colours: Vec<Vector3d> = (0..size * size)
+            .map(|i| {
+                let (x, y) = (i % size, i / size);
+                noisy(if x < size / 2 { 100.0 } else { 130.0 }, x, y)
+            })
+            .collect();
+
+        let mut aovs = AovBuffers::new(size, size);
+        aovs.samples = (0..size * size)
+            .map(|i| if i % size < size / 2 { left } else { right })
+            .collect();
+
+        let denoised = denoise(&colours, &aovs, &DenoiseOptions::default());
+
+        for y in 0..size {
+            let last_left = denoised[y * size + size / 2 - 1];
+            let first_right = denoised[y * size + size / 2];
+            assert!((last_left.x - 100.0).abs() < 5.0, "{}", last_left.x);
+            assert!((first_right.x - 130.0).abs() < 5.0, "{}", first_right.x);
+        }
+
+        // Without the edge in the passes the two halves run into each other
+        aovs.samples = vec![left; size * size];
+        let blurred = denoise(&colours, &aovs, &DenoiseOptions::default());
+        let row = size / 2 * size;
+        let step = blurred[row + size / 2].x - blurred[row + size / 2 - 1].x;
+        let kept_step = denoised[row + size / 2].x - denoised[row + size / 2 - 1].x;
+        assert!(step < kept_step - 5.0, "{} {}", step, kept_step);
+    }
+
+    #[test]
+    fn test_passes_past_the_edges_of_the_image_change_nothing() {
+        let size = 8;
+        let mut aovs = AovBuffers::new(size, size);
+        aovs.samples = vec![surface(vector(0.0, 0.0, -1.0), 3.0); size * size];
+
+        let colours: Vec<Vector3d> = (0..size * size)
+            .map(|i| noisy(128.0, i % size, i / size))
+            .collect();
+
+        // From the fourth pass on every neighbour is off the 8 by 8 image
+        let options = |iterations| DenoiseOptions {
+            iterations,
+            ..DenoiseOptions::default()
+        };
+        let three_passes = denoise(&colours, &aovs, &options(3));
+
+        for iterations in [MAX_ITERATIONS, 40, u32::MAX] {
+            let denoised = denoise(&colours, &aovs, &options(iterations));
+
+            for (a, b) in denoised.iter().zip(&three_passes) {
+                assert!((*a - *b).length() < 1e-9);
+            }
+        }
+    }
+}
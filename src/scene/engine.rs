@@ -1,4 +1,13 @@
-use super::{entities::Color, raytracer::RayTracer};
+use crate::collision::{packet::PACKET_WIDTH, ray::Ray};
+
+use super::{
+    aov::{AovBuffers, SurfaceSample},
+    denoise::{denoise, DenoiseOptions},
+    entities::Color,
+    raytracer::RayTracer,
+    sampling::Sampler,
+};
+use image::{ImageResult, Rgb, RgbImage};
 use minifb::{Window, WindowOptions};
 use rayon::prelude::*;
 use std::{
@@ -83,11 +92,11 @@ impl Div<f64> for Vector3d {
 
 impl Vector3d {
     pub fn dot(&self, other: &Self) -> f64 {
-        return (self.x * other.x) + (self.y * other.y) + (self.z * other.z);
+        (self.x * other.x) + (self.y * other.y) + (self.z * other.z)
     }
 
     pub fn length(&self) -> f64 {
-        return f64::sqrt(self.x.powi(2) + self.y.powi(2) + self.z.powi(2));
+        f64::sqrt(self.x.powi(2) + self.y.powi(2) + self.z.powi(2))
     }
 
     pub fn cross(&self, other: &Self) -> Self {
@@ -99,26 +108,74 @@ impl Vector3d {
     }
 
     pub fn normalised(&self) -> Self {
-        return self.clone() / self.length();
+        *self / self.length()
+    }
+}
+
+/// Bound on the relative rounding error after `n` floating point operations,
+/// as in Physically Based Rendering's error analysis
+pub fn gamma(n: u32) -> f64 {
+    let error = n as f64 * f64::EPSILON * 0.5;
+    error / (1.0 - error)
+}
+
+pub(crate) fn abs(v: Vector3d) -> Vector3d {
+    Vector3d {
+        x: v.x.abs(),
+        y: v.y.abs(),
+        z: v.z.abs(),
     }
 }
 
-struct Viewport {
-    width: f64,
-    height: f64,
-    distance: f64,
+/// Precision geometry is stored at. Building with `--features f32` halves the memory taken
+/// by vertices and acceleration structure bounds, all the maths is still done in f64.
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+#[cfg(feature = "f32")]
+pub type Float = f32;
+
+/// A stored value as an f64 to do maths with, which is always exact
+// The cast does nothing unless the f32 feature is on
+#[allow(clippy::unnecessary_cast)]
+pub fn widen(value: Float) -> f64 {
+    value as f64
 }
 
-impl Viewport {
-    pub fn default() -> Self {
-        Viewport {
-            width: 1.0,
-            height: 1.0,
-            distance: 1.0,
+/// A `Vector3d` stored at `Float` precision, for the big per-vertex buffers
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CompactVector {
+    pub x: Float,
+    pub y: Float,
+    pub z: Float,
+}
+
+impl From<Vector3d> for CompactVector {
+    fn from(v: Vector3d) -> Self {
+        CompactVector {
+            x: v.x as Float,
+            y: v.y as Float,
+            z: v.z as Float,
         }
     }
 }
 
+impl From<CompactVector> for Vector3d {
+    fn from(v: CompactVector) -> Self {
+        Vector3d {
+            x: widen(v.x),
+            y: widen(v.y),
+            z: widen(v.z),
+        }
+    }
+}
+
+/// Offsets (in pixels) of the sub-pixel rays traced for every pixel, one per packet lane
+static SUB_PIXEL_OFFSETS: [(f64, f64); PACKET_WIDTH] =
+    [(0.0, 0.0), (0.5, 0.0), (0.0, 0.5), (0.5, 0.5)];
+
+/// Canvas coordinates, unclamped colour and (when capturing passes) surface sample of a drawn pixel
+pub(super) type RenderedPixel = (i32, i32, Vector3d, Option<SurfaceSample>);
+
 /// A very simple canvas that can be drawn to and rendered
 pub struct Canvas {
     pub width: usize,
@@ -144,6 +201,14 @@ impl Canvas {
     /// Put the color at the coordinate given by (x, y) using normal coordinates.
     /// i.e (0,0) is the pixel in the centre of the screen.
     pub fn put_pixel(&mut self, x: i32, y: i32, color: u32) {
+        if let Some(index) = self.buffer_index(x, y) {
+            self.buffer[index] = color;
+        }
+    }
+
+    /// Index into the pixel buffer for the coordinate given by (x, y) using normal coordinates,
+    /// or `None` if the coordinate is off the canvas.
+    pub fn buffer_index(&self, x: i32, y: i32) -> Option<usize> {
         let new_x = x + (self.width as i32) / 2;
 
         // Minus from self.height as y=0 is the top of the screen, if we don't the image will be upside down.
@@ -151,10 +216,21 @@ impl Canvas {
 
         if new_x < 0 || new_x >= self.width as i32 || new_y < 0 || new_y >= self.height as i32 {
             // Coordinates are out of bounds (will crash if we try to use these as buffer coords)
-            return;
+            return None;
         }
 
-        self.buffer[new_y as usize * self.width + new_x as usize] = color;
+        Some(new_y as usize * self.width + new_x as usize)
+    }
+
+    /// Save the current buffer as an image, the format is picked from the file extension.
+    pub fn save_image(&self, path: &str) -> ImageResult<()> {
+        let image = RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let pixel = self.buffer[y as usize * self.width + x as usize];
+
+            Rgb([(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8])
+        });
+
+        image.save(path)
     }
 
     /// Draw the current buffer to the screen.
@@ -166,18 +242,95 @@ impl Canvas {
     }
 }
 
+/// Trace every ray through the pixel at (x, y) in normal coordinates, see `Canvas::put_pixel`,
+/// on a canvas `width` by `height` pixels
+pub(super) fn render_pixel(
+    rt: &RayTracer,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    capture_aovs: bool,
+) -> RenderedPixel {
+    let aspect_ratio = width as f64 / height as f64;
+    let mut sampler = Sampler::for_pixel(x, y);
+    let samples = rt.camera.sub_pixel_sample_count() as usize;
+    let mut rays = Vec::with_capacity(SUB_PIXEL_OFFSETS.len() * samples);
+
+    // We are going to split the (x, y) pair into corners and render a ray for each corner,
+    // this makes the end render result look less jagged (a form of anti aliasing).
+    // With a thin lens camera or an open shutter each corner is also sampled at several
+    // points on the lens and moments in time.
+    for (x_offset, y_offset) in SUB_PIXEL_OFFSETS {
+        let screen_x = (x as f64 + x_offset) / width as f64;
+        let screen_y = (y as f64 + y_offset) / height as f64;
+
+        for _ in 0..samples {
+            rays.push(
+                rt.camera
+                    .primary_ray(screen_x, screen_y, aspect_ratio, &mut sampler),
+            );
+        }
+    }
+
+    // The four corners' rays for the same lens and time sample are nearly
+    // parallel, so they are traced together as a packet
+    let mut colour = Vector3d {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+    let mut hits: Vec<SurfaceSample> = vec![];
+
+    for sample in 0..samples {
+        let packet: [Option<&Ray>; PACKET_WIDTH] =
+            std::array::from_fn(|corner| rays[corner * samples + sample].as_ref());
+
+        for (ray_colour, hit) in rt
+            .get_ray_colours_and_surfaces(packet, capture_aovs)
+            .into_iter()
+            .flatten()
+        {
+            colour += ray_colour;
+            hits.extend(hit);
+        }
+    }
+
+    // The passes are averaged over every ray, like the colour
+    let surface_sample = if capture_aovs {
+        SurfaceSample::average(&hits)
+    } else {
+        None
+    };
+
+    // Rays outside the projection count as black
+    (x, y, colour / rays.len() as f64, surface_sample)
+}
+
 /// The entrypoint class for the engine, encapsulates all entities and main classes needed to raycast a scene.
 /// The internal canvas is where the actual pixels will reside after drawing the scene.
 pub struct Scene {
-    viewport: Viewport,
     pub canvas: Canvas,
+    /// Every pixel's colour as it was drawn, laid out like the canvas, before being clamped and
+    /// rounded to 8 bits for the canvas
+    pub colours: Vec<Vector3d>,
+    /// Set this to capture extra passes (depth, normals etc.) while drawing
+    pub aovs: Option<AovBuffers>,
 }
 
 impl Scene {
     pub fn new(width: usize, height: usize) -> Scene {
         Scene {
-            viewport: Viewport::default(),
             canvas: Canvas::new(width, height),
+            colours: vec![
+                Vector3d {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                };
+                width * height
+            ],
+            aovs: None,
         }
     }
 
@@ -185,10 +338,18 @@ impl Scene {
     /// You still need to update the canvas for it to show the changes.
     pub fn draw_scene(&mut self, rt: RayTracer) {
         let rt_arc = Arc::new(rt);
+        let capture_aovs = self.aovs.is_some();
+
+        if let Some(aovs) = &mut self.aovs {
+            aovs.material_names = rt_arc
+                .scene_data
+                .material_map
+                .materials
+                .iter()
+                .map(|m| m.name.clone())
+                .collect();
+        }
 
-        let x_scale = self.viewport.width / self.canvas.width as f64;
-        let y_scale = self.viewport.height / self.canvas.height as f64;
-        let z_value = self.viewport.distance;
         let height = self.canvas.height as i32;
         let width = self.canvas.width as i32;
 
@@ -198,54 +359,29 @@ impl Scene {
         for chunk_start in (-(height / 2)..(height / 2)).step_by(chunk_size as usize) {
             let chunk_end = (chunk_start + chunk_size).min(height / 2);
 
-            let rows: Vec<Vec<(i32, i32, Color)>> = (chunk_start..chunk_end)
+            let rows: Vec<Vec<RenderedPixel>> = (chunk_start..chunk_end)
                 .into_par_iter()
                 .map(|y| {
                     let rt_ref = &rt_arc;
                     (-(width / 2)..(width / 2))
-                        .map(|x| {
-                            let direction1 = Vector3d {
-                                x: x as f64 * x_scale,
-                                y: y as f64 * y_scale,
-                                z: z_value,
-                            };
-
-                            // We are going to split the (x, y) pair into corners and render a ray for each corner,
-                            // this makes the end render result look less jagged (a form of anti aliasing)
-                            let color1 = rt_ref.get_ray_colour(rt_ref.origin, direction1);
-
-                            let direction2 = Vector3d {
-                                x: (x as f64 + 0.5) * x_scale,
-                                y: y as f64 * y_scale,
-                                z: z_value,
-                            };
-                            let color2 = rt_ref.get_ray_colour(rt_ref.origin, direction2);
-
-                            let direction3 = Vector3d {
-                                x: x as f64 * x_scale,
-                                y: (y as f64 + 0.5) * y_scale,
-                                z: z_value,
-                            };
-                            let color3 = rt_ref.get_ray_colour(rt_ref.origin, direction3);
-
-                            let direction4 = Vector3d {
-                                x: (x as f64 + 0.5) * x_scale,
-                                y: (y as f64 + 0.5) * y_scale,
-                                z: z_value,
-                            };
-                            let color4 = rt_ref.get_ray_colour(rt_ref.origin, direction4);
-
-                            let final_color = Color::mix(&[color1, color2, color3, color4]);
-                            (x, y, final_color)
-                        })
+                        .map(|x| render_pixel(rt_ref, x, y, width, height, capture_aovs))
                         .collect()
                 })
                 .collect();
 
             // Apply all pixels from this chunk to canvas
             for row in rows {
-                for (x, y, col) in row {
-                    self.canvas.put_pixel(x, y, col.into());
+                for (x, y, colour, surface_sample) in row {
+                    self.canvas
+                        .put_pixel(x, y, Color::from_radiance(colour).into());
+
+                    if let Some(index) = self.canvas.buffer_index(x, y) {
+                        self.colours[index] = colour;
+
+                        if let Some(aovs) = &mut self.aovs {
+                            aovs.samples[index] = surface_sample;
+                        }
+                    }
                 }
             }
 
@@ -253,4 +389,19 @@ impl Scene {
             self.canvas.update();
         }
     }
+
+    /// Run the denoiser over the drawn colours, before they were clamped and rounded for the
+    /// canvas, guided by the passes captured while drawing. The canvas then shows the result.
+    /// Does nothing unless `aovs` was set before the scene was drawn.
+    pub fn denoise(&mut self, options: &DenoiseOptions) {
+        let Some(aovs) = &self.aovs else {
+            return;
+        };
+
+        self.colours = denoise(&self.colours, aovs, options);
+
+        for (pixel, colour) in self.canvas.buffer.iter_mut().zip(&self.colours) {
+            *pixel = Color::from_radiance(*colour).into();
+        }
+    }
 }
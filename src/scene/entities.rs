@@ -1,7 +1,11 @@
-use std::{ops::Mul, sync::Arc};
+use std::ops::Mul;
 
-use super::{engine::Vector3d, material::Material};
+use super::{
+    engine::Vector3d,
+    transform::{Quaternion, Transform},
+};
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Light {
     Ambient { intensity: f64 },
     Point { intensity: f64, position: Vector3d },
@@ -19,33 +23,40 @@ impl Mul<f64> for Color {
     type Output = Self;
 
     fn mul(self, rhs: f64) -> Self::Output {
-        return {
-            Color {
-                r: (self.r as f64 * rhs) as u8,
-                g: (self.g as f64 * rhs) as u8,
-                b: (self.b as f64 * rhs) as u8,
-            }
-        };
+        Color {
+            r: (self.r as f64 * rhs) as u8,
+            g: (self.g as f64 * rhs) as u8,
+            b: (self.b as f64 * rhs) as u8,
+        }
     }
 }
 
-impl Into<u32> for Color {
-    fn into(self) -> u32 {
-        return self.b as u32 + ((self.g as u32) << 8) + ((self.r as u32) << 16);
+impl From<Color> for u32 {
+    fn from(val: Color) -> Self {
+        val.b as u32 + ((val.g as u32) << 8) + ((val.r as u32) << 16)
     }
 }
 
-impl Into<Vector3d> for Color {
-    fn into(self) -> Vector3d {
+impl From<Color> for Vector3d {
+    fn from(val: Color) -> Self {
         Vector3d {
-            x: self.r as f64,
-            y: self.g as f64,
-            z: self.b as f64,
+            x: val.r as f64,
+            y: val.g as f64,
+            z: val.b as f64,
         }
     }
 }
 
 impl Color {
+    /// A colour in 0-255 units clamped into that range
+    pub fn from_radiance(radiance: Vector3d) -> Color {
+        Color {
+            r: radiance.x.clamp(0.0, 255.0) as u8,
+            g: radiance.y.clamp(0.0, 255.0) as u8,
+            b: radiance.z.clamp(0.0, 255.0) as u8,
+        }
+    }
+
     pub fn mix(colors: &[Color]) -> Color {
         let mut r = 0;
         let mut g = 0;
@@ -69,18 +80,90 @@ impl Color {
     }
 }
 
-#[derive(PartialEq, Debug, Clone)]
-pub struct Triangle {
-    pub v1: Vector3d,
-    pub v2: Vector3d,
-    pub v3: Vector3d,
-    pub v1_tex_coords: Vector3d,
-    pub v2_tex_coords: Vector3d,
-    pub v3_tex_coords: Vector3d,
-    pub v1_normal_coords: Vector3d,
-    pub v2_normal_coords: Vector3d,
-    pub v3_normal_coords: Vector3d,
-    pub material: Arc<Material>,
+/// Translation, rotation and scale, as `Transform::to_parts` splits a transform
+type Parts = (Vector3d, Quaternion, Vector3d);
+
+/// Movement over the frame, from the `start` transform at time 0.0 to `end` at 1.0.
+/// The shutter is open for some part of that, see `Camera::shutter_open`.
+/// In between, translation and scale change linearly and rotation turns at a constant speed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Motion {
+    start: Transform,
+    end: Transform,
+    /// `start` and `end` split up once here rather than every time a moving triangle is tested
+    start_parts: Parts,
+    end_parts: Parts,
+}
+
+impl Motion {
+    pub fn new(start: Transform, end: Transform) -> Motion {
+        Motion {
+            start,
+            end,
+            start_parts: start.to_parts(),
+            end_parts: end.to_parts(),
+        }
+    }
+
+    /// Moving by `offset` over the frame
+    pub fn translation(offset: Vector3d) -> Motion {
+        Motion::new(Transform::identity(), Transform::translation(offset))
+    }
+
+    pub fn start(&self) -> Transform {
+        self.start
+    }
+
+    pub fn end(&self) -> Transform {
+        self.end
+    }
+
+    /// The translation, rotation and scale at the start and at the end
+    pub fn parts(&self) -> [Parts; 2] {
+        [self.start_parts, self.end_parts]
+    }
+
+    /// The transform at `time`. Transforms are split into their translation, rotation and
+    /// scale to be blended, so any shear in them is lost.
+    pub fn transform_at(&self, time: f64) -> Transform {
+        let (start_translation, start_rotation, start_scale) = self.start_parts;
+        let (end_translation, end_rotation, end_scale) = self.end_parts;
+        let lerp = |a: Vector3d, b: Vector3d| a + (b - a) * time;
+
+        Transform::from_parts(
+            lerp(start_translation, end_translation),
+            start_rotation.slerp(&end_rotation, time),
+            lerp(start_scale, end_scale),
+        )
+    }
+
+    /// The same motion for geometry that has been moved by `transform`
+    pub fn placed(&self, transform: &Transform) -> Motion {
+        let unscaled = Vector3d {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let place = |step: &Transform, parts: Parts| match parts {
+            // Translations are kept as translations rather than picking up the transform's rounding
+            (offset, rotation, scale)
+                if rotation == Quaternion::identity() && scale == unscaled =>
+            {
+                Transform::translation(transform.transform_vector(offset))
+            }
+            _ => *transform * *step * transform.inverse(),
+        };
+
+        Motion::new(
+            place(&self.start, self.start_parts),
+            place(&self.end, self.end_parts),
+        )
+    }
+
+    /// Whether anything turns, otherwise every point moves in a straight line
+    pub fn rotates(&self) -> bool {
+        self.start_parts.1 != self.end_parts.1
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -0,0 +1,491 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use super::{
+    camera::Camera,
+    engine::Vector3d,
+    entities::Light,
+    mesh::{Face, Mesh},
+    transform::Transform,
+};
+
+/// Index of the root node, which every other node is under
+pub static ROOT_NODE: usize = 0;
+
+/// Some of a mesh's triangles, held by a node
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshPart {
+    pub mesh: Arc<Mesh>,
+    /// Indices of the triangles in `mesh`
+    pub triangles: Vec<usize>,
+}
+
+/// A named part of the scene. Nodes are placed relative to their parent,
+/// so moving, hiding or re-materialing a node does the same to everything under it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneNode {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// Places the node relative to its parent
+    pub transform: Transform,
+    pub meshes: Vec<MeshPart>,
+    pub lights: Vec<Light>,
+    pub cameras: Vec<Camera>,
+    /// Hidden nodes and everything under them are left out of the scene
+    pub visible: bool,
+    /// Id of a material used for every triangle under this node instead of their own,
+    /// the nearest override up the tree wins
+    pub material: Option<u32>,
+}
+
+/// A tree of named nodes, filled in from a model's `o` (object) and `g` (group) statements.
+/// Editing the graph doesn't change what's rendered until the scene is rebuilt from it,
+/// see `SceneData::rebuild_from_graph`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneGraph {
+    /// Every node, `ROOT_NODE` first
+    pub nodes: Vec<SceneNode>,
+}
+
+impl Default for SceneGraph {
+    fn default() -> Self {
+        SceneGraph::new()
+    }
+}
+
+impl SceneGraph {
+    /// A graph with nothing but the root node
+    pub fn new() -> SceneGraph {
+        SceneGraph {
+            nodes: vec![SceneNode {
+                name: String::from("root"),
+                parent: None,
+                children: vec![],
+                transform: Transform::identity(),
+                meshes: vec![],
+                lights: vec![],
+                cameras: vec![],
+                visible: true,
+                material: None,
+            }],
+        }
+    }
+
+    /// Add an empty node under `parent`, returning its index
+    pub fn add_node(&mut self, parent: usize, name: &str) -> usize {
+        let index = self.nodes.len();
+
+        self.nodes.push(SceneNode {
+            name: name.to_string(),
+            parent: Some(parent),
+            children: vec![],
+            transform: Transform::identity(),
+            meshes: vec![],
+            lights: vec![],
+            cameras: vec![],
+            visible: true,
+            material: None,
+        });
+        self.nodes[parent].children.push(index);
+
+        index
+    }
+
+    /// The first child of `parent` with the given name
+    pub fn child(&self, parent: usize, name: &str) -> Option<usize> {
+        self.nodes[parent]
+            .children
+            .iter()
+            .copied()
+            .find(|&child| self.nodes[child].name == name)
+    }
+
+    /// The node at a path of names from the root separated by `/`, e.g. `table/leg`
+    pub fn find(&self, path: &str) -> Option<usize> {
+        path.split('/')
+            .try_fold(ROOT_NODE, |node, name| self.child(node, name))
+    }
+
+    /// Where the node is in the scene, its own transform after all of its ancestors'
+    pub fn world_transform(&self, node: usize) -> Transform {
+        let mut transform = self.nodes[node].transform;
+        let mut parent = self.nodes[node].parent;
+
+        while let Some(index) = parent {
+            transform = self.nodes[index].transform * transform;
+            parent = self.nodes[index].parent;
+        }
+
+        transform
+    }
+
+    /// Whether the node and all of its ancestors are visible
+    pub fn is_visible(&self, node: usize) -> bool {
+        self.ancestry(node).all(|index| self.nodes[index].visible)
+    }
+
+    /// The material override that applies to the node, its own or the nearest ancestor's
+    pub fn material(&self, node: usize) -> Option<u32> {
+        self.ancestry(node)
+            .find_map(|index| self.nodes[index].material)
+    }
+
+    /// Every visible node's lights, moved into the scene
+    pub fn lights(&self) -> Vec<Light> {
+        self.visible_nodes()
+            .into_iter()
+            .flat_map(|(node, transform)| {
+                self.nodes[node]
+                    .lights
+                    .iter()
+                    .map(move |light| match *light {
+                        Light::Point {
+                            intensity,
+                            position,
+                        } => Light::Point {
+                            intensity,
+                            position: transform.transform_point(position),
+                        },
+                        Light::Directional {
+                            intensity,
+                            direction,
+                        } => Light::Directional {
+                            intensity,
+                            direction: transform.transform_vector(direction),
+                        },
+                        ambient => ambient,
+                    })
+            })
+            .collect()
+    }
+
+    /// Every visible node's cameras, moved into the scene. The node's transform moves each
+    /// camera's origin and turns the way it looks along with it.
+    pub fn cameras(&self) -> Vec<Camera> {
+        self.visible_nodes()
+            .into_iter()
+            .flat_map(|(node, transform)| {
+                self.nodes[node].cameras.iter().map(move |camera| Camera {
+                    origin: transform.transform_point(camera.origin),
+                    orientation: transform * camera.orientation,
+                    ..camera.clone()
+                })
+            })
+            .collect()
+    }
+
+    /// One mesh of every visible node's triangles, moved into the scene and with their
+    /// material overrides applied. Nodes come in depth first order, each node's triangles in
+    /// the order its parts list them. Nodes that haven't been moved keep their exact corners.
+    /// A triangle held by several nodes (a face in more than one group) is only built once,
+    /// by the first visible node that holds it.
+    pub fn build_mesh(&self) -> Mesh {
+        let zero = Vector3d {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+
+        let mut positions = vec![];
+        let mut tex_coords = vec![zero];
+        let mut normals = vec![zero];
+        let mut faces = vec![];
+        let mut motions = vec![];
+        let mut built: HashSet<(*const Mesh, usize)> = HashSet::new();
+
+        for (node, transform) in self.visible_nodes() {
+            let material = self.material(node);
+            let moved = transform != Transform::identity();
+
+            for part in &self.nodes[node].meshes {
+                let mesh = &part.mesh;
+
+                // Where each of the part's corners, texture coordinates, normals and motions
+                // ended up in the new buffers, entry 0 of the shared ones is always the zero vector
+                let mut new_positions: HashMap<u32, u32> = HashMap::new();
+                let mut new_tex_coords: HashMap<u32, u32> = HashMap::from([(0, 0)]);
+                let mut new_normals: HashMap<u32, u32> = HashMap::from([(0, 0)]);
+                let mut new_motions: HashMap<u32, u32> = HashMap::new();
+
+                for &triangle in &part.triangles {
+                    if !built.insert((Arc::as_ptr(mesh), triangle)) {
+                        continue;
+                    }
+
+                    let face = &mesh.faces[triangle];
+
+                    let face_positions = face.positions.map(|p| {
+                        *new_positions.entry(p).or_insert_with(|| {
+                            let position = Vector3d::from(mesh.positions[p as usize]);
+                            positions.push(if moved {
+                                transform.transform_point(position)
+                            } else {
+                                position
+                            });
+                            positions.len() as u32 - 1
+                        })
+                    });
+
+                    let face_tex_coords = face.tex_coords.map(|t| {
+                        *new_tex_coords.entry(t).or_insert_with(|| {
+                            tex_coords.push(mesh.tex_coords[t as usize].into());
+                            tex_coords.len() as u32 - 1
+                        })
+                    });
+
+                    let face_normals = face.normals.map(|n| {
+                        *new_normals.entry(n).or_insert_with(|| {
+                            let normal = Vector3d::from(mesh.normals[n as usize]);
+                            normals.push(if moved {
+                                transform.transform_normal(normal).normalised()
+                            } else {
+                                normal
+                            });
+                            normals.len() as u32 - 1
+                        })
+                    });
+
+                    let face_motion = face.motion.map(|m| {
+                        *new_motions.entry(m).or_insert_with(|| {
+                            let motion = mesh.motions[m as usize];
+                            motions.push(if moved {
+                                motion.placed(&transform)
+                            } else {
+                                motion
+                            });
+                            motions.len() as u32 - 1
+                        })
+                    });
+
+                    faces.push(Face {
+                        positions: face_positions,
+                        tex_coords: face_tex_coords,
+                        normals: face_normals,
+                        material: material.unwrap_or(face.material),
+                        motion: face_motion,
+                    });
+                }
+            }
+        }
+
+        Mesh::new(positions, tex_coords, normals, faces, motions)
+    }
+
+    /// The node followed by its parent, grandparent and so on up to the root
+    fn ancestry(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(Some(node), |&index| self.nodes[index].parent)
+    }
+
+    /// Every visible node with where it is in the scene, depth first
+    fn visible_nodes(&self) -> Vec<(usize, Transform)> {
+        let mut found = vec![];
+        let mut stack = vec![(ROOT_NODE, Transform::identity())];
+
+        while let Some((node, parent_transform)) = stack.pop() {
+            if !self.nodes[node].visible {
+                continue;
+            }
+
+            let transform = parent_transform * self.nodes[node].transform;
+            found.push((node, transform));
+
+            // Pushed in reverse so children come out in the order they were added
+            for &child in self.nodes[node].children.iter().rev() {
+                stack.push((child, transform));
+            }
+        }
+
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{Rgb, RgbImage};
+
+    use crate::file_management::utils::parse_obj_file_lines;
+    use crate::scene::{sampling::Sampler, transform::Quaternion};
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    /// Three unit triangles side by side along x
+    fn three_triangles() -> Arc<Mesh> {
+        let triangles: Vec<[Vector3d; 3]> = (0..3)
+            .map(|i| {
+                let x = i as f64 * 2.0;
+                [
+                    vector(x, 0.0, 0.0),
+                    vector(x + 1.0, 0.0, 0.0),
+                    vector(x, 1.0, 0.0),
+                ]
+            })
+            .collect();
+
+        Arc::new(Mesh::from_triangles(&triangles))
+    }
+
+    #[test]
+    fn test_nodes_are_placed_relative_to_their_parents() {
+        let mut graph = SceneGraph::new();
+        let table = graph.add_node(ROOT_NODE, "table");
+        let leg = graph.add_node(table, "leg");
+        let teapot = graph.add_node(ROOT_NODE, "teapot");
+
+        assert_eq!(graph.find("table/leg"), Some(leg));
+        assert_eq!(graph.find("teapot"), Some(teapot));
+        assert_eq!(graph.find("table/teapot"), None);
+
+        graph.nodes[table].transform = Transform::translation(vector(0.0, 0.0, 5.0));
+        graph.nodes[leg].transform = Transform::scaling(vector(2.0, 2.0, 2.0));
+
+        let leg_origin = graph
+            .world_transform(leg)
+            .transform_point(vector(1.0, 0.0, 0.0));
+        assert!((leg_origin - vector(2.0, 0.0, 5.0)).length() < 1e-12);
+
+        graph.nodes[table].material = Some(3);
+        graph.nodes[table].visible = false;
+
+        assert_eq!(graph.material(leg), Some(3));
+        assert_eq!(graph.material(teapot), None);
+        assert!(!graph.is_visible(leg));
+        assert!(graph.is_visible(teapot));
+
+        graph.nodes[leg].lights.push(Light::Point {
+            intensity: 1.0,
+            position: vector(0.0, 1.0, 0.0),
+        });
+        assert!(graph.lights().is_empty());
+
+        graph.nodes[table].visible = true;
+        assert_eq!(
+            graph.lights(),
+            [Light::Point {
+                intensity: 1.0,
+                position: vector(0.0, 2.0, 5.0),
+            }]
+        );
+
+        // A camera on a turned node looks the way the node turns it
+        graph.nodes[teapot].transform = Transform::translation(vector(3.0, 0.0, 0.0))
+            * Transform::rotation(Quaternion::from_axis_angle(
+                vector(0.0, 1.0, 0.0),
+                std::f64::consts::FRAC_PI_2,
+            ));
+        graph.nodes[teapot]
+            .cameras
+            .push(Camera::new(vector(0.0, 0.0, 0.0)));
+
+        let cameras = graph.cameras();
+        assert_eq!(cameras.len(), 1);
+        let ray = cameras[0]
+            .primary_ray(0.0, 0.0, 1.0, &mut Sampler::new(1))
+            .unwrap();
+        assert!((ray.origin - vector(3.0, 0.0, 0.0)).length() < 1e-12);
+        assert!((ray.direction - vector(1.0, 0.0, 0.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_built_mesh_applies_node_changes() {
+        let mesh = three_triangles();
+        let mut graph = SceneGraph::new();
+        let left = graph.add_node(ROOT_NODE, "left");
+        let right = graph.add_node(ROOT_NODE, "right");
+
+        graph.nodes[left].meshes.push(MeshPart {
+            mesh: Arc::clone(&mesh),
+            triangles: vec![0, 1],
+        });
+        graph.nodes[right].meshes.push(MeshPart {
+            mesh: Arc::clone(&mesh),
+            triangles: vec![2],
+        });
+
+        // Nothing changed, so the same triangles come back in the same order
+        let unchanged = graph.build_mesh();
+        assert_eq!(unchanged.len(), 3);
+        for triangle in 0..3 {
+            assert_eq!(unchanged.vertices(triangle), mesh.vertices(triangle));
+        }
+
+        graph.nodes[left].visible = false;
+        graph.nodes[right].transform = Transform::translation(vector(0.0, 0.0, 1.0));
+        graph.nodes[right].material = Some(2);
+
+        let changed = graph.build_mesh();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(
+            changed.vertices(0),
+            mesh.vertices(2).map(|v| v + vector(0.0, 0.0, 1.0))
+        );
+        assert_eq!(changed.material(0), 2);
+    }
+
+    #[test]
+    fn test_obj_objects_and_groups_become_nodes() {
+        let directory = std::env::temp_dir().join("rust_ray_tracer_graph_test");
+        fs::create_dir_all(&directory).unwrap();
+
+        let texture = directory.join("white.png");
+        RgbImage::from_pixel(1, 1, Rgb([255, 255, 255]))
+            .save(&texture)
+            .unwrap();
+
+        let materials = directory.join("white.mtl");
+        fs::write(
+            &materials,
+            format!("newmtl white\nmap_Ka {}\n", texture.display()),
+        )
+        .unwrap();
+
+        let obj = format!(
+            "mtllib {}\nusemtl white\n\
+             v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+             f 1 2 3\n\
+             o table\nf 1 2 3\n\
+             g leg\nf 1 2 3\nf 1 2 3\n\
+             g\nf 1 2 3\n\
+             o teapot\ng lid\nf 1 2 3\n\
+             o table\ng leg\nf 1 2 3\n\
+             g top leg\nf 1 2 3\n",
+            materials.display()
+        );
+
+        let scene_data = parse_obj_file_lines(obj.lines());
+        let graph = &scene_data.graph;
+
+        let triangles = |node: usize| -> Vec<usize> {
+            graph.nodes[node]
+                .meshes
+                .iter()
+                .flat_map(|part| part.triangles.clone())
+                .collect()
+        };
+
+        assert_eq!(triangles(ROOT_NODE), [0]);
+        assert_eq!(triangles(graph.find("table").unwrap()), [1, 4]);
+        assert_eq!(triangles(graph.find("table/leg").unwrap()), [2, 3, 6, 7]);
+        assert_eq!(triangles(graph.find("table/top").unwrap()), [7]);
+        assert_eq!(triangles(graph.find("teapot/lid").unwrap()), [5]);
+        assert!(triangles(graph.find("teapot").unwrap()).is_empty());
+        assert_eq!(graph.nodes.len(), 6);
+
+        // The face in both groups is only built once, and still shows if one of them is hidden
+        assert!(graph.nodes[ROOT_NODE].meshes[0].mesh == scene_data.mesh);
+        assert_eq!(graph.build_mesh().len(), scene_data.mesh.len());
+
+        let mut graph = graph.clone();
+        let leg = graph.find("table/leg").unwrap();
+        graph.nodes[leg].visible = false;
+        assert_eq!(graph.build_mesh().len(), scene_data.mesh.len() - 3);
+    }
+}
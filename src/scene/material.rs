@@ -10,6 +10,8 @@ use super::{engine::Vector3d, entities::Texture};
 
 pub struct Material {
     pub name: String,
+    /// Unique per material in a material map, in the order the materials were defined
+    pub id: usize,
     /// The three below coefficients should be somewhere between { 0.0, 0.0, 0.0 } and { 1.0, 1.0, 1.0}
     /// They are used to weight the R, G, B values sampled from the texture.
     pub ambient_color_coefficient: Vector3d, // Ka
@@ -24,5 +26,7 @@ pub struct Material {
 #[derive(Debug, PartialEq)]
 pub struct MaterialMap {
     pub textures: Vec<Arc<Texture>>,
-    pub materials: HashMap<String, Arc<Material>>,
+    /// Indexed by material id, triangles refer to their material by id
+    pub materials: Vec<Material>,
+    pub ids_by_name: HashMap<String, usize>,
 }
@@ -0,0 +1,326 @@
+use rayon::prelude::*;
+
+use crate::collision::aabb::Aabb;
+
+use super::{
+    engine::{CompactVector, Float, Vector3d},
+    entities::Motion,
+    shape::{Hittable, Primitive, Shape, SurfaceHit, Triangle},
+};
+
+static ZERO: Vector3d = Vector3d {
+    x: 0.0,
+    y: 0.0,
+    z: 0.0,
+};
+
+/// One triangle, as indices into its mesh's shared buffers
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Face {
+    pub positions: [u32; 3],
+    /// Entry 0 of the texture coordinate and normal buffers is a zero vector,
+    /// corners without their own point at it
+    pub tex_coords: [u32; 3],
+    pub normals: [u32; 3],
+    /// Id of the triangle's material in the scene's `MaterialMap`
+    pub material: u32,
+    /// Index into `Mesh::motions`, for triangles that move over the frame
+    pub motion: Option<u32>,
+}
+
+/// An analytic shape or distance field stored alongside a mesh's triangles
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshShape {
+    pub shape: Shape,
+    /// Id of the shape's material in the scene's `MaterialMap`
+    pub material: u32,
+}
+
+/// Each triangle's first corner and its two edges from there, worked out once when the mesh
+/// is made rather than on every ray test. Each component is in its own array so ray tests
+/// only read the numbers they need, one after another.
+///
+/// The edges are differences of the stored corners and are kept as f64 even when the corners are
+/// `Float`, rounding them again would move the far corners off the ones neighbouring triangles share.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TriangleEdges {
+    pub v1: [Vec<Float>; 3],
+    pub edge1: [Vec<f64>; 3],
+    pub edge2: [Vec<f64>; 3],
+}
+
+impl TriangleEdges {
+    /// The corners have to be ones already stored at `Float` precision
+    fn push(&mut self, v1: Vector3d, v2: Vector3d, v3: Vector3d) {
+        self.v1[0].push(v1.x as Float);
+        self.v1[1].push(v1.y as Float);
+        self.v1[2].push(v1.z as Float);
+
+        for (values, edge) in [(&mut self.edge1, v2 - v1), (&mut self.edge2, v3 - v1)] {
+            values[0].push(edge.x);
+            values[1].push(edge.y);
+            values[2].push(edge.z);
+        }
+    }
+
+    fn get(values: &[Vec<impl Copy + Into<f64>>; 3], index: usize) -> Vector3d {
+        Vector3d {
+            x: values[0][index].into(),
+            y: values[1][index].into(),
+            z: values[2][index].into(),
+        }
+    }
+}
+
+/// Every triangle of a model. Corners, texture coordinates and normals are each stored once
+/// and shared by all the triangles using them. The acceleration structures share the mesh
+/// rather than keeping their own copies of the triangles.
+/// Everything is stored at `Float` precision and handed out as `Vector3d`s.
+///
+/// Analytic shapes like spheres and boxes can be added to `shapes`, so they go in the same
+/// acceleration structures as the triangles. Primitive indices count the triangles first and
+/// then the shapes, a hit's `triangle_index` can be either.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mesh {
+    pub positions: Vec<CompactVector>,
+    pub tex_coords: Vec<CompactVector>,
+    pub normals: Vec<CompactVector>,
+    pub faces: Vec<Face>,
+    pub motions: Vec<Motion>,
+    pub shapes: Vec<MeshShape>,
+    edges: TriangleEdges,
+}
+
+impl Mesh {
+    /// A mesh of the given faces, `tex_coords` and `normals` should start with a zero vector
+    pub fn new(
+        positions: Vec<Vector3d>,
+        tex_coords: Vec<Vector3d>,
+        normals: Vec<Vector3d>,
+        faces: Vec<Face>,
+        motions: Vec<Motion>,
+    ) -> Mesh {
+        let compact = |vectors: Vec<Vector3d>| -> Vec<CompactVector> {
+            vectors.into_iter().map(CompactVector::from).collect()
+        };
+
+        let mut mesh = Mesh {
+            positions: compact(positions),
+            tex_coords: compact(tex_coords),
+            normals: compact(normals),
+            faces,
+            motions,
+            shapes: vec![],
+            edges: TriangleEdges::default(),
+        };
+
+        // Worked out from the stored corners, so they agree with `vertices`
+        for triangle in 0..mesh.triangle_count() {
+            let [v1, v2, v3] = mesh.vertices(triangle);
+            mesh.edges.push(v1, v2, v3);
+        }
+
+        mesh
+    }
+
+    /// A mesh of bare triangles that don't share corners, with no texture coordinates,
+    /// normals or motion, all using material 0
+    pub fn from_triangles(triangles: &[[Vector3d; 3]]) -> Mesh {
+        let faces = (0..triangles.len() as u32)
+            .map(|i| Face {
+                positions: [i * 3, i * 3 + 1, i * 3 + 2],
+                tex_coords: [0; 3],
+                normals: [0; 3],
+                material: 0,
+                motion: None,
+            })
+            .collect();
+
+        Mesh::new(
+            triangles.iter().flatten().copied().collect(),
+            vec![ZERO],
+            vec![ZERO],
+            faces,
+            vec![],
+        )
+    }
+
+    /// Number of primitives, triangles and shapes
+    pub fn len(&self) -> usize {
+        self.faces.len() + self.shapes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faces.is_empty() && self.shapes.is_empty()
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.faces.len()
+    }
+
+    pub fn is_triangle(&self, index: usize) -> bool {
+        index < self.faces.len()
+    }
+
+    /// The shape at a primitive index, `None` for triangles
+    pub fn shape(&self, index: usize) -> Option<&Shape> {
+        let shape = index.checked_sub(self.faces.len())?;
+        self.shapes.get(shape).map(|shape| &shape.shape)
+    }
+
+    /// The primitive at an index, for working with triangles and shapes alike
+    pub fn primitive(&self, index: usize) -> Primitive<'_> {
+        match self.shape(index) {
+            Some(shape) => Primitive::Shape(shape),
+            None => Primitive::Triangle(Triangle { mesh: self, index }),
+        }
+    }
+
+    /// A box around every primitive that has one, see `Hittable::bounds`
+    pub fn bounds(&self) -> Aabb {
+        (0..self.len())
+            .into_par_iter()
+            .filter_map(|index| self.primitive(index).bounds())
+            .reduce(Aabb::empty, |a, b| a.union(&b))
+    }
+
+    /// The triangle's corners where they are when not moving
+    pub fn vertices(&self, triangle: usize) -> [Vector3d; 3] {
+        self.faces[triangle]
+            .positions
+            .map(|p| self.positions[p as usize].into())
+    }
+
+    /// Where the triangle's corners are at `time`
+    pub fn vertices_at(&self, triangle: usize, time: f64) -> [Vector3d; 3] {
+        let vertices = self.vertices(triangle);
+
+        match self.motion(triangle) {
+            Some(motion) => {
+                let transform = motion.transform_at(time);
+                vertices.map(|v| transform.transform_point(v))
+            }
+            None => vertices,
+        }
+    }
+
+    pub fn motion(&self, triangle: usize) -> Option<&Motion> {
+        self.faces[triangle]
+            .motion
+            .map(|motion| &self.motions[motion as usize])
+    }
+
+    pub fn tex_coords(&self, triangle: usize) -> [Vector3d; 3] {
+        self.faces[triangle]
+            .tex_coords
+            .map(|t| self.tex_coords[t as usize].into())
+    }
+
+    pub fn normals(&self, triangle: usize) -> [Vector3d; 3] {
+        self.faces[triangle]
+            .normals
+            .map(|n| self.normals[n as usize].into())
+    }
+
+    /// Id of the primitive's material in the scene's `MaterialMap`
+    pub fn material(&self, index: usize) -> usize {
+        match index.checked_sub(self.faces.len()) {
+            Some(shape) => self.shapes[shape].material as usize,
+            None => self.faces[index].material as usize,
+        }
+    }
+
+    /// Id of the material at a hit on the primitive at an index. It's the primitive's own
+    /// material, except on CSG shapes where it's the material of whichever solid was hit.
+    pub fn material_at(&self, index: usize, hit: &SurfaceHit) -> usize {
+        match self.shape(index) {
+            Some(Shape::Csg(csg)) => csg.material_at(hit) as usize,
+            _ => self.material(index),
+        }
+    }
+
+    /// The triangle's first corner and the edges from it to the second and third,
+    /// where they are when not moving
+    pub fn edges(&self, triangle: usize) -> (Vector3d, Vector3d, Vector3d) {
+        (
+            TriangleEdges::get(&self.edges.v1, triangle),
+            TriangleEdges::get(&self.edges.edge1, triangle),
+            TriangleEdges::get(&self.edges.edge2, triangle),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::ray::{Ray, TriangleTest};
+
+    use super::super::sampling::Sampler;
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    #[test]
+    fn test_triangles_round_trip_through_the_mesh() {
+        let mut sampler = Sampler::new(60);
+        let mut random_point = || {
+            vector(
+                sampler.next_f64() * 20.0 - 10.0,
+                sampler.next_f64() * 20.0 - 10.0,
+                sampler.next_f64() * 20.0 - 10.0,
+            )
+        };
+
+        // A fan of triangles around a shared centre, each sharing an edge with the next
+        let centre = random_point();
+        let rim: Vec<Vector3d> = (0..50).map(|_| random_point()).collect();
+        let triangles: Vec<[Vector3d; 3]> = (0..rim.len() - 1)
+            .map(|i| [centre, rim[i], rim[i + 1]])
+            .collect();
+
+        let mesh = Mesh::from_triangles(&triangles);
+        let stored = |v: Vector3d| -> Vector3d { CompactVector::from(v).into() };
+
+        for (index, triangle) in triangles.iter().enumerate() {
+            // The same triangles come out, at the precision they're stored at
+            let vertices = mesh.vertices(index);
+            assert_eq!(vertices, triangle.map(stored));
+
+            // The edges lead to the stored corners, the same ones the neighbours have, give or
+            // take f64 rounding, which is far smaller than an f32 rounding of the edges would be
+            let (v1, edge1, edge2) = mesh.edges(index);
+            assert_eq!(v1, vertices[0]);
+            assert!((v1 + edge1 - vertices[1]).length() < 1e-12);
+            assert!((v1 + edge2 - vertices[2]).length() < 1e-12);
+
+            if index > 0 {
+                let (_, _, previous_edge2) = mesh.edges(index - 1);
+                assert_eq!(edge1, previous_edge2);
+            }
+
+            // A ray aimed at a point on the triangle hits it there
+            let [a, b, c] = vertices;
+            let (u, v) = (0.2, 0.5);
+            let target = a * (1.0 - u - v) + b * u + c * v;
+            let origin =
+                target + (b - a).cross(&(c - a)).normalised() * 5.0 + vector(0.1, 0.2, 0.3);
+            let ray = Ray {
+                origin,
+                direction: target - origin,
+                time: 0.0,
+                ignored_triangle: None,
+            };
+
+            for test in [TriangleTest::MollerTrumbore, TriangleTest::Watertight] {
+                let hit = ray
+                    .triangle_intersector(test)
+                    .intersect(&mesh, index)
+                    .expect("The ray is aimed at the triangle");
+
+                assert!((hit.t - 1.0).abs() < 1e-9);
+                assert!((hit.u - u).abs() < 1e-9 && (hit.v - v).abs() < 1e-9);
+            }
+        }
+    }
+}
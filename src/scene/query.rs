@@ -0,0 +1,594 @@
+use crate::collision::{
+    accelerator::Accelerator,
+    closest_point::ClosestPoint,
+    mesh_intersection::{intersect_meshes, intersect_transformed_meshes, TrianglePairIntersection},
+    ray::{Ray, RayTriangleIntersectionResult},
+    region::Region,
+};
+
+use super::{engine::Vector3d, scenedata::SceneData, transform::Transform};
+
+/// Fraction of a segment's length at each end where surfaces are ignored, so a segment
+/// between two points lying on surfaces doesn't count those surfaces as crossings.
+static SEGMENT_END_TOLERANCE: f64 = 1e-6;
+
+/// Directions rays are fired in to decide whether a point is inside the mesh. None of them
+/// line up with an axis, and using a few means a ray grazing an edge gets outvoted.
+static INSIDE_TEST_DIRECTIONS: [Vector3d; 3] = [
+    Vector3d {
+        x: 0.5773,
+        y: 0.5774,
+        z: 0.5775,
+    },
+    Vector3d {
+        x: -0.6391,
+        y: 0.2417,
+        z: 0.7301,
+    },
+    Vector3d {
+        x: 0.1263,
+        y: -0.8466,
+        z: -0.5171,
+    },
+];
+
+/// Where on a primitive a ray hit it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HitCoordinates {
+    /// Weights of the triangle's v1, v2 and v3 at the hit, they always add up to 1
+    Barycentric(f64, f64, f64),
+    /// A shape's own surface coordinates, see `Hittable`
+    Surface(f64, f64),
+}
+
+/// A point where a ray crosses a surface of the scene
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RayHit {
+    /// Distance along the ray in multiples of its direction
+    pub t: f64,
+    pub point: Vector3d,
+    pub coordinates: HitCoordinates,
+    /// Index of the triangle in `SceneData::mesh`, which is the order faces appear in the model file,
+    /// or in the instance's mesh for hits on an instance
+    pub triangle_index: usize,
+    /// The instance that was hit, `None` for the scene's own mesh
+    pub instance: Option<usize>,
+    /// Whether the ray hit the side the triangle's winding normal, (v2 - v1) x (v3 - v1), points out of.
+    /// For closed meshes wound anticlockwise this is true when entering and false when leaving.
+    /// For shapes it's whether the ray hit them from outside, CSG shapes included.
+    pub front_face: bool,
+}
+
+impl RayHit {
+    fn from_intersection(
+        scene_data: &SceneData,
+        ray: &Ray,
+        intersection: &RayTriangleIntersectionResult,
+    ) -> RayHit {
+        // The winding normal for triangles, the outward one for shapes
+        let normal = scene_data.surface_point(intersection, ray).normal;
+
+        let coordinates = if scene_data
+            .mesh_of(intersection)
+            .is_triangle(intersection.triangle_index)
+        {
+            HitCoordinates::Barycentric(
+                1.0 - intersection.u - intersection.v,
+                intersection.u,
+                intersection.v,
+            )
+        } else {
+            HitCoordinates::Surface(intersection.u, intersection.v)
+        };
+
+        RayHit {
+            t: intersection.t,
+            point: ray.origin + ray.direction * intersection.t,
+            coordinates,
+            triangle_index: intersection.triangle_index,
+            instance: intersection.instance,
+            front_face: normal.dot(&ray.direction) < 0.0,
+        }
+    }
+}
+
+/// Geometry queries that don't need anything to be rendered, e.g. for visibility
+/// and line of sight analysis. They all use the scene's acceleration structure.
+/// The ray queries include instances, the point, region and mesh queries only cover the
+/// scene's own mesh.
+impl SceneData {
+    /// The nearest surface the ray hits before `max_t`
+    pub fn closest_hit(&self, ray: &Ray, max_t: f64) -> Option<RayHit> {
+        self.intersect(ray, max_t)
+            .map(|intersection| RayHit::from_intersection(self, ray, &intersection))
+    }
+
+    /// Every surface the ray crosses before `max_t`, nearest first
+    pub fn all_hits(&self, ray: &Ray, max_t: f64) -> Vec<RayHit> {
+        self.intersect_all(ray, max_t)
+            .iter()
+            .map(|intersection| RayHit::from_intersection(self, ray, intersection))
+            .collect()
+    }
+
+    /// Whether any surface lies between two points at the given time.
+    /// Surfaces touching either end point don't count.
+    pub fn is_occluded(&self, from: Vector3d, to: Vector3d, time: f64) -> bool {
+        let direction = to - from;
+
+        let ray = Ray {
+            origin: from + direction * SEGMENT_END_TOLERANCE,
+            direction: direction * (1.0 - 2.0 * SEGMENT_END_TOLERANCE),
+            time,
+            ignored_triangle: None,
+        };
+
+        self.intersects_anything(&ray, 1.0)
+    }
+
+    /// Every surface crossed between two points, nearest to `from` first. Hit t values
+    /// go from 0 at `from` to 1 at `to`, surfaces touching either end point are left out.
+    pub fn segment_hits(&self, from: Vector3d, to: Vector3d, time: f64) -> Vec<RayHit> {
+        let ray = Ray {
+            origin: from,
+            direction: to - from,
+            time,
+            ignored_triangle: None,
+        };
+
+        self.all_hits(&ray, 1.0 - SEGMENT_END_TOLERANCE)
+            .into_iter()
+            .filter(|hit| hit.t > SEGMENT_END_TOLERANCE)
+            .collect()
+    }
+
+    /// The nearest point on the mesh, along with the triangle it's on
+    pub fn closest_point(&self, point: Vector3d, time: f64) -> Option<ClosestPoint> {
+        self.acceleration_structure
+            .closest_point(point, f64::INFINITY, time)
+    }
+
+    /// Distance to the nearest point on the mesh, infinite if there are no triangles
+    pub fn unsigned_distance(&self, point: Vector3d, time: f64) -> f64 {
+        self.closest_point(point, time)
+            .map_or(f64::INFINITY, |closest| closest.distance)
+    }
+
+    /// Distance to the nearest point on the mesh, negative inside it.
+    /// Only meaningful for closed meshes, see `is_inside`.
+    pub fn signed_distance(&self, point: Vector3d, time: f64) -> f64 {
+        let distance = self.unsigned_distance(point, time);
+
+        if self.is_inside(point, time) {
+            -distance
+        } else {
+            distance
+        }
+    }
+
+    /// Whether the point is enclosed by the mesh, which needs to be closed for this to make sense.
+    /// A ray leaving an enclosed point crosses the surface an odd number of times,
+    /// a few rays are fired and they vote.
+    pub fn is_inside(&self, point: Vector3d, time: f64) -> bool {
+        let inside_votes = INSIDE_TEST_DIRECTIONS
+            .iter()
+            .filter(|&&direction| {
+                let ray = Ray {
+                    origin: point,
+                    direction,
+                    time,
+                    ignored_triangle: None,
+                };
+
+                self.acceleration_structure
+                    .all_hits(&ray, f64::INFINITY)
+                    .len()
+                    % 2
+                    == 1
+            })
+            .count();
+
+        inside_votes * 2 > INSIDE_TEST_DIRECTIONS.len()
+    }
+
+    /// Every triangle with some part within `radius` of the point, nearest first,
+    /// each with the point on it nearest to the query point.
+    pub fn triangles_within_radius(
+        &self,
+        point: Vector3d,
+        radius: f64,
+        time: f64,
+    ) -> Vec<ClosestPoint> {
+        self.acceleration_structure
+            .triangles_within(point, radius, time)
+    }
+
+    /// Indices of every triangle with some part inside the region, in ascending order.
+    /// Regions can be boxes, oriented boxes or frustums, see `Region` and `Camera::view_region`.
+    pub fn triangles_in_region(&self, region: &Region, time: f64) -> Vec<usize> {
+        self.acceleration_structure
+            .triangles_in_region(region, time)
+    }
+
+    /// Every pair of triangles, one from this mesh and one from the other, that intersect,
+    /// along with the segment where they cross. Useful for finding parts that interpenetrate.
+    pub fn intersections_with(
+        &self,
+        other: &SceneData,
+        time: f64,
+    ) -> Vec<TrianglePairIntersection> {
+        intersect_meshes(
+            &self.acceleration_structure,
+            &other.acceleration_structure,
+            time,
+        )
+    }
+
+    /// Like `intersections_with`, with each mesh moved into place first, see `intersect_transformed_meshes`
+    pub fn transformed_intersections_with(
+        &self,
+        transform: &Transform,
+        other: &SceneData,
+        other_transform: &Transform,
+        time: f64,
+    ) -> Vec<TrianglePairIntersection> {
+        intersect_transformed_meshes(
+            &self.acceleration_structure,
+            transform,
+            &other.acceleration_structure,
+            other_transform,
+            time,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, f64::consts::PI, sync::Arc};
+
+    use crate::collision::{
+        aabb::Aabb,
+        accelerator::{AccelerationStructure, AcceleratorKind},
+        bvh::Bvh,
+        closest_point::closest_point_on_triangle,
+        instance::{Instance, InstanceTree},
+        mesh_intersection::TriangleContact,
+    };
+    use crate::scene::{
+        camera::Camera,
+        entities::{Color, Texture},
+        graph::SceneGraph,
+        material::{Material, MaterialMap},
+        mesh::{Mesh, MeshShape},
+        shape::{Hittable, Shape, Sphere},
+        transform::{Quaternion, Transform},
+    };
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    /// A cube from -1 to 1 on every axis, wound anticlockwise when seen from outside
+    fn cube_scene(kind: AcceleratorKind) -> SceneData {
+        let material = Material {
+            name: String::from("test"),
+            id: 0,
+            ambient_color_coefficient: vector(1.0, 1.0, 1.0),
+            diffuse_color_coefficient: vector(1.0, 1.0, 1.0),
+            specular_color_coefficient: vector(1.0, 1.0, 1.0),
+            specular_weight: 240.0,
+            texture: Arc::new(Texture {
+                colours: vec![Color { r: 255, g: 0, b: 0 }],
+                width: 1,
+                height: 1,
+            }),
+            bump_map: None,
+            reflectivity: 0.0,
+        };
+
+        let corner = |i: usize| {
+            vector(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { -1.0 } else { 1.0 },
+            )
+        };
+
+        let faces = [
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+        ];
+
+        let triangles: Vec<[Vector3d; 3]> = faces
+            .iter()
+            .flat_map(|&[a, b, c, d]| [[a, b, c], [a, c, d]])
+            .map(|corners| corners.map(corner))
+            .collect();
+
+        let mut scene_data = SceneData {
+            mesh: Arc::new(Mesh::from_triangles(&triangles)),
+            material_map: MaterialMap {
+                textures: vec![],
+                ids_by_name: HashMap::from([(material.name.clone(), 0)]),
+                materials: vec![material],
+            },
+            acceleration_structure: AccelerationStructure::Bvh(Bvh::build(Arc::default())),
+            instances: InstanceTree::default(),
+            graph: SceneGraph::default(),
+        };
+
+        scene_data.rebuild_acceleration_structure(kind);
+        scene_data
+    }
+
+    fn scenes() -> Vec<SceneData> {
+        vec![
+            cube_scene(AcceleratorKind::Octree(Default::default())),
+            cube_scene(AcceleratorKind::Bvh),
+        ]
+    }
+
+    #[test]
+    fn test_closest_hit_reports_barycentrics_and_point() {
+        for scene_data in scenes() {
+            let ray = Ray {
+                origin: vector(0.2, -0.3, -5.0),
+                direction: vector(0.0, 0.0, 1.0),
+                time: 0.0,
+                ignored_triangle: None,
+            };
+
+            let hit = scene_data.closest_hit(&ray, f64::INFINITY).unwrap();
+            let [v1, v2, v3] = scene_data.mesh.vertices(hit.triangle_index);
+            let HitCoordinates::Barycentric(w, u, v) = hit.coordinates else {
+                panic!("a triangle was hit, got {:?}", hit.coordinates);
+            };
+
+            assert!((hit.t - 4.0).abs() < 1e-9);
+            assert!(hit.front_face);
+            assert!((w + u + v - 1.0).abs() < 1e-9);
+            assert!(((v1 * w + v2 * u + v3 * v) - hit.point).length() < 1e-9);
+            assert!((hit.point - vector(0.2, -0.3, -1.0)).length() < 1e-9);
+
+            // Shapes report their own surface coordinates instead
+            let mut scene_data = scene_data;
+            let sphere = Sphere {
+                centre: vector(0.0, 0.0, -3.0),
+                radius: 0.5,
+            };
+            scene_data.add_shapes([MeshShape {
+                shape: Shape::Sphere(sphere),
+                material: 0,
+            }]);
+
+            let hit = scene_data.closest_hit(&ray, f64::INFINITY).unwrap();
+            let (_, u, v) = sphere.intersect(&ray).unwrap();
+            assert!(!scene_data.mesh.is_triangle(hit.triangle_index));
+            assert_eq!(hit.coordinates, HitCoordinates::Surface(u, v));
+        }
+    }
+
+    #[test]
+    fn test_segment_hits_are_ordered_crossings() {
+        for scene_data in scenes() {
+            let hits = scene_data.segment_hits(vector(0.3, 0.1, -5.0), vector(0.3, 0.1, 5.0), 0.0);
+
+            assert_eq!(hits.len(), 2);
+            assert!((hits[0].t - 0.4).abs() < 1e-9);
+            assert!((hits[1].t - 0.6).abs() < 1e-9);
+            assert!(hits[0].front_face);
+            assert!(!hits[1].front_face);
+        }
+    }
+
+    #[test]
+    fn test_ray_queries_include_instances() {
+        for mut scene_data in scenes() {
+            // A copy of the cube stretched along x then turned to face along z, so it reaches
+            // from z = 8 to z = 12, drawn with a different material
+            let instance = Instance {
+                prototype: 0,
+                transform: Transform::from_parts(
+                    vector(0.0, 0.0, 10.0),
+                    Quaternion::from_axis_angle(vector(0.0, 1.0, 0.0), PI / 2.0),
+                    vector(2.0, 1.0, 1.0),
+                ),
+                material: Some(7),
+            };
+            scene_data.instances = InstanceTree::build(
+                vec![scene_data.acceleration_structure.clone()],
+                vec![instance],
+            );
+
+            let hits =
+                scene_data.segment_hits(vector(0.2, -0.3, -5.0), vector(0.2, -0.3, 15.0), 0.0);
+            let crossings: Vec<(Option<usize>, bool)> = hits
+                .iter()
+                .map(|hit| (hit.instance, hit.front_face))
+                .collect();
+
+            assert_eq!(
+                crossings,
+                [
+                    (None, true),
+                    (None, false),
+                    (Some(0), true),
+                    (Some(0), false)
+                ]
+            );
+            assert!((hits[2].t - 0.65).abs() < 1e-9);
+            assert!((hits[3].point - vector(0.2, -0.3, 12.0)).length() < 1e-9);
+
+            let ray = Ray {
+                origin: vector(0.2, -0.3, 5.0),
+                direction: vector(0.0, 0.0, 1.0),
+                time: 0.0,
+                ignored_triangle: None,
+            };
+            let intersection = scene_data.intersect(&ray, f64::INFINITY).unwrap();
+            let surface = scene_data.surface_point(&intersection, &ray);
+
+            assert!((intersection.t - 3.0).abs() < 1e-9);
+            assert_eq!(scene_data.material_id(&intersection, &ray), 7);
+            assert!((surface.point - vector(0.2, -0.3, 8.0)).length() < 1e-9);
+            assert!((surface.normal - vector(0.0, 0.0, -1.0)).length() < 1e-9);
+
+            assert!(scene_data.is_occluded(vector(0.0, 0.0, 5.0), vector(0.0, 0.0, 15.0), 0.0));
+            assert!(!scene_data.is_occluded(vector(0.0, 0.0, 2.0), vector(0.0, 0.0, 7.9), 0.0));
+        }
+    }
+
+    #[test]
+    fn test_closest_point_and_distances() {
+        for scene_data in scenes() {
+            // Nearest to the middle of the top face
+            let closest = scene_data
+                .closest_point(vector(0.25, 3.0, 0.5), 0.0)
+                .unwrap();
+            let [v1, v2, v3] = scene_data.mesh.vertices(closest.triangle_index);
+            let (w, u, v) = closest.barycentrics;
+
+            assert!((closest.point - vector(0.25, 1.0, 0.5)).length() < 1e-9);
+            assert!((closest.distance - 2.0).abs() < 1e-9);
+            assert!(((v1 * w + v2 * u + v3 * v) - closest.point).length() < 1e-9);
+
+            // Nearest to a corner
+            let corner = scene_data
+                .closest_point(vector(2.0, 2.0, 2.0), 0.0)
+                .unwrap();
+            assert!((corner.point - vector(1.0, 1.0, 1.0)).length() < 1e-9);
+            assert!(
+                (scene_data.unsigned_distance(vector(2.0, 2.0, 2.0), 0.0) - 3f64.sqrt()).abs()
+                    < 1e-9
+            );
+
+            assert!((scene_data.signed_distance(vector(0.1, 0.2, 0.3), 0.0) + 0.7).abs() < 1e-9);
+            assert!((scene_data.signed_distance(vector(0.1, 1.5, 0.3), 0.0) - 0.5).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_triangles_within_radius() {
+        for scene_data in scenes() {
+            // Only the two triangles making up the x = 1 face are close enough,
+            // the nearest point on the second is on the diagonal they share
+            let near_face = scene_data.triangles_within_radius(vector(1.5, 0.3, -0.2), 0.7, 0.0);
+
+            assert_eq!(near_face.len(), 2);
+            assert!((near_face[0].distance - 0.5).abs() < 1e-9);
+            assert!((near_face[1].distance - 0.375f64.sqrt()).abs() < 1e-9);
+
+            // A big enough radius finds every triangle exactly once, nearest first
+            let everything = scene_data.triangles_within_radius(vector(1.5, 0.3, -0.2), 10.0, 0.0);
+
+            assert_eq!(everything.len(), scene_data.mesh.triangle_count());
+            assert!(everything
+                .windows(2)
+                .all(|w| w[0].distance <= w[1].distance));
+
+            let brute_force: Vec<usize> = (0..scene_data.mesh.triangle_count())
+                .filter(|&i| {
+                    closest_point_on_triangle(
+                        &scene_data.mesh.vertices(i),
+                        i,
+                        vector(1.5, 0.3, -0.2),
+                    )
+                    .distance
+                        <= 1.2
+                })
+                .collect();
+            let mut within: Vec<usize> = scene_data
+                .triangles_within_radius(vector(1.5, 0.3, -0.2), 1.2, 0.0)
+                .iter()
+                .map(|c| c.triangle_index)
+                .collect();
+            within.sort();
+
+            assert_eq!(within, brute_force);
+        }
+    }
+
+    #[test]
+    fn test_triangles_in_camera_view() {
+        for scene_data in scenes() {
+            let camera = Camera::new(vector(0.0, 0.0, -10.0));
+
+            let whole_view = camera
+                .view_region((-0.5, -0.5), (0.5, 0.5), 1.0, 0.1, 100.0, 0.0)
+                .unwrap();
+            let right_edge = camera
+                .view_region((0.3, -0.5), (0.5, 0.5), 1.0, 0.1, 100.0, 0.0)
+                .unwrap();
+            let too_near = camera
+                .view_region((-0.5, -0.5), (0.5, 0.5), 1.0, 0.1, 8.5, 0.0)
+                .unwrap();
+
+            assert_eq!(
+                scene_data.triangles_in_region(&whole_view, 0.0),
+                (0..12).collect::<Vec<usize>>()
+            );
+            assert!(scene_data.triangles_in_region(&right_edge, 0.0).is_empty());
+            assert!(scene_data.triangles_in_region(&too_near, 0.0).is_empty());
+
+            // Just the two triangles of the near face
+            let box_region = Region::from_aabb(&Aabb::new(-0.5, 0.5, -0.5, 0.5, -1.5, -0.5));
+            assert_eq!(scene_data.triangles_in_region(&box_region, 0.0), vec![0, 1]);
+        }
+    }
+
+    #[test]
+    fn test_overlapping_cubes_intersect() {
+        for scene_data in scenes() {
+            let other = cube_scene(AcceleratorKind::Bvh);
+
+            // Slid along x so the second cube's x = -1 face cuts through the first cube
+            let moved = Transform::translation(vector(1.5, 0.25, 0.3));
+            let identity = Transform::identity();
+
+            let intersections =
+                scene_data.transformed_intersections_with(&identity, &other, &moved, 0.0);
+
+            assert!(!intersections.is_empty());
+
+            for intersection in &intersections {
+                let TriangleContact::Segment { start, end } = intersection.contact else {
+                    panic!("the cubes' faces aren't in the same plane");
+                };
+
+                // Every contact is on the boundary of both cubes
+                for point in [start, end] {
+                    assert!(point.x >= 0.5 - 1e-9 && point.x <= 1.0 + 1e-9);
+                    assert!(point.y >= -0.75 - 1e-9 && point.y <= 1.0 + 1e-9);
+                    assert!(point.z >= -0.7 - 1e-9 && point.z <= 1.0 + 1e-9);
+                }
+            }
+
+            // Far enough apart that nothing touches
+            let far = Transform::translation(vector(2.5, 0.0, 0.0));
+            assert!(scene_data
+                .transformed_intersections_with(&identity, &other, &far, 0.0)
+                .is_empty());
+
+            // A cube against itself touches everywhere
+            assert!(!scene_data.intersections_with(&other, 0.0).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_occlusion_between_points() {
+        for scene_data in scenes() {
+            let outside = vector(-3.0, 0.5, 0.0);
+
+            assert!(scene_data.is_occluded(outside, vector(3.0, 0.5, 0.0), 0.0));
+            assert!(!scene_data.is_occluded(outside, vector(-3.0, 0.5, 4.0), 0.0));
+            // Ending exactly on the surface doesn't count as being blocked by it
+            assert!(!scene_data.is_occluded(outside, vector(-1.0, 0.5, 0.0), 0.0));
+        }
+    }
+}
@@ -1,10 +1,17 @@
-use crate::collision::ray::{Ray, RayTriangleIntersectionResult};
+use crate::collision::{
+    packet::PACKET_WIDTH,
+    ray::{Ray, RayTriangleIntersectionResult},
+    spawn::{SurfacePoint, SHADOW_EPSILON},
+};
 
 use super::{
+    aov::SurfaceSample,
+    camera::Camera,
     engine::Vector3d,
     entities::{Color, Light},
     material::Material,
     scenedata::SceneData,
+    shape::{Hittable, SurfaceHit},
 };
 
 static WHITE: Color = Color {
@@ -13,117 +20,256 @@ static WHITE: Color = Color {
     b: 255,
 };
 
-/// Small offset to prevent self-intersection when tracing secondary rays (shadows, reflections)
-static SURFACE_OFFSET: f64 = 0.0001;
-
 /// Maximum recursion depth for reflections to prevent infinite loops
 static MAX_REFLECTION_DEPTH: u32 = 5;
 
+/// The texture colour at an intersection and where on the texture it came from
+struct TextureSample {
+    colour: Color,
+    tex_x: f64,
+    tex_y: f64,
+    tex_x_index: usize,
+    tex_y_index: usize,
+}
+
 pub struct RayTracer {
     pub scene_data: SceneData,
     pub lights: Vec<Light>,
-    pub origin: Vector3d,
+    pub camera: Camera,
+    /// Secondary rays skip the triangle they leave from, on top of being offset from it
+    pub ignore_origin_triangle: bool,
 }
 
 impl RayTracer {
-    pub fn get_ray_colour(&self, origin: Vector3d, direction: Vector3d) -> Color {
-        self.get_ray_colour_recursive(origin, direction, 0)
+    pub fn get_ray_colour(&self, ray: &Ray) -> Color {
+        Color::from_radiance(self.get_ray_colour_recursive(ray, 0))
     }
 
-    fn get_ray_colour_recursive(&self, origin: Vector3d, direction: Vector3d, depth: u32) -> Color {
-        let ray = Ray { origin, direction };
+    /// Colours for several primary rays at once, in 0-255 units but not yet clamped to that
+    /// range or rounded. The rays, and then their shadow rays towards each light, are traced
+    /// together as packets where they head the same way. Lanes without a ray have no colour.
+    pub fn get_ray_colours(
+        &self,
+        rays: [Option<&Ray>; PACKET_WIDTH],
+    ) -> [Option<Vector3d>; PACKET_WIDTH] {
+        self.get_ray_colours_and_surfaces(rays, false)
+            .map(|lane| lane.map(|(colour, _)| colour))
+    }
 
-        let triangle_intersection = ray.intersect_with_octant(&self.scene_data.octree, 0);
+    /// `get_ray_colours`, along with what each ray hit for the AOV passes if `capture_surfaces`.
+    /// Both come from the same trace, so the passes don't cost another primary ray.
+    pub fn get_ray_colours_and_surfaces(
+        &self,
+        rays: [Option<&Ray>; PACKET_WIDTH],
+        capture_surfaces: bool,
+    ) -> [Option<(Vector3d, Option<SurfaceSample>)>; PACKET_WIDTH] {
+        let hits = self.scene_data.intersect_packet(rays, f64::INFINITY);
+
+        let surfaces: [Option<SurfacePoint>; PACKET_WIDTH] = std::array::from_fn(|lane| {
+            Some(
+                self.scene_data
+                    .surface_point(hits[lane].as_ref()?, rays[lane]?),
+            )
+        });
+
+        let mut lights_visible: [Vec<bool>; PACKET_WIDTH] =
+            std::array::from_fn(|_| vec![true; self.lights.len()]);
+
+        for (light_index, light) in self.lights.iter().enumerate() {
+            if let Light::Point { position, .. } = light {
+                let shadow_rays: [Option<Ray>; PACKET_WIDTH] = std::array::from_fn(|lane| {
+                    Some(surfaces[lane]?.spawn_ray_to(
+                        *position,
+                        rays[lane]?.time,
+                        self.ignore_origin_triangle,
+                    ))
+                });
 
-        if let Some(intersection) = triangle_intersection {
-            let p = origin + direction * intersection.t;
+                let blocked = self.scene_data.intersects_anything_packet(
+                    shadow_rays.each_ref().map(Option::as_ref),
+                    1.0 - SHADOW_EPSILON,
+                );
 
-            let tex = &intersection.triangle.material.texture;
+                for (visible, blocked) in lights_visible.iter_mut().zip(blocked) {
+                    visible[light_index] = !blocked;
+                }
+            }
+        }
 
-            let w = 1.0 - intersection.u - intersection.v;
+        std::array::from_fn(|lane| {
+            let ray = rays[lane]?;
+
+            Some(match (&hits[lane], &surfaces[lane]) {
+                (Some(intersection), Some(surface)) => (
+                    self.shade(ray, intersection, surface, &lights_visible[lane], 0),
+                    capture_surfaces.then(|| self.surface_sample(intersection, ray)),
+                ),
+                _ => (WHITE.into(), None), // nothing, void
+            })
+        })
+    }
 
-            let tex_x = intersection.triangle.v2_tex_coords.x * intersection.u
-                + intersection.triangle.v3_tex_coords.x * intersection.v
-                + intersection.triangle.v1_tex_coords.x * w;
-            let tex_y = intersection.triangle.v2_tex_coords.y * intersection.u
-                + intersection.triangle.v3_tex_coords.y * intersection.v
-                + intersection.triangle.v1_tex_coords.y * w;
+    fn get_ray_colour_recursive(&self, ray: &Ray, depth: u32) -> Vector3d {
+        let triangle_intersection = self.scene_data.intersect(ray, f64::INFINITY);
 
-            let tex_x_index = ((tex_x * tex.width as f64) as usize) % tex.width;
-            let tex_y_index = ((tex_y * tex.height as f64) as usize) % tex.height;
+        if let Some(intersection) = triangle_intersection {
+            let surface = self.scene_data.surface_point(&intersection, ray);
+            let lights_visible = self.lights_visible(&surface, ray.time);
 
-            let col = tex.colours[tex.width * tex_y_index + tex_x_index];
+            self.shade(ray, &intersection, &surface, &lights_visible, depth)
+        } else {
+            WHITE.into() // nothing, void
+        }
+    }
 
-            let n = self.get_normal_at_intersection(&intersection, tex_x_index, tex_y_index);
+    /// The colour of a surface a ray hit, unclamped, `lights_visible` says which lights aren't
+    /// in shadow
+    fn shade(
+        &self,
+        ray: &Ray,
+        intersection: &RayTriangleIntersectionResult,
+        surface: &SurfacePoint,
+        lights_visible: &[bool],
+        depth: u32,
+    ) -> Vector3d {
+        let direction = ray.direction;
+        let time = ray.time;
+
+        let material = self.material(intersection, ray);
+        let tex_sample = self.sample_texture(intersection, ray);
+        let col = tex_sample.colour;
+
+        let n = self.get_normal_at_intersection(
+            intersection,
+            ray,
+            tex_sample.tex_x_index,
+            tex_sample.tex_y_index,
+        );
+
+        let lighting_intensity =
+            self.compute_lighting_intensity(surface, &n, &-direction, lights_visible, material);
+
+        // Calculate the local (non-reflected) color
+        let local_color = Vector3d {
+            x: col.r as f64 * lighting_intensity.x,
+            y: col.g as f64 * lighting_intensity.y,
+            z: col.b as f64 * lighting_intensity.z,
+        };
 
-            let lighting_intensity = self.compute_lighting_intensity(
-                &p,
-                &n,
-                &-direction,
-                &intersection.triangle.material,
-            );
+        let reflectivity = material.reflectivity;
 
-            // Calculate the local (non-reflected) color
-            let local_color = Vector3d {
-                x: col.r as f64 * lighting_intensity.x,
-                y: col.g as f64 * lighting_intensity.y,
-                z: col.b as f64 * lighting_intensity.z,
-            };
+        // If the material is reflective and we haven't exceeded max depth
+        if reflectivity > 0.0 && depth < MAX_REFLECTION_DEPTH {
+            // Calculate reflection direction: R = D - 2(D·N)N
+            let d_dot_n = direction.dot(&n);
+            let reflect_dir = (direction - n * 2.0 * d_dot_n).normalised();
 
-            let reflectivity = intersection.triangle.material.reflectivity;
+            // Start just off the surface, on the side the reflection leaves from
+            let reflect_ray = surface.spawn_ray(reflect_dir, time, self.ignore_origin_triangle);
 
-            // If the material is reflective and we haven't exceeded max depth
-            if reflectivity > 0.0 && depth < MAX_REFLECTION_DEPTH {
-                // Calculate reflection direction: R = D - 2(D·N)N
-                let d_dot_n = direction.dot(&n);
-                let reflect_dir = (direction - n * 2.0 * d_dot_n).normalised();
+            // Recursively trace the reflected ray
+            let reflected_color = self.get_ray_colour_recursive(&reflect_ray, depth + 1);
 
-                // Offset the origin slightly to avoid self-intersection
-                let reflect_origin = p + n * SURFACE_OFFSET;
+            // Blend local color with reflected color based on reflectivity
+            return local_color * (1.0 - reflectivity) + reflected_color * reflectivity;
+        }
 
-                // Recursively trace the reflected ray
-                let reflected_color =
-                    self.get_ray_colour_recursive(reflect_origin, reflect_dir, depth + 1);
+        local_color
+    }
 
-                // Blend local color with reflected color based on reflectivity
-                let reflected_vec = Vector3d {
-                    x: reflected_color.r as f64,
-                    y: reflected_color.g as f64,
-                    z: reflected_color.b as f64,
-                };
+    /// Whether each light reaches the surface, only point lights can be blocked
+    fn lights_visible(&self, surface: &SurfacePoint, time: f64) -> Vec<bool> {
+        self.lights
+            .iter()
+            .map(|light| match light {
+                Light::Point { position, .. } => {
+                    self.triangle_exists_between_points(surface, position, time)
+                }
+                _ => true,
+            })
+            .collect()
+    }
 
-                let final_color = local_color * (1.0 - reflectivity) + reflected_vec * reflectivity;
+    /// Find what the first surface along the ray looks like before any lighting is applied,
+    /// used to fill the arbitrary output variable buffers.
+    pub fn get_surface_sample(&self, ray: &Ray) -> Option<SurfaceSample> {
+        let intersection = self.scene_data.intersect(ray, f64::INFINITY)?;
 
-                return Color {
-                    r: final_color.x.clamp(0.0, 255.0) as u8,
-                    g: final_color.y.clamp(0.0, 255.0) as u8,
-                    b: final_color.z.clamp(0.0, 255.0) as u8,
-                };
-            }
+        Some(self.surface_sample(&intersection, ray))
+    }
 
-            return Color {
-                r: local_color.x.clamp(0.0, 255.0) as u8,
-                g: local_color.y.clamp(0.0, 255.0) as u8,
-                b: local_color.z.clamp(0.0, 255.0) as u8,
-            };
-        } else {
-            return WHITE; // nothing, void
+    fn surface_sample(
+        &self,
+        intersection: &RayTriangleIntersectionResult,
+        ray: &Ray,
+    ) -> SurfaceSample {
+        let tex_sample = self.sample_texture(intersection, ray);
+        let normal = self.get_normal_at_intersection(
+            intersection,
+            ray,
+            tex_sample.tex_x_index,
+            tex_sample.tex_y_index,
+        );
+
+        SurfaceSample {
+            depth: intersection.t,
+            normal,
+            albedo: tex_sample.colour,
+            uv: (tex_sample.tex_x, tex_sample.tex_y),
+            triangle_index: intersection.triangle_index,
+            material_id: self.material(intersection, ray).id,
+        }
+    }
+
+    /// The material of the triangle the ray hit
+    fn material(&self, intersection: &RayTriangleIntersectionResult, ray: &Ray) -> &Material {
+        let id = self.scene_data.material_id(intersection, ray);
+        &self.scene_data.material_map.materials[id]
+    }
+
+    /// The material's texture where the ray hit
+    fn sample_texture(
+        &self,
+        intersection: &RayTriangleIntersectionResult,
+        ray: &Ray,
+    ) -> TextureSample {
+        let tex = &self.material(intersection, ray).texture;
+        let object_ray = self.scene_data.object_ray(intersection, ray);
+        let (tex_x, tex_y) = self
+            .scene_data
+            .mesh_of(intersection)
+            .primitive(intersection.triangle_index)
+            .tex_coords_at(&SurfaceHit::from_intersection(&object_ray, intersection));
+
+        let tex_x_index = ((tex_x * tex.width as f64) as usize) % tex.width;
+        let tex_y_index = ((tex_y * tex.height as f64) as usize) % tex.height;
+
+        TextureSample {
+            colour: tex.colours[tex.width * tex_y_index + tex_x_index],
+            tex_x,
+            tex_y,
+            tex_x_index,
+            tex_y_index,
         }
     }
 
     pub fn get_normal_at_intersection(
         &self,
         intersection: &RayTriangleIntersectionResult,
+        ray: &Ray,
         tex_x_index: usize,
         tex_y_index: usize,
     ) -> Vector3d {
-        let w = 1.0 - intersection.u - intersection.v;
+        let object_ray = self.scene_data.object_ray(intersection, ray);
+        let normal = self
+            .scene_data
+            .mesh_of(intersection)
+            .primitive(intersection.triangle_index)
+            .normal_at(&SurfaceHit::from_intersection(&object_ray, intersection));
 
-        let mut n = intersection.triangle.v2_normal_coords * intersection.u
-            + intersection.triangle.v3_normal_coords * intersection.v
-            + intersection.triangle.v1_normal_coords * w;
+        let mut n = self.scene_data.normal_to_scene(intersection, normal);
 
-        if let Some(bump_map) = &intersection.triangle.material.bump_map {
+        if let Some(bump_map) = &self.material(intersection, ray).bump_map {
             let mut bump_vector: Vector3d =
                 bump_map.colours[bump_map.width * tex_y_index + tex_x_index].into();
             bump_vector = bump_vector.normalised();
@@ -158,42 +304,31 @@ impl RayTracer {
             };
         }
 
-        return n.normalised();
+        n.normalised()
     }
 
     fn triangle_exists_between_points(
         &self,
-        origin: &Vector3d,
-        normal: &Vector3d,
+        origin: &SurfacePoint,
         target: &Vector3d,
+        time: f64,
     ) -> bool {
-        let direction = *target - *origin;
-        // Offset along the surface normal to avoid self-intersection
-        let new_origin = *origin + *normal * SURFACE_OFFSET;
-
-        let ray = Ray {
-            origin: new_origin,
-            direction,
-        };
-
-        let max_t = direction.length();
-
-        let tri = ray.intersect_with_octant_with_max_t(&self.scene_data.octree, 0, max_t);
-
-        if let None = tri {
-            return true;
-        }
+        // The target is at t = 1, stop just short of it
+        let ray = origin.spawn_ray_to(*target, time, self.ignore_origin_triangle);
 
-        return false;
+        !self
+            .scene_data
+            .intersects_anything(&ray, 1.0 - SHADOW_EPSILON)
     }
 
     /// Given all the lights in the scene, calculate a vector of intensities
     /// for an r, g, b colour value.
     fn compute_lighting_intensity(
         &self,
-        point: &Vector3d,
+        surface: &SurfacePoint,
         normal: &Vector3d,
         v: &Vector3d,
+        lights_visible: &[bool],
         material: &Material,
     ) -> Vector3d {
         let mut i = Vector3d {
@@ -202,7 +337,7 @@ impl RayTracer {
             z: 0.0,
         };
 
-        for light in &self.lights {
+        for (light, &light_hits_point) in self.lights.iter().zip(lights_visible) {
             match light {
                 Light::Ambient { intensity } => {
                     i += material.ambient_color_coefficient * *intensity;
@@ -229,14 +364,11 @@ impl RayTracer {
                     intensity,
                     position,
                 } => {
-                    let light_hits_point =
-                        self.triangle_exists_between_points(point, normal, position);
-
                     if !light_hits_point {
-                        break;
+                        continue;
                     }
 
-                    let l = *position - *point;
+                    let l = *position - surface.point;
                     let n_dot_l = normal.dot(&l);
 
                     i += self.compute_diffuse_lighting_intensity(
@@ -254,7 +386,7 @@ impl RayTracer {
             }
         }
 
-        return i;
+        i
     }
 
     fn compute_diffuse_lighting_intensity(
@@ -286,8 +418,8 @@ impl RayTracer {
         material: &Material,
     ) -> Vector3d {
         if s != -1.0 {
-            let r = (*normal * 2.0) * normal.dot(&l) - *l;
-            let r_dot_v = r.dot(&v);
+            let r = (*normal * 2.0) * normal.dot(l) - *l;
+            let r_dot_v = r.dot(v);
 
             if r_dot_v > 0.0 {
                 return material.specular_color_coefficient
@@ -303,3 +435,78 @@ impl RayTracer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::collision::{accelerator::AccelerationStructure, bvh::Bvh, instance::InstanceTree};
+    use crate::scene::{entities::Texture, graph::SceneGraph, material::MaterialMap, mesh::Mesh};
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    #[test]
+    fn test_lights_after_a_shadowed_one_still_light_the_surface() {
+        let point_light = |x| Light::Point {
+            intensity: 0.5,
+            position: vector(x, 0.0, -5.0),
+        };
+        let tracer = RayTracer {
+            scene_data: SceneData {
+                mesh: Arc::new(Mesh::default()),
+                material_map: MaterialMap {
+                    textures: vec![],
+                    materials: vec![],
+                    ids_by_name: HashMap::new(),
+                },
+                acceleration_structure: AccelerationStructure::Bvh(Bvh::build(Arc::default())),
+                instances: InstanceTree::default(),
+                graph: SceneGraph::default(),
+            },
+            lights: vec![point_light(-1.0), point_light(1.0)],
+            camera: Camera::new(vector(0.0, 0.0, -10.0)),
+            ignore_origin_triangle: false,
+        };
+        let material = Material {
+            name: String::from("grey"),
+            id: 0,
+            ambient_color_coefficient: vector(1.0, 1.0, 1.0),
+            diffuse_color_coefficient: vector(1.0, 1.0, 1.0),
+            specular_color_coefficient: vector(0.0, 0.0, 0.0),
+            specular_weight: -1.0,
+            texture: Arc::new(Texture {
+                colours: vec![WHITE],
+                width: 1,
+                height: 1,
+            }),
+            bump_map: None,
+            reflectivity: 0.0,
+        };
+        let surface = SurfacePoint {
+            point: vector(0.0, 0.0, 0.0),
+            error: vector(0.0, 0.0, 0.0),
+            normal: vector(0.0, 0.0, -1.0),
+            triangle_index: 0,
+            instance: None,
+        };
+        let lit = |lights_visible: &[bool]| {
+            tracer.compute_lighting_intensity(
+                &surface,
+                &surface.normal,
+                &vector(0.0, 0.0, -1.0),
+                lights_visible,
+                &material,
+            )
+        };
+
+        // The two lights are mirror images, so each gives the surface the same light
+        let second_only = lit(&[false, true]);
+        assert!(second_only.x > 0.0);
+        assert_eq!(second_only, lit(&[true, false]));
+        assert_eq!(lit(&[true, true]), second_only + second_only);
+    }
+}
@@ -0,0 +1,133 @@
+use std::f64::consts::PI;
+
+/// A small, fast pseudo random number generator (splitmix64).
+/// It is seeded per pixel so renders are deterministic and each rayon worker
+/// can own its own generator without any locking.
+pub struct Sampler {
+    state: u64,
+}
+
+impl Sampler {
+    pub fn new(seed: u64) -> Sampler {
+        Sampler { state: seed }
+    }
+
+    /// Create a sampler whose sequence depends on the pixel it is used for.
+    pub fn for_pixel(x: i32, y: i32) -> Sampler {
+        let seed = ((x as u32 as u64) << 32) | (y as u32 as u64);
+
+        Sampler::new(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+
+        z ^ (z >> 31)
+    }
+
+    /// A uniformly distributed value in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        // Use the top 53 bits so every value is exactly representable.
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Map two uniform values in [0, 1) to a point on the unit disk using the
+/// concentric mapping, which keeps stratified samples well spread out.
+pub fn sample_unit_disk(u1: f64, u2: f64) -> (f64, f64) {
+    let ox = 2.0 * u1 - 1.0;
+    let oy = 2.0 * u2 - 1.0;
+
+    if ox == 0.0 && oy == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let (r, theta) = if ox.abs() > oy.abs() {
+        (ox, (PI / 4.0) * (oy / ox))
+    } else {
+        (oy, (PI / 2.0) - (PI / 4.0) * (ox / oy))
+    };
+
+    (r * theta.cos(), r * theta.sin())
+}
+
+/// Uniformly sample a point inside a regular polygon inscribed in the unit circle.
+/// The polygon is split into `sides` wedges around the centre, `u1` picks the wedge
+/// and `u2`, `u3` pick a point inside it.
+pub fn sample_regular_polygon(sides: u32, rotation: f64, u1: f64, u2: f64, u3: f64) -> (f64, f64) {
+    let wedge_angle = 2.0 * PI / sides as f64;
+    let wedge = ((u1 * sides as f64) as u32).min(sides - 1);
+
+    let a0 = rotation + wedge as f64 * wedge_angle;
+    let a1 = a0 + wedge_angle;
+
+    // Uniform point in the triangle (centre, corner a0, corner a1)
+    let su = u2.sqrt();
+    let b0 = su * (1.0 - u3);
+    let b1 = su * u3;
+
+    (b0 * a0.cos() + b1 * a1.cos(), b0 * a0.sin() + b1 * a1.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_samples_stay_on_the_disk() {
+        let mut sampler = Sampler::new(7);
+
+        for _ in 0..10_000 {
+            let (x, y) = sample_unit_disk(sampler.next_f64(), sampler.next_f64());
+            assert!(x * x + y * y <= 1.0 + 1e-12, "({x}, {y}) is off the disk");
+        }
+
+        // The corners of the square map onto the rim
+        for (u1, u2) in [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0), (0.5, 1.0)] {
+            let (x, y) = sample_unit_disk(u1, u2);
+            assert!(((x * x + y * y).sqrt() - 1.0).abs() < 1e-12);
+        }
+        assert_eq!(sample_unit_disk(0.5, 0.5), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_polygon_samples_stay_inside_the_polygon() {
+        let mut sampler = Sampler::new(11);
+
+        for sides in 3..9 {
+            let rotation = 0.3 * sides as f64;
+            let wedge_angle = 2.0 * PI / sides as f64;
+            // Distance from the centre to the middle of each side
+            let apothem = (wedge_angle / 2.0).cos();
+
+            for _ in 0..2_000 {
+                let (x, y) = sample_regular_polygon(
+                    sides,
+                    rotation,
+                    sampler.next_f64(),
+                    sampler.next_f64(),
+                    sampler.next_f64(),
+                );
+
+                // Inside every side's half plane
+                for side in 0..sides {
+                    let middle = rotation + (side as f64 + 0.5) * wedge_angle;
+                    let distance = x * middle.cos() + y * middle.sin();
+                    assert!(
+                        distance <= apothem + 1e-12,
+                        "({x}, {y}) is outside the {sides}-gon"
+                    );
+                }
+            }
+
+            // u1 of 1.0 is clamped into the last wedge rather than past it
+            let (x, y) = sample_regular_polygon(sides, rotation, 1.0, 1.0, 0.0);
+            let last_corner = rotation + (sides - 1) as f64 * wedge_angle;
+            assert!((x - last_corner.cos()).abs() < 1e-12 && (y - last_corner.sin()).abs() < 1e-12);
+        }
+    }
+}
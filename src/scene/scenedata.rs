@@ -1,13 +1,203 @@
-use crate::collision::octree::Octree;
+use std::sync::Arc;
 
-use super::{engine::Vector3d, entities::Triangle, material::MaterialMap};
+use crate::collision::{
+    accelerator::{sort_hits, AccelerationStructure, Accelerator, AcceleratorKind},
+    bvh::Bvh,
+    instance::InstanceTree,
+    octree::Octree,
+    packet::{PacketHits, PACKET_WIDTH},
+    ray::{Ray, RayTriangleIntersectionResult, TriangleTest},
+    spawn::SurfacePoint,
+};
+
+use super::{
+    engine::Vector3d,
+    graph::SceneGraph,
+    material::MaterialMap,
+    mesh::{Mesh, MeshShape},
+    shape::SurfaceHit,
+};
 
 #[derive(Debug, PartialEq)]
 pub struct SceneData {
-    pub triangles: Vec<Triangle>,
-    pub vertices: Vec<Vector3d>,
-    pub vertex_texture_coords: Vec<Vector3d>,
-    pub vertex_normal_coords: Vec<Vector3d>,
+    /// Shared with the acceleration structure, triangles are only stored once
+    pub mesh: Arc<Mesh>,
     pub material_map: MaterialMap,
-    pub octree: Octree,
+    pub acceleration_structure: AccelerationStructure,
+    /// Copies of shared meshes placed around the scene, using the same material map
+    pub instances: InstanceTree,
+    /// The named objects and groups the mesh was loaded from
+    pub graph: SceneGraph,
+}
+
+impl SceneData {
+    /// Replace the acceleration structure with a freshly built one of the given kind
+    pub fn rebuild_acceleration_structure(&mut self, kind: AcceleratorKind) {
+        self.acceleration_structure = match kind {
+            AcceleratorKind::Octree(options) => {
+                AccelerationStructure::Octree(Octree::build_around(Arc::clone(&self.mesh), options))
+            }
+            AcceleratorKind::Bvh => AccelerationStructure::Bvh(Bvh::build(Arc::clone(&self.mesh))),
+        };
+    }
+
+    /// Replace the mesh with one built from the scene graph, so changes to its nodes show up,
+    /// and rebuild the acceleration structure over it. Triangle indices then follow the graph's
+    /// order rather than the model file's, see `SceneGraph::build_mesh`. The mesh's shapes
+    /// aren't part of the graph and are kept as they are.
+    pub fn rebuild_from_graph(&mut self) {
+        let mut mesh = self.graph.build_mesh();
+        mesh.shapes.clone_from(&self.mesh.shapes);
+
+        self.mesh = Arc::new(mesh);
+        self.rebuild_acceleration_structure(self.acceleration_structure.kind());
+    }
+
+    /// Add analytic shapes to the scene's mesh and rebuild the acceleration structure,
+    /// so they're traced alongside the triangles
+    pub fn add_shapes(&mut self, shapes: impl IntoIterator<Item = MeshShape>) {
+        let mut mesh = (*self.mesh).clone();
+        mesh.shapes.extend(shapes);
+
+        self.mesh = Arc::new(mesh);
+        self.rebuild_acceleration_structure(self.acceleration_structure.kind());
+    }
+
+    /// Switch the ray-triangle test used by the scene's structure and every instance's
+    pub fn set_triangle_test(&mut self, test: TriangleTest) {
+        self.acceleration_structure.set_triangle_test(test);
+
+        for prototype in &mut self.instances.prototypes {
+            prototype.set_triangle_test(test);
+        }
+    }
+
+    /// The closest intersection with the scene's mesh or any instance before `max_t`
+    pub fn intersect(&self, ray: &Ray, max_t: f64) -> Option<RayTriangleIntersectionResult> {
+        let own = self.acceleration_structure.closest_hit(ray, max_t);
+        let instanced = self
+            .instances
+            .closest_hit(ray, own.map_or(max_t, |hit| hit.t));
+
+        instanced.or(own)
+    }
+
+    /// `intersect` for several rays at once, the scene's own mesh is traced as a packet
+    pub fn intersect_packet(&self, rays: [Option<&Ray>; PACKET_WIDTH], max_t: f64) -> PacketHits {
+        let mut hits = self.acceleration_structure.closest_hits(rays, max_t);
+
+        if !self.instances.is_empty() {
+            for (hit, ray) in hits.iter_mut().zip(rays) {
+                if let Some(ray) = ray {
+                    let max_t = hit.map_or(max_t, |hit| hit.t);
+
+                    if let Some(instanced) = self.instances.closest_hit(ray, max_t) {
+                        *hit = Some(instanced);
+                    }
+                }
+            }
+        }
+
+        hits
+    }
+
+    /// Whether the scene's mesh or any instance intersects the ray before `max_t`
+    pub fn intersects_anything(&self, ray: &Ray, max_t: f64) -> bool {
+        self.acceleration_structure.any_hit(ray, max_t) || self.instances.any_hit(ray, max_t)
+    }
+
+    /// `intersects_anything` for several rays at once
+    pub fn intersects_anything_packet(
+        &self,
+        rays: [Option<&Ray>; PACKET_WIDTH],
+        max_t: f64,
+    ) -> [bool; PACKET_WIDTH] {
+        let mut blocked = self.acceleration_structure.any_hits(rays, max_t);
+
+        for (blocked, ray) in blocked.iter_mut().zip(rays) {
+            if let Some(ray) = ray {
+                *blocked = *blocked || self.instances.any_hit(ray, max_t);
+            }
+        }
+
+        blocked
+    }
+
+    /// Every intersection with the scene's mesh and instances before `max_t`, nearest first
+    pub fn intersect_all(&self, ray: &Ray, max_t: f64) -> Vec<RayTriangleIntersectionResult> {
+        let mut hits = self.acceleration_structure.all_hits(ray, max_t);
+
+        if !self.instances.is_empty() {
+            hits.extend(self.instances.all_hits(ray, max_t));
+            sort_hits(&mut hits);
+        }
+
+        hits
+    }
+
+    /// The mesh an intersection's triangle is one of
+    pub fn mesh_of(&self, intersection: &RayTriangleIntersectionResult) -> &Mesh {
+        match intersection.instance {
+            Some(instance) => self.instances.mesh(instance),
+            None => &self.mesh,
+        }
+    }
+
+    /// Id of the material at the ray's intersection, an instance's material override if it has one
+    pub fn material_id(&self, intersection: &RayTriangleIntersectionResult, ray: &Ray) -> usize {
+        let object_ray = self.object_ray(intersection, ray);
+        let triangle_material = self.mesh_of(intersection).material_at(
+            intersection.triangle_index,
+            &SurfaceHit::from_intersection(&object_ray, intersection),
+        );
+
+        match intersection.instance {
+            Some(instance) => self.instances.instances[instance]
+                .material
+                .map_or(triangle_material, |id| id as usize),
+            None => triangle_material,
+        }
+    }
+
+    /// The ray that made an intersection moved into the space of the intersection's mesh,
+    /// a copy of it for the scene's own mesh
+    pub fn object_ray(&self, intersection: &RayTriangleIntersectionResult, ray: &Ray) -> Ray {
+        match intersection.instance {
+            Some(instance) => self.instances.object_ray(instance, ray),
+            None => ray.clone(),
+        }
+    }
+
+    /// Where the ray's intersection is in the scene, see `SurfacePoint::from_intersection`
+    pub fn surface_point(
+        &self,
+        intersection: &RayTriangleIntersectionResult,
+        ray: &Ray,
+    ) -> SurfacePoint {
+        let surface = SurfacePoint::from_intersection(
+            self.mesh_of(intersection),
+            intersection,
+            &self.object_ray(intersection, ray),
+        );
+
+        match intersection.instance {
+            Some(instance) => surface.transformed(&self.instances.instances[instance].transform),
+            None => surface,
+        }
+    }
+
+    /// A normal from an intersection's mesh moved into the scene, unchanged for the scene's
+    /// own mesh. The result isn't normalised.
+    pub fn normal_to_scene(
+        &self,
+        intersection: &RayTriangleIntersectionResult,
+        normal: Vector3d,
+    ) -> Vector3d {
+        match intersection.instance {
+            Some(instance) => self.instances.instances[instance]
+                .transform
+                .transform_normal(normal),
+            None => normal,
+        }
+    }
 }
@@ -0,0 +1,728 @@
+use std::{error::Error, fmt};
+
+use crate::collision::{aabb::Aabb, ray::Ray};
+
+use super::{
+    engine::{abs, gamma, Vector3d},
+    shape::{face_axes, fraction, to_array, Hittable, SurfaceHit},
+};
+
+/// Most steps a ray takes through a field's box before giving up on it. Rays that only graze
+/// a surface creep along it in tiny steps, and are counted as missing it once they run out.
+static MAX_STEPS: usize = 512;
+
+/// How close to the surface a ray has to get to hit it, as a fraction of the size of the
+/// field's box
+static TOLERANCE: f64 = 1e-5;
+
+/// Mandelbulb points this far from its centre, in units of its scale, are outside the set
+/// after a single iteration, so the whole fractal is inside a box of twice this size
+static MANDELBULB_BAILOUT: f64 = 2.0;
+
+/// A signed distance field: how far any point is from a surface, negative inside it.
+///
+/// Primitives are exact and the combinations only ever underestimate the distance, which is
+/// all sphere tracing needs to never step through the surface. The Mandelbulb's distance is an
+/// estimate that can overshoot, tracing goes back to the surface when it does.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sdf {
+    Sphere {
+        centre: Vector3d,
+        radius: f64,
+    },
+    Cuboid {
+        centre: Vector3d,
+        half_size: Vector3d,
+    },
+    /// A ring around the y axis
+    Torus {
+        centre: Vector3d,
+        major_radius: f64,
+        minor_radius: f64,
+    },
+    /// Every point within `radius` of the line from `a` to `b`
+    Capsule {
+        a: Vector3d,
+        b: Vector3d,
+        radius: f64,
+    },
+    /// The power `power` Mandelbulb, `scale` times its usual size
+    Mandelbulb {
+        centre: Vector3d,
+        scale: f64,
+        power: f64,
+        iterations: u32,
+    },
+    /// Both fields, blended together where they're within `smoothness` of each other.
+    /// A smoothness of 0 is a plain union.
+    Union {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        smoothness: f64,
+    },
+    /// `a` with `b` carved out of it, blended like `Union`
+    Subtraction {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        smoothness: f64,
+    },
+    /// Only where both fields are, blended like `Union`
+    Intersection {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        smoothness: f64,
+    },
+    /// The field repeated forever every `period` along each axis, an axis with a period of 0
+    /// isn't repeated. The field should fit inside one period to keep its distances right.
+    Repeat {
+        sdf: Box<Sdf>,
+        period: Vector3d,
+    },
+}
+
+impl Sdf {
+    pub fn distance(&self, p: Vector3d) -> f64 {
+        match self {
+            Sdf::Sphere { centre, radius } => (p - *centre).length() - radius,
+            Sdf::Cuboid { centre, half_size } => {
+                let q = abs(p - *centre) - *half_size;
+                let outside = Vector3d {
+                    x: q.x.max(0.0),
+                    y: q.y.max(0.0),
+                    z: q.z.max(0.0),
+                };
+
+                outside.length() + q.x.max(q.y).max(q.z).min(0.0)
+            }
+            Sdf::Torus {
+                centre,
+                major_radius,
+                minor_radius,
+            } => {
+                let p = p - *centre;
+                let across = f64::hypot(p.x, p.z) - major_radius;
+
+                f64::hypot(across, p.y) - minor_radius
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let pa = p - *a;
+                let ba = *b - *a;
+                let along = (pa.dot(&ba) / ba.dot(&ba)).clamp(0.0, 1.0);
+
+                (pa - ba * along).length() - radius
+            }
+            Sdf::Mandelbulb {
+                centre,
+                scale,
+                power,
+                iterations,
+            } => mandelbulb((p - *centre) / *scale, *power, *iterations) * scale,
+            Sdf::Union { a, b, smoothness } => {
+                smooth_min(a.distance(p), b.distance(p), *smoothness)
+            }
+            Sdf::Subtraction { a, b, smoothness } => {
+                -smooth_min(-a.distance(p), b.distance(p), *smoothness)
+            }
+            Sdf::Intersection { a, b, smoothness } => {
+                -smooth_min(-a.distance(p), -b.distance(p), *smoothness)
+            }
+            Sdf::Repeat { sdf, period } => {
+                let wrap = |x: f64, period: f64| {
+                    if period > 0.0 {
+                        x - period * (x / period).round()
+                    } else {
+                        x
+                    }
+                };
+
+                sdf.distance(Vector3d {
+                    x: wrap(p.x, period.x),
+                    y: wrap(p.y, period.y),
+                    z: wrap(p.z, period.z),
+                })
+            }
+        }
+    }
+
+    /// A box around everything inside the field, `None` for repeated fields that go on forever
+    pub fn bounds(&self) -> Option<Aabb> {
+        let around = |centre: Vector3d, half_size: Vector3d| Aabb {
+            min_coords: centre - half_size,
+            max_coords: centre + half_size,
+        };
+        let cube = |size: f64| Vector3d {
+            x: size,
+            y: size,
+            z: size,
+        };
+
+        match self {
+            Sdf::Sphere { centre, radius } => Some(around(*centre, cube(*radius))),
+            Sdf::Cuboid { centre, half_size } => Some(around(*centre, *half_size)),
+            Sdf::Torus {
+                centre,
+                major_radius,
+                minor_radius,
+            } => {
+                let across = major_radius + minor_radius;
+
+                Some(around(
+                    *centre,
+                    Vector3d {
+                        x: across,
+                        y: *minor_radius,
+                        z: across,
+                    },
+                ))
+            }
+            Sdf::Capsule { a, b, radius } => {
+                Some(around(*a, cube(*radius)).union(&around(*b, cube(*radius))))
+            }
+            Sdf::Mandelbulb { centre, scale, .. } => {
+                Some(around(*centre, cube(MANDELBULB_BAILOUT * scale)))
+            }
+            // Blending only ever adds up to a quarter of the smoothness to the closer distance
+            Sdf::Union { a, b, smoothness } => {
+                let bounds = a.bounds()?.union(&b.bounds()?);
+                Some(around(
+                    bounds.centre(),
+                    (bounds.max_coords - bounds.min_coords) * 0.5 + cube(smoothness * 0.25),
+                ))
+            }
+            Sdf::Subtraction { a, .. } => a.bounds(),
+            Sdf::Intersection { a, b, .. } => match (a.bounds(), b.bounds()) {
+                (Some(a), Some(b)) => Some(a.intersection(&b)),
+                (a, b) => a.or(b),
+            },
+            Sdf::Repeat { .. } => None,
+        }
+    }
+}
+
+/// Distance estimate to the Mandelbulb at its usual size, from how quickly the point escapes
+fn mandelbulb(p: Vector3d, power: f64, iterations: u32) -> f64 {
+    let mut z = p;
+    let mut derivative = 1.0;
+    let mut r = z.length();
+
+    for _ in 0..iterations {
+        if r > MANDELBULB_BAILOUT {
+            break;
+        }
+
+        let theta = f64::atan2(f64::hypot(z.x, z.z), z.y) * power;
+        let phi = f64::atan2(z.z, z.x) * power;
+        derivative = r.powf(power - 1.0) * power * derivative + 1.0;
+
+        z = Vector3d {
+            x: theta.sin() * phi.cos(),
+            y: theta.cos(),
+            z: theta.sin() * phi.sin(),
+        } * r.powf(power)
+            + p;
+        r = z.length();
+    }
+
+    // Points that never move, like the centre, are deep inside
+    if r == 0.0 {
+        return 0.0;
+    }
+
+    0.5 * r.ln() * r / derivative
+}
+
+/// The smaller of two distances, rounded off where they're within `smoothness` of each other
+fn smooth_min(a: f64, b: f64, smoothness: f64) -> f64 {
+    if smoothness <= 0.0 {
+        return a.min(b);
+    }
+
+    let h = f64::max(smoothness - (a - b).abs(), 0.0) / smoothness;
+    a.min(b) - h * h * smoothness * 0.25
+}
+
+/// A signed distance field traced by sphere tracing inside a box, which acceleration
+/// structures sort it by like any other shape. Hits have no surface coordinates of their own,
+/// the point, normal and texture coordinates all come from where along the ray it was hit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SdfShape {
+    pub sdf: Sdf,
+    /// Nothing outside the box is traced, a field that sticks out of it is cut off
+    pub bounds: Aabb,
+}
+
+/// A field with no bounds of its own, like one with an `Sdf::Repeat` in it, was given to
+/// `SdfShape::new`. It has to be cut off with `SdfShape::with_bounds` instead.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UnboundedSdf;
+
+impl fmt::Display for UnboundedSdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "repeated fields go on forever and need to be given bounds"
+        )
+    }
+}
+
+impl Error for UnboundedSdf {}
+
+impl SdfShape {
+    /// The field in its own bounds, grown a little so rays always start tracing off the surface
+    pub fn new(sdf: Sdf) -> Result<SdfShape, UnboundedSdf> {
+        let bounds = sdf.bounds().ok_or(UnboundedSdf)?;
+        let margin = (bounds.max_coords - bounds.min_coords).length() * 0.01;
+        let margin = Vector3d {
+            x: margin,
+            y: margin,
+            z: margin,
+        };
+
+        Ok(SdfShape::with_bounds(
+            sdf,
+            Aabb {
+                min_coords: bounds.min_coords - margin,
+                max_coords: bounds.max_coords + margin,
+            },
+        ))
+    }
+
+    /// The field cut off at `bounds`, which is how fields that go on forever are traced
+    pub fn with_bounds(sdf: Sdf, bounds: Aabb) -> SdfShape {
+        SdfShape { sdf, bounds }
+    }
+
+    fn tolerance(&self) -> f64 {
+        (self.bounds.max_coords - self.bounds.min_coords).length() * TOLERANCE
+    }
+
+    /// Sphere trace the ray through the box, calling `visit` with t wherever it crosses the
+    /// surface in front of its origin until `visit` returns false
+    fn trace(&self, ray: &Ray, mut visit: impl FnMut(f64) -> bool) {
+        let Some((entry, exit)) =
+            ray.intersect_aabb_interval(&self.bounds, &ray.inverse_direction())
+        else {
+            return;
+        };
+        let tolerance = self.tolerance();
+        let speed = ray.direction.length();
+
+        // Which side of the surface the ray is on, 1 outside and -1 inside. Rays starting
+        // outside the box are outside the field, rays starting right on the surface don't know
+        // until they've moved away from it.
+        let mut side = if entry > 0.0 { 1.0 } else { 0.0 };
+        let mut t = entry;
+        let mut previous_t = entry;
+        let distance_at = |t: f64| self.sdf.distance(ray.origin + ray.direction * t);
+
+        for _ in 0..MAX_STEPS {
+            if t > exit {
+                return;
+            }
+
+            let mut distance = distance_at(t);
+
+            if side == 0.0 {
+                if distance.abs() >= tolerance {
+                    side = distance.signum();
+                }
+            } else if distance * side < tolerance {
+                // Fields that only estimate their distance, like the Mandelbulb, can step
+                // right through the surface, so go back to it from the other side
+                let mut before = previous_t;
+                while distance * side < -tolerance && (t - before) * speed > tolerance {
+                    let middle = (before + t) * 0.5;
+                    let middle_distance = distance_at(middle);
+
+                    if middle_distance * side >= tolerance {
+                        before = middle;
+                    } else {
+                        t = middle;
+                        distance = middle_distance;
+                    }
+                }
+
+                if t > f64::EPSILON && !visit(t) {
+                    return;
+                }
+
+                // Carry on through the surface, the ray is on its other side once clear of it
+                side = 0.0;
+            }
+
+            previous_t = t;
+            t += f64::max(distance.abs(), tolerance) / speed;
+        }
+    }
+}
+
+impl Hittable for SdfShape {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        let mut hit = None;
+        self.trace(ray, |t| {
+            hit = Some((t, 0.0, 0.0));
+            false
+        });
+
+        hit
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        let mut hits = vec![];
+        self.trace(ray, |t| {
+            hits.push((t, 0.0, 0.0));
+            true
+        });
+
+        hits
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        let offset = hit.ray.direction * hit.t;
+        let tolerance = self.tolerance();
+
+        // The surface is anywhere within the tolerance, give or take the rounding of getting here
+        (
+            hit.ray.origin + offset,
+            Vector3d {
+                x: tolerance,
+                y: tolerance,
+                z: tolerance,
+            } * 3.0
+                + (abs(hit.ray.origin) + abs(offset)) * gamma(3),
+        )
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        let p = hit.ray.origin + hit.ray.direction * hit.t;
+        let h = self.tolerance();
+        let slope = |step: Vector3d| self.sdf.distance(p + step) - self.sdf.distance(p - step);
+
+        let gradient = Vector3d {
+            x: slope(Vector3d {
+                x: h,
+                y: 0.0,
+                z: 0.0,
+            }),
+            y: slope(Vector3d {
+                x: 0.0,
+                y: h,
+                z: 0.0,
+            }),
+            z: slope(Vector3d {
+                x: 0.0,
+                y: 0.0,
+                z: h,
+            }),
+        };
+
+        // Flat spots in the field, like the middle of a blend, face back along the ray
+        if gradient.length() == 0.0 {
+            return -hit.ray.direction.normalised();
+        }
+
+        gradient.normalised()
+    }
+
+    /// Projected onto the sides of the box, from whichever one the surface faces most
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        let point = to_array(hit.ray.origin + hit.ray.direction * hit.t);
+        let normal = to_array(self.normal_at(hit));
+        let min = to_array(self.bounds.min_coords);
+        let max = to_array(self.bounds.max_coords);
+
+        let axis = (0..3)
+            .max_by(|&a, &b| normal[a].abs().total_cmp(&normal[b].abs()))
+            .unwrap();
+        let (_, across, up) = face_axes(axis * 2);
+
+        (
+            fraction(point[across], min[across], max[across]),
+            fraction(point[up], min[up], max[up]),
+        )
+    }
+
+    fn is_flat(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::spawn::SurfacePoint;
+    use crate::scene::{
+        csg::SolidHit,
+        mesh::{Mesh, MeshShape},
+        sampling::Sampler,
+        shape::{Shape, Sphere},
+    };
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    fn random_point(sampler: &mut Sampler, extent: f64) -> Vector3d {
+        vector(
+            (sampler.next_f64() * 2.0 - 1.0) * extent,
+            (sampler.next_f64() * 2.0 - 1.0) * extent,
+            (sampler.next_f64() * 2.0 - 1.0) * extent,
+        )
+    }
+
+    /// Rays from well outside the box towards somewhere near its middle
+    fn random_ray(sampler: &mut Sampler, bounds: &Aabb) -> Ray {
+        let origin = random_point(sampler, 1.0).normalised() * 20.0;
+        let target = bounds.centre() + random_point(sampler, 1.0);
+
+        Ray {
+            origin,
+            direction: target - origin,
+            time: 0.0,
+            ignored_triangle: None,
+        }
+    }
+
+    fn ray(origin: Vector3d, direction: Vector3d) -> Ray {
+        Ray {
+            origin,
+            direction,
+            time: 0.0,
+            ignored_triangle: None,
+        }
+    }
+
+    #[test]
+    fn test_sphere_field_hits_where_the_sphere_does() {
+        let mut sampler = Sampler::new(49);
+        let sphere = Sphere {
+            centre: vector(1.0, -0.5, 0.5),
+            radius: 1.5,
+        };
+        let field = SdfShape::new(Sdf::Sphere {
+            centre: sphere.centre,
+            radius: sphere.radius,
+        })
+        .unwrap();
+        let mut hits = 0;
+
+        for _ in 0..2000 {
+            let ray = random_ray(&mut sampler, &field.bounds);
+            let (expected, found) = match (sphere.intersect(&ray), field.intersect(&ray)) {
+                (Some(expected), Some(found)) => (expected, found),
+                (None, None) => continue,
+                // Rays only just grazing the sphere can go either way
+                _ => {
+                    let along = (sphere.centre - ray.origin).dot(&ray.direction)
+                        / ray.direction.dot(&ray.direction);
+                    let closest = ray.origin + ray.direction * along;
+                    assert!(((closest - sphere.centre).length() - sphere.radius).abs() < 1e-2);
+                    continue;
+                }
+            };
+            hits += 1;
+
+            // Tracing stops just short of the surface, never past it
+            assert!(found.0 <= expected.0);
+
+            let hit = SurfaceHit {
+                ray: &ray,
+                t: found.0,
+                u: found.1,
+                v: found.2,
+                solid: SolidHit::default(),
+            };
+            let exact = SurfaceHit {
+                ray: &ray,
+                t: expected.0,
+                u: expected.1,
+                v: expected.2,
+                solid: SolidHit::default(),
+            };
+            let (point, error) = field.point_at(&hit);
+            let distance = (point - sphere.centre).length() - sphere.radius;
+            assert!(distance.abs() < field.tolerance());
+            assert!(error.x.min(error.y).min(error.z) > field.tolerance());
+            assert!((field.normal_at(&hit) - sphere.normal_at(&exact)).length() < 1e-3);
+
+            let (tex_x, tex_y) = field.tex_coords_at(&hit);
+            assert!((0.0..=1.0).contains(&tex_x) && (0.0..=1.0).contains(&tex_y));
+        }
+
+        assert!(hits > 500, "only {hits} rays hit the sphere");
+    }
+
+    #[test]
+    fn test_combined_and_repeated_fields() {
+        let cube = Sdf::Cuboid {
+            centre: vector(0.0, 0.0, 0.0),
+            half_size: vector(1.0, 1.0, 1.0),
+        };
+        let ball = |x: f64| Sdf::Sphere {
+            centre: vector(x, 0.0, 0.0),
+            radius: 0.5,
+        };
+
+        // A ray from the middle of the cube, where a ball was carved out, hits the hole's wall
+        let carved = SdfShape::new(Sdf::Subtraction {
+            a: Box::new(cube.clone()),
+            b: Box::new(ball(0.0)),
+            smoothness: 0.0,
+        })
+        .unwrap();
+        let (t, _, _) = carved
+            .intersect(&ray(vector(0.0, 0.0, 0.0), vector(0.0, 2.0, 0.0)))
+            .unwrap();
+        assert!((t - 0.25).abs() < 1e-4);
+
+        // and one from outside hits the cube's side
+        let (t, _, _) = carved
+            .intersect(&ray(vector(-5.0, 0.7, 0.0), vector(1.0, 0.0, 0.0)))
+            .unwrap();
+        assert!((t - 4.0).abs() < 1e-4);
+
+        // Smoothing fills in the gap between two balls, a plain union leaves it
+        let pair = |smoothness| {
+            SdfShape::new(Sdf::Union {
+                a: Box::new(ball(-0.55)),
+                b: Box::new(ball(0.55)),
+                smoothness,
+            })
+            .unwrap()
+        };
+        let between = ray(vector(0.0, 5.0, 0.0), vector(0.0, -1.0, 0.0));
+        assert!(pair(0.0).intersect(&between).is_none());
+        assert!(pair(0.5).intersect(&between).is_some());
+
+        let rounded = Sdf::Intersection {
+            a: Box::new(cube),
+            b: Box::new(Sdf::Sphere {
+                centre: vector(0.0, 0.0, 0.0),
+                radius: 1.3,
+            }),
+            smoothness: 0.1,
+        };
+        assert!(rounded.distance(vector(1.0, 1.0, 1.0)) > 0.0);
+        assert!(rounded.distance(vector(0.9, 0.0, 0.0)) < 0.0);
+
+        // Balls every 2 along x and z, in a box big enough for a few of them
+        let balls = Sdf::Repeat {
+            sdf: Box::new(ball(0.0)),
+            period: vector(2.0, 0.0, 2.0),
+        };
+        assert_eq!(SdfShape::new(balls.clone()), Err(UnboundedSdf));
+        assert_eq!(
+            SdfShape::new(Sdf::Union {
+                a: Box::new(ball(0.0)),
+                b: Box::new(balls.clone()),
+                smoothness: 0.0,
+            }),
+            Err(UnboundedSdf)
+        );
+
+        let repeated = SdfShape::with_bounds(balls, Aabb::new(-7.0, 7.0, -1.0, 1.0, -7.0, 7.0));
+        let down = |x, z| repeated.intersect(&ray(vector(x, 5.0, z), vector(0.0, -1.0, 0.0)));
+        assert!(down(4.0, -6.0).is_some_and(|(t, _, _)| (t - 4.5).abs() < 1e-3));
+        assert!(down(3.0, -6.0).is_none());
+        assert!(down(8.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_mandelbulb_hits_are_on_its_surface() {
+        let mut sampler = Sampler::new(50);
+        let field = SdfShape::new(Sdf::Mandelbulb {
+            centre: vector(0.0, 1.0, 0.0),
+            scale: 2.0,
+            power: 8.0,
+            iterations: 12,
+        })
+        .unwrap();
+        let mut hits = 0;
+        let mut facing = 0;
+
+        for _ in 0..200 {
+            let ray = random_ray(&mut sampler, &field.bounds);
+            let Some((t, u, v)) = field.intersect(&ray) else {
+                continue;
+            };
+            hits += 1;
+
+            let hit = SurfaceHit {
+                ray: &ray,
+                t,
+                u,
+                v,
+                solid: SolidHit::default(),
+            };
+            let (point, _) = field.point_at(&hit);
+            let normal = field.normal_at(&hit);
+
+            // The estimate jumps where points stop escaping within the iterations, so hits
+            // can end up a little inside but never outside
+            assert!(field.sdf.distance(point) < field.tolerance());
+            assert!((normal.length() - 1.0).abs() < 1e-9);
+            if normal.dot(&ray.direction) < 0.0 {
+                facing += 1;
+            }
+        }
+
+        // Detail finer than the tolerance can tip the odd normal over
+        assert!(hits > 100, "only {hits} rays hit the Mandelbulb");
+        assert!(
+            facing * 10 > hits * 9,
+            "only {facing} of {hits} normals face the rays"
+        );
+    }
+
+    #[test]
+    fn test_rays_leaving_a_field_only_hit_it_again_going_in() {
+        let mut sampler = Sampler::new(51);
+
+        let fields = [
+            Sdf::Capsule {
+                a: vector(-1.0, 0.0, 0.5),
+                b: vector(1.0, 1.0, 0.0),
+                radius: 0.6,
+            },
+            Sdf::Sphere {
+                centre: vector(0.5, 0.0, 0.0),
+                radius: 0.8,
+            },
+        ];
+
+        for sdf in fields {
+            let shape = Shape::Sdf(SdfShape::new(sdf).unwrap());
+            let mut mesh = Mesh::default();
+            mesh.shapes.push(MeshShape {
+                shape: shape.clone(),
+                material: 0,
+            });
+
+            for _ in 0..1000 {
+                let ray = random_ray(&mut sampler, &shape.bounds().unwrap());
+                let Some(hit) = ray.intersect_with_shape(&shape, 0) else {
+                    continue;
+                };
+
+                let surface = SurfacePoint::from_intersection(&mesh, &hit, &ray);
+
+                // Both fields are convex, so nothing leaving the surface outwards hits it
+                let outwards = surface.normal + random_point(&mut sampler, 0.5);
+                if outwards.dot(&surface.normal) > 0.0 {
+                    let leaving = surface.spawn_ray(outwards, 0.0, true);
+                    assert!(leaving.intersect_with_shape(&shape, 0).is_none());
+                }
+
+                // and anything going in comes out the other side
+                let entering = surface.spawn_ray(-surface.normal, 0.0, true);
+                let exit = entering.intersect_with_shape(&shape, 0);
+                assert!(exit.is_some_and(|exit| exit.t > 0.0), "{shape:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,1392 @@
+use std::f64::consts::{PI, TAU};
+
+use crate::collision::{
+    aabb::Aabb,
+    ray::{Ray, RayTriangleIntersectionResult},
+};
+
+use super::{
+    csg::{CsgShape, SolidHit},
+    engine::{abs, gamma, Vector3d},
+    mesh::Mesh,
+    sdf::SdfShape,
+};
+
+/// The largest position along a single face, see `Hittable`. Staying below 1 keeps the
+/// face number in the whole part of u from being rounded up to the next face's.
+static MAX_FACE_POSITION: f64 = 1.0 - 1e-9;
+
+/// Something rays can hit, with an exact surface to shade.
+///
+/// A hit is found as a t value along the ray and a pair of surface coordinates (u, v) saying
+/// where on the surface it is, and the hit point, normal and texture coordinates are all worked
+/// out again from those. For a triangle they're its barycentric coordinates. Shapes made of
+/// several faces, like a box's six sides or a cylinder's tube and end caps, put the number of
+/// the face that was hit in the whole part of u. Surfaces without coordinates of their own,
+/// like signed distance fields, work from the point along the ray instead.
+pub trait Hittable {
+    /// The nearest hit in front of the ray's origin as (t, u, v). The direction doesn't need
+    /// to be normalised, t is in multiples of it.
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)>;
+
+    /// Every hit in front of the ray's origin, nearest first, for finding where it goes in and
+    /// out of closed shapes
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)>;
+
+    /// A box around the whole surface, for sorting it into an acceleration structure.
+    /// `None` for surfaces that go on forever, like planes, which structures keep out of their
+    /// trees and test every ray against.
+    fn bounds(&self) -> Option<Aabb>;
+
+    /// The hit point with a bound on the rounding error in each of its coordinates.
+    /// Moving surfaces are wherever they are at the ray's time.
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d);
+
+    /// Normal for shading at the hit, pointing out of closed shapes. Analytic shapes' are
+    /// exact and unit length, a triangle's is blended from its corners'.
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d;
+
+    /// Texture coordinates at the hit
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64);
+
+    /// Flat surfaces can't be hit again by a ray leaving them, so traversal can skip them
+    fn is_flat(&self) -> bool;
+}
+
+/// Where a ray hit a primitive, from `Hittable::intersect`
+#[derive(Copy, Clone, Debug)]
+pub struct SurfaceHit<'r> {
+    /// The ray in the primitive's own space
+    pub ray: &'r Ray,
+    pub t: f64,
+    pub u: f64,
+    pub v: f64,
+    /// Which solid a hit on a CSG shape is on, see `Shape::intersect_solid`
+    pub solid: SolidHit,
+}
+
+impl<'r> SurfaceHit<'r> {
+    /// The hit an intersection result describes, `ray` being the one that made it
+    /// moved into the space of the intersection's mesh
+    pub fn from_intersection(
+        ray: &'r Ray,
+        intersection: &RayTriangleIntersectionResult,
+    ) -> SurfaceHit<'r> {
+        SurfaceHit {
+            ray,
+            t: intersection.t,
+            u: intersection.u,
+            v: intersection.v,
+            solid: intersection.solid,
+        }
+    }
+}
+
+/// One of a mesh's triangles
+#[derive(Copy, Clone, Debug)]
+pub struct Triangle<'m> {
+    pub mesh: &'m Mesh,
+    pub index: usize,
+}
+
+impl Hittable for Triangle<'_> {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        ray.intersect_with_triangle(self.mesh, self.index)
+            .map(|hit| (hit.t, hit.u, hit.v))
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        self.intersect(ray).into_iter().collect()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::from_moving_triangle(self.mesh, self.index))
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        let (u, v) = (hit.u, hit.v);
+        let [v1, v2, v3] = self.mesh.vertices(self.index);
+        let w = 1.0 - u - v;
+
+        // Seven operations to weight and sum the corners
+        let point = v1 * w + v2 * u + v3 * v;
+        let error = (abs(v1 * w) + abs(v2 * u) + abs(v3 * v)) * gamma(7);
+
+        // A moving triangle is hit where it rests, then the point is moved with it
+        match self.mesh.motion(self.index) {
+            Some(motion) => {
+                let transform = motion.transform_at(hit.ray.time);
+                (
+                    transform.transform_point(point),
+                    transform.point_error(point, error),
+                )
+            }
+            None => (point, error),
+        }
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        let (u, v) = (hit.u, hit.v);
+        let [n1, n2, n3] = self.mesh.normals(self.index);
+        let normal = n2 * u + n3 * v + n1 * (1.0 - u - v);
+
+        match self.mesh.motion(self.index) {
+            Some(motion) => motion.transform_at(hit.ray.time).transform_normal(normal),
+            None => normal,
+        }
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        let (u, v) = (hit.u, hit.v);
+        let [t1, t2, t3] = self.mesh.tex_coords(self.index);
+        let tex = t2 * u + t3 * v + t1 * (1.0 - u - v);
+
+        (tex.x, tex.y)
+    }
+
+    fn is_flat(&self) -> bool {
+        true
+    }
+}
+
+/// A perfect sphere. u goes once around the y axis and v from the bottom pole to the top one,
+/// and they're used as texture coordinates as they are.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sphere {
+    pub centre: Vector3d,
+    pub radius: f64,
+}
+
+impl Sphere {
+    /// Unit direction from the centre to the surface coordinates
+    fn direction(u: f64, v: f64) -> Vector3d {
+        let theta = (1.0 - v) * PI;
+        let phi = u * TAU;
+
+        Vector3d {
+            x: theta.sin() * phi.cos(),
+            y: theta.cos(),
+            z: theta.sin() * phi.sin(),
+        }
+    }
+
+    /// Everywhere the ray's line crosses the surface, whether in front of its origin or not
+    fn hits(&self, ray: &Ray) -> [Option<(f64, f64, f64)>; 2] {
+        let f = ray.origin - self.centre;
+        let d = ray.direction;
+
+        // The form from Ray Tracing Gems' "Precision Improvements for Ray/Sphere Intersection",
+        // which keeps its accuracy for small spheres far from the ray's origin
+        let a = d.dot(&d);
+        let b = -f.dot(&d);
+        let c = f.dot(&f) - self.radius * self.radius;
+        let closest_approach = f + d * (b / a);
+        let discriminant = self.radius * self.radius - closest_approach.dot(&closest_approach);
+
+        if discriminant < 0.0 {
+            return [None; 2];
+        }
+
+        let q = b + f64::sqrt(a * discriminant).copysign(b);
+
+        // Only a ray starting on the sphere and just grazing it, which can't hit anything else
+        if q == 0.0 {
+            return [None; 2];
+        }
+
+        let (t0, t1) = (c / q, q / a);
+
+        [t0, t1].map(|t| {
+            let local = f + d * t;
+
+            let u = turn(local.x, local.z);
+            let theta = f64::atan2(f64::sqrt(local.x * local.x + local.z * local.z), local.y);
+
+            Some((t, u, 1.0 - theta / PI))
+        })
+    }
+}
+
+impl Hittable for Sphere {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        nearest(self.hits(ray))
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        in_front(self.hits(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = self.radius;
+        let c = self.centre;
+
+        Some(Aabb::new(
+            c.x - r,
+            c.x + r,
+            c.y - r,
+            c.y + r,
+            c.z - r,
+            c.z + r,
+        ))
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        let offset = Sphere::direction(hit.u, hit.v) * self.radius;
+
+        // A few operations for the trigonometry and a few more to move it into place
+        (
+            self.centre + offset,
+            (abs(self.centre) + abs(offset)) * gamma(10),
+        )
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        Sphere::direction(hit.u, hit.v)
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        (hit.u, hit.v)
+    }
+
+    fn is_flat(&self) -> bool {
+        false
+    }
+}
+
+/// A plane through `point` facing `normal`, going on forever. u and v are distances along
+/// the plane, the texture repeats once every unit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Plane {
+    pub point: Vector3d,
+    pub normal: Vector3d,
+}
+
+impl Plane {
+    fn frame(&self) -> Frame {
+        Frame::new(self.point, self.normal.normalised())
+    }
+
+    /// Everywhere the ray's line crosses the surface, whether in front of its origin or not
+    fn hits(&self, ray: &Ray) -> [Option<(f64, f64, f64)>; 1] {
+        let (origin, direction) = self.frame().ray_to_local(ray);
+
+        [flat_hit(origin, direction, 0.0)]
+    }
+}
+
+impl Hittable for Plane {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        nearest(self.hits(ray))
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        in_front(self.hits(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        self.frame().point_with_error(Vector3d {
+            x: hit.u,
+            y: hit.v,
+            z: 0.0,
+        })
+    }
+
+    fn normal_at(&self, _hit: &SurfaceHit) -> Vector3d {
+        self.normal.normalised()
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        (hit.u.rem_euclid(1.0), hit.v.rem_euclid(1.0))
+    }
+
+    fn is_flat(&self) -> bool {
+        true
+    }
+}
+
+/// A flat disk facing `normal`. u goes once around the centre and v from the centre to the rim.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Disk {
+    pub centre: Vector3d,
+    pub normal: Vector3d,
+    pub radius: f64,
+}
+
+impl Disk {
+    fn frame(&self) -> Frame {
+        Frame::new(self.centre, self.normal.normalised())
+    }
+
+    /// Everywhere the ray's line crosses the surface, whether in front of its origin or not
+    fn hits(&self, ray: &Ray) -> [Option<(f64, f64, f64)>; 1] {
+        let (origin, direction) = self.frame().ray_to_local(ray);
+
+        [cap_hit(origin, direction, 0.0, self.radius, 0)]
+    }
+}
+
+impl Hittable for Disk {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        nearest(self.hits(ray))
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        in_front(self.hits(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(disk_bounds(
+            self.centre,
+            self.normal.normalised(),
+            self.radius,
+        ))
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        self.frame()
+            .point_with_error(around_axis(hit.u, hit.v * self.radius, 0.0))
+    }
+
+    fn normal_at(&self, _hit: &SurfaceHit) -> Vector3d {
+        self.normal.normalised()
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        (hit.u, hit.v)
+    }
+
+    fn is_flat(&self) -> bool {
+        true
+    }
+}
+
+/// A solid cylinder between the centres of its two ends, closed by a flat cap at each.
+/// Face 0 is the tube, with u going once around it and v from `base` to `top`. Faces 1 and 2
+/// are the caps at `base` and `top`, with u going once around and v from the centre to the rim.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cylinder {
+    pub base: Vector3d,
+    pub top: Vector3d,
+    pub radius: f64,
+}
+
+impl Cylinder {
+    fn frame(&self) -> (Frame, f64) {
+        let axis = self.top - self.base;
+        (Frame::new(self.base, axis.normalised()), axis.length())
+    }
+
+    /// Everywhere the ray's line crosses the surface, whether in front of its origin or not
+    fn hits(&self, ray: &Ray) -> [Option<(f64, f64, f64)>; 4] {
+        let (frame, height) = self.frame();
+        let (o, d) = frame.ray_to_local(ray);
+
+        let roots = solve_quadratic(
+            d.x * d.x + d.y * d.y,
+            2.0 * (o.x * d.x + o.y * d.y),
+            o.x * o.x + o.y * o.y - self.radius * self.radius,
+        );
+
+        let tube = |t: f64| {
+            let z = o.z + d.z * t;
+            (0.0..=height)
+                .contains(&z)
+                .then(|| (t, turn(o.x + d.x * t, o.y + d.y * t), z / height))
+        };
+
+        [
+            roots.and_then(|(t0, _)| tube(t0)),
+            roots.and_then(|(_, t1)| tube(t1)),
+            cap_hit(o, d, 0.0, self.radius, 1),
+            cap_hit(o, d, height, self.radius, 2),
+        ]
+    }
+}
+
+impl Hittable for Cylinder {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        nearest(self.hits(ray))
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        in_front(self.hits(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let axis = (self.top - self.base).normalised();
+
+        Some(
+            disk_bounds(self.base, axis, self.radius).union(&disk_bounds(
+                self.top,
+                axis,
+                self.radius,
+            )),
+        )
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        let (frame, height) = self.frame();
+        let (face, u) = face_of(hit.u);
+        let v = hit.v;
+
+        frame.point_with_error(match face {
+            0 => around_axis(u, self.radius, v * height),
+            1 => around_axis(u, v * self.radius, 0.0),
+            _ => around_axis(u, v * self.radius, height),
+        })
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        let (frame, _) = self.frame();
+        let (face, u) = face_of(hit.u);
+
+        match face {
+            0 => frame.vector_to_world(around_axis(u, 1.0, 0.0)),
+            1 => -frame.z,
+            _ => frame.z,
+        }
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        (face_of(hit.u).1, hit.v)
+    }
+
+    fn is_flat(&self) -> bool {
+        false
+    }
+}
+
+/// A solid cone from a round base up to a point, closed by a flat cap at the base.
+/// Face 0 is the sloping side, with u going once around it and v from the base to the apex.
+/// Face 1 is the cap, with u going once around and v from the centre to the rim.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cone {
+    pub base: Vector3d,
+    pub apex: Vector3d,
+    pub radius: f64,
+}
+
+impl Cone {
+    fn frame(&self) -> (Frame, f64) {
+        let axis = self.apex - self.base;
+        (Frame::new(self.base, axis.normalised()), axis.length())
+    }
+
+    /// Everywhere the ray's line crosses the surface, whether in front of its origin or not
+    fn hits(&self, ray: &Ray) -> [Option<(f64, f64, f64)>; 3] {
+        let (frame, height) = self.frame();
+        let (o, d) = frame.ray_to_local(ray);
+
+        // The side is where the distance from the axis is k times the height left to the apex
+        let k = self.radius / height;
+        let k2 = k * k;
+        let below_apex = height - o.z;
+
+        let roots = solve_quadratic(
+            d.x * d.x + d.y * d.y - k2 * d.z * d.z,
+            2.0 * (o.x * d.x + o.y * d.y + k2 * below_apex * d.z),
+            o.x * o.x + o.y * o.y - k2 * below_apex * below_apex,
+        );
+
+        // The equation describes a double cone, only the half between base and apex is real
+        let side = |t: f64| {
+            let z = o.z + d.z * t;
+            (0.0..=height)
+                .contains(&z)
+                .then(|| (t, turn(o.x + d.x * t, o.y + d.y * t), z / height))
+        };
+
+        [
+            roots.and_then(|(t0, _)| side(t0)),
+            roots.and_then(|(_, t1)| side(t1)),
+            cap_hit(o, d, 0.0, self.radius, 1),
+        ]
+    }
+}
+
+impl Hittable for Cone {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        nearest(self.hits(ray))
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        in_front(self.hits(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let axis = (self.apex - self.base).normalised();
+
+        Some(disk_bounds(self.base, axis, self.radius).expanded_to(self.apex))
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        let (frame, height) = self.frame();
+        let (face, u) = face_of(hit.u);
+        let v = hit.v;
+
+        frame.point_with_error(match face {
+            0 => around_axis(u, (1.0 - v) * self.radius, v * height),
+            _ => around_axis(u, v * self.radius, 0.0),
+        })
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        let (frame, height) = self.frame();
+        let (face, u) = face_of(hit.u);
+
+        match face {
+            // Out from the axis, tipped up by how steep the side is
+            0 => frame
+                .vector_to_world(around_axis(u, height, self.radius))
+                .normalised(),
+            _ => -frame.z,
+        }
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        (face_of(hit.u).1, hit.v)
+    }
+
+    fn is_flat(&self) -> bool {
+        false
+    }
+}
+
+/// A solid box lined up with the axes. Faces 0 to 5 are the sides at the smallest and largest
+/// x, then y, then z. u and v go across each face along the next two axes round, starting
+/// from its corner nearest `min`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cuboid {
+    pub min: Vector3d,
+    pub max: Vector3d,
+}
+
+impl Cuboid {
+    /// Everywhere the ray's line crosses the surface, whether in front of its origin or not
+    fn hits(&self, ray: &Ray) -> [Option<(f64, f64, f64)>; 2] {
+        let origin = to_array(ray.origin);
+        let direction = to_array(ray.direction);
+        let min = to_array(self.min);
+        let max = to_array(self.max);
+
+        // Where the ray enters and leaves the box, along with the face it crosses
+        let mut near = (f64::NEG_INFINITY, 0);
+        let mut far = (f64::INFINITY, 0);
+
+        for axis in 0..3 {
+            if direction[axis] == 0.0 {
+                if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                    return [None; 2];
+                }
+                continue;
+            }
+
+            let to_min = ((min[axis] - origin[axis]) / direction[axis], axis * 2);
+            let to_max = ((max[axis] - origin[axis]) / direction[axis], axis * 2 + 1);
+            let (entry, exit) = if direction[axis] > 0.0 {
+                (to_min, to_max)
+            } else {
+                (to_max, to_min)
+            };
+
+            if entry.0 > near.0 {
+                near = entry;
+            }
+            if exit.0 < far.0 {
+                far = exit;
+            }
+        }
+
+        if near.0 > far.0 {
+            return [None; 2];
+        }
+
+        let face_hit = |(t, face): (f64, usize)| {
+            let point = to_array(ray.origin + ray.direction * t);
+            let (_, across, up) = face_axes(face);
+
+            let u = fraction(point[across], min[across], max[across]);
+            let v = fraction(point[up], min[up], max[up]);
+
+            Some((t, on_face(face, u), v))
+        };
+
+        [face_hit(near), face_hit(far)]
+    }
+}
+
+impl Hittable for Cuboid {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        nearest(self.hits(ray))
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        in_front(self.hits(ray))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb {
+            min_coords: self.min,
+            max_coords: self.max,
+        })
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        let (face, u) = face_of(hit.u);
+        let v = hit.v;
+        let (axis, across, up) = face_axes(face);
+        let min = to_array(self.min);
+        let max = to_array(self.max);
+
+        let mut point = [0.0; 3];
+        point[axis] = if face % 2 == 0 { min[axis] } else { max[axis] };
+        point[across] = min[across] + u * (max[across] - min[across]);
+        point[up] = min[up] + v * (max[up] - min[up]);
+
+        let point = from_array(point);
+        (point, abs(point) * gamma(3))
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        let (face, _) = face_of(hit.u);
+        let mut normal = [0.0; 3];
+        normal[face / 2] = if face % 2 == 0 { -1.0 } else { 1.0 };
+
+        from_array(normal)
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        (face_of(hit.u).1, hit.v)
+    }
+
+    fn is_flat(&self) -> bool {
+        false
+    }
+}
+
+/// Any of the analytic shapes, a signed distance field or a CSG shape, as stored in a mesh
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    Sphere(Sphere),
+    Plane(Plane),
+    Disk(Disk),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Cuboid(Cuboid),
+    Sdf(SdfShape),
+    Csg(CsgShape),
+}
+
+impl Shape {
+    fn inner(&self) -> &dyn Hittable {
+        match self {
+            Shape::Sphere(sphere) => sphere,
+            Shape::Plane(plane) => plane,
+            Shape::Disk(disk) => disk,
+            Shape::Cylinder(cylinder) => cylinder,
+            Shape::Cone(cone) => cone,
+            Shape::Cuboid(cuboid) => cuboid,
+            Shape::Sdf(sdf) => sdf,
+            Shape::Csg(csg) => csg,
+        }
+    }
+
+    /// `Hittable::intersect`, along with which solid was hit for CSG shapes
+    pub fn intersect_solid(&self, ray: &Ray) -> Option<(f64, f64, f64, SolidHit)> {
+        match self {
+            Shape::Csg(csg) => csg
+                .crossings(ray)
+                .first()
+                .map(|crossing| (crossing.t, crossing.u, crossing.v, crossing.solid)),
+            shape => shape
+                .intersect(ray)
+                .map(|(t, u, v)| (t, u, v, SolidHit::default())),
+        }
+    }
+
+    /// `Hittable::intersect_all`, along with which solid each hit is on for CSG shapes
+    pub fn intersect_all_solids(&self, ray: &Ray) -> Vec<(f64, f64, f64, SolidHit)> {
+        match self {
+            Shape::Csg(csg) => csg
+                .crossings(ray)
+                .into_iter()
+                .map(|crossing| (crossing.t, crossing.u, crossing.v, crossing.solid))
+                .collect(),
+            shape => shape
+                .intersect_all(ray)
+                .into_iter()
+                .map(|(t, u, v)| (t, u, v, SolidHit::default()))
+                .collect(),
+        }
+    }
+}
+
+impl Hittable for Shape {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        self.inner().intersect(ray)
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        self.inner().intersect_all(ray)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.inner().bounds()
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        self.inner().point_at(hit)
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        self.inner().normal_at(hit)
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        self.inner().tex_coords_at(hit)
+    }
+
+    fn is_flat(&self) -> bool {
+        self.inner().is_flat()
+    }
+}
+
+/// One of a mesh's primitives, whichever kind it is, see `Mesh::primitive`
+#[derive(Copy, Clone, Debug)]
+pub enum Primitive<'m> {
+    Triangle(Triangle<'m>),
+    Shape(&'m Shape),
+}
+
+impl Primitive<'_> {
+    fn inner(&self) -> &dyn Hittable {
+        match self {
+            Primitive::Triangle(triangle) => triangle,
+            Primitive::Shape(shape) => *shape,
+        }
+    }
+}
+
+impl Hittable for Primitive<'_> {
+    fn intersect(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        self.inner().intersect(ray)
+    }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        self.inner().intersect_all(ray)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.inner().bounds()
+    }
+
+    fn point_at(&self, hit: &SurfaceHit) -> (Vector3d, Vector3d) {
+        self.inner().point_at(hit)
+    }
+
+    fn normal_at(&self, hit: &SurfaceHit) -> Vector3d {
+        self.inner().normal_at(hit)
+    }
+
+    fn tex_coords_at(&self, hit: &SurfaceHit) -> (f64, f64) {
+        self.inner().tex_coords_at(hit)
+    }
+
+    fn is_flat(&self) -> bool {
+        self.inner().is_flat()
+    }
+}
+
+/// Axes with `z` along a shape's axis or normal, for working in the shape's own space
+struct Frame {
+    origin: Vector3d,
+    x: Vector3d,
+    y: Vector3d,
+    z: Vector3d,
+}
+
+impl Frame {
+    /// Duff et al.'s "Building an Orthonormal Basis, Revisited", `z` must be unit length.
+    /// The same `z` always gets the same `x` and `y`, so surface coordinates stay put.
+    fn new(origin: Vector3d, z: Vector3d) -> Frame {
+        let sign = 1.0f64.copysign(z.z);
+        let a = -1.0 / (sign + z.z);
+        let b = z.x * z.y * a;
+
+        Frame {
+            origin,
+            x: Vector3d {
+                x: 1.0 + sign * z.x * z.x * a,
+                y: sign * b,
+                z: -sign * z.x,
+            },
+            y: Vector3d {
+                x: b,
+                y: sign + z.y * z.y * a,
+                z: -z.y,
+            },
+            z,
+        }
+    }
+
+    fn vector_to_local(&self, v: Vector3d) -> Vector3d {
+        Vector3d {
+            x: v.dot(&self.x),
+            y: v.dot(&self.y),
+            z: v.dot(&self.z),
+        }
+    }
+
+    /// The ray's origin and direction in the frame
+    fn ray_to_local(&self, ray: &Ray) -> (Vector3d, Vector3d) {
+        (
+            self.vector_to_local(ray.origin - self.origin),
+            self.vector_to_local(ray.direction),
+        )
+    }
+
+    fn vector_to_world(&self, v: Vector3d) -> Vector3d {
+        self.x * v.x + self.y * v.y + self.z * v.z
+    }
+
+    fn to_world(&self, point: Vector3d) -> Vector3d {
+        self.origin + self.vector_to_world(point)
+    }
+
+    /// A point in the frame moved into the scene, with a bound on its rounding error
+    fn point_with_error(&self, point: Vector3d) -> (Vector3d, Vector3d) {
+        let terms = abs(self.origin)
+            + abs(self.x * point.x)
+            + abs(self.y * point.y)
+            + abs(self.z * point.z);
+
+        // A few operations for the point's own trigonometry and a few more to move it into place
+        (self.to_world(point), terms * gamma(10))
+    }
+}
+
+/// A local point `distance` from the z axis at height `z`, `u` of a turn round from the x axis
+fn around_axis(u: f64, distance: f64, z: f64) -> Vector3d {
+    let angle = u * TAU;
+
+    Vector3d {
+        x: angle.cos() * distance,
+        y: angle.sin() * distance,
+        z,
+    }
+}
+
+/// How far round from the positive first axis a point is, as a fraction of a turn in [0, 1)
+fn turn(x: f64, y: f64) -> f64 {
+    let angle = y.atan2(x);
+    let angle = if angle < 0.0 { angle + TAU } else { angle };
+
+    f64::min(angle / TAU, MAX_FACE_POSITION)
+}
+
+/// u for a position on one of a shape's faces, see `Hittable`
+fn on_face(face: usize, u: f64) -> f64 {
+    face as f64 + u.clamp(0.0, MAX_FACE_POSITION)
+}
+
+/// Which face u is on and the position along that face
+fn face_of(u: f64) -> (usize, f64) {
+    let face = u.floor().max(0.0);
+    (face as usize, u - face)
+}
+
+/// The axis a box face is across from, followed by the two axes running along it
+pub(super) fn face_axes(face: usize) -> (usize, usize, usize) {
+    let axis = face / 2;
+    (axis, (axis + 1) % 3, (axis + 2) % 3)
+}
+
+/// How far `value` is from `min` to `max`, 0 when they're the same
+pub(super) fn fraction(value: f64, min: f64, max: f64) -> f64 {
+    if max > min {
+        ((value - min) / (max - min)).clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Where a local ray crosses the plane z = `z`, as (t, x, y)
+fn flat_hit(origin: Vector3d, direction: Vector3d, z: f64) -> Option<(f64, f64, f64)> {
+    if direction.z == 0.0 {
+        return None;
+    }
+
+    let t = (z - origin.z) / direction.z;
+    Some((t, origin.x + direction.x * t, origin.y + direction.y * t))
+}
+
+/// A local ray's hit on a round cap of the given radius at height `z`, on the given face
+fn cap_hit(
+    origin: Vector3d,
+    direction: Vector3d,
+    z: f64,
+    radius: f64,
+    face: usize,
+) -> Option<(f64, f64, f64)> {
+    let (t, x, y) = flat_hit(origin, direction, z)?;
+    let distance = f64::sqrt(x * x + y * y);
+
+    (distance <= radius).then(|| (t, on_face(face, turn(x, y)), distance / radius))
+}
+
+/// A box around a disk, which only reaches as far along each axis as its tilt allows
+fn disk_bounds(centre: Vector3d, normal: Vector3d, radius: f64) -> Aabb {
+    let reach = |n: f64| radius * f64::sqrt(f64::max(1.0 - n * n, 0.0));
+    let extent = Vector3d {
+        x: reach(normal.x),
+        y: reach(normal.y),
+        z: reach(normal.z),
+    };
+
+    Aabb {
+        min_coords: centre - extent,
+        max_coords: centre + extent,
+    }
+}
+
+/// Both roots of a t² + b t + c, smallest first, in the form that avoids cancelling out
+/// digits when `b` is much bigger than the rest
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+
+        let t = -c / b;
+        return Some((t, t));
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let q = -0.5 * (b + f64::sqrt(discriminant).copysign(b));
+
+    if q == 0.0 {
+        return Some((0.0, 0.0));
+    }
+
+    let (t0, t1) = (q / a, c / q);
+    Some((f64::min(t0, t1), f64::max(t0, t1)))
+}
+
+/// The nearest hit in front of the ray's origin. Like triangles, hits closer than machine
+/// epsilon are treated as the surface the ray started on. Rays running exactly along a flat
+/// face can come out at an infinite t, which isn't a hit either.
+fn nearest<const N: usize>(hits: [Option<(f64, f64, f64)>; N]) -> Option<(f64, f64, f64)> {
+    hits.into_iter()
+        .flatten()
+        .filter(|hit| hit.0 > f64::EPSILON && hit.0.is_finite())
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+/// Every hit in front of the ray's origin, nearest first, see `nearest`
+fn in_front<const N: usize>(hits: [Option<(f64, f64, f64)>; N]) -> Vec<(f64, f64, f64)> {
+    let mut hits: Vec<(f64, f64, f64)> = hits
+        .into_iter()
+        .flatten()
+        .filter(|hit| hit.0 > f64::EPSILON && hit.0.is_finite())
+        .collect();
+
+    hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+    hits
+}
+
+pub(super) fn to_array(v: Vector3d) -> [f64; 3] {
+    [v.x, v.y, v.z]
+}
+
+fn from_array([x, y, z]: [f64; 3]) -> Vector3d {
+    Vector3d { x, y, z }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::collision::{
+        accelerator::Accelerator,
+        bvh::Bvh,
+        octree::{Octree, OctreeBuildOptions},
+        ray::TriangleTest,
+        spawn::SurfacePoint,
+        test_support::{
+            self, assert_matches_brute_force_with, assert_packets_match_single_rays, build_octree,
+            random_triangles,
+        },
+    };
+    use crate::scene::{
+        csg::{CsgOperation, CsgShape, Solid},
+        mesh::MeshShape,
+        sampling::Sampler,
+        sdf::Sdf,
+    };
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    fn shapes() -> Vec<Shape> {
+        vec![
+            Shape::Sphere(Sphere {
+                centre: vector(1.0, 2.0, 3.0),
+                radius: 1.5,
+            }),
+            Shape::Plane(Plane {
+                point: vector(0.0, -1.0, 0.0),
+                normal: vector(0.2, 1.0, 0.1),
+            }),
+            Shape::Disk(Disk {
+                centre: vector(2.0, 0.0, -1.0),
+                normal: vector(1.0, 1.0, 0.0),
+                radius: 2.0,
+            }),
+            Shape::Cylinder(Cylinder {
+                base: vector(-1.0, 0.0, 0.0),
+                top: vector(1.0, 2.0, 1.0),
+                radius: 0.7,
+            }),
+            Shape::Cone(Cone {
+                base: vector(0.0, 0.0, 2.0),
+                apex: vector(0.5, 3.0, 2.0),
+                radius: 1.2,
+            }),
+            Shape::Cuboid(Cuboid {
+                min: vector(-1.0, -2.0, -0.5),
+                max: vector(2.0, 1.0, 0.5),
+            }),
+        ]
+    }
+
+    /// Distance along the axis and out from it of a point, for shapes with a round cross section
+    fn axial(base: Vector3d, end: Vector3d, p: Vector3d) -> (f64, f64, f64) {
+        let axis = (end - base).normalised();
+        let along = (p - base).dot(&axis);
+
+        (
+            along,
+            (p - base - axis * along).length(),
+            (end - base).length(),
+        )
+    }
+
+    /// How far a point is from the shape's surface, worked out directly from its definition
+    fn distance_to_surface(shape: &Shape, p: Vector3d) -> f64 {
+        let flat = |centre: Vector3d, normal: Vector3d, radius: f64| {
+            let normal = normal.normalised();
+            let height = (p - centre).dot(&normal);
+            let radial = (p - centre - normal * height).length();
+
+            if radial <= radius * (1.0 + 1e-9) {
+                height.abs()
+            } else {
+                f64::INFINITY
+            }
+        };
+
+        match shape {
+            Shape::Sphere(sphere) => ((p - sphere.centre).length() - sphere.radius).abs(),
+            Shape::Plane(plane) => flat(plane.point, plane.normal, f64::INFINITY),
+            Shape::Disk(disk) => flat(disk.centre, disk.normal, disk.radius),
+            Shape::Cylinder(cylinder) => {
+                let (along, radial, height) = axial(cylinder.base, cylinder.top, p);
+                let axis = cylinder.top - cylinder.base;
+
+                let tube = if (0.0..=height).contains(&along) {
+                    (radial - cylinder.radius).abs()
+                } else {
+                    f64::INFINITY
+                };
+
+                tube.min(flat(cylinder.base, axis, cylinder.radius))
+                    .min(flat(cylinder.top, axis, cylinder.radius))
+            }
+            Shape::Cone(cone) => {
+                let (along, radial, height) = axial(cone.base, cone.apex, p);
+
+                let side = if (0.0..=height).contains(&along) {
+                    (radial - cone.radius * (1.0 - along / height)).abs()
+                } else {
+                    f64::INFINITY
+                };
+
+                side.min(flat(cone.base, cone.apex - cone.base, cone.radius))
+            }
+            Shape::Cuboid(cuboid) => {
+                let (p, min, max) = (to_array(p), to_array(cuboid.min), to_array(cuboid.max));
+
+                if (0..3).any(|axis| p[axis] < min[axis] - 1e-9 || p[axis] > max[axis] + 1e-9) {
+                    return f64::INFINITY;
+                }
+
+                (0..3)
+                    .map(|axis| f64::min((p[axis] - min[axis]).abs(), (p[axis] - max[axis]).abs()))
+                    .fold(f64::INFINITY, f64::min)
+            }
+            Shape::Sdf(shape) => shape.sdf.distance(p).abs(),
+            Shape::Csg(_) => unreachable!("CSG shapes are tested in their own module"),
+        }
+    }
+
+    fn random_point(sampler: &mut Sampler, extent: f64) -> Vector3d {
+        vector(
+            (sampler.next_f64() * 2.0 - 1.0) * extent,
+            (sampler.next_f64() * 2.0 - 1.0) * extent,
+            (sampler.next_f64() * 2.0 - 1.0) * extent,
+        )
+    }
+
+    /// Rays from well outside every shape towards somewhere near the middle of this one
+    fn random_ray(sampler: &mut Sampler, shape: &Shape) -> Ray {
+        let centre = match shape {
+            Shape::Plane(plane) => plane.point,
+            _ => shape.bounds().unwrap().centre(),
+        };
+        let origin = random_point(sampler, 1.0).normalised() * 20.0;
+        let target = centre + random_point(sampler, 2.5);
+
+        Ray {
+            origin,
+            direction: target - origin,
+            time: 0.0,
+            ignored_triangle: None,
+        }
+    }
+
+    #[test]
+    fn test_hits_points_and_normals_are_on_the_surface() {
+        let mut sampler = Sampler::new(48);
+
+        for shape in shapes() {
+            let mut hits = 0;
+
+            for _ in 0..2000 {
+                let ray = random_ray(&mut sampler, &shape);
+                let Some((t, u, v)) = shape.intersect(&ray) else {
+                    continue;
+                };
+                hits += 1;
+
+                let hit_point = ray.origin + ray.direction * t;
+                let at = |u, v| SurfaceHit {
+                    ray: &ray,
+                    t,
+                    u,
+                    v,
+                    solid: SolidHit::default(),
+                };
+                let (point, error) = shape.point_at(&at(u, v));
+
+                assert!(distance_to_surface(&shape, hit_point) < 1e-9 * 20.0);
+                assert!((point - hit_point).length() < 1e-6, "{shape:?} at {u}, {v}");
+                assert!(error.x >= 0.0 && error.y >= 0.0 && error.z >= 0.0);
+
+                let normal = shape.normal_at(&at(u, v));
+                assert!((normal.length() - 1.0).abs() < 1e-9);
+
+                // Rays from outside always meet closed shapes from the front
+                if !shape.is_flat() {
+                    assert!(normal.dot(&ray.direction) < 0.0, "{shape:?} at {u}, {v}");
+                }
+
+                // The normal is square to the surface along both surface coordinates,
+                // unless stepping along u goes off the edge of the face
+                let h = 1e-6;
+                if face_of(u + h).0 == face_of(u - h).0 {
+                    let along_u = shape.point_at(&at(u + h, v)).0 - shape.point_at(&at(u - h, v)).0;
+                    assert!(along_u.dot(&normal).abs() <= 1e-6 * along_u.length() + 1e-12);
+                }
+                let along_v = shape.point_at(&at(u, v + h)).0 - shape.point_at(&at(u, v - h)).0;
+                assert!(along_v.dot(&normal).abs() <= 1e-6 * along_v.length() + 1e-12);
+
+                let (tex_x, tex_y) = shape.tex_coords_at(&at(u, v));
+                assert!((0.0..=1.0).contains(&tex_x) && (0.0..=1.0).contains(&tex_y));
+            }
+
+            assert!(hits > 200, "only {hits} rays hit {shape:?}");
+        }
+    }
+
+    #[test]
+    fn test_rays_leaving_a_closed_shape_only_hit_it_again_going_in() {
+        let mut sampler = Sampler::new(49);
+
+        for shape in shapes().into_iter().filter(|shape| !shape.is_flat()) {
+            let mut mesh = Mesh::default();
+            mesh.shapes.push(MeshShape {
+                shape: shape.clone(),
+                material: 0,
+            });
+
+            for _ in 0..2000 {
+                let ray = random_ray(&mut sampler, &shape);
+                let Some(hit) = ray.intersect_with_shape(&shape, 0) else {
+                    continue;
+                };
+
+                let surface = SurfacePoint::from_intersection(&mesh, &hit, &ray);
+
+                // Every shape here is convex, so nothing leaving its surface outwards hits it
+                let outwards = surface.normal + random_point(&mut sampler, 0.5);
+                if outwards.dot(&surface.normal) > 0.0 {
+                    let leaving = surface.spawn_ray(outwards, 0.0, true);
+                    assert!(leaving.intersect_with_shape(&shape, 0).is_none());
+                }
+
+                // and anything going in comes out the other side
+                let entering = surface.spawn_ray(-surface.normal, 0.0, true);
+                let exit = entering.intersect_with_shape(&shape, 0);
+                assert!(exit.is_some_and(|exit| exit.t > 0.0), "{shape:?}");
+            }
+        }
+    }
+
+    /// Random triangles with spheres, disks, cylinders, cones and boxes scattered among them,
+    /// and a plane underneath everything
+    fn random_triangles_and_shapes(sampler: &mut Sampler) -> Arc<Mesh> {
+        let mut primitives = Mesh::from_triangles(&random_triangles(sampler));
+
+        for i in 0..40 {
+            let centre = random_point(sampler, 18.0);
+            let other = centre + random_point(sampler, 4.0);
+            let size = 0.5 + sampler.next_f64() * 3.0;
+
+            let shape = match i % 5 {
+                0 => Shape::Sphere(Sphere {
+                    centre,
+                    radius: size,
+                }),
+                1 => Shape::Disk(Disk {
+                    centre,
+                    normal: other - centre,
+                    radius: size,
+                }),
+                2 => Shape::Cylinder(Cylinder {
+                    base: centre,
+                    top: other,
+                    radius: size,
+                }),
+                3 => Shape::Cone(Cone {
+                    base: centre,
+                    apex: other,
+                    radius: size,
+                }),
+                _ => Shape::Cuboid(Cuboid {
+                    min: centre,
+                    max: centre + vector(size, size * 0.5, size * 2.0),
+                }),
+            };
+
+            primitives.shapes.push(MeshShape { shape, material: 0 });
+        }
+
+        primitives.shapes.push(MeshShape {
+            shape: Shape::Plane(Plane {
+                point: vector(0.0, -22.0, 0.0),
+                normal: vector(0.1, 1.0, 0.05),
+            }),
+            material: 0,
+        });
+        primitives.shapes.push(MeshShape {
+            shape: Shape::Sdf(
+                SdfShape::new(Sdf::Torus {
+                    centre: vector(4.0, 6.0, -3.0),
+                    major_radius: 3.0,
+                    minor_radius: 1.0,
+                })
+                .unwrap(),
+            ),
+            material: 0,
+        });
+        primitives.shapes.push(MeshShape {
+            shape: Shape::Csg(CsgShape::new(
+                CsgOperation::Difference,
+                Solid::Shape(MeshShape {
+                    shape: Shape::Sphere(Sphere {
+                        centre: vector(-6.0, -4.0, 5.0),
+                        radius: 4.0,
+                    }),
+                    material: 0,
+                }),
+                Solid::Shape(MeshShape {
+                    shape: Shape::Cylinder(Cylinder {
+                        base: vector(-12.0, -4.0, 5.0),
+                        top: vector(0.0, -3.0, 6.0),
+                        radius: 2.0,
+                    }),
+                    material: 0,
+                }),
+            )),
+            material: 0,
+        });
+
+        Arc::new(primitives)
+    }
+
+    #[test]
+    fn test_shapes_match_brute_force_alongside_triangles() {
+        let primitives = random_triangles_and_shapes(&mut Sampler::new(50));
+
+        let mut octree = build_octree(&primitives);
+        let mut fitted_octree =
+            Octree::build_around(primitives.clone(), OctreeBuildOptions::default());
+        let mut bvh = Bvh::build(primitives.clone());
+
+        for test in [TriangleTest::MollerTrumbore, TriangleTest::Watertight] {
+            octree.triangle_test = test;
+            fitted_octree.triangle_test = test;
+            bvh.triangle_test = test;
+
+            assert_matches_brute_force_with(&octree, &primitives, test);
+            assert_matches_brute_force_with(&fitted_octree, &primitives, test);
+            assert_matches_brute_force_with(&bvh, &primitives, test);
+            assert_packets_match_single_rays(&octree, &primitives);
+            assert_packets_match_single_rays(&fitted_octree, &primitives);
+            assert_packets_match_single_rays(&bvh, &primitives);
+        }
+
+        // The plane is in neither tree and is hit however far out the ray meets it
+        let plane = primitives.triangle_count() + 40;
+        assert_eq!(octree.unbounded, [plane]);
+        assert_eq!(bvh.unbounded, [plane]);
+
+        let far_out = Ray {
+            origin: vector(1e8, 0.0, 1e8),
+            direction: vector(0.0, -1.0, 0.0),
+            time: 0.0,
+            ignored_triangle: None,
+        };
+        for hit in [
+            octree.closest_hit(&far_out, f64::INFINITY),
+            fitted_octree.closest_hit(&far_out, f64::INFINITY),
+            bvh.closest_hit(&far_out, f64::INFINITY),
+        ] {
+            assert_eq!(hit.map(|hit| hit.triangle_index), Some(plane));
+        }
+        assert!(bvh.any_hit(&far_out, f64::INFINITY));
+
+        // Make sure the shapes are in front often enough to be tested properly
+        let mut sampler = Sampler::new(51);
+        let shape_hits = (0..1000)
+            .filter_map(|_| {
+                let ray = test_support::random_ray(&mut sampler);
+                bvh.closest_hit(&ray, f64::INFINITY)
+            })
+            .filter(|hit| !primitives.is_triangle(hit.triangle_index))
+            .count();
+        assert!(shape_hits > 100, "only {shape_hits} rays hit a shape first");
+    }
+}
@@ -0,0 +1,704 @@
+use std::ops::Mul;
+
+use super::engine::{gamma, Vector3d};
+
+/// Matrices whose inverse would magnify rounding errors by more than one over this
+/// are treated as singular when inverting
+static SINGULAR_EPSILON: f64 = 1e-12;
+
+/// A 4x4 matrix, stored row by row. Points and vectors are columns multiplied on the right,
+/// so in `a * b` the matrix `b` is applied first.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Matrix4 {
+    pub rows: [[f64; 4]; 4],
+}
+
+impl Matrix4 {
+    pub fn identity() -> Matrix4 {
+        Matrix4 {
+            rows: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    pub fn translation(offset: Vector3d) -> Matrix4 {
+        Matrix4 {
+            rows: [
+                [1.0, 0.0, 0.0, offset.x],
+                [0.0, 1.0, 0.0, offset.y],
+                [0.0, 0.0, 1.0, offset.z],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    /// Scales each axis by the matching component of `scale`
+    pub fn scaling(scale: Vector3d) -> Matrix4 {
+        Matrix4 {
+            rows: [
+                [scale.x, 0.0, 0.0, 0.0],
+                [0.0, scale.y, 0.0, 0.0],
+                [0.0, 0.0, scale.z, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        Matrix4 {
+            rows: std::array::from_fn(|i| std::array::from_fn(|j| self.rows[j][i])),
+        }
+    }
+
+    pub fn determinant(&self) -> f64 {
+        let (_, determinant) = self.adjugate_and_determinant();
+        determinant
+    }
+
+    /// The inverse, or `None` if the matrix squashes space flat and can't be undone.
+    /// How flat counts as flat is relative to the size of the matrix, so uniformly tiny or huge
+    /// scales can still be inverted.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let (adjugate, determinant) = self.adjugate_and_determinant();
+
+        // The condition number, |A| |A⁻¹|, with A⁻¹ = adjugate / determinant. It's how much
+        // the inverse magnifies rounding and doesn't change when the whole matrix is scaled.
+        let norm = |m: &Matrix4| m.rows.iter().flatten().map(|v| v * v).sum::<f64>().sqrt();
+
+        if determinant.abs() <= SINGULAR_EPSILON * norm(self) * norm(&adjugate) {
+            return None;
+        }
+
+        Some(Matrix4 {
+            rows: adjugate
+                .rows
+                .map(|row| row.map(|value| value / determinant)),
+        })
+    }
+
+    /// Cofactor expansion using the 2x2 determinants of the top and bottom two rows
+    fn adjugate_and_determinant(&self) -> (Matrix4, f64) {
+        let m = &self.rows;
+
+        let s0 = m[0][0] * m[1][1] - m[1][0] * m[0][1];
+        let s1 = m[0][0] * m[1][2] - m[1][0] * m[0][2];
+        let s2 = m[0][0] * m[1][3] - m[1][0] * m[0][3];
+        let s3 = m[0][1] * m[1][2] - m[1][1] * m[0][2];
+        let s4 = m[0][1] * m[1][3] - m[1][1] * m[0][3];
+        let s5 = m[0][2] * m[1][3] - m[1][2] * m[0][3];
+
+        let c5 = m[2][2] * m[3][3] - m[3][2] * m[2][3];
+        let c4 = m[2][1] * m[3][3] - m[3][1] * m[2][3];
+        let c3 = m[2][1] * m[3][2] - m[3][1] * m[2][2];
+        let c2 = m[2][0] * m[3][3] - m[3][0] * m[2][3];
+        let c1 = m[2][0] * m[3][2] - m[3][0] * m[2][2];
+        let c0 = m[2][0] * m[3][1] - m[3][0] * m[2][1];
+
+        let determinant = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
+
+        let adjugate = Matrix4 {
+            rows: [
+                [
+                    m[1][1] * c5 - m[1][2] * c4 + m[1][3] * c3,
+                    -m[0][1] * c5 + m[0][2] * c4 - m[0][3] * c3,
+                    m[3][1] * s5 - m[3][2] * s4 + m[3][3] * s3,
+                    -m[2][1] * s5 + m[2][2] * s4 - m[2][3] * s3,
+                ],
+                [
+                    -m[1][0] * c5 + m[1][2] * c2 - m[1][3] * c1,
+                    m[0][0] * c5 - m[0][2] * c2 + m[0][3] * c1,
+                    -m[3][0] * s5 + m[3][2] * s2 - m[3][3] * s1,
+                    m[2][0] * s5 - m[2][2] * s2 + m[2][3] * s1,
+                ],
+                [
+                    m[1][0] * c4 - m[1][1] * c2 + m[1][3] * c0,
+                    -m[0][0] * c4 + m[0][1] * c2 - m[0][3] * c0,
+                    m[3][0] * s4 - m[3][1] * s2 + m[3][3] * s0,
+                    -m[2][0] * s4 + m[2][1] * s2 - m[2][3] * s0,
+                ],
+                [
+                    -m[1][0] * c3 + m[1][1] * c1 - m[1][2] * c0,
+                    m[0][0] * c3 - m[0][1] * c1 + m[0][2] * c0,
+                    -m[3][0] * s3 + m[3][1] * s1 - m[3][2] * s0,
+                    m[2][0] * s3 - m[2][1] * s1 + m[2][2] * s0,
+                ],
+            ],
+        };
+
+        (adjugate, determinant)
+    }
+
+    /// Transform a position, translation applies. Projective matrices divide through by w.
+    pub fn transform_point(&self, p: Vector3d) -> Vector3d {
+        let m = &self.rows;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+
+        if w == 1.0 {
+            Vector3d { x, y, z }
+        } else {
+            Vector3d {
+                x: x / w,
+                y: y / w,
+                z: z / w,
+            }
+        }
+    }
+
+    /// Transform a direction or offset, translation doesn't apply
+    pub fn transform_vector(&self, v: Vector3d) -> Vector3d {
+        let m = &self.rows;
+
+        Vector3d {
+            x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        }
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+
+    fn mul(self, other: Matrix4) -> Matrix4 {
+        Matrix4 {
+            rows: std::array::from_fn(|i| {
+                std::array::from_fn(|j| (0..4).map(|k| self.rows[i][k] * other.rows[k][j]).sum())
+            }),
+        }
+    }
+}
+
+/// A rotation, stored as a unit quaternion
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quaternion {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion {
+            w: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    /// A rotation of `angle` radians around `axis`, anticlockwise when looking down the axis
+    /// towards the origin
+    pub fn from_axis_angle(axis: Vector3d, angle: f64) -> Quaternion {
+        let axis = axis.normalised();
+        let (sin, cos) = (angle / 2.0).sin_cos();
+
+        Quaternion {
+            w: cos,
+            x: axis.x * sin,
+            y: axis.y * sin,
+            z: axis.z * sin,
+        }
+    }
+
+    /// Rotations around x, then y, then z, in radians
+    pub fn from_euler(x: f64, y: f64, z: f64) -> Quaternion {
+        let axis = |x: f64, y: f64, z: f64| Vector3d { x, y, z };
+
+        Quaternion::from_axis_angle(axis(0.0, 0.0, 1.0), z)
+            * Quaternion::from_axis_angle(axis(0.0, 1.0, 0.0), y)
+            * Quaternion::from_axis_angle(axis(1.0, 0.0, 0.0), x)
+    }
+
+    /// The rotation made by a matrix's top left 3x3 part, which should be a pure rotation
+    pub fn from_matrix(matrix: &Matrix4) -> Quaternion {
+        let m = &matrix.rows;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+
+        // Worked out from whichever of w, x, y and z is largest, so nothing is divided by ~0
+        let q = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quaternion {
+                w: 0.25 * s,
+                x: (m[2][1] - m[1][2]) / s,
+                y: (m[0][2] - m[2][0]) / s,
+                z: (m[1][0] - m[0][1]) / s,
+            }
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+            Quaternion {
+                w: (m[2][1] - m[1][2]) / s,
+                x: 0.25 * s,
+                y: (m[0][1] + m[1][0]) / s,
+                z: (m[0][2] + m[2][0]) / s,
+            }
+        } else if m[1][1] > m[2][2] {
+            let s = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+            Quaternion {
+                w: (m[0][2] - m[2][0]) / s,
+                x: (m[0][1] + m[1][0]) / s,
+                y: 0.25 * s,
+                z: (m[1][2] + m[2][1]) / s,
+            }
+        } else {
+            let s = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+            Quaternion {
+                w: (m[1][0] - m[0][1]) / s,
+                x: (m[0][2] + m[2][0]) / s,
+                y: (m[1][2] + m[2][1]) / s,
+                z: 0.25 * s,
+            }
+        };
+
+        q.normalised()
+    }
+
+    pub fn dot(&self, other: &Quaternion) -> f64 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn normalised(&self) -> Quaternion {
+        let length = self.dot(self).sqrt();
+
+        Quaternion {
+            w: self.w / length,
+            x: self.x / length,
+            y: self.y / length,
+            z: self.z / length,
+        }
+    }
+
+    /// The opposite rotation
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    pub fn rotate(&self, v: Vector3d) -> Vector3d {
+        let u = Vector3d {
+            x: self.x,
+            y: self.y,
+            z: self.z,
+        };
+        let t = u.cross(&v) * 2.0;
+
+        v + t * self.w + u.cross(&t)
+    }
+
+    pub fn to_matrix(&self) -> Matrix4 {
+        let Quaternion { w, x, y, z } = *self;
+
+        Matrix4 {
+            rows: [
+                [
+                    1.0 - 2.0 * (y * y + z * z),
+                    2.0 * (x * y - w * z),
+                    2.0 * (x * z + w * y),
+                    0.0,
+                ],
+                [
+                    2.0 * (x * y + w * z),
+                    1.0 - 2.0 * (x * x + z * z),
+                    2.0 * (y * z - w * x),
+                    0.0,
+                ],
+                [
+                    2.0 * (x * z - w * y),
+                    2.0 * (y * z + w * x),
+                    1.0 - 2.0 * (x * x + y * y),
+                    0.0,
+                ],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    /// The rotation `t` of the way from this one to `other` along the shortest arc,
+    /// turning at a constant speed
+    pub fn slerp(&self, other: &Quaternion, t: f64) -> Quaternion {
+        let mut other = *other;
+        let mut cos = self.dot(&other);
+
+        // q and -q are the same rotation, going via the nearer one takes the short way round
+        if cos < 0.0 {
+            other = Quaternion {
+                w: -other.w,
+                x: -other.x,
+                y: -other.y,
+                z: -other.z,
+            };
+            cos = -cos;
+        }
+
+        let (a, b) = if cos > 0.9995 {
+            // Nearly the same rotation, a straight line is close enough and avoids dividing by ~0
+            (1.0 - t, t)
+        } else {
+            let angle = cos.acos();
+            let sin = angle.sin();
+            (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+        };
+
+        Quaternion {
+            w: self.w * a + other.w * b,
+            x: self.x * a + other.x * b,
+            y: self.y * a + other.y * b,
+            z: self.z * a + other.z * b,
+        }
+        .normalised()
+    }
+}
+
+impl Mul for Quaternion {
+    type Output = Quaternion;
+
+    /// The rotation `other` followed by `self`
+    fn mul(self, other: Quaternion) -> Quaternion {
+        Quaternion {
+            w: self.w * other.w - self.x * other.x - self.y * other.y - self.z * other.z,
+            x: self.w * other.x + self.x * other.w + self.y * other.z - self.z * other.y,
+            y: self.w * other.y - self.x * other.z + self.y * other.w + self.z * other.x,
+            z: self.w * other.z + self.x * other.y - self.y * other.x + self.z * other.w,
+        }
+    }
+}
+
+/// A transform along with its inverse, which is worked out once up front. Points, vectors and
+/// normals each transform differently: normals go through the inverse transpose so they stay
+/// perpendicular to surfaces that have been scaled unevenly.
+///
+/// Transforms compose by multiplication, in `a * b` the transform `b` is applied first.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub matrix: Matrix4,
+    pub inverse: Matrix4,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            matrix: Matrix4::identity(),
+            inverse: Matrix4::identity(),
+        }
+    }
+
+    /// A transform from any matrix, `None` if the matrix can't be inverted
+    pub fn from_matrix(matrix: Matrix4) -> Option<Transform> {
+        Some(Transform {
+            matrix,
+            inverse: matrix.inverse()?,
+        })
+    }
+
+    pub fn translation(offset: Vector3d) -> Transform {
+        Transform {
+            matrix: Matrix4::translation(offset),
+            inverse: Matrix4::translation(-offset),
+        }
+    }
+
+    /// Scales each axis by the matching component of `scale`, which mustn't be zero
+    pub fn scaling(scale: Vector3d) -> Transform {
+        Transform {
+            matrix: Matrix4::scaling(scale),
+            inverse: Matrix4::scaling(Vector3d {
+                x: 1.0 / scale.x,
+                y: 1.0 / scale.y,
+                z: 1.0 / scale.z,
+            }),
+        }
+    }
+
+    pub fn rotation(rotation: Quaternion) -> Transform {
+        let rotation = rotation.normalised();
+
+        Transform {
+            matrix: rotation.to_matrix(),
+            inverse: rotation.conjugate().to_matrix(),
+        }
+    }
+
+    /// Scale, then rotate, then translate, the usual way of placing an object
+    pub fn from_parts(translation: Vector3d, rotation: Quaternion, scale: Vector3d) -> Transform {
+        Transform::translation(translation)
+            * Transform::rotation(rotation)
+            * Transform::scaling(scale)
+    }
+
+    /// The translation, rotation and scale `from_parts` would build this transform from.
+    /// Mirroring goes into the scale's x, and any shear is lost.
+    pub fn to_parts(&self) -> (Vector3d, Quaternion, Vector3d) {
+        let m = &self.matrix.rows;
+        let column = |i: usize| Vector3d {
+            x: m[0][i],
+            y: m[1][i],
+            z: m[2][i],
+        };
+
+        let mut scale = [column(0).length(), column(1).length(), column(2).length()];
+        if column(0).dot(&column(1).cross(&column(2))) < 0.0 {
+            scale[0] = -scale[0];
+        }
+
+        let mut rotation = Matrix4::identity();
+        for (row, rotation_row) in rotation.rows.iter_mut().take(3).enumerate() {
+            for (col, value) in rotation_row.iter_mut().take(3).enumerate() {
+                *value = m[row][col] / scale[col];
+            }
+        }
+
+        (
+            column(3),
+            Quaternion::from_matrix(&rotation),
+            Vector3d {
+                x: scale[0],
+                y: scale[1],
+                z: scale[2],
+            },
+        )
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    pub fn transform_point(&self, p: Vector3d) -> Vector3d {
+        self.matrix.transform_point(p)
+    }
+
+    pub fn transform_vector(&self, v: Vector3d) -> Vector3d {
+        self.matrix.transform_vector(v)
+    }
+
+    /// Transform a surface normal, the result isn't normalised
+    pub fn transform_normal(&self, n: Vector3d) -> Vector3d {
+        self.inverse.transpose().transform_vector(n)
+    }
+
+    /// Bound on how far each coordinate of `transform_point(p)` can be from the exact result,
+    /// when `p` itself can be up to `p_error` off in each coordinate. Only holds for affine
+    /// transforms, which are all this module builds apart from `from_matrix`.
+    pub fn point_error(&self, p: Vector3d, p_error: Vector3d) -> Vector3d {
+        let bound = |row: &[f64; 4]| {
+            let rounding =
+                (row[0] * p.x).abs() + (row[1] * p.y).abs() + (row[2] * p.z).abs() + row[3].abs();
+            let carried =
+                row[0].abs() * p_error.x + row[1].abs() * p_error.y + row[2].abs() * p_error.z;
+
+            rounding * gamma(3) + carried * (1.0 + gamma(3))
+        };
+        let m = &self.matrix.rows;
+
+        Vector3d {
+            x: bound(&m[0]),
+            y: bound(&m[1]),
+            z: bound(&m[2]),
+        }
+    }
+}
+
+impl Mul for Transform {
+    type Output = Transform;
+
+    fn mul(self, other: Transform) -> Transform {
+        Transform {
+            matrix: self.matrix * other.matrix,
+            inverse: other.inverse * self.inverse,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    fn vector(x: f64, y: f64, z: f64) -> Vector3d {
+        Vector3d { x, y, z }
+    }
+
+    fn assert_close(a: Vector3d, b: Vector3d) {
+        assert!((a - b).length() < 1e-9, "expected {b:?}, got {a:?}");
+    }
+
+    #[test]
+    fn test_points_vectors_and_normals_transform_differently() {
+        let transform = Transform::translation(vector(1.0, 2.0, 3.0))
+            * Transform::scaling(vector(2.0, 1.0, 1.0));
+
+        assert_close(
+            transform.transform_point(vector(1.0, 1.0, 1.0)),
+            vector(3.0, 3.0, 4.0),
+        );
+        assert_close(
+            transform.transform_vector(vector(1.0, 1.0, 0.0)),
+            vector(2.0, 1.0, 0.0),
+        );
+
+        // A normal of the plane x + y = 0, which is stretched into 0.5x + y = 0
+        let normal = transform.transform_normal(vector(1.0, 1.0, 0.0));
+        let along_plane = transform.transform_vector(vector(1.0, -1.0, 0.0));
+        assert!(normal.dot(&along_plane).abs() < 1e-9);
+        assert_close(normal, vector(0.5, 1.0, 0.0));
+    }
+
+    #[test]
+    fn test_rotations_agree() {
+        let axis = vector(1.0, 2.0, -0.5);
+        let rotation = Quaternion::from_axis_angle(axis, 1.1);
+        let p = vector(0.3, -2.0, 4.0);
+
+        assert_close(rotation.rotate(p), rotation.to_matrix().transform_point(p));
+        assert_close(
+            Quaternion::from_axis_angle(vector(0.0, 0.0, 1.0), PI / 2.0)
+                .rotate(vector(1.0, 0.0, 0.0)),
+            vector(0.0, 1.0, 0.0),
+        );
+
+        // Points on the axis stay put
+        assert_close(rotation.rotate(axis * 3.0), axis * 3.0);
+
+        let euler = Quaternion::from_euler(0.4, -0.2, 1.3);
+        let by_axis = Quaternion::from_axis_angle(vector(0.0, 0.0, 1.0), 1.3)
+            * Quaternion::from_axis_angle(vector(0.0, 1.0, 0.0), -0.2)
+            * Quaternion::from_axis_angle(vector(1.0, 0.0, 0.0), 0.4);
+        assert_close(euler.rotate(p), by_axis.rotate(p));
+    }
+
+    #[test]
+    fn test_composition_and_inverse() {
+        let a = Transform::from_parts(
+            vector(4.0, -1.0, 2.0),
+            Quaternion::from_euler(0.3, 1.2, -0.7),
+            vector(1.5, 0.5, 2.0),
+        );
+        let b = Transform::rotation(Quaternion::from_axis_angle(vector(0.0, 1.0, 1.0), 2.0))
+            * Transform::translation(vector(0.0, 3.0, 0.0));
+        let p = vector(-1.0, 0.25, 7.0);
+
+        assert_close(
+            (a * b).transform_point(p),
+            a.transform_point(b.transform_point(p)),
+        );
+        assert_close(
+            (a * b)
+                .inverse()
+                .transform_point((a * b).transform_point(p)),
+            p,
+        );
+
+        // The inverse worked out alongside matches inverting the matrix directly
+        let inverse = (a * b).matrix.inverse().unwrap();
+        assert_close(
+            inverse.transform_point(p),
+            (a * b).inverse.transform_point(p),
+        );
+
+        assert!(Matrix4::scaling(vector(1.0, 0.0, 1.0)).inverse().is_none());
+        assert!((Matrix4::scaling(vector(2.0, 3.0, 4.0)).determinant() - 24.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tiny_and_huge_scales_invert() {
+        let p = vector(0.3, -2.0, 4.0);
+
+        for scale in [1e-4, 1e4] {
+            let matrix = Matrix4::translation(vector(100.0, -50.0, 20.0))
+                * Quaternion::from_euler(0.3, 1.2, -0.7).to_matrix()
+                * Matrix4::scaling(vector(scale, scale, scale));
+
+            // The determinant is scale^3, far below any fixed cut off for the tiny scale
+            let transform = Transform::from_matrix(matrix).expect("A uniform scale can be undone");
+            let there = transform.transform_point(p);
+            let back = transform.inverse().transform_point(there);
+
+            assert!((back - p).length() < 1e-9 * p.length(), "{back:?}");
+            assert!((there - matrix.transform_point(p)).length() < 1e-9 * there.length());
+        }
+
+        // Flattened, however big it is
+        let flattened =
+            Matrix4::translation(vector(1e6, 0.0, 0.0)) * Matrix4::scaling(vector(1e6, 1e6, 1e-20));
+        assert!(flattened.inverse().is_none());
+
+        // Two rows almost in the same direction, with a big determinant all the same
+        let mut parallel = Matrix4::scaling(vector(1e6, 1e6, 1e6));
+        parallel.rows[1] = parallel.rows[0].map(|v| v * 0.1);
+        parallel.rows[1][1] = 1e6 * 1e-14;
+        assert!(parallel.inverse().is_none());
+    }
+
+    #[test]
+    fn test_parts_round_trip() {
+        let p = vector(0.3, -2.0, 4.0);
+
+        for (translation, rotation, scale) in [
+            (
+                vector(1.0, 2.0, 3.0),
+                Quaternion::from_euler(0.3, 1.2, -0.7),
+                vector(1.5, 0.5, 2.0),
+            ),
+            // Half turns, where w is 0
+            (
+                vector(0.0, -4.0, 0.0),
+                Quaternion::from_axis_angle(vector(1.0, 1.0, 0.0), PI),
+                vector(1.0, 1.0, 1.0),
+            ),
+            // Mirrored
+            (
+                vector(-2.0, 0.0, 5.0),
+                Quaternion::from_axis_angle(vector(0.0, 0.0, 1.0), 2.5),
+                vector(-2.0, 3.0, 1.0),
+            ),
+        ] {
+            let transform = Transform::from_parts(translation, rotation, scale);
+            let (t, r, s) = transform.to_parts();
+
+            assert_close(t, translation);
+            assert_close(s, scale);
+            assert_close(r.rotate(p), rotation.rotate(p));
+            assert_close(
+                Transform::from_parts(t, r, s).transform_point(p),
+                transform.transform_point(p),
+            );
+        }
+    }
+
+    #[test]
+    fn test_slerp() {
+        let start = Quaternion::identity();
+        let end = Quaternion::from_axis_angle(vector(0.0, 1.0, 0.0), PI / 2.0);
+        let halfway = start.slerp(&end, 0.5);
+
+        assert_close(
+            halfway.rotate(vector(1.0, 0.0, 0.0)),
+            Quaternion::from_axis_angle(vector(0.0, 1.0, 0.0), PI / 4.0)
+                .rotate(vector(1.0, 0.0, 0.0)),
+        );
+        assert_close(
+            start.slerp(&end, 1.0).rotate(vector(0.0, 0.0, 1.0)),
+            end.rotate(vector(0.0, 0.0, 1.0)),
+        );
+    }
+}